@@ -1,9 +1,6 @@
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use rand::prelude::*;
-use BTrees::avltree::*;
-use BTrees::prelude::*;
-use BTrees::rbtree::*;
-use BTrees::tree::*;
+use BinaryTrees::avltree::*;
+use BinaryTrees::arena::{ArenaAVLTree, ArenaRedBlackTree};
 
 
 
@@ -16,10 +13,41 @@ fn bench_insert_test_avl(array:[i32;5],index:usize) {
 
 }
 
-fn bench_search_test_avl(tree:AVLTree<i32>,tree_size:i32) {
+fn bench_from_sorted_test_avl(array:[i32;5],index:usize) {
+    let tree_size = array[index];
+    let tree = AVLTree::from_sorted_iter(0..tree_size);
+    black_box(tree);
+}
+
+fn bench_search_test_avl(tree:&AVLTree<i32>,tree_size:i32) {
 
     for i in 0..tree_size/10 {
-        tree.search(i);
+        tree.search(&i);
+        }
+}
+
+fn bench_insert_test_arena_avl(array:[i32;5],index:usize) {
+    let tree_size = array[index];
+    let mut tree = ArenaAVLTree::new();
+    for i in 0..tree_size {
+        tree.insert(i);
+    }
+    black_box(tree);
+}
+
+fn bench_insert_test_arena_rb(array:[i32;5],index:usize) {
+    let tree_size = array[index];
+    let mut tree = ArenaRedBlackTree::new();
+    for i in 0..tree_size {
+        tree.insert(i);
+    }
+    black_box(tree);
+}
+
+fn bench_search_test_arena_avl(tree:&ArenaAVLTree<i32>,tree_size:i32) {
+
+    for i in 0..tree_size/10 {
+        black_box(tree.search(&i));
         }
 }
 
@@ -30,6 +58,13 @@ fn avl_benchmark_insert(c: &mut Criterion) {
     c.bench_function("Avl_insert_benchmark", |b| b.iter(|| bench_insert_test_avl(black_box(array),array_index)));
 }
 
+fn avl_benchmark_from_sorted(c: &mut Criterion) {
+    let array =[10000, 40000, 70000, 100000, 130000];
+    let array_index = 4; // 0 to 4 for different size test, change this manually for 5 tests
+    println!("Avl benchmark from_sorted_iter test with size {}",array[array_index]);
+    c.bench_function("Avl_from_sorted_benchmark", |b| b.iter(|| bench_from_sorted_test_avl(black_box(array),array_index)));
+}
+
 fn avl_benchmark_search(c: &mut Criterion) {
     let array =[10000, 40000, 70000, 100000, 130000];
     let array_index = 4; // 0 to 4 for different size test, change this manually for 5 tests
@@ -41,10 +76,29 @@ fn avl_benchmark_search(c: &mut Criterion) {
         tree.insert(i);
     }
 
-    c.bench_function("Avl_search_benchmark", |b| b.iter(|| bench_search_test_avl(black_box(tree.clone()),tree_size)));
+    c.bench_function("Avl_search_benchmark", |b| b.iter(|| bench_search_test_avl(black_box(&tree),tree_size)));
+}
+
+fn arena_benchmark_insert(c: &mut Criterion) {
+    let array =[10000, 40000, 70000, 100000, 130000];
+    let array_index = 4; // 0 to 4 for different size test, change this manually for 5 tests
+    println!("Arena benchmark insert test with size {}",array[array_index]);
+    c.bench_function("Arena_avl_insert_benchmark", |b| b.iter(|| bench_insert_test_arena_avl(black_box(array),array_index)));
+    c.bench_function("Arena_rb_insert_benchmark", |b| b.iter(|| bench_insert_test_arena_rb(black_box(array),array_index)));
+}
+
+fn arena_benchmark_search(c: &mut Criterion) {
+    let array =[10000, 40000, 70000, 100000, 130000];
+    let array_index = 4; // 0 to 4 for different size test, change this manually for 5 tests
+    println!("Arena benchmark search test with size {}",array[array_index]);
+
+    let tree_size = array[array_index];
+    let tree: ArenaAVLTree<i32> = (0..tree_size).collect();
+
+    c.bench_function("Arena_avl_search_benchmark", |b| b.iter(|| bench_search_test_arena_avl(black_box(&tree),tree_size)));
 }
 
-criterion_group!(benches, avl_benchmark_insert,avl_benchmark_search);
+criterion_group!(benches, avl_benchmark_insert,avl_benchmark_from_sorted,avl_benchmark_search,arena_benchmark_insert,arena_benchmark_search);
 criterion_main!(benches);
 
 
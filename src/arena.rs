@@ -0,0 +1,1541 @@
+//! Arena Trees
+//!
+//! Red black and AVL trees whose nodes live in one `Vec` and link to each other
+//! through `u32` indices. Removed nodes leave their slot on a freelist for the
+//! next insertion, so there is no allocation, reference count or lock per node.
+//!
+//! The balancing is the one of RedBlackTree and AVLTree; only the storage differs.
+//! The fix-ups are written out again here rather than shared through a node trait:
+//! the pointer trees walk `Shared` handles, borrowing each node through its cell and
+//! reaching parents through weak links, while here every node is reached through the
+//! one `&mut Arena`. A trait over both would have to thread the arena through every
+//! step and clone a handle wherever this module copies a `u32`. Keep each fix-up in step
+//! with its counterpart: ArenaRedBlackTree::try_insert() and try_delete() follow
+//! RedBlackTree::insert() and delete(), ArenaAVLTree::try_insert() and try_delete()
+//! follow AVLTree::insert() and delete().
+//!
+//! Links to vacant slots and missing nodes come back as an Error from the `try_`
+//! operations; the infallible ones panic with it.
+
+use core::fmt::{self, Debug};
+use core::cmp::{max, Ordering};
+use core::mem;
+use core::ops::{Index, IndexMut};
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::format;
+
+pub use crate::tree::{SimpleTreeTrait, Direction};
+pub use crate::rbtree::NodeColor;
+use crate::tree::{Comparator, NaturalOrder, Stdout};
+use crate::error::Error;
+
+/// Index of a node in an Arena
+pub type NodeId = u32;
+
+/// Slot of an Arena, holding a node or the next vacant slot
+#[derive(Clone, Debug)]
+enum Slot<N>{
+    Occupied(N),
+    Vacant(Option<NodeId>)
+}
+
+/// Node store handing out `u32` indices
+///
+/// Removed nodes put their slot on a freelist, which later insertions reuse
+#[derive(Clone, Debug)]
+pub struct Arena<N>{
+    slots: Vec<Slot<N>>,
+    /// Head of the freelist
+    free: Option<NodeId>,
+    len: usize
+}
+
+impl<N> Default for Arena<N>{
+    fn default()->Self{
+        Self::new()
+    }
+}
+
+impl<N> Arena<N>{
+    /// Create an empty Arena
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::Arena;
+    /// let arena: Arena<u32> = Arena::new();
+    /// ```
+    pub fn new()->Self{
+        Arena{slots: Vec::new(), free: None, len: 0}
+    }
+
+    /// Create an empty Arena with room for `capacity` nodes
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::Arena;
+    /// let arena: Arena<u32> = Arena::with_capacity(100);
+    /// assert!(arena.capacity() >= 100);
+    /// ```
+    pub fn with_capacity(capacity: usize)->Self{
+        Arena{slots: Vec::with_capacity(capacity), free: None, len: 0}
+    }
+
+    /// Store a node and return its index, reusing a vacant slot if there is one
+    ///
+    /// # Panic
+    /// More than `u32::MAX` slots, see try_insert()
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::Arena;
+    /// let mut arena = Arena::new();
+    /// let a = arena.insert(8);
+    /// arena.remove(a);
+    /// assert_eq!(arena.insert(9), a);
+    /// ```
+    pub fn insert(&mut self, node: N)->NodeId{
+        self.try_insert(node).unwrap_or_else(Error::raise)
+    }
+
+    /// Store a node and return its index, failing when every `u32` index is taken
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::Arena;
+    /// let mut arena = Arena::new();
+    /// assert_eq!(arena.try_insert(8), Ok(0));
+    /// ```
+    pub fn try_insert(&mut self, node: N)->Result<NodeId, Error>{
+        if let Some(id) = self.free{
+            let slot = mem::replace(&mut self.slots[id as usize], Slot::Occupied(node));
+            self.free = match slot{
+                Slot::Vacant(next)=>next,
+                Slot::Occupied(_)=>unreachable!()
+            };
+            self.len += 1;
+            return Ok(id);
+        }
+        let id = NodeId::try_from(self.slots.len()).map_err(|_| Error::ArenaFull)?;
+        self.slots.push(Slot::Occupied(node));
+        self.len += 1;
+        Ok(id)
+    }
+
+    /// Take the node at `id` out and put its slot on the freelist
+    ///
+    /// Return None, leaving the Arena as it was, if there is no node at `id`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::Arena;
+    /// let mut arena = Arena::new();
+    /// let a = arena.insert(8);
+    /// assert_eq!(arena.remove(a), Some(8));
+    /// assert_eq!(arena.remove(a), None);
+    /// ```
+    pub fn remove(&mut self, id: NodeId)->Option<N>{
+        let slot = self.slots.get_mut(id as usize)?;
+        if let Slot::Vacant(_) = slot{
+            return None;
+        }
+        let node = match mem::replace(slot, Slot::Vacant(self.free)){
+            Slot::Occupied(node)=>node,
+            Slot::Vacant(_)=>unreachable!()
+        };
+        self.free = Some(id);
+        self.len -= 1;
+        Some(node)
+    }
+
+    /// Get the node at `id`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::Arena;
+    /// let mut arena = Arena::new();
+    /// let a = arena.insert(8);
+    /// assert_eq!(arena.get(a), Some(&8));
+    /// ```
+    pub fn get(&self, id: NodeId)->Option<&N>{
+        match self.slots.get(id as usize){
+            Some(Slot::Occupied(node))=>Some(node),
+            _=>None
+        }
+    }
+
+    /// Mutably get the node at `id`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::Arena;
+    /// let mut arena = Arena::new();
+    /// let a = arena.insert(8);
+    /// *arena.get_mut(a).unwrap() = 9;
+    /// assert_eq!(arena[a], 9);
+    /// ```
+    pub fn get_mut(&mut self, id: NodeId)->Option<&mut N>{
+        match self.slots.get_mut(id as usize){
+            Some(Slot::Occupied(node))=>Some(node),
+            _=>None
+        }
+    }
+
+    /// Get the number of stored nodes
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::Arena;
+    /// let mut arena = Arena::new();
+    /// arena.insert(8);
+    /// assert_eq!(arena.len(), 1);
+    /// ```
+    pub fn len(&self)->usize{
+        self.len
+    }
+
+    /// Check if the Arena stores no node
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::Arena;
+    /// let arena: Arena<u32> = Arena::new();
+    /// assert!(arena.is_empty());
+    /// ```
+    pub fn is_empty(&self)->bool{
+        self.len == 0
+    }
+
+    /// Get the number of nodes the Arena holds without reallocating
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::Arena;
+    /// let arena: Arena<u32> = Arena::with_capacity(10);
+    /// assert!(arena.capacity() >= 10);
+    /// ```
+    pub fn capacity(&self)->usize{
+        self.slots.capacity()
+    }
+
+    /// Drop all nodes, keeping the allocation
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::Arena;
+    /// let mut arena = Arena::new();
+    /// arena.insert(8);
+    /// arena.clear();
+    /// assert!(arena.is_empty());
+    /// ```
+    pub fn clear(&mut self){
+        self.slots.clear();
+        self.free = None;
+        self.len = 0;
+    }
+}
+
+impl<N> Index<NodeId> for Arena<N>{
+    type Output = N;
+
+    fn index(&self, id: NodeId)->&N{
+        self.get(id).expect("Error! No node at this index!")
+    }
+}
+
+impl<N> IndexMut<NodeId> for Arena<N>{
+    fn index_mut(&mut self, id: NodeId)->&mut N{
+        self.get_mut(id).expect("Error! No node at this index!")
+    }
+}
+
+type Link = Option<NodeId>;
+
+/// Node of an ArenaTree
+#[derive(Clone, Debug)]
+struct TreeNode<T, B>{
+    value: T,
+    /// Color in the red black tree, height in the AVL tree
+    balance: B,
+    parent: Link,
+    left: Link,
+    right: Link
+}
+
+impl<T, B> TreeNode<T, B>{
+    fn child(&self, direction: &Direction)->Link{
+        match direction{
+            Direction::Left=>self.left,
+            Direction::Right=>self.right
+        }
+    }
+
+    fn set_child(&mut self, direction: &Direction, child: Link){
+        match direction{
+            Direction::Left=>self.left = child,
+            Direction::Right=>self.right = child
+        }
+    }
+}
+
+/// Struct of a tree stored in an Arena
+///
+/// `B` is the balancing information of the nodes, which picks the balancing:
+/// NodeColor for ArenaRedBlackTree and the `u32` height for ArenaAVLTree
+#[derive(Clone)]
+pub struct ArenaTree<T, B, C=NaturalOrder>{
+    arena: Arena<TreeNode<T, B>>,
+    root: Link,
+    /// Comparator ordering the values
+    comparator: C
+}
+
+/// Red black tree stored in an Arena
+pub type ArenaRedBlackTree<T, C=NaturalOrder> = ArenaTree<T, NodeColor, C>;
+
+/// AVL tree stored in an Arena
+pub type ArenaAVLTree<T, C=NaturalOrder> = ArenaTree<T, u32, C>;
+
+/// In-order iterator over an ArenaTree
+pub struct Iter<'a, T, B>{
+    arena: &'a Arena<TreeNode<T, B>>,
+    next: Link,
+    len: usize
+}
+
+impl<T: Debug, C: Comparator<T>> SimpleTreeTrait<T> for ArenaRedBlackTree<T, C>{
+    fn insert(&mut self, value: T)->bool{
+        ArenaRedBlackTree::<T, C>::insert(self, value)
+    }
+    fn delete(&mut self, value: &T)->Option<T>{
+        ArenaRedBlackTree::<T, C>::delete(self, value)
+    }
+    fn try_insert(&mut self, value: T)->Result<bool, Error>{
+        ArenaRedBlackTree::<T, C>::try_insert(self, value)
+    }
+    fn try_delete(&mut self, value: &T)->Result<Option<T>, Error>{
+        ArenaRedBlackTree::<T, C>::try_delete(self, value)
+    }
+    fn count_leaves(&self)->u32{
+        ArenaRedBlackTree::<T, C>::count_leaves(self)
+    }
+    fn is_empty(&self)->bool{
+        ArenaTree::is_empty(self)
+    }
+    #[cfg(feature = "std")]
+    fn print(&self, verbose: bool){
+        ArenaRedBlackTree::<T, C>::print(self, verbose)
+    }
+    fn height(&self)->u32{
+        ArenaRedBlackTree::<T, C>::height(self)
+    }
+    fn in_order_traverse(&self)->Vec<T> where T: Clone{
+        ArenaTree::in_order_traverse(self)
+    }
+}
+
+impl<T: Debug, C: Comparator<T>> SimpleTreeTrait<T> for ArenaAVLTree<T, C>{
+    fn insert(&mut self, value: T)->bool{
+        ArenaAVLTree::<T, C>::insert(self, value)
+    }
+    fn delete(&mut self, value: &T)->Option<T>{
+        ArenaAVLTree::<T, C>::delete(self, value)
+    }
+    fn try_insert(&mut self, value: T)->Result<bool, Error>{
+        ArenaAVLTree::<T, C>::try_insert(self, value)
+    }
+    fn try_delete(&mut self, value: &T)->Result<Option<T>, Error>{
+        ArenaAVLTree::<T, C>::try_delete(self, value)
+    }
+    fn count_leaves(&self)->u32{
+        ArenaAVLTree::<T, C>::count_leaves(self)
+    }
+    fn is_empty(&self)->bool{
+        ArenaTree::is_empty(self)
+    }
+    #[cfg(feature = "std")]
+    fn print(&self, verbose: bool){
+        ArenaAVLTree::<T, C>::print(self, verbose)
+    }
+    fn height(&self)->u32{
+        ArenaAVLTree::<T, C>::height(self)
+    }
+    fn in_order_traverse(&self)->Vec<T> where T: Clone{
+        ArenaTree::in_order_traverse(self)
+    }
+}
+
+impl<'a, T: Debug, B, C: Comparator<T>> IntoIterator for &'a ArenaTree<T, B, C>{
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T, B>;
+
+    fn into_iter(self)->Iter<'a, T, B>{
+        self.iter()
+    }
+}
+
+impl<T: Debug, B, C: Comparator<T>+Default> Default for ArenaTree<T, B, C>{
+    fn default()->Self{
+        Self::with_comparator(C::default())
+    }
+}
+
+impl<T: Debug, B, C: Comparator<T>> Extend<T> for ArenaTree<T, B, C> where Self: SimpleTreeTrait<T>{
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I){
+        for value in iter{
+            SimpleTreeTrait::insert(self, value);
+        }
+    }
+}
+
+impl<T: Debug, B, C: Comparator<T>+Default> FromIterator<T> for ArenaTree<T, B, C> where Self: SimpleTreeTrait<T>{
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I)->Self{
+        let mut tree = Self::default();
+        tree.extend(iter);
+        tree
+    }
+}
+
+impl<T: Ord+Debug, B> ArenaTree<T, B>{
+    /// Create a new tree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::{ArenaRedBlackTree, ArenaAVLTree};
+    /// let rbtree: ArenaRedBlackTree<u32> = ArenaRedBlackTree::new();
+    /// let avltree: ArenaAVLTree<u32> = ArenaAVLTree::new();
+    /// ```
+    pub fn new()->Self{
+        Self::with_comparator(NaturalOrder)
+    }
+
+    /// Create a new tree with room for `capacity` nodes
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let mut tree: ArenaAVLTree<u32> = ArenaAVLTree::with_capacity(1000);
+    /// tree.extend(0..1000);
+    /// ```
+    pub fn with_capacity(capacity: usize)->Self{
+        ArenaTree{arena: Arena::with_capacity(capacity), root: None, comparator: NaturalOrder}
+    }
+}
+
+impl<T: Debug, B, C: Comparator<T>> ArenaTree<T, B, C>{
+    /// Create a new tree ordered by `comparator`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaRedBlackTree;
+    /// let mut tree = ArenaRedBlackTree::with_comparator(|a: &u32, b: &u32| b.cmp(a));
+    /// tree.extend(vec![3, 8]);
+    /// assert_eq!(tree.in_order_traverse(), vec![8, 3]);
+    /// ```
+    pub fn with_comparator(comparator: C)->Self{
+        ArenaTree{arena: Arena::new(), root: None, comparator}
+    }
+
+    /// Get a reference to the value in the tree equal to `value`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaRedBlackTree;
+    /// let mut tree = ArenaRedBlackTree::new();
+    /// tree.insert(String::from("a"));
+    /// assert_eq!(tree.get(&String::from("a")).unwrap(), "a");
+    /// ```
+    pub fn get(&self, value: &T)->Option<&T>{
+        let id = self.find(value).unwrap_or_else(Error::raise)?;
+        Some(&self.arena[id].value)
+    }
+
+    /// Search a value in the tree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let mut tree = ArenaAVLTree::new();
+    /// tree.insert(8);
+    /// assert!(tree.search(&8));
+    /// ```
+    pub fn search(&self, value: &T)->bool{
+        self.find(value).unwrap_or_else(Error::raise).is_some()
+    }
+
+    /// Get the number of values in the tree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let tree: ArenaAVLTree<u32> = (1..10).collect();
+    /// assert_eq!(tree.len(), 9);
+    /// ```
+    pub fn len(&self)->usize{
+        self.arena.len()
+    }
+
+    /// Check if the tree is empty
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let tree: ArenaAVLTree<u32> = ArenaAVLTree::new();
+    /// assert!(tree.is_empty());
+    /// ```
+    pub fn is_empty(&self)->bool{
+        self.root.is_none()
+    }
+
+    /// Remove all values, keeping the allocated slots
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaRedBlackTree;
+    /// let mut tree: ArenaRedBlackTree<u32> = (1..10).collect();
+    /// tree.clear();
+    /// assert!(tree.is_empty());
+    /// ```
+    pub fn clear(&mut self){
+        self.arena.clear();
+        self.root = None;
+    }
+
+    /// Iterate over the values in order
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaRedBlackTree;
+    /// let tree: ArenaRedBlackTree<u32> = vec![3, 1, 2].into_iter().collect();
+    /// assert_eq!(tree.iter().cloned().collect::<Vec<_>>(), vec![1, 2, 3]);
+    /// ```
+    pub fn iter(&self)->Iter<'_, T, B>{
+        Iter{arena: &self.arena, next: self.root.map(|root| self.get_min_node(root)), len: self.len()}
+    }
+
+    /// In-order traverse of the tree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let tree: ArenaAVLTree<u32> = vec![3, 1, 2].into_iter().collect();
+    /// assert_eq!(tree.in_order_traverse(), vec![1, 2, 3]);
+    /// ```
+    pub fn in_order_traverse(&self)->Vec<T> where T: Clone{
+        self.iter().cloned().collect()
+    }
+
+    /// Get the node at `id`, failing on a link to a vacant slot
+    fn node(&self, id: NodeId)->Result<&TreeNode<T, B>, Error>{
+        self.arena.get(id).ok_or(Error::DanglingLink)
+    }
+
+    /// Mutably get the node at `id`, failing on a link to a vacant slot
+    fn node_mut(&mut self, id: NodeId)->Result<&mut TreeNode<T, B>, Error>{
+        self.arena.get_mut(id).ok_or(Error::DanglingLink)
+    }
+
+    /// Find the node holding `value`
+    fn find(&self, value: &T)->Result<Link, Error>{
+        let mut current = self.root;
+        while let Some(id) = current{
+            let node = self.node(id)?;
+            current = match self.comparator.compare(value, &node.value){
+                Ordering::Less=>node.left,
+                Ordering::Greater=>node.right,
+                Ordering::Equal=>return Ok(Some(id))
+            };
+        }
+        Ok(None)
+    }
+
+    /// Helper of try_insert()
+    ///
+    /// Attach `value` as a leaf with `balance`, or return None if it is already in the tree
+    fn attach(&mut self, value: T, balance: B)->Result<Option<NodeId>, Error>{
+        let mut parent = None;
+        let mut direction = Direction::Left;
+        let mut current = self.root;
+        while let Some(id) = current{
+            let node = self.node(id)?;
+            direction = match self.comparator.compare(&value, &node.value){
+                Ordering::Less=>Direction::Left,
+                Ordering::Greater=>Direction::Right,
+                Ordering::Equal=>return Ok(None)
+            };
+            parent = Some(id);
+            current = node.child(&direction);
+        }
+        let id = self.arena.try_insert(TreeNode{value, balance, parent, left: None, right: None})?;
+        match parent{
+            None=>self.root = Some(id),
+            Some(p)=>self.node_mut(p)?.set_child(&direction, Some(id))
+        }
+        Ok(Some(id))
+    }
+
+    /// Helper of try_delete()
+    ///
+    /// Splice out the node of `value`, or of its successor when it has two children,
+    /// in which case the successor's value takes its place.
+    /// Return the removed value, the balance of the spliced node,
+    /// the child that took the spliced node's place and that child's parent
+    fn detach(&mut self, value: &T)->Result<Option<(T, B, Link, Link)>, Error>{
+        let Some(target) = self.find(value)? else{
+            return Ok(None);
+        };
+        let mut spliced = target;
+        if let (Some(_), Some(right)) = (self.node(target)?.left, self.node(target)?.right){
+            spliced = right;
+            while let Some(left) = self.node(spliced)?.left{
+                spliced = left;
+            }
+        }
+        let node = self.node(spliced)?;
+        let (child, parent) = (node.left.or(node.right), node.parent);
+        if let Some(c) = child{
+            self.node_mut(c)?.parent = parent;
+        }
+        self.replace_child(parent, spliced, child)?;
+        let node = self.arena.remove(spliced).ok_or(Error::DanglingLink)?;
+        let removed = if spliced == target{
+            node.value
+        }
+        else{
+            mem::replace(&mut self.node_mut(target)?.value, node.value)
+        };
+        Ok(Some((removed, node.balance, child, parent)))
+    }
+
+    /// Put `new` in place of the child `old` of `parent`
+    fn replace_child(&mut self, parent: Link, old: NodeId, new: Link)->Result<(), Error>{
+        match parent{
+            None=>self.root = new,
+            Some(p)=>{
+                let node = self.node_mut(p)?;
+                if node.left == Some(old){
+                    node.left = new;
+                }
+                else{
+                    node.right = new;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Rotate `id` down to the side given by `direction`, lifting its other child
+    ///
+    /// Fails when there is no child to lift
+    fn rotate(&mut self, id: NodeId, direction: &Direction)->Result<(), Error>{
+        let lifted = self.node(id)?.child(&direction.opposite()).ok_or(Error::MissingChild)?;
+        let inner = self.node(lifted)?.child(direction);
+        self.node_mut(id)?.set_child(&direction.opposite(), inner);
+        if let Some(inner) = inner{
+            self.node_mut(inner)?.parent = Some(id);
+        }
+        let parent = self.node(id)?.parent;
+        self.replace_child(parent, id, Some(lifted))?;
+        let node = self.node_mut(lifted)?;
+        node.parent = parent;
+        node.set_child(direction, Some(id));
+        self.node_mut(id)?.parent = Some(lifted);
+        Ok(())
+    }
+
+    /// Side of its parent that `id` hangs on
+    fn direction_to_parent(&self, id: NodeId, parent: NodeId)->Result<Direction, Error>{
+        Ok(if self.node(parent)?.left == Some(id) {Direction::Left} else {Direction::Right})
+    }
+
+    fn get_min_node(&self, id: NodeId)->NodeId{
+        let mut id = id;
+        while let Some(left) = self.arena[id].left{
+            id = left;
+        }
+        id
+    }
+
+    /// Helper of height()
+    fn get_height(&self)->u32{
+        let mut height = 0;
+        let mut stack: Vec<(NodeId, u32)> = self.root.map(|root| (root, 1)).into_iter().collect();
+        while let Some((id, depth)) = stack.pop(){
+            height = max(height, depth);
+            let node = &self.arena[id];
+            stack.extend(node.left.iter().chain(node.right.iter()).map(|&child| (child, depth + 1)));
+        }
+        height
+    }
+
+    /// Helper of count_leaves()
+    fn get_leaves(&self)->u32{
+        let mut leaves = 0;
+        let mut stack: Vec<NodeId> = self.root.into_iter().collect();
+        while let Some(id) = stack.pop(){
+            let node = &self.arena[id];
+            if node.left.is_none() && node.right.is_none(){
+                leaves += 1;
+            }
+            stack.extend(node.left.iter().chain(node.right.iter()));
+        }
+        leaves
+    }
+
+    /// Helper of render()
+    ///
+    /// Write the structure sideways, the right sub-trees above, each node shown by `show`
+    fn render_with<W: fmt::Write+?Sized>(&self, out: &mut W, verbose: bool, show: impl Fn(&TreeNode<T, B>)->String)->fmt::Result{
+        let Some(root) = self.root else{
+            return writeln!(out, "Empty tree!");
+        };
+        if verbose{
+            for id in self.in_order_ids(){
+                let node = &self.arena[id];
+                writeln!(out, "(Index: {}, {}, Parent: {:?}, Left: {:?}, Right: {:?})", id, show(node),
+                    node.parent, node.left, node.right)?;
+            }
+        }
+        // reverse in-order walk, the right-most node first
+        let mut stack = Vec::new();
+        let mut current = Some((root, 0));
+        loop{
+            while let Some((id, depth)) = current{
+                stack.push((id, depth));
+                current = self.arena[id].right.map(|right| (right, depth + 1));
+            }
+            let Some((id, depth)) = stack.pop() else{
+                return Ok(());
+            };
+            writeln!(out, "{}{}", "    ".repeat(depth), show(&self.arena[id]))?;
+            current = self.arena[id].left.map(|left| (left, depth + 1));
+        }
+    }
+
+    fn in_order_ids(&self)->Vec<NodeId>{
+        let mut ids = Vec::with_capacity(self.len());
+        let mut next = self.root.map(|root| self.get_min_node(root));
+        while let Some(id) = next{
+            ids.push(id);
+            next = get_next_node(&self.arena, id);
+        }
+        ids
+    }
+
+    /// Helper of check_valid_into()
+    ///
+    /// Check that every link leads to a stored node, the parent links, the order of the values
+    /// and the number of nodes
+    fn check_links<W: fmt::Write+?Sized>(&self, out: &mut W)->bool{
+        if let Some(root) = self.root{
+            match self.arena.get(root){
+                None=>{
+                    report!(out, "Root {} is a vacant slot", root);
+                    return false;
+                },
+                Some(node) if node.parent.is_some()=>{
+                    report!(out, "Root {:?} has a parent", node.value);
+                    return false;
+                },
+                Some(_)=>{}
+            }
+        }
+        let mut stack: Vec<NodeId> = self.root.into_iter().collect();
+        let mut count = 0;
+        while let Some(id) = stack.pop(){
+            count += 1;
+            let node = &self.arena[id];
+            for &child in node.left.iter().chain(node.right.iter()){
+                match self.arena.get(child){
+                    None=>{
+                        report!(out, "Child {} of {:?} is a vacant slot", child, node.value);
+                        return false;
+                    },
+                    Some(c) if c.parent != Some(id)=>{
+                        report!(out, "Parent link of {:?} doesn't lead to its parent {:?}", c.value, node.value);
+                        return false;
+                    },
+                    Some(_)=>stack.push(child)
+                }
+            }
+            if count > self.len(){
+                report!(out, "More nodes are linked than stored");
+                return false;
+            }
+        }
+        if count != self.len(){
+            report!(out, "{} nodes are linked but {} are stored", count, self.len());
+            return false;
+        }
+        let ids = self.in_order_ids();
+        let (prev, next) = (|w: &[NodeId]| &self.arena[w[0]].value, |w: &[NodeId]| &self.arena[w[1]].value);
+        if let Some(w) = ids.windows(2).find(|w| self.comparator.compare(prev(w), next(w)) != Ordering::Less){
+            report!(out, "Order error: {:?} comes after {:?}", next(w), prev(w));
+            return false;
+        }
+        true
+    }
+}
+
+impl<T: Debug, C: Comparator<T>> ArenaRedBlackTree<T, C>{
+    /// Insert a value, return false if it was already in the tree
+    ///
+    /// # Panic
+    /// Broken structure or a full arena, see try_insert()
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaRedBlackTree;
+    /// let mut tree = ArenaRedBlackTree::new();
+    /// assert!(tree.insert(8));
+    /// assert!(!tree.insert(8));
+    /// ```
+    pub fn insert(&mut self, value: T)->bool{
+        self.try_insert(value).unwrap_or_else(Error::raise)
+    }
+
+    /// Insert a value, reporting a broken structure or a full arena instead of panicking
+    ///
+    /// The fix-up is the one of RedBlackTree::insert(). The tree may be left inconsistent after an error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaRedBlackTree;
+    /// let mut tree = ArenaRedBlackTree::new();
+    /// assert_eq!(tree.try_insert(8), Ok(true));
+    /// assert_eq!(tree.try_insert(8), Ok(false));
+    /// ```
+    pub fn try_insert(&mut self, value: T)->Result<bool, Error>{
+        let Some(mut id) = self.attach(value, NodeColor::Red)? else{
+            return Ok(false);
+        };
+        // a red node with a red parent moves the violation up or ends it by rotating
+        while let Some(parent) = self.red(self.node(id)?.parent)?{
+            // a red parent is never the root
+            let grandparent = self.node(parent)?.parent.ok_or(Error::MissingParent)?;
+            let side = self.direction_to_parent(parent, grandparent)?;
+            if let Some(uncle) = self.red(self.node(grandparent)?.child(&side.opposite()))?{
+                self.node_mut(parent)?.balance = NodeColor::Black;
+                self.node_mut(uncle)?.balance = NodeColor::Black;
+                self.node_mut(grandparent)?.balance = NodeColor::Red;
+                id = grandparent;
+                continue;
+            }
+            let mut parent = parent;
+            if self.node(parent)?.child(&side.opposite()) == Some(id){
+                self.rotate(parent, &side)?;
+                parent = id;
+            }
+            self.node_mut(parent)?.balance = NodeColor::Black;
+            self.node_mut(grandparent)?.balance = NodeColor::Red;
+            self.rotate(grandparent, &side.opposite())?;
+            break;
+        }
+        if let Some(root) = self.root{
+            self.node_mut(root)?.balance = NodeColor::Black;
+        }
+        Ok(true)
+    }
+
+    /// Delete a value, return it if it was in the tree
+    ///
+    /// # Panic
+    /// Broken structure, see try_delete()
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaRedBlackTree;
+    /// let mut tree: ArenaRedBlackTree<u32> = (1..10).collect();
+    /// assert_eq!(tree.delete(&5), Some(5));
+    /// assert_eq!(tree.delete(&5), None);
+    /// ```
+    pub fn delete(&mut self, value: &T)->Option<T>{
+        self.try_delete(value).unwrap_or_else(Error::raise)
+    }
+
+    /// Delete a value, reporting a broken structure instead of panicking
+    ///
+    /// The fix-up is the one of RedBlackTree::delete(). The tree may be left inconsistent after an error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaRedBlackTree;
+    /// let mut tree: ArenaRedBlackTree<u32> = (1..10).collect();
+    /// assert_eq!(tree.try_delete(&5), Ok(Some(5)));
+    /// assert_eq!(tree.try_delete(&5), Ok(None));
+    /// ```
+    pub fn try_delete(&mut self, value: &T)->Result<Option<T>, Error>{
+        let Some((removed, color, mut child, mut parent)) = self.detach(value)? else{
+            return Ok(None);
+        };
+        if color == NodeColor::Red{
+            return Ok(Some(removed));
+        }
+        // `child` is one black node short, push the shortage up or end it by rotating
+        while child != self.root && self.red(child)?.is_none(){
+            let p = parent.ok_or(Error::MissingParent)?;
+            let side = if self.node(p)?.left == child {Direction::Left} else {Direction::Right};
+            // the other side holds at least one more black node, so the sibling exists
+            let mut sibling = self.node(p)?.child(&side.opposite()).ok_or(Error::MissingChild)?;
+            if self.red(Some(sibling))?.is_some(){
+                self.node_mut(sibling)?.balance = NodeColor::Black;
+                self.node_mut(p)?.balance = NodeColor::Red;
+                self.rotate(p, &side)?;
+                sibling = self.node(p)?.child(&side.opposite()).ok_or(Error::MissingChild)?;
+            }
+            let near = self.red(self.node(sibling)?.child(&side))?;
+            let far = self.red(self.node(sibling)?.child(&side.opposite()))?;
+            if near.is_none() && far.is_none(){
+                self.node_mut(sibling)?.balance = NodeColor::Red;
+                child = Some(p);
+                parent = self.node(p)?.parent;
+                continue;
+            }
+            if let (Some(near), None) = (near, far){
+                self.node_mut(near)?.balance = NodeColor::Black;
+                self.node_mut(sibling)?.balance = NodeColor::Red;
+                self.rotate(sibling, &side.opposite())?;
+                sibling = self.node(p)?.child(&side.opposite()).ok_or(Error::MissingChild)?;
+            }
+            let color = self.node(p)?.balance;
+            self.node_mut(sibling)?.balance = color;
+            self.node_mut(p)?.balance = NodeColor::Black;
+            let far = self.node(sibling)?.child(&side.opposite()).ok_or(Error::MissingChild)?;
+            self.node_mut(far)?.balance = NodeColor::Black;
+            self.rotate(p, &side)?;
+            child = self.root;
+        }
+        if let Some(c) = child{
+            self.node_mut(c)?.balance = NodeColor::Black;
+        }
+        Ok(Some(removed))
+    }
+
+    /// Get height of the tree
+    ///
+    /// An empty tree has height 1, counting the NIL leaf as in RedBlackTree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaRedBlackTree;
+    /// let tree: ArenaRedBlackTree<u32> = (1..4).collect();
+    /// assert_eq!(tree.height(), 2);
+    /// ```
+    pub fn height(&self)->u32{
+        if self.is_empty() {1} else {self.get_height()}
+    }
+
+    /// Count number of leaves in the tree
+    ///
+    /// An empty tree has 2 NIL leaves as in RedBlackTree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaRedBlackTree;
+    /// let tree: ArenaRedBlackTree<u32> = (1..4).collect();
+    /// assert_eq!(tree.count_leaves(), 2);
+    /// ```
+    pub fn count_leaves(&self)->u32{
+        if self.is_empty() {2} else {self.get_leaves()}
+    }
+
+    /// Render the tree into `out`
+    ///
+    /// Write the structure sideways, the right sub-trees above, with `b` marking black nodes;
+    ///
+    /// Additional verbose information of the nodes if verbose is true
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaRedBlackTree;
+    /// let tree: ArenaRedBlackTree<u32> = (1..4).collect();
+    /// let mut text = String::new();
+    /// tree.render(&mut text, false).unwrap();
+    /// assert_eq!(text, "    3\n2b\n    1\n");
+    /// ```
+    pub fn render<W: fmt::Write+?Sized>(&self, out: &mut W, verbose: bool)->fmt::Result{
+        self.render_with(out, verbose, |node| match node.balance{
+            NodeColor::Red=>format!("{:?}", node.value),
+            NodeColor::Black=>format!("{:?}b", node.value)
+        })
+    }
+
+    /// Print the tree, see render()
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaRedBlackTree;
+    /// let tree: ArenaRedBlackTree<u32> = (1..4).collect();
+    /// tree.print(true);
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn print(&self, verbose: bool){
+        let _ = self.render(&mut Stdout, verbose);
+    }
+
+    /// Check whether the tree is a valid red black tree
+    ///
+    /// With the `std` feature why it isn't is printed, see check_valid_into()
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaRedBlackTree;
+    /// let tree: ArenaRedBlackTree<u32> = (1..100).collect();
+    /// assert!(tree.check_valid());
+    /// ```
+    pub fn check_valid(&self)->bool{
+        self.check_valid_into(&mut Stdout)
+    }
+
+    /// Check whether the tree is a valid red black tree, writing why it isn't into `out`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaRedBlackTree;
+    /// let tree: ArenaRedBlackTree<u32> = (1..100).collect();
+    /// let mut report = String::new();
+    /// assert!(tree.check_valid_into(&mut report));
+    /// assert!(report.is_empty());
+    /// ```
+    pub fn check_valid_into<W: fmt::Write+?Sized>(&self, out: &mut W)->bool{
+        if !self.check_links(out){
+            return false;
+        }
+        let root = match self.root{
+            None=>return true,
+            Some(root)=>root
+        };
+        // check_links() found every link stored
+        let is_red = |id: NodeId| self.arena[id].balance == NodeColor::Red;
+        if is_red(root){
+            report!(out, "Root node {:?} should be black", self.arena[root].value);
+            return false;
+        }
+        // black height of every NIL leaf, read off at the nodes missing a child
+        let mut black_height = None;
+        let mut stack = vec![(root, 1)];
+        while let Some((id, depth)) = stack.pop(){
+            let node = &self.arena[id];
+            for child in [node.left, node.right]{
+                match child{
+                    Some(c) if is_red(c)=>{
+                        if node.balance == NodeColor::Red{
+                            report!(out, "Red node {:?} has a red child {:?}", node.value, self.arena[c].value);
+                            return false;
+                        }
+                        stack.push((c, depth));
+                    },
+                    Some(c)=>stack.push((c, depth + 1)),
+                    None=>{
+                        let expected = *black_height.get_or_insert(depth);
+                        if expected != depth{
+                            report!(out, "Black nodes in the paths don't agree at {:?}: {} instead of {}", node.value, depth, expected);
+                            return false;
+                        }
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Get `link` back if it leads to a red node
+    fn red(&self, link: Link)->Result<Link, Error>{
+        match link{
+            Some(id) if self.node(id)?.balance == NodeColor::Red=>Ok(link),
+            _=>Ok(None)
+        }
+    }
+}
+
+impl<T: Debug, C: Comparator<T>> ArenaAVLTree<T, C>{
+    /// Insert a value, return false if it was already in the tree
+    ///
+    /// # Panic
+    /// Broken structure or a full arena, see try_insert()
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let mut tree = ArenaAVLTree::new();
+    /// assert!(tree.insert(8));
+    /// assert!(!tree.insert(8));
+    /// ```
+    pub fn insert(&mut self, value: T)->bool{
+        self.try_insert(value).unwrap_or_else(Error::raise)
+    }
+
+    /// Insert a value, reporting a broken structure or a full arena instead of panicking
+    ///
+    /// The fix-up is the one of AVLTree::insert(). The tree may be left inconsistent after an error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let mut tree = ArenaAVLTree::new();
+    /// assert_eq!(tree.try_insert(8), Ok(true));
+    /// assert_eq!(tree.try_insert(8), Ok(false));
+    /// ```
+    pub fn try_insert(&mut self, value: T)->Result<bool, Error>{
+        let Some(id) = self.attach(value, 1)? else{
+            return Ok(false);
+        };
+        let parent = self.node(id)?.parent;
+        self.rebalance_to_root(parent)?;
+        Ok(true)
+    }
+
+    /// Delete a value, return it if it was in the tree
+    ///
+    /// # Panic
+    /// Broken structure, see try_delete()
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let mut tree: ArenaAVLTree<u32> = (1..10).collect();
+    /// assert_eq!(tree.delete(&5), Some(5));
+    /// assert_eq!(tree.delete(&5), None);
+    /// ```
+    pub fn delete(&mut self, value: &T)->Option<T>{
+        self.try_delete(value).unwrap_or_else(Error::raise)
+    }
+
+    /// Delete a value, reporting a broken structure instead of panicking
+    ///
+    /// The fix-up is the one of AVLTree::delete(). The tree may be left inconsistent after an error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let mut tree: ArenaAVLTree<u32> = (1..10).collect();
+    /// assert_eq!(tree.try_delete(&5), Ok(Some(5)));
+    /// assert_eq!(tree.try_delete(&5), Ok(None));
+    /// ```
+    pub fn try_delete(&mut self, value: &T)->Result<Option<T>, Error>{
+        let Some((removed, _, _, parent)) = self.detach(value)? else{
+            return Ok(None);
+        };
+        self.rebalance_to_root(parent)?;
+        Ok(Some(removed))
+    }
+
+    /// Get height of the tree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let tree: ArenaAVLTree<u32> = (1..4).collect();
+    /// assert_eq!(tree.height(), 2);
+    /// ```
+    pub fn height(&self)->u32{
+        self.root.map_or(0, |root| self.arena[root].balance)
+    }
+
+    /// Count number of leaves in the tree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let tree: ArenaAVLTree<u32> = (1..4).collect();
+    /// assert_eq!(tree.count_leaves(), 2);
+    /// ```
+    pub fn count_leaves(&self)->u32{
+        self.get_leaves()
+    }
+
+    /// Render the tree into `out`
+    ///
+    /// Write the structure sideways, the right sub-trees above, with the height of each node;
+    ///
+    /// Additional verbose information of the nodes if verbose is true
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let tree: ArenaAVLTree<u32> = (1..4).collect();
+    /// let mut text = String::new();
+    /// tree.render(&mut text, false).unwrap();
+    /// assert_eq!(text, "    3(1)\n2(2)\n    1(1)\n");
+    /// ```
+    pub fn render<W: fmt::Write+?Sized>(&self, out: &mut W, verbose: bool)->fmt::Result{
+        self.render_with(out, verbose, |node| format!("{:?}({})", node.value, node.balance))
+    }
+
+    /// Print the tree, see render()
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let tree: ArenaAVLTree<u32> = (1..4).collect();
+    /// tree.print(true);
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn print(&self, verbose: bool){
+        let _ = self.render(&mut Stdout, verbose);
+    }
+
+    /// Check whether the tree is a valid AVL tree
+    ///
+    /// With the `std` feature why it isn't is printed, see check_valid_into()
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let tree: ArenaAVLTree<u32> = (1..100).collect();
+    /// assert!(tree.check_valid());
+    /// ```
+    pub fn check_valid(&self)->bool{
+        self.check_valid_into(&mut Stdout)
+    }
+
+    /// Check whether the tree is a valid AVL tree, writing why it isn't into `out`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::arena::ArenaAVLTree;
+    /// let tree: ArenaAVLTree<u32> = (1..100).collect();
+    /// let mut report = String::new();
+    /// assert!(tree.check_valid_into(&mut report));
+    /// assert!(report.is_empty());
+    /// ```
+    pub fn check_valid_into<W: fmt::Write+?Sized>(&self, out: &mut W)->bool{
+        if !self.check_links(out){
+            return false;
+        }
+        // check_links() found every link stored
+        let height = |link: Link| link.map_or(0, |id| self.arena[id].balance);
+        // children are checked before their parents, so their heights can be trusted
+        for id in self.post_order_ids(){
+            let node = &self.arena[id];
+            let (left, right) = (height(node.left), height(node.right));
+            if node.balance != max(left, right) + 1{
+                report!(out, "Height of {:?} is out of date", node.value);
+                return false;
+            }
+            if left.abs_diff(right) > 1{
+                report!(out, "{:?} is unbalanced", node.value);
+                return false;
+            }
+        }
+        true
+    }
+
+    fn node_height(&self, link: Link)->Result<u32, Error>{
+        link.map_or(Ok(0), |id| Ok(self.node(id)?.balance))
+    }
+
+    fn update_height(&mut self, id: NodeId)->Result<(), Error>{
+        let node = self.node(id)?;
+        let height = max(self.node_height(node.left)?, self.node_height(node.right)?) + 1;
+        self.node_mut(id)?.balance = height;
+        Ok(())
+    }
+
+    /// Rotate `id` towards `direction` and refresh the heights of the two moved nodes
+    fn rotate_updating(&mut self, id: NodeId, direction: &Direction)->Result<(), Error>{
+        self.rotate(id, direction)?;
+        self.update_height(id)?;
+        let lifted = self.node(id)?.parent.ok_or(Error::MissingParent)?;
+        self.update_height(lifted)
+    }
+
+    /// Refresh the heights from `start` up to the root, rotating where a node is unbalanced
+    ///
+    /// Fails when a child the heights promise is missing
+    fn rebalance_to_root(&mut self, start: Link)->Result<(), Error>{
+        let mut current = start;
+        while let Some(id) = current{
+            self.update_height(id)?;
+            let node = self.node(id)?;
+            let (left, right) = (self.node_height(node.left)?, self.node_height(node.right)?);
+            if left.abs_diff(right) > 1{
+                let high = if left > right {Direction::Left} else {Direction::Right};
+                let child = node.child(&high).ok_or(Error::MissingChild)?;
+                let child_node = self.node(child)?;
+                // a child leaning the other way is rotated first
+                if self.node_height(child_node.child(&high.opposite()))? > self.node_height(child_node.child(&high))?{
+                    self.rotate_updating(child, &high)?;
+                }
+                self.rotate_updating(id, &high.opposite())?;
+            }
+            current = self.node(id)?.parent;
+        }
+        Ok(())
+    }
+
+    fn post_order_ids(&self)->Vec<NodeId>{
+        // reversed root-right-left order
+        let mut ids = Vec::with_capacity(self.len());
+        let mut stack: Vec<NodeId> = self.root.into_iter().collect();
+        while let Some(id) = stack.pop(){
+            ids.push(id);
+            let node = &self.arena[id];
+            stack.extend(node.left.iter().chain(node.right.iter()));
+        }
+        ids.reverse();
+        ids
+    }
+}
+
+/// Get the in-order successor of `id`
+fn get_next_node<T, B>(arena: &Arena<TreeNode<T, B>>, id: NodeId)->Link{
+    if let Some(right) = arena[id].right{
+        let mut next = right;
+        while let Some(left) = arena[next].left{
+            next = left;
+        }
+        return Some(next);
+    }
+    let mut child = id;
+    let mut parent = arena[id].parent;
+    while let Some(p) = parent{
+        if arena[p].left == Some(child){
+            return Some(p);
+        }
+        child = p;
+        parent = arena[p].parent;
+    }
+    None
+}
+
+impl<'a, T, B> Iterator for Iter<'a, T, B>{
+    type Item = &'a T;
+
+    fn next(&mut self)->Option<&'a T>{
+        let id = self.next?;
+        self.next = get_next_node(self.arena, id);
+        self.len -= 1;
+        Some(&self.arena[id].value)
+    }
+
+    fn size_hint(&self)->(usize, Option<usize>){
+        (self.len, Some(self.len))
+    }
+}
+
+impl<T, B> ExactSizeIterator for Iter<'_, T, B>{}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::collections::BTreeSet;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn random_ops<B>(tree: &mut ArenaTree<i32, B>, check: fn(&ArenaTree<i32, B>)->bool, seed: u64)
+        where ArenaTree<i32, B>: SimpleTreeTrait<i32>{
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut expected = BTreeSet::new();
+        for i in 0..4000{
+            let v = rng.gen_range(0, 1000);
+            if rng.gen_bool(0.6){
+                assert_eq!(SimpleTreeTrait::insert(tree, v), expected.insert(v));
+            }
+            else{
+                assert_eq!(SimpleTreeTrait::delete(tree, &v), expected.take(&v));
+            }
+            if i % 500 == 0{
+                assert!(check(tree));
+            }
+        }
+        assert!(check(tree));
+        assert_eq!(tree.len(), expected.len());
+        assert_eq!(tree.in_order_traverse(), expected.iter().cloned().collect::<Vec<_>>());
+        for v in expected{
+            assert_eq!(SimpleTreeTrait::delete(tree, &v), Some(v));
+        }
+        assert!(check(tree));
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_rbtree(){
+        let mut tree = ArenaRedBlackTree::new();
+        random_ops(&mut tree, ArenaRedBlackTree::check_valid, 16);
+        assert_eq!(tree.height(), 1);
+        assert_eq!(tree.count_leaves(), 2);
+
+        let tree: ArenaRedBlackTree<i32> = (0..1023).collect();
+        assert!(tree.check_valid());
+        assert!(tree.height() <= 2 * 10);
+    }
+
+    #[test]
+    fn test_avltree(){
+        let mut tree = ArenaAVLTree::new();
+        random_ops(&mut tree, ArenaAVLTree::check_valid, 17);
+        assert_eq!(tree.height(), 0);
+        assert_eq!(tree.count_leaves(), 0);
+
+        // sorted input still ends up perfectly balanced
+        let tree: ArenaAVLTree<i32> = (0..1023).collect();
+        assert!(tree.check_valid());
+        assert_eq!(tree.height(), 10);
+        assert_eq!(tree.count_leaves(), 512);
+    }
+
+    #[test]
+    fn test_freelist(){
+        let mut tree: ArenaRedBlackTree<i32> = (0..100).collect();
+        let slots = tree.arena.slots.len();
+        // churn reuses the vacant slots instead of growing the arena
+        for round in 0..10{
+            for v in 0..100{
+                tree.delete(&v);
+                tree.insert(v + 1000 * round);
+            }
+            for v in 0..100{
+                tree.delete(&(v + 1000 * round));
+                tree.insert(v);
+            }
+        }
+        assert!(tree.check_valid());
+        assert_eq!(tree.arena.slots.len(), slots);
+
+        let mut arena = Arena::new();
+        let ids: Vec<NodeId> = (0..5).map(|v| arena.insert(v)).collect();
+        assert_eq!(arena.remove(ids[1]), Some(1));
+        assert_eq!(arena.remove(ids[3]), Some(3));
+        assert_eq!(arena.len(), 3);
+        // most recently freed first
+        assert_eq!(arena.insert(7), ids[3]);
+        assert_eq!(arena.insert(8), ids[1]);
+        assert_eq!(arena.insert(9), 5);
+        assert_eq!(arena[ids[1]], 8);
+    }
+
+    #[test]
+    fn test_remove_vacant(){
+        let mut arena = Arena::new();
+        let id = arena.insert(1);
+        assert_eq!(arena.remove(id), Some(1));
+        assert_eq!(arena.remove(id), None);
+        assert_eq!(arena.remove(id + 1), None);
+        // the freelist is left as it was
+        assert_eq!(arena.len(), 0);
+        assert_eq!(arena.insert(2), id);
+        assert_eq!(arena.insert(3), id + 1);
+    }
+
+    #[test]
+    fn test_dangling_link(){
+        let mut tree: ArenaRedBlackTree<i32> = (0..10).collect();
+        let root = tree.root.unwrap();
+        let left = tree.arena[root].left.unwrap();
+        tree.arena.remove(left);
+        let mut report = String::new();
+        assert!(!tree.check_valid_into(&mut report));
+        assert_eq!(report, format!("Child {} of 3 is a vacant slot\n", left));
+        assert_eq!(tree.try_insert(-1), Err(Error::DanglingLink));
+        assert_eq!(tree.try_delete(&0), Err(Error::DanglingLink));
+
+        let mut tree: ArenaAVLTree<i32> = (0..10).collect();
+        let root = tree.root.unwrap();
+        let right = tree.arena[root].right.unwrap();
+        tree.arena.remove(right);
+        assert!(!tree.check_valid());
+        assert_eq!(SimpleTreeTrait::try_insert(&mut tree, 100), Err(Error::DanglingLink));
+        assert_eq!(SimpleTreeTrait::try_delete(&mut tree, &9), Err(Error::DanglingLink));
+    }
+
+    #[test]
+    fn test_missing_child(){
+        // a black leaf without the sibling its black height needs
+        let mut tree: ArenaRedBlackTree<i32> = (0..3).collect();
+        let root = tree.root.unwrap();
+        let (left, right) = (tree.arena[root].left.unwrap(), tree.arena[root].right.unwrap());
+        tree.arena[left].balance = NodeColor::Black;
+        tree.arena[root].right = None;
+        tree.arena.remove(right);
+        let mut report = String::new();
+        assert!(!tree.check_valid_into(&mut report));
+        assert_eq!(report, "Black nodes in the paths don't agree at 0: 2 instead of 1\n");
+        assert_eq!(tree.try_delete(&0), Err(Error::MissingChild));
+    }
+
+    #[test]
+    #[should_panic(expected = "Link to a vacant arena slot")]
+    fn test_dangling_link_panics(){
+        let mut tree: ArenaRedBlackTree<i32> = (0..10).collect();
+        let root = tree.root.unwrap();
+        tree.arena.remove(root);
+        tree.insert(10);
+    }
+
+    #[test]
+    fn test_render(){
+        let tree: ArenaRedBlackTree<i32> = (1..6).collect();
+        let mut text = String::new();
+        tree.render(&mut text, false).unwrap();
+        assert_eq!(text, "        5\n    4b\n        3\n2b\n    1b\n");
+
+        let tree: ArenaAVLTree<i32> = (1..3).collect();
+        let mut text = String::new();
+        tree.render(&mut text, true).unwrap();
+        assert_eq!(text, "(Index: 0, 1(2), Parent: None, Left: None, Right: Some(1))\n\
+            (Index: 1, 2(1), Parent: Some(0), Left: None, Right: None)\n    2(1)\n1(2)\n");
+
+        let mut text = String::new();
+        ArenaAVLTree::<i32>::new().render(&mut text, false).unwrap();
+        assert_eq!(text, "Empty tree!\n");
+    }
+
+    #[test]
+    fn test_send_sync(){
+        fn assert_send_sync<X: Send+Sync>(){}
+        assert_send_sync::<ArenaRedBlackTree<i32>>();
+        assert_send_sync::<ArenaAVLTree<String>>();
+    }
+
+    #[test]
+    fn test_comparator(){
+        let mut tree = ArenaAVLTree::with_comparator(|a: &i32, b: &i32| b.cmp(a));
+        tree.extend(0..100);
+        assert!(tree.check_valid());
+        assert_eq!(tree.in_order_traverse(), (0..100).rev().collect::<Vec<_>>());
+        assert_eq!(tree.iter().len(), 100);
+    }
+}
@@ -2,170 +2,496 @@
 //!
 //! An implementation of AVL Tree
 
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::fmt::{Debug, Display};
-use std::cmp::max;
+use core::fmt;
+use core::fmt::Debug;
+use core::cmp::max;
+use core::mem;
+use core::cmp::Ordering;
+use core::ops::{Bound, RangeBounds};
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::string::String;
+use alloc::format;
 
 
 pub use crate::tree::{TreeTrait, TreeNodeTrait, Direction, SimpleTreeTrait};
-use crate::tree::{rotate, search_node, search_insert_point};
+use crate::tree;
+use crate::multiset::{MultiSet, MultiSetMap};
+use crate::error::Error;
+use crate::tree::{Augment, SetOperation, Stdout, InvariantViolation, NodeCell, Shared, Weak, Comparator, NaturalOrder, aggregate_range, rotate, search_node, search_insert_point, search_bound, precedes, get_min_node, detach_node, link_children,
+    ValueRef, ValueMut};
+#[cfg(feature = "std")]
+use crate::snapshot::{self, SnapshotError, SnapshotValue, TreeKind};
+#[cfg(feature = "std")]
+use std::io::{Read, Write};
+
+/// Node of the AVL tree
+#[derive(Clone, Debug)]
+pub struct TreeNode<T: Debug, V=(), A=()>{
+    value: T,
+    /// Data stored next to the value, `()` for AVLTree
+    data: V,
+    parent: ParentLink<T, V, A>,
+    left: TreeRoot<T, V, A>, 
+    right: TreeRoot<T, V, A>,
+    height: u32,
+    /// Number of nodes in the sub-tree rooted here
+    size: usize,
+    /// Summary of the sub-tree rooted here, `()` unless augmented
+    augmentation: A
+}
+
+type TreeRoot<T, V=(), A=()> = Option<Shared<NodeCell<TreeNode<T, V, A>>>>;
+/// Parent link of a node, which doesn't keep the parent alive
+type ParentLink<T, V=(), A=()> = Option<Weak<NodeCell<TreeNode<T, V, A>>>>;
+
+// the parent is left out, it follows from the children of the parent
+impl<T: Debug+PartialEq, V: PartialEq, A: PartialEq> PartialEq for TreeNode<T, V, A>{
+    fn eq(&self, other: &Self)->bool{
+        self.value == other.value
+            && self.data == other.data
+            && self.left == other.left
+            && self.right == other.right
+            && self.height == other.height
+            && self.size == other.size
+            && self.augmentation == other.augmentation
+    }
+}
 
-#[derive(Clone, Debug, PartialEq)]
-struct TreeNode<T: Ord+Copy+Debug+Display>{
-    pub value: T,
-    pub parent: TreeRoot<T>,
-    left: TreeRoot<T>, 
-    right: TreeRoot<T>,
-    height: u32
+impl<T: Debug, V, A> TreeNode<T, V, A>{
+    /// Unlink both children, used by tree::drop_tree
+    pub(crate) fn take_children(&mut self)->[TreeRoot<T, V, A>; 2]{
+        [self.left.take(), self.right.take()]
+    }
 }
 
-type TreeRoot<T> = Option<Rc<RefCell<TreeNode<T>>>>;
+/// Parts of a split: the smaller values, the node holding the key and the greater values
+type SplitParts<T, V, A> = (TreeRoot<T, V, A>, TreeRoot<T, V, A>, TreeRoot<T, V, A>);
+
+/// The detached node of the minimum of a tree and the rest of it
+type SplitFirst<T, V, A> = (TreeRoot<T, V, A>, TreeRoot<T, V, A>);
 
 /// Struct of AVLTree
 ///
 /// connected with private struct of AVL tree node
-#[derive(Clone, Debug, PartialEq)]
-pub struct AVLTree<T: Ord+Copy+Debug+Display> {
-    root: TreeRoot<T>
+#[derive(PartialEq)]
+pub struct AVLTree<T: Debug, C=NaturalOrder, A=()> {
+    root: TreeRoot<T, (), A>,
+    len: usize,
+    /// Comparator ordering the values
+    comparator: C
+}
+
+/// In-order iterator over an AVLTree
+pub type Iter<'a, T, A=()> = tree::Iter<'a, T, TreeNode<T, (), A>>;
+
+/// In-order iterator over a range of an AVLTree
+pub type Range<'a, T, A=()> = tree::Range<'a, T, TreeNode<T, (), A>>;
+
+/// Lazy set operation over two AVLTrees
+pub type SetIter<'a, T, C=NaturalOrder, A=()> = tree::SetIter<'a, T, TreeNode<T, (), A>, C>;
+
+/// Struct of AVLMap
+///
+/// An ordered map sharing the nodes and the rebalancing of AVLTree
+pub struct AVLMap<K: Debug, V, C=NaturalOrder> {
+    root: TreeRoot<K, V>,
+    len: usize,
+    /// Comparator ordering the keys
+    comparator: C
 }
 
-impl<T: Ord+Copy+Debug+Display> TreeTrait<T, TreeNode<T>> for AVLTree<T>{
-    fn root(&self)->TreeRoot<T>{
+/// Ordered multiset on the nodes and the rebalancing of AVLMap, see MultiSet
+pub type AVLMultiSet<T, C=NaturalOrder> = MultiSet<T, AVLMap<T, Vec<T>, C>>;
+
+/// In-order iterator over a AVLMultiSet yielding every duplicate
+pub type MultiIter<'a, T> = tree::MultiIter<'a, T, TreeNode<T, Vec<T>>>;
+
+impl<T: Debug, C: Comparator<T>, A: Augment<T>> TreeTrait<T, TreeNode<T, (), A>> for AVLTree<T, C, A>{
+    type Order = C;
+
+    fn root(&self)->TreeRoot<T, (), A>{
         self.root.clone()
     }
 
+    fn comparator(&self)->&C{
+        &self.comparator
+    }
+
+    fn len(&self)->usize{
+        self.len
+    }
+
     /// Check whether the AVL tree is valid
     ///
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::avltree::AVLTree;
+    /// use BinaryTrees::avltree::AVLTree;
+    /// use BinaryTrees::prelude::*;
     /// let mut avltree: AVLTree<u32> = AVLTree::new();
     /// avltree.insert(8);
-    /// println!("{}", avltree.check_valid());
+    /// let mut why = String::new();
+    /// assert!(avltree.check_valid_into(&mut why));
+    /// assert!(why.is_empty());
     /// ```
-    fn check_valid(&self)->bool{
-        if self.root.is_none(){
-            return true;
-        }
-        let vec = self.in_order_traverse();
-        let order = vec.iter().zip(vec.iter().skip(1))
-            .all(|(current, next)| current<next);
-        if !order{
-            println!("Order error");
-            return false;
-        }
-
-        self.root.clone().unwrap().borrow().is_balanced()
+    fn check_valid_into<W: fmt::Write+?Sized>(&self, out: &mut W)->bool{
+        check_valid_node(&self.root, &self.comparator, out)
     }
 }
 
-impl<T: Ord+Copy+Debug+Display> SimpleTreeTrait<T> for AVLTree<T>{
+impl<T: Debug, C: Comparator<T>, A: Augment<T>> SimpleTreeTrait<T> for AVLTree<T, C, A>{
     fn insert(&mut self, value: T)->bool{
-        AVLTree::<T>::insert(self, value)
+        AVLTree::<T, C, A>::insert(self, value)
+    }
+    fn delete(&mut self, value: &T)->Option<T>{
+        AVLTree::<T, C, A>::delete(self, value)
+    }
+    fn try_insert(&mut self, value: T)->Result<bool, Error>{
+        AVLTree::<T, C, A>::try_insert(self, value)
     }
-    fn delete(&mut self, value: T)->Option<T>{
-        AVLTree::<T>::delete(self, value)
+    fn try_delete(&mut self, value: &T)->Result<Option<T>, Error>{
+        AVLTree::<T, C, A>::try_delete(self, value)
     }
     fn count_leaves(&self)->u32{
-        AVLTree::<T>::count_leaves(self)
+        AVLTree::<T, C, A>::count_leaves(self)
     }
     fn is_empty(&self)->bool{
-        AVLTree::<T>::is_empty(self)
+        AVLTree::<T, C, A>::is_empty(self)
     }
+    #[cfg(feature = "std")]
     fn print(&self, verbose: bool){
-        AVLTree::<T>::print(self, verbose)
+        AVLTree::<T, C, A>::print(self, verbose)
     }
     fn height(&self)->u32{
-        AVLTree::<T>::height(self)
+        AVLTree::<T, C, A>::height(self)
+    }
+    fn in_order_traverse(&self)->Vec<T> where T: Clone{
+        AVLTree::<T, C, A>::in_order_traverse(self)
+    }
+}
+
+impl<'a, T: Debug, C: Comparator<T>, A: Augment<T>> IntoIterator for &'a AVLTree<T, C, A>{
+    type Item = tree::ValueRef<'a, T>;
+    type IntoIter = Iter<'a, T, A>;
+
+    fn into_iter(self)->Iter<'a, T, A>{
+        self.iter()
+    }
+}
+
+impl<T: Debug, C: Comparator<T>+Default, A: Augment<T>> Default for AVLTree<T, C, A>{
+    fn default()->Self{
+        Self::with_augment(C::default())
+    }
+}
+
+/// Deep copy, the clone owns new nodes so changing either tree leaves the other intact
+impl<T: Debug+Clone, C: Comparator<T>+Clone, A: Augment<T>> Clone for AVLTree<T, C, A>{
+    fn clone(&self)->Self{
+        let mut entries = self.iter().map(|value| (value.clone(), ()));
+        let mut tree = Self::with_augment(self.comparator.clone());
+        tree.set_subtree(build_sorted(&mut entries, self.len));
+        tree
+    }
+}
+
+impl<T: Debug, C, A> Drop for AVLTree<T, C, A>{
+    fn drop(&mut self){
+        tree::drop_tree(self.root.take(), TreeNode::take_children);
+    }
+}
+
+impl<T: Debug, C: Comparator<T>, A: Augment<T>> fmt::Display for AVLTree<T, C, A>{
+    /// Draw the structure of the tree, as print(false) does
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        self.render(f, false)
+    }
+}
+
+impl<T: Debug, C, A: Augment<T>> fmt::Debug for AVLTree<T, C, A>{
+    /// List every node under its parent, as print(true) does before drawing the structure
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        match &self.root{
+            None => writeln!(f, "Empty tree!"),
+            Some(root) => root.borrow().write_tree_as_fmt(f, "  ")
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: Debug+serde::Serialize, C: Comparator<T>, A: Augment<T>> serde::Serialize for AVLTree<T, C, A>{
+    /// Serialize the values in order as a sequence
+    fn serialize<S: serde::Serializer>(&self, serializer: S)->Result<S::Ok, S::Error>{
+        tree::serialize_sorted(self.iter(), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: Debug+serde::Deserialize<'de>, C: Comparator<T>+Default, A: Augment<T>> serde::Deserialize<'de> for AVLTree<T, C, A>{
+    /// Build the tree in linear time from a sequence of strictly increasing values
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D)->Result<Self, D::Error>{
+        let comparator = C::default();
+        let values = tree::deserialize_sorted(deserializer, &comparator)?;
+        Ok(Self::build_sorted_by(values, comparator))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Debug+SnapshotValue, C: Comparator<T>, A: Augment<T>> AVLTree<T, C, A>{
+    /// Write the tree as a binary snapshot, see the snapshot module for the format
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let avltree: AVLTree<u32> = (0..1000).collect();
+    /// let mut bytes = Vec::new();
+    /// avltree.write_snapshot(&mut bytes).unwrap();
+    /// println!("{} bytes", bytes.len());
+    /// ```
+    pub fn write_snapshot<W: Write>(&self, out: W)->Result<(), SnapshotError>{
+        snapshot::write_values(TreeKind::AVL, self.iter(), out)
+    }
+
+    /// Read a tree written by write_snapshot, building it in linear time
+    ///
+    /// Fails on truncated or corrupt data, snapshots of another kind of tree and
+    /// values that are not strictly increasing under the comparator.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let avltree: AVLTree<u32> = (0..1000).collect();
+    /// let mut bytes = Vec::new();
+    /// avltree.write_snapshot(&mut bytes).unwrap();
+    /// let copy: AVLTree<u32> = AVLTree::read_snapshot(bytes.as_slice()).unwrap();
+    /// assert_eq!(copy.len(), 1000);
+    /// ```
+    pub fn read_snapshot<R: Read>(input: R)->Result<Self, SnapshotError> where C: Default{
+        let comparator = C::default();
+        let values = snapshot::read_values(TreeKind::AVL, input, &comparator)?;
+        Ok(Self::build_sorted_by(values, comparator))
+    }
+}
+
+impl<T: Debug, C: Comparator<T>+Default, A: Augment<T>> FromIterator<T> for AVLTree<T, C, A>{
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I)->Self{
+        let comparator = C::default();
+        let mut values: Vec<T> = iter.into_iter().collect();
+        // sorted input skips straight to the linear build
+        if !values.is_sorted_by(|a, b| comparator.compare(a, b) != Ordering::Greater){
+            values.sort_by(|a, b| comparator.compare(a, b));
+        }
+        Self::build_sorted_by(values, comparator)
     }
-    fn in_order_traverse(&self)->Vec<T>{
-        AVLTree::<T>::in_order_traverse(self)
+}
+
+impl<T: Debug, C: Comparator<T>, A: Augment<T>> Extend<T> for AVLTree<T, C, A>{
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I){
+        let mut values: Vec<T> = iter.into_iter().collect();
+        if !values.is_sorted_by(|a, b| self.comparator.compare(a, b) != Ordering::Greater){
+            for value in values{
+                self.insert(value);
+            }
+            return;
+        }
+        // sorted input is built in linear time and joined in, keeping the values already present
+        values.dedup_by(|a, b| self.comparator.compare(a, b) == Ordering::Equal);
+        let len = values.len();
+        let other = build_sorted(&mut values.into_iter().map(|value| (value, ())), len);
+        let tree = merge(other, self.take_subtree(), &self.comparator).unwrap_or_else(Error::raise);
+        self.set_subtree(tree);
     }
 }
 
-impl <T: Ord+Copy+Debug+Display> AVLTree<T>{
+impl <T: Ord+Debug> AVLTree<T>{
     /// Create a new AVLTree
     ///
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::avltree::AVLTree;
+    /// use BinaryTrees::avltree::AVLTree;
     /// let mut avltree: AVLTree<u32> = AVLTree::new();
     /// ```
     pub fn new()->Self{
-        AVLTree{root: None}
+        Self::with_comparator(NaturalOrder)
+    }
+
+    /// Build a AVLTree from values in ascending order in O(n)
+    ///
+    /// Repeated values are kept once
+    ///
+    /// # Panic
+    /// the values are not sorted
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let avltree = AVLTree::from_sorted_iter(1..=100);
+    /// assert_eq!(avltree.len(), 100);
+    /// assert!(avltree.check_valid());
+    /// ```
+    pub fn from_sorted_iter<I: IntoIterator<Item = T>>(iter: I)->Self{
+        Self::from_sorted_iter_by(iter, NaturalOrder)
+    }
+}
+
+impl <T: Debug, C: Comparator<T>> AVLTree<T, C>{
+    /// Create a new AVLTree ordered by `comparator`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree = AVLTree::with_comparator(|a: &u32, b: &u32| b.cmp(a));
+    /// avltree.insert(3);
+    /// avltree.insert(8);
+    /// assert_eq!(avltree.in_order_traverse(), vec![8, 3]);
+    /// ```
+    pub fn with_comparator(comparator: C)->Self{
+        Self::with_augment(comparator)
+    }
+
+    /// Build a AVLTree from values sorted by `comparator` in O(n)
+    ///
+    /// Repeated values are kept once
+    ///
+    /// # Panic
+    /// the values are not sorted
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let avltree = AVLTree::from_sorted_iter_by((1..=100).rev(), |a: &u32, b: &u32| b.cmp(a));
+    /// assert_eq!(*avltree.first().unwrap(), 100);
+    /// assert!(avltree.check_valid());
+    /// ```
+    pub fn from_sorted_iter_by<I: IntoIterator<Item = T>>(iter: I, comparator: C)->Self{
+        Self::build_sorted_by(iter, comparator)
+    }
+}
+
+impl <T: Debug, C: Comparator<T>, A: Augment<T>> AVLTree<T, C, A>{
+    /// Create a new AVLTree ordered by `comparator`, keeping the summaries `A` in its nodes
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// use BinaryTrees::tree::{Augment, NaturalOrder};
+    /// struct Count(usize);
+    /// impl Augment<u32> for Count{
+    ///     fn identity()->Count{
+    ///         Count(0)
+    ///     }
+    ///     fn summarize(_value: &u32)->Count{
+    ///         Count(1)
+    ///     }
+    ///     fn combine(&self, other: &Count)->Count{
+    ///         Count(self.0 + other.0)
+    ///     }
+    /// }
+    /// let mut avltree: AVLTree<u32, NaturalOrder, Count> = AVLTree::with_augment(NaturalOrder);
+    /// avltree.insert(3);
+    /// avltree.insert(8);
+    /// assert_eq!(avltree.aggregate(..5).0, 1);
+    /// ```
+    pub fn with_augment(comparator: C)->Self{
+        AVLTree{root: None, len: 0, comparator}
+    }
+
+    /// Helper for from_sorted_iter_by() and from_iter()
+    fn build_sorted_by<I: IntoIterator<Item = T>>(iter: I, comparator: C)->Self{
+        let mut values: Vec<T> = iter.into_iter().collect();
+        values.dedup_by(|a, b| comparator.compare(a, b) == Ordering::Equal);
+        assert!(values.windows(2).all(|w| comparator.compare(&w[0], &w[1]) == Ordering::Less),
+            "Error! Values are not sorted!");
+        let len = values.len();
+        let mut entries = values.into_iter().map(|value| (value, ()));
+        let mut tree = Self::with_augment(comparator);
+        tree.set_subtree(build_sorted(&mut entries, len));
+        tree
     }
 
 
     /// Delete a node in the AVLTree
     ///
+    /// # Panic
+    /// Illegal cases for rotation, see try_delete()
+    ///
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::avltree::AVLTree;
+    /// use BinaryTrees::avltree::AVLTree;
     /// let mut avltree: AVLTree<u32> = AVLTree::new();
-    /// let deleted = avltree.delete(8);
+    /// let deleted = avltree.delete(&8);
     /// println!("{:?}", deleted.is_none());
     /// ```
-    pub fn delete(&mut self, value: T)->Option<T>{
-        let node = search_node(self.root.clone(), value);
-        if node.is_none(){
-            return None;
-        }
+    pub fn delete(&mut self, value: &T)->Option<T>{
+        self.try_delete(value).unwrap_or_else(Error::raise)
+    }
 
-        let (deleted, new_root) = delete_node(node.unwrap(), value);
-        if new_root.is_some(){
-            self.root = new_root.unwrap().clone();
-        }
-        return deleted;
+    /// Delete a node in the AVLTree, reporting a corrupted tree instead of panicking
+    ///
+    /// The tree may be poisoned after an error, see Error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = (0..10).collect();
+    /// assert_eq!(avltree.try_delete(&8), Ok(Some(8)));
+    /// assert_eq!(avltree.try_delete(&8), Ok(None));
+    /// ```
+    pub fn try_delete(&mut self, value: &T)->Result<Option<T>, Error>{
+        let Some(node) = search_node(self.root.clone(), value, &self.comparator).flatten() else{
+            return Ok(None);
+        };
+        let (deleted, _) = try_delete_node(&mut self.root, node)?;
+        self.len -= 1;
+        Ok(Some(deleted))
     }
 
     /// Insert a node to the AVLTree
     ///
     /// # Panic
-    /// Illegal cases for rotation
+    /// Illegal cases for rotation, see try_insert()
     ///
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::avltree::AVLTree;
+    /// use BinaryTrees::avltree::AVLTree;
     /// let mut avltree: AVLTree<u32> = AVLTree::new();
     /// let inserted = avltree.insert(8);
-    /// println!("{}", avltree.search(8));
+    /// println!("{}", avltree.search(&8));
     /// ```
     pub fn insert(&mut self, value:T)->bool{
-        if self.root.is_none(){
-            self.root = TreeNode::new_root(value);
-            return true;
-        }
-        let parent = search_insert_point(self.root.clone(), value);
-        if parent.is_none(){
-            return false;
-        }
-        let parent_nd = parent.clone().unwrap();
-        let nd = TreeNode::new_root(value);
-        nd.clone().unwrap().borrow_mut().set_parent(parent.clone());
-        if value > parent_nd.borrow().value{
-            parent_nd.borrow_mut().set_right(nd.clone());
-        }
-        else{
-            parent_nd.borrow_mut().set_left(nd.clone());
-        }
+        self.try_insert(value).unwrap_or_else(Error::raise)
+    }
 
-        let new_root = rebalance_helper(parent.clone());
-        if new_root.is_some(){
-            let new_root = new_root.unwrap();
-            let new_root_val = new_root.clone().unwrap().borrow().value;
-            let root_val = self.root.clone().unwrap().borrow().value;
-            if new_root_val != root_val{
-                self.root = new_root.clone();
-            }
+    /// Insert a node to the AVLTree, reporting a corrupted tree instead of panicking
+    ///
+    /// The tree may be poisoned after an error, see Error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// assert_eq!(avltree.try_insert(8), Ok(true));
+    /// assert_eq!(avltree.try_insert(8), Ok(false));
+    /// ```
+    pub fn try_insert(&mut self, value:T)->Result<bool, Error>{
+        let (new_root, old) = try_insert_entry(self.root.clone(), value, (), &self.comparator)?;
+        self.root = new_root;
+        if old.is_none(){
+            self.len += 1;
         }
-
-        return true;
-
+        Ok(old.is_none())
     }
 
     /// Get height of the AVLTree
@@ -173,7 +499,7 @@ impl <T: Ord+Copy+Debug+Display> AVLTree<T>{
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::avltree::AVLTree;
+    /// use BinaryTrees::avltree::AVLTree;
     /// let mut avltree: AVLTree<u32> = AVLTree::new();
     /// avltree.insert(8);
     /// println!("{}", avltree.height());
@@ -192,26 +518,26 @@ impl <T: Ord+Copy+Debug+Display> AVLTree<T>{
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::avltree::AVLTree;
+    /// use BinaryTrees::avltree::AVLTree;
     /// let mut avltree: AVLTree<u32> = AVLTree::new();
     /// avltree.insert(8);
     /// println!("{}", avltree.is_empty());
     /// ```
-    fn is_empty(&self)->bool{
-        TreeTrait::<T, TreeNode<T>>::is_empty(self)
+    pub fn is_empty(&self)->bool{
+        TreeTrait::<T, TreeNode<T, (), A>>::is_empty(self)
     }
     /// Count number of leaves in the AVLTree
     ///
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::avltree::AVLTree;
+    /// use BinaryTrees::avltree::AVLTree;
     /// let mut avltree: AVLTree<u32> = AVLTree::new();
     /// avltree.insert(8);
     /// println!("{}", avltree.count_leaves());
     /// ```
-    fn count_leaves(&self)->u32{
-        TreeTrait::<T, TreeNode<T>>::count_leaves(self)
+    pub fn count_leaves(&self)->u32{
+        TreeTrait::<T, TreeNode<T, (), A>>::count_leaves(self)
     }
     /// Print the information of the tree
     ///
@@ -222,13 +548,14 @@ impl <T: Ord+Copy+Debug+Display> AVLTree<T>{
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::avltree::AVLTree;
+    /// use BinaryTrees::avltree::AVLTree;
     /// let mut avltree: AVLTree<u32> = AVLTree::new();
     /// avltree.insert(8);
-    /// println!("{}", avltree.print(true));
+    /// avltree.print(true);
     /// ```
-    fn print(&self, verbose: bool){
-        TreeTrait::<T, TreeNode<T>>::print(self, verbose)
+    #[cfg(feature = "std")]
+    pub fn print(&self, verbose: bool){
+        TreeTrait::<T, TreeNode<T, (), A>>::print(self, verbose)
     }
     /// In-order traverse of the tree
     ///
@@ -237,82 +564,877 @@ impl <T: Ord+Copy+Debug+Display> AVLTree<T>{
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::avltree::AVLTree;
+    /// use BinaryTrees::avltree::AVLTree;
     /// let mut avltree: AVLTree<u32> = AVLTree::new();
     /// avltree.insert(8);
     /// avltree.insert(10);
-    /// println!("{}", avltree.in_order_traverse());
+    /// println!("{:?}", avltree.in_order_traverse());
     /// ```
-    fn in_order_traverse(&self)->Vec<T>{
-        TreeTrait::<T, TreeNode<T>>::in_order_traverse(self)
+    pub fn in_order_traverse(&self)->Vec<T> where T: Clone{
+        TreeTrait::<T, TreeNode<T, (), A>>::in_order_traverse(self)
     }
-}
-
 
-impl<T: Ord+Copy+Debug+Display> TreeNodeTrait<T> for TreeNode <T>{
-    fn left(&self)->TreeRoot<T>{
-        self.left.clone()
-    }
-    fn right(&self)->TreeRoot<T>{
-        self.right.clone()
-    }
-    fn parent(&self)->TreeRoot<T>{
-        self.parent.clone()
-    }
-    fn value(&self)->T{
-        self.value
+    /// Number of values in the AVLTree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(8);
+    /// assert_eq!(avltree.len(), 1);
+    /// ```
+    pub fn len(&self)->usize{
+        TreeTrait::<T, TreeNode<T, (), A>>::len(self)
     }
 
-    fn set_left(&mut self, v: TreeRoot<T>){
-        self.left = v
+    /// Lazy in-order iterator over the values of the AVLTree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(8);
+    /// avltree.insert(3);
+    /// let first = avltree.iter().next().unwrap();
+    /// assert_eq!(*first, 3);
+    /// ```
+    pub fn iter(&self)->Iter<'_, T, A>{
+        TreeTrait::<T, TreeNode<T, (), A>>::iter(self)
     }
-    fn set_right(&mut self, v: TreeRoot<T>){
-        self.right = v
+
+    /// Iterate over the values within `range` in order
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// for v in 1..10{
+    ///     avltree.insert(v);
+    /// }
+    /// let inner: Vec<u32> = avltree.range(3..6).map(|v| *v).collect();
+    /// assert_eq!(inner, vec![3, 4, 5]);
+    /// ```
+    pub fn range<R: RangeBounds<T>>(&self, range: R)->Range<'_, T, A>{
+        TreeTrait::<T, TreeNode<T, (), A>>::range(self, range)
     }
-    fn set_parent(&mut self, v: TreeRoot<T>){
-        self.parent = v
+
+    /// Combine the summaries of the values within `range` in order
+    ///
+    /// Uses the summaries kept in the nodes, O(log n)
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// use BinaryTrees::tree::{Augment, NaturalOrder};
+    /// struct Sum(u64);
+    /// impl Augment<u64> for Sum{
+    ///     fn identity()->Sum{
+    ///         Sum(0)
+    ///     }
+    ///     fn summarize(value: &u64)->Sum{
+    ///         Sum(*value)
+    ///     }
+    ///     fn combine(&self, other: &Sum)->Sum{
+    ///         Sum(self.0 + other.0)
+    ///     }
+    /// }
+    /// let avltree: AVLTree<u64, NaturalOrder, Sum> = (1..=100).collect();
+    /// assert_eq!(avltree.aggregate(10..=20).0, 165);
+    /// assert_eq!(avltree.aggregate(..).0, 5050);
+    /// ```
+    pub fn aggregate<R: RangeBounds<T>>(&self, range: R)->A{
+        aggregate_range(self.root.clone(), range, &self.comparator, TreeNode::augmentation)
     }
-    fn set_value(&mut self, v: T){
-        self.value = v;
+
+    /// Get the `k`-th smallest value, counting from 0
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(8);
+    /// avltree.insert(3);
+    /// assert_eq!(*avltree.select(1).unwrap(), 8);
+    /// assert!(avltree.select(2).is_none());
+    /// ```
+    pub fn select(&self, k: usize)->Option<ValueRef<'_, T>>{
+        TreeTrait::<T, TreeNode<T, (), A>>::select(self, k)
     }
 
-    fn structure_info(&self)->String{
-        let val = self.value.to_string();
-        return val;
+    /// Count the values smaller than `value`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(8);
+    /// avltree.insert(3);
+    /// assert_eq!(avltree.rank(&8), 1);
+    /// assert_eq!(avltree.rank(&9), 2);
+    /// ```
+    pub fn rank(&self, value: &T)->usize{
+        TreeTrait::<T, TreeNode<T, (), A>>::rank(self, value)
     }
 
-    fn fmt_info(&self)->String{
-        format!(
-            "(Value: {:?}, Height: {:?}, Is Leaf: {:?})",
-            self.value, self.height, self.is_leaf()
-        )
+    /// Get the greatest value not greater than `value`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(8);
+    /// avltree.insert(3);
+    /// assert_eq!(*avltree.floor(&5).unwrap(), 3);
+    /// ```
+    pub fn floor(&self, value: &T)->Option<ValueRef<'_, T>>{
+        TreeTrait::<T, TreeNode<T, (), A>>::floor(self, value)
     }
-}
 
-impl <T: Ord+Copy+Debug+Display> TreeNode<T>{
+    /// Get the smallest value not smaller than `value`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(8);
+    /// avltree.insert(3);
+    /// assert_eq!(*avltree.ceiling(&5).unwrap(), 8);
+    /// ```
+    pub fn ceiling(&self, value: &T)->Option<ValueRef<'_, T>>{
+        TreeTrait::<T, TreeNode<T, (), A>>::ceiling(self, value)
+    }
 
-    fn new_root(value: T)->TreeRoot<T>{
-        let nd = TreeNode{
-            value: value,
-            left: None,
-            right: None,
-            parent: None,
-            height: 1
-        };
-        Some(Rc::new(RefCell::new(nd)))
+    /// Get the greatest value smaller than `value`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(8);
+    /// avltree.insert(3);
+    /// assert_eq!(*avltree.predecessor(&8).unwrap(), 3);
+    /// ```
+    pub fn predecessor(&self, value: &T)->Option<ValueRef<'_, T>>{
+        TreeTrait::<T, TreeNode<T, (), A>>::predecessor(self, value)
     }
-    fn height(&self)->u32{
-        self.height
+
+    /// Get the smallest value greater than `value`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(8);
+    /// avltree.insert(3);
+    /// assert_eq!(*avltree.successor(&3).unwrap(), 8);
+    /// ```
+    pub fn successor(&self, value: &T)->Option<ValueRef<'_, T>>{
+        TreeTrait::<T, TreeNode<T, (), A>>::successor(self, value)
     }
 
-    fn is_balanced(&self)->bool{
-        let (left_height, right_height): (u32, u32) = self.get_children_height();
-        i32::abs(left_height as i32 - right_height as i32) < (2 as i32)
+    /// Get the minimum value
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(8);
+    /// avltree.insert(3);
+    /// assert_eq!(*avltree.first().unwrap(), 3);
+    /// ```
+    pub fn first(&self)->Option<ValueRef<'_, T>>{
+        TreeTrait::<T, TreeNode<T, (), A>>::first(self)
     }
 
-    fn get_children_height(&self)->(u32, u32){
-        let left_height = if let Some(ln)=self.left.clone(){
-            ln.borrow().height
+    /// Get the maximum value
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(8);
+    /// avltree.insert(3);
+    /// assert_eq!(*avltree.last().unwrap(), 8);
+    /// ```
+    pub fn last(&self)->Option<ValueRef<'_, T>>{
+        TreeTrait::<T, TreeNode<T, (), A>>::last(self)
+    }
+
+    /// Remove and return the minimum value
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(8);
+    /// avltree.insert(3);
+    /// assert_eq!(avltree.pop_first(), Some(3));
+    /// assert_eq!(avltree.pop_first(), Some(8));
+    /// assert_eq!(avltree.pop_first(), None);
+    /// ```
+    pub fn pop_first(&mut self)->Option<T>{
+        let node = search_bound(self.root.clone(), Bound::Unbounded, &Direction::Right, &self.comparator)?;
+        let (deleted, _) = delete_node(&mut self.root, node);
+        self.len -= 1;
+        Some(deleted)
+    }
+
+    /// Remove and return the maximum value
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(8);
+    /// avltree.insert(3);
+    /// assert_eq!(avltree.pop_last(), Some(8));
+    /// assert_eq!(avltree.pop_last(), Some(3));
+    /// assert_eq!(avltree.pop_last(), None);
+    /// ```
+    pub fn pop_last(&mut self)->Option<T>{
+        let node = search_bound(self.root.clone(), Bound::Unbounded, &Direction::Left, &self.comparator)?;
+        let (deleted, _) = delete_node(&mut self.root, node);
+        self.len -= 1;
+        Some(deleted)
+    }
+
+    /// Move the values not smaller than `key` into a new AVLTree
+    ///
+    /// Splits along the search path for `key` in O(log n)
+    ///
+    /// # Panic
+    /// A corrupted tree, see Error
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// for v in 1..10{
+    ///     avltree.insert(v);
+    /// }
+    /// let upper = avltree.split_off(&6);
+    /// assert_eq!(avltree.in_order_traverse(), vec![1, 2, 3, 4, 5]);
+    /// assert_eq!(upper.in_order_traverse(), vec![6, 7, 8, 9]);
+    /// ```
+    pub fn split_off(&mut self, key: &T)->Self where C: Clone{
+        let (less, found, rest) = split(self.take_subtree(), key, &self.comparator).unwrap_or_else(Error::raise);
+        let rest = match found{
+            Some(found)=>join(None, found, rest).unwrap_or_else(Error::raise),
+            None=>rest
+        };
+        let mut upper = Self::with_augment(self.comparator.clone());
+        upper.set_subtree(rest);
+        self.set_subtree(less);
+        upper
+    }
+
+    /// Move all values of `other` into the AVLTree, leaving `other` empty
+    ///
+    /// When all values of one tree are smaller than those of the other
+    /// the trees are joined in O(log n); otherwise they are merged like into_union
+    ///
+    /// # Panic
+    /// A corrupted tree, see Error
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// let mut other: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(1);
+    /// other.insert(2);
+    /// avltree.append(&mut other);
+    /// assert_eq!(avltree.in_order_traverse(), vec![1, 2]);
+    /// assert!(other.is_empty());
+    /// ```
+    pub fn append(&mut self, other: &mut Self){
+        let tree = merge(self.take_subtree(), other.take_subtree(), &self.comparator).unwrap_or_else(Error::raise);
+        self.set_subtree(tree);
+    }
+
+    /// Union with `other`, built by joining instead of re-inserting
+    ///
+    /// Trees whose values do not overlap are joined in O(log n),
+    /// otherwise the cost is O(m log(n/m + 1)) for sizes m <= n
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut a: AVLTree<u32> = AVLTree::new();
+    /// let mut b: AVLTree<u32> = AVLTree::new();
+    /// a.insert(1);
+    /// a.insert(2);
+    /// b.insert(2);
+    /// b.insert(3);
+    /// assert_eq!(a.into_union(b).in_order_traverse(), vec![1, 2, 3]);
+    /// ```
+    pub fn into_union(mut self, mut other: Self)->Self{
+        self.append(&mut other);
+        self
+    }
+
+    /// Intersection with `other`, built by joining instead of re-inserting
+    ///
+    /// # Panic
+    /// A corrupted tree, see Error
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut a: AVLTree<u32> = AVLTree::new();
+    /// let mut b: AVLTree<u32> = AVLTree::new();
+    /// a.insert(1);
+    /// a.insert(2);
+    /// b.insert(2);
+    /// b.insert(3);
+    /// assert_eq!(a.into_intersection(b).in_order_traverse(), vec![2]);
+    /// ```
+    pub fn into_intersection(mut self, mut other: Self)->Self{
+        let tree = combine(self.take_subtree(), other.take_subtree(), &self.comparator, SetOperation::Intersection)
+            .unwrap_or_else(Error::raise);
+        self.set_subtree(tree);
+        self
+    }
+
+    /// Values not in `other`, built by joining instead of re-inserting
+    ///
+    /// # Panic
+    /// A corrupted tree, see Error
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut a: AVLTree<u32> = AVLTree::new();
+    /// let mut b: AVLTree<u32> = AVLTree::new();
+    /// a.insert(1);
+    /// a.insert(2);
+    /// b.insert(2);
+    /// b.insert(3);
+    /// assert_eq!(a.into_difference(b).in_order_traverse(), vec![1]);
+    /// ```
+    pub fn into_difference(mut self, mut other: Self)->Self{
+        let tree = combine(self.take_subtree(), other.take_subtree(), &self.comparator, SetOperation::Difference)
+            .unwrap_or_else(Error::raise);
+        self.set_subtree(tree);
+        self
+    }
+
+    /// Values in exactly one of the trees, built by joining instead of re-inserting
+    ///
+    /// # Panic
+    /// A corrupted tree, see Error
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut a: AVLTree<u32> = AVLTree::new();
+    /// let mut b: AVLTree<u32> = AVLTree::new();
+    /// a.insert(1);
+    /// a.insert(2);
+    /// b.insert(2);
+    /// b.insert(3);
+    /// assert_eq!(a.into_symmetric_difference(b).in_order_traverse(), vec![1, 3]);
+    /// ```
+    pub fn into_symmetric_difference(mut self, mut other: Self)->Self{
+        let tree = combine(self.take_subtree(), other.take_subtree(), &self.comparator, SetOperation::SymmetricDifference)
+            .unwrap_or_else(Error::raise);
+        self.set_subtree(tree);
+        self
+    }
+
+    /// Lazily iterate over the values in either tree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut a: AVLTree<u32> = AVLTree::new();
+    /// let mut b: AVLTree<u32> = AVLTree::new();
+    /// a.insert(1);
+    /// b.insert(2);
+    /// let union: Vec<u32> = a.union(&b).map(|v| *v).collect();
+    /// assert_eq!(union, vec![1, 2]);
+    /// ```
+    pub fn union<'a>(&'a self, other: &'a Self)->SetIter<'a, T, C, A>{
+        TreeTrait::<T, TreeNode<T, (), A>>::union(self, other)
+    }
+
+    /// Lazily iterate over the values in both trees
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut a: AVLTree<u32> = AVLTree::new();
+    /// let mut b: AVLTree<u32> = AVLTree::new();
+    /// a.insert(1);
+    /// a.insert(2);
+    /// b.insert(2);
+    /// let both: Vec<u32> = a.intersection(&b).map(|v| *v).collect();
+    /// assert_eq!(both, vec![2]);
+    /// ```
+    pub fn intersection<'a>(&'a self, other: &'a Self)->SetIter<'a, T, C, A>{
+        TreeTrait::<T, TreeNode<T, (), A>>::intersection(self, other)
+    }
+
+    /// Lazily iterate over the values not in `other`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut a: AVLTree<u32> = AVLTree::new();
+    /// let mut b: AVLTree<u32> = AVLTree::new();
+    /// a.insert(1);
+    /// a.insert(2);
+    /// b.insert(2);
+    /// let only: Vec<u32> = a.difference(&b).map(|v| *v).collect();
+    /// assert_eq!(only, vec![1]);
+    /// ```
+    pub fn difference<'a>(&'a self, other: &'a Self)->SetIter<'a, T, C, A>{
+        TreeTrait::<T, TreeNode<T, (), A>>::difference(self, other)
+    }
+
+    /// Lazily iterate over the values in exactly one of the trees
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut a: AVLTree<u32> = AVLTree::new();
+    /// let mut b: AVLTree<u32> = AVLTree::new();
+    /// a.insert(1);
+    /// a.insert(2);
+    /// b.insert(2);
+    /// b.insert(3);
+    /// let either: Vec<u32> = a.symmetric_difference(&b).map(|v| *v).collect();
+    /// assert_eq!(either, vec![1, 3]);
+    /// ```
+    pub fn symmetric_difference<'a>(&'a self, other: &'a Self)->SetIter<'a, T, C, A>{
+        TreeTrait::<T, TreeNode<T, (), A>>::symmetric_difference(self, other)
+    }
+
+    /// Take the nodes out of the tree for join and split
+    fn take_subtree(&mut self)->TreeRoot<T, (), A>{
+        self.len = 0;
+        self.root.take()
+    }
+
+    /// Put the result of join and split into the tree
+    fn set_subtree(&mut self, root: TreeRoot<T, (), A>){
+        self.len = root.as_ref().map_or(0, |nd| nd.borrow().size);
+        self.root = root;
+    }
+
+    /// Search a node in the AVLTree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(8);
+    /// assert!(avltree.search(&8));
+    /// ```
+    pub fn search(&self, value: &T)->bool{
+        TreeTrait::<T, TreeNode<T, (), A>>::search(self, value)
+    }
+
+    /// Get a reference to the value in the AVLTree equal to `value`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<String> = AVLTree::new();
+    /// avltree.insert(String::from("a"));
+    /// assert_eq!(*avltree.get(&String::from("a")).unwrap(), "a");
+    /// ```
+    pub fn get(&self, value: &T)->Option<ValueRef<'_, T>>{
+        let node = search_node(self.root.clone(), value, &self.comparator)?.unwrap();
+        Some(ValueRef::new(node, |nd: &TreeNode<T, (), A>| &nd.value))
+    }
+
+    /// Check whether the AVL tree is valid
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let mut avltree: AVLTree<u32> = AVLTree::new();
+    /// avltree.insert(8);
+    /// assert!(avltree.check_valid());
+    /// ```
+    pub fn check_valid(&self)->bool{
+        TreeTrait::<T, TreeNode<T, (), A>>::check_valid(self)
+    }
+
+    /// Check every invariant of the tree, telling which one broke and at which values
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLTree;
+    /// let avltree: AVLTree<u32> = (0..100).collect();
+    /// assert_eq!(avltree.validate(), Ok(()));
+    /// ```
+    pub fn validate(&self)->Result<(), InvariantViolation<T>> where T: Clone{
+        validate_node(&self.root, &self.comparator, &T::clone)
+    }
+}
+
+impl<K: Debug, V, C: Comparator<K>+Default> Default for AVLMap<K, V, C>{
+    fn default()->Self{
+        Self::with_comparator(C::default())
+    }
+}
+
+impl<K: Debug, V, C> Drop for AVLMap<K, V, C>{
+    fn drop(&mut self){
+        tree::drop_tree(self.root.take(), TreeNode::take_children);
+    }
+}
+
+impl<K: Ord+Debug, V> AVLMap<K, V>{
+    /// Create a new AVLMap
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLMap;
+    /// let mut map: AVLMap<u32, String> = AVLMap::new();
+    /// ```
+    pub fn new()->Self{
+        Self::with_comparator(NaturalOrder)
+    }
+}
+
+impl<K: Debug, V, C: Comparator<K>> AVLMap<K, V, C>{
+    /// Create a new AVLMap ordered by `comparator`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLMap;
+    /// let mut map = AVLMap::with_comparator(|a: &String, b: &String| a.to_lowercase().cmp(&b.to_lowercase()));
+    /// map.insert(String::from("Key"), 1);
+    /// assert_eq!(*map.get(&String::from("KEY")).unwrap(), 1);
+    /// ```
+    pub fn with_comparator(comparator: C)->Self{
+        AVLMap{root: None, len: 0, comparator}
+    }
+
+    /// Insert a key-value pair to the AVLMap
+    ///
+    /// Return the old value if the key was already present
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLMap;
+    /// let mut map = AVLMap::new();
+    /// assert!(map.insert(8, "a").is_none());
+    /// assert_eq!(map.insert(8, "b"), Some("a"));
+    /// ```
+    pub fn insert(&mut self, key: K, value: V)->Option<V>{
+        let (new_root, old) = insert_entry(self.root.clone(), key, value, &self.comparator);
+        self.root = new_root;
+        if old.is_none(){
+            self.len += 1;
+        }
+        old
+    }
+
+    /// Remove a key from the AVLMap
+    ///
+    /// Return the value of the key if it was present
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLMap;
+    /// let mut map = AVLMap::new();
+    /// map.insert(8, "a");
+    /// assert_eq!(map.remove(&8), Some("a"));
+    /// assert_eq!(map.remove(&8), None);
+    /// ```
+    pub fn remove(&mut self, key: &K)->Option<V>{
+        self.remove_entry(key).map(|(_, value)| value)
+    }
+
+    /// Remove a key from the AVLMap
+    ///
+    /// Return the stored key and its value if it was present
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLMap;
+    /// let mut map = AVLMap::with_comparator(|a: &String, b: &String| a.to_lowercase().cmp(&b.to_lowercase()));
+    /// map.insert(String::from("Key"), 1);
+    /// assert_eq!(map.remove_entry(&String::from("KEY")), Some((String::from("Key"), 1)));
+    /// assert_eq!(map.remove_entry(&String::from("KEY")), None);
+    /// ```
+    pub fn remove_entry(&mut self, key: &K)->Option<(K, V)>{
+        let node = search_node(self.root.clone(), key, &self.comparator).flatten()?;
+        let entry = delete_node(&mut self.root, node);
+        self.len -= 1;
+        Some(entry)
+    }
+
+    /// Get a reference to the value of a key
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLMap;
+    /// let mut map = AVLMap::new();
+    /// map.insert(8, "a");
+    /// assert_eq!(*map.get(&8).unwrap(), "a");
+    /// ```
+    pub fn get(&self, key: &K)->Option<ValueRef<'_, V>>{
+        let node = search_node(self.root.clone(), key, &self.comparator)?.unwrap();
+        Some(ValueRef::new(node, |nd: &TreeNode<K, V>| &nd.data))
+    }
+
+    /// Get a mutable reference to the value of a key
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLMap;
+    /// let mut map = AVLMap::new();
+    /// map.insert(8, 1);
+    /// *map.get_mut(&8).unwrap() += 1;
+    /// assert_eq!(*map.get(&8).unwrap(), 2);
+    /// ```
+    pub fn get_mut(&mut self, key: &K)->Option<ValueMut<'_, V>>{
+        let node = search_node(self.root.clone(), key, &self.comparator)?.unwrap();
+        Some(ValueMut::new(node, |nd: &mut TreeNode<K, V>| &mut nd.data))
+    }
+
+    /// Check whether the AVLMap contains a key
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLMap;
+    /// let mut map = AVLMap::new();
+    /// map.insert(8, "a");
+    /// assert!(map.contains_key(&8));
+    /// ```
+    pub fn contains_key(&self, key: &K)->bool{
+        search_node(self.root.clone(), key, &self.comparator).is_some()
+    }
+
+    /// Number of entries in the AVLMap
+    pub fn len(&self)->usize{
+        self.len
+    }
+
+    /// Check if the AVLMap is empty
+    pub fn is_empty(&self)->bool{
+        self.root.is_none()
+    }
+
+    /// Check whether the underlying AVL tree is valid, writing why it isn't into `out`
+    pub fn check_valid_into<W: fmt::Write+?Sized>(&self, out: &mut W)->bool{
+        check_valid_node(&self.root, &self.comparator, out)
+    }
+
+    /// Check whether the underlying AVL tree is valid
+    ///
+    /// With the `std` feature why it isn't is printed, see check_valid_into()
+    pub fn check_valid(&self)->bool{
+        self.check_valid_into(&mut Stdout)
+    }
+}
+
+impl<T: Debug, C: Comparator<T>> MultiSetMap<T> for AVLMap<T, Vec<T>, C>{
+    type Order = C;
+    type Node = TreeNode<T, Vec<T>>;
+
+    fn with_comparator(comparator: C)->Self{
+        AVLMap::with_comparator(comparator)
+    }
+    fn comparator(&self)->&C{
+        &self.comparator
+    }
+    fn root(&self)->TreeRoot<T, Vec<T>>{
+        self.root.clone()
+    }
+    fn copies(node: &TreeNode<T, Vec<T>>)->&[T]{
+        &node.data
+    }
+    fn insert(&mut self, key: T, copies: Vec<T>)->Option<Vec<T>>{
+        AVLMap::insert(self, key, copies)
+    }
+    fn remove_entry(&mut self, key: &T)->Option<(T, Vec<T>)>{
+        AVLMap::remove_entry(self, key)
+    }
+    fn get(&self, key: &T)->Option<ValueRef<'_, Vec<T>>>{
+        AVLMap::get(self, key)
+    }
+    fn get_mut(&mut self, key: &T)->Option<ValueMut<'_, Vec<T>>>{
+        AVLMap::get_mut(self, key)
+    }
+    fn len(&self)->usize{
+        AVLMap::len(self)
+    }
+    fn check_valid_into<W: fmt::Write+?Sized>(&self, out: &mut W)->bool{
+        AVLMap::check_valid_into(self, out)
+    }
+}
+
+/// Helper for check_valid()
+fn check_valid_node<T: Debug, V, A: Augment<T>, C: Comparator<T>, W: fmt::Write+?Sized>(root: &TreeRoot<T, V, A>, cmp: &C,
+    out: &mut W)->bool{
+    match validate_node(root, cmp, &tree::debug_text){
+        Ok(()) => true,
+        Err(violation) => {
+            report!(out, "{}", violation);
+            false
+        }
+    }
+}
+
+/// Helper for validate() and check_valid()
+///
+/// Check every invariant of the AVL tree rooted at `root`, naming the values involved by `capture`
+fn validate_node<T: Debug, V, A: Augment<T>, C: Comparator<T>, U>(root: &TreeRoot<T, V, A>, cmp: &C,
+    capture: &impl Fn(&T)->U)->Result<(), InvariantViolation<U>>{
+    tree::validate_links_and_order(root.clone(), cmp, capture)?;
+    // height and size of every sub-tree, from the leaves up
+    tree::fold_post_order(root.clone(), &mut |nd: &TreeNode<T, V, A>, left: Option<(u32, usize)>, right|{
+        let ((left_height, left_size), (right_height, right_size)) = (left.unwrap_or((0, 0)), right.unwrap_or((0, 0)));
+        let height = max(left_height, right_height) + 1;
+        if nd.height != height{
+            return Err(InvariantViolation::StaleHeight{ node: capture(&nd.value), stored: nd.height, actual: height });
+        }
+        if left_height.abs_diff(right_height) > 1{
+            return Err(InvariantViolation::Unbalanced{ node: capture(&nd.value), left: left_height, right: right_height });
+        }
+        let size = left_size + right_size + 1;
+        if nd.size != size{
+            return Err(InvariantViolation::Size{ node: capture(&nd.value), stored: nd.size, actual: size });
+        }
+        Ok((height, size))
+    })?;
+    Ok(())
+}
+
+
+impl<T: Debug, V, A: Augment<T>> TreeNodeTrait<T> for TreeNode<T, V, A>{
+    fn left(&self)->TreeRoot<T, V, A>{
+        self.left.clone()
+    }
+    fn right(&self)->TreeRoot<T, V, A>{
+        self.right.clone()
+    }
+    fn parent(&self)->TreeRoot<T, V, A>{
+        self.parent.as_ref().and_then(Weak::upgrade)
+    }
+    fn value(&self)->&T{
+        &self.value
+    }
+
+    fn set_left(&mut self, v: TreeRoot<T, V, A>){
+        self.left = v
+    }
+    fn set_right(&mut self, v: TreeRoot<T, V, A>){
+        self.right = v
+    }
+    fn set_parent(&mut self, v: TreeRoot<T, V, A>){
+        self.parent = v.as_ref().map(Shared::downgrade)
+    }
+    fn set_value(&mut self, v: T){
+        self.value = v;
+    }
+    fn size(&self)->usize{
+        self.size
+    }
+    fn set_size(&mut self, v: usize){
+        self.size = v;
+    }
+    fn update_augmentation(&mut self){
+        let left = self.left.as_ref().map(|nd| nd.borrow());
+        let right = self.right.as_ref().map(|nd| nd.borrow());
+        self.augmentation = A::refresh(&self.value, left.as_ref().map(|nd| &nd.augmentation),
+            right.as_ref().map(|nd| &nd.augmentation));
+    }
+
+    fn structure_info(&self)->String{
+        let val = format!("{:?}", self.value);
+        val
+    }
+
+    fn fmt_info(&self)->String{
+        format!(
+            "(Value: {:?}, Height: {:?}, Is Leaf: {:?})",
+            self.value, self.height, self.is_leaf()
+        )
+    }
+
+    /// Label with the height and the balance factor, the right height minus the left one
+    fn dot_info(&self)->String{
+        let (left_height, right_height) = self.get_children_height();
+        format!("label=\"{}\\nh={} b={}\"",
+            tree::dot_escape(&format!("{:?}", self.value)), self.height, right_height as i64 - left_height as i64)
+    }
+}
+
+impl <T: Debug, V, A: Augment<T>> TreeNode<T, V, A>{
+
+    fn new_root(value: T, data: V)->TreeRoot<T, V, A>{
+        let nd = TreeNode{
+            augmentation: A::refresh(&value, None, None),
+            value,
+            data,
+            left: None,
+            right: None,
+            parent: None,
+            height: 1,
+            size: 1
+        };
+        Some(Shared::new(NodeCell::new(nd)))
+    }
+    fn height(&self)->u32{
+        self.height
+    }
+
+    /// Get the summary of the sub-tree rooted at the node
+    fn augmentation(&self)->&A{
+        &self.augmentation
+    }
+
+    fn is_balanced(&self)->bool{
+        let (left_height, right_height): (u32, u32) = self.get_children_height();
+        i32::abs(left_height as i32 - right_height as i32) < 2
+    }
+
+    fn get_children_height(&self)->(u32, u32){
+        let left_height = if let Some(ln)=self.left.clone(){
+            ln.borrow().height
         }
         else{
             0
@@ -323,159 +1445,391 @@ impl <T: Ord+Copy+Debug+Display> TreeNode<T>{
         else{
             0
         };
-        return (left_height, right_height);
+        (left_height, right_height)
+    }
+
+    fn update_height(&mut self){
+        let (left_height, right_height) = self.get_children_height();
+        self.height = max(left_height, right_height) + 1;
+    }
+
+    /// Exchange value and data with another node
+    ///
+    /// Used for deletion
+    fn swap_entry(&mut self, other: &mut Self){
+        mem::swap(&mut self.value, &mut other.value);
+        mem::swap(&mut self.data, &mut other.data);
+    }
+}
+
+
+/// Helper for insert()
+///
+/// Insert the value or replace the data of an existing value;
+/// Return the new root and the replaced data
+fn insert_entry<T: Debug, V, A: Augment<T>, C: Comparator<T>>(root: TreeRoot<T, V, A>, value: T, data: V, cmp: &C)->
+    (TreeRoot<T, V, A>, Option<V>){
+    try_insert_entry(root, value, data, cmp).unwrap_or_else(Error::raise)
+}
+
+/// New root of the tree and the data replaced by an insertion
+type Inserted<T, V, A> = (TreeRoot<T, V, A>, Option<V>);
+
+/// Helper for try_insert(), see insert_entry()
+///
+/// Fails when the rebalancing finds the tree corrupted
+fn try_insert_entry<T: Debug, V, A: Augment<T>, C: Comparator<T>>(root: TreeRoot<T, V, A>, value: T, data: V, cmp: &C)->
+    Result<Inserted<T, V, A>, Error>{
+    if root.is_none(){
+        return Ok((TreeNode::new_root(value, data), None));
+    }
+    if let Some(node) = search_node(root.clone(), &value, cmp).flatten(){
+        let old = mem::replace(&mut node.borrow_mut().data, data);
+        return Ok((root, Some(old)));
+    }
+    // a tree without the value always has a leaf to hang it from
+    let parent = search_insert_point(root.clone(), &value, cmp).ok_or(Error::MissingParent)?;
+    let is_right = cmp.compare(&value, &parent.borrow().value) == Ordering::Greater;
+    let nd = TreeNode::new_root(value, data);
+    if let Some(leaf) = &nd{
+        leaf.borrow_mut().set_parent(Some(parent.clone()));
+    }
+    if is_right{
+        parent.borrow_mut().set_right(nd);
+    }
+    else{
+        parent.borrow_mut().set_left(nd);
+    }
+
+    Ok((rebalance_helper(Some(parent))?, None))
+}
+
+/// Helper for delete()
+///
+/// Remove `node` from the tree and update its `root`;
+/// Return the removed value and data
+fn delete_node<T: Debug, V, A: Augment<T>>(root: &mut TreeRoot<T, V, A>, node: Shared<NodeCell<TreeNode<T, V, A>>>)->(T, V){
+    try_delete_node(root, node).unwrap_or_else(Error::raise)
+}
+
+/// Helper for try_delete(), see delete_node()
+///
+/// Fails when the rebalancing finds the tree corrupted. StillReferenced and TwoChildren
+/// are found before anything changes; after any other error the tree is poisoned
+fn try_delete_node<T: Debug, V, A: Augment<T>>(root: &mut TreeRoot<T, V, A>, node: Shared<NodeCell<TreeNode<T, V, A>>>)->
+    Result<(T, V), Error>{
+    let mut node = node;
+    // the node taken out of the tree, the right minimum of a node with two children
+    let right = node.borrow().right.clone();
+    let spliced = match (&node.borrow().left, right){
+        (Some(_), Some(right))=>get_min_node(right),
+        _=>node.clone()
+    };
+    // only its parent or `root` and the locals here may hold it
+    let holders = if Shared::ptr_eq(&spliced, &node) {3} else {2};
+    if Shared::strong_count(&spliced) > holders{
+        return Err(Error::StillReferenced);
     }
+    let (child, _direction) = spliced.borrow().get_child_delete_helper()?;
 
-    fn update_height(&mut self){
-        let (left_height, right_height) = self.get_children_height();
-        self.height = max(left_height, right_height) + 1;
+    // Two children
+    // => like BSTree, take the place of the right minimum and delete that node
+    if !Shared::ptr_eq(&spliced, &node){
+        node.borrow_mut().swap_entry(&mut spliced.borrow_mut());
     }
+    node = spliced;
+    let parent = node.borrow().parent();
+    node.borrow_mut().try_delete_node()?;
+    *root = match parent.is_some(){
+        true=>rebalance_helper(parent)?,
+        false=>child
+    };
+    // checked above, nothing else holds the node
+    let removed = Shared::try_unwrap(node).map_err(|_| Error::StillReferenced)?.into_inner();
+    Ok((removed.value, removed.data))
+}
 
-    fn check_balance_recurse(&self)->bool{
-        if self.left.is_some(){
-            if !self.left.clone().unwrap().borrow().is_balanced(){
-                return false;
-            }
-        }
-        if self.right.is_some(){
-            if !self.right.clone().unwrap().borrow().is_balanced(){
-                return false;
+/// Restore the balance from `root` up to the root of the tree
+///
+/// Return the root of the tree;
+/// Fails when a child the heights promise is missing
+fn rebalance_helper<T: Debug, V, A: Augment<T>>(root: TreeRoot<T, V, A>)->Result<TreeRoot<T, V, A>, Error>{
+    let Some(mut node) = root else{
+        return Ok(None);
+    };
+    loop{
+        node.borrow_mut().update_height();
+        node.borrow_mut().update_size();
+        if !node.borrow().is_balanced(){
+            let (left_height, right_height) = node.borrow().get_children_height();
+            let unbalanced = Some(node.clone());
+            // the node that takes the place of `node`, it may be the new root
+            node = if left_height<right_height{
+                let right = node.borrow().right.clone().ok_or(Error::MissingChild)?;
+                let (rlh, rrh) = right.borrow().get_children_height();
+                if rrh >= rlh{
+                    left_left_rotate(&unbalanced)
+                }
+                else{
+                    left_right_rotate(&unbalanced)
+                }
             }
+            else{
+                let left = node.borrow().left.clone().ok_or(Error::MissingChild)?;
+                let (llh, lrh) = left.borrow().get_children_height();
+                if llh >= lrh{
+                    right_right_rotate(&unbalanced)
+                }
+                else{
+                    right_left_rotate(&unbalanced)
+                }
+            };
         }
-        if !self.is_balanced(){
-            return false;
+        let p = node.borrow().parent();
+        match p{
+            None=>return Ok(Some(node)),
+            Some(p)=>node = p
         }
-        return true;
     }
 }
 
-
-fn delete_node<T: Ord+Copy+Debug+Display>(root: TreeRoot<T>, value: T)->(Option<T>, Option<TreeRoot<T>>){
-    if root.is_none(){
-        return (None, None);
-    }
-
-    let mut root = root.clone();
-    if root.clone().unwrap().borrow().value != value{
-        let _root = search_node(root.clone(), value);
-        if _root.is_none(){
-            return (None, None);
-        }
-        root = _root.unwrap();
+/// Join `left`, the detached node `mid` and `right` into one tree
+///
+/// All values of `left` must be smaller than `mid` and all values of `right` greater;
+/// Descends the spine of the taller tree to a sub-tree of about the other's height,
+/// hangs `mid` there and rebalances the path, O(|h(left) - h(right)| + 1);
+/// Fails when the heights show a tree was corrupted
+fn join<T: Debug, V, A: Augment<T>>(left: TreeRoot<T, V, A>, mid: Shared<NodeCell<TreeNode<T, V, A>>>, right: TreeRoot<T, V, A>)->
+    Result<TreeRoot<T, V, A>, Error>{
+    let height = |nd: &TreeRoot<T, V, A>| nd.as_ref().map_or(0, |nd| nd.borrow().height);
+    let (left_height, right_height) = (height(&left), height(&right));
+    if left_height <= right_height + 1 && right_height <= left_height + 1{
+        link_children(&mid, left, right);
+        mid.borrow_mut().update_height();
+        return Ok(Some(mid));
     }
-    let node = root.clone().unwrap();
-    
-    // Two children
-    // => like BSTree
-    if node.borrow().left.is_some() && node.borrow().right.is_some(){
-        let right_min = node.borrow().right.clone().unwrap().borrow().get_min();
-        let rchild = node.borrow().right.clone();
-        let (_v, r) = delete_node(rchild, right_min);
-        node.borrow_mut().value = right_min;
-        return (Some(value), r);
-    }
-    let parent = node.borrow_mut().parent.clone();
-    let (child, _direction) = node.borrow().get_child_delete_helper();
-    let ret0 = node.borrow_mut().delete_node();
-    let ret = match child.is_some(){
-        true=>rebalance_helper(child.clone()),
-        false=>rebalance_helper(parent.clone())
+    let (taller, shorter, direction) = match left_height > right_height{
+        true=>(left, right, Direction::Right),
+        false=>(right, left, Direction::Left)
     };
-    let r = match &ret{
-        None=>ret0,
-        _=> ret
+    let shorter_height = height(&shorter);
+    let child = |nd: &Shared<NodeCell<TreeNode<T, V, A>>>| match direction{
+        Direction::Left=>nd.borrow().left.clone(),
+        Direction::Right=>nd.borrow().right.clone()
     };
-    return (Some(value), r);
-}
-
-fn rebalance_helper<T: Ord+Copy+Debug+Display>(root: TreeRoot<T>)->Option<TreeRoot<T>> {
-    if root.is_none(){
-        return None;
+    // the parent links are weak, the root has to be held while descending
+    let root = taller.ok_or(Error::MissingChild)?;
+    let mut parent = root.clone();
+    let mut current = child(&parent);
+    while height(&current) > shorter_height + 1{
+        parent = current.ok_or(Error::MissingChild)?;
+        current = child(&parent);
     }
- 
-    let node = root.clone().unwrap();
-    node.borrow_mut().update_height();
-    if node.borrow().is_balanced(){
-        let par = node.borrow().parent.clone();
-        return rebalance_helper(par);
+    match direction{
+        Direction::Left=>{
+            link_children(&mid, shorter, current);
+            parent.borrow_mut().left = Some(mid.clone());
+        },
+        Direction::Right=>{
+            link_children(&mid, current, shorter);
+            parent.borrow_mut().right = Some(mid.clone());
+        }
     }
+    mid.borrow_mut().parent = Some(Shared::downgrade(&parent));
+    rebalance_helper(Some(mid))
+}
 
+/// Build a tree from the next `len` entries of the sorted `entries`
+///
+/// Linked by link_sorted; the sub-tree sizes differ by at most one, so their heights do too
+fn build_sorted<T: Debug, V, A: Augment<T>, I: Iterator<Item = (T, V)>>(entries: &mut I, len: usize)->TreeRoot<T, V, A>{
+    let nodes = entries.take(len).filter_map(|(value, data)| TreeNode::new_root(value, data)).collect();
+    tree::link_sorted(nodes, |node, _| node.borrow_mut().update_height())
+}
 
-    let (left_height, right_height) = node.borrow().get_children_height();
-    if left_height<right_height{
-        if node.borrow().right.is_none(){
-            panic!("Error!");
-        }
-        let (rlh, rrh) = node.borrow().right.clone().unwrap().borrow().get_children_height();
-        if rrh >= rlh{
-            left_left_rotate(&root.clone());
+/// Split `root` into the values smaller than `key`, the detached node holding `key`
+/// and the values greater than `key`
+///
+/// Walks down the search path for `key`, then joins the detached nodes back
+/// from the bottom up, O(log n)
+fn split<T: Debug, V, A: Augment<T>, C: Comparator<T>>(root: TreeRoot<T, V, A>, key: &T, cmp: &C)->Result<SplitParts<T, V, A>, Error>{
+    // the nodes on the path, each with the side it was left by and the other sub-tree
+    let mut path = Vec::new();
+    let mut current = root;
+    let (mut less, found, mut greater) = loop{
+        let Some(node) = current else{
+            break (None, None, None);
+        };
+        let (left, right) = detach_node(&node);
+        let ordering = cmp.compare(key, &node.borrow().value);
+        match ordering{
+            Ordering::Equal=>break (left, Some(node), right),
+            Ordering::Less=>{
+                current = left;
+                path.push((node, Direction::Left, right));
+            },
+            Ordering::Greater=>{
+                current = right;
+                path.push((node, Direction::Right, left));
+            }
         }
-        else{
-            left_right_rotate(&root.clone());
+    };
+    while let Some((node, direction, other)) = path.pop(){
+        match direction{
+            Direction::Left=>greater = join(greater, node, other)?,
+            Direction::Right=>less = join(other, node, less)?
         }
     }
-    else{
-        if node.borrow().left.is_none(){
-            panic!("Error!");
-        }
-        let (llh, lrh) = node.borrow().left.clone().unwrap().borrow().get_children_height();
-        if llh >= lrh{
-            right_right_rotate(&root.clone());
-        }
-        else{
-            right_left_rotate(&root.clone());
+    Ok((less, found, greater))
+}
+
+/// Split the minimum off the tree rooted at `root`
+///
+/// Walks down the left spine, then joins the detached nodes back from the bottom up;
+/// Return the detached node of the minimum and the rest of the tree
+fn split_first<T: Debug, V, A: Augment<T>>(root: TreeRoot<T, V, A>)->Result<SplitFirst<T, V, A>, Error>{
+    // the nodes on the left spine, each with its right sub-tree
+    let mut spine = Vec::new();
+    let mut current = root;
+    let (first, mut rest) = loop{
+        let Some(node) = current else{
+            break (None, None);
+        };
+        let (left, right) = detach_node(&node);
+        if left.is_none(){
+            break (Some(node), right);
         }
+        current = left;
+        spine.push((node, right));
+    };
+    while let Some((node, right)) = spine.pop(){
+        rest = join(rest, node, right)?;
     }
-    let ret = Some(node.borrow().parent.clone());
-    let p = node.borrow().parent.clone();
-    let retp = rebalance_helper(p);
-    if retp.is_none(){
-        return ret;
+    Ok((first, rest))
+}
+
+/// Join two trees where all values of `left` are smaller than those of `right`
+///
+/// The minimum of `right` is split off to join them
+fn concat<T: Debug, V, A: Augment<T>>(left: TreeRoot<T, V, A>, right: TreeRoot<T, V, A>)->Result<TreeRoot<T, V, A>, Error>{
+    match split_first(right)?{
+        (None, _)=>Ok(left),
+        (Some(first), rest)=>join(left, first, rest)
     }
-    else{
-        return retp;
+}
+
+/// Union of two trees, joined directly when their values do not overlap
+fn merge<T: Debug, V, A: Augment<T>, C: Comparator<T>>(left: TreeRoot<T, V, A>, right: TreeRoot<T, V, A>, cmp: &C)->
+    Result<TreeRoot<T, V, A>, Error>{
+    if precedes(&left, &right, cmp){
+        return concat(left, right);
+    }
+    if precedes(&right, &left, cmp){
+        return concat(right, left);
+    }
+    combine(left, right, cmp, SetOperation::Union)
+}
+
+/// Pending work of combine
+enum CombineStep<T: Debug, V, A>{
+    /// Combine the two sub-trees and push the result
+    Visit(TreeRoot<T, V, A>, TreeRoot<T, V, A>),
+    /// Pop the combined lower and upper parts and join them, through the node when it is kept
+    Join(Shared<NodeCell<TreeNode<T, V, A>>>, bool)
+}
+
+/// Set `operation` of two trees, keeping the entries of `right` on equal values
+///
+/// Splits `left` by the root of `right` and joins the results for both sides,
+/// O(m log(n/m + 1)) for trees of sizes m <= n; the halves wait on an explicit
+/// stack instead of recursing
+fn combine<T: Debug, V, A: Augment<T>, C: Comparator<T>>(left: TreeRoot<T, V, A>, right: TreeRoot<T, V, A>, cmp: &C,
+    operation: SetOperation)->Result<TreeRoot<T, V, A>, Error>{
+    let mut steps = vec![CombineStep::Visit(left, right)];
+    let mut results = Vec::new();
+    while let Some(step) = steps.pop(){
+        match step{
+            CombineStep::Visit(None, right)=>results.push(match operation{
+                SetOperation::Union | SetOperation::SymmetricDifference=>right,
+                SetOperation::Intersection | SetOperation::Difference=>None
+            }),
+            CombineStep::Visit(left, None)=>results.push(match operation{
+                SetOperation::Intersection=>None,
+                _=>left
+            }),
+            CombineStep::Visit(left, Some(node))=>{
+                let (right_left, right_right) = detach_node(&node);
+                let (less, found, greater) = split(left, &node.borrow().value, cmp)?;
+                let keep = match operation{
+                    SetOperation::Union=>true,
+                    SetOperation::Intersection=>found.is_some(),
+                    SetOperation::Difference=>false,
+                    SetOperation::SymmetricDifference=>found.is_none()
+                };
+                steps.push(CombineStep::Join(node, keep));
+                steps.push(CombineStep::Visit(greater, right_right));
+                steps.push(CombineStep::Visit(less, right_left));
+            },
+            CombineStep::Join(node, keep)=>{
+                // both halves were visited after the Join was pushed, so their results are on top
+                let (Some(upper), Some(lower)) = (results.pop(), results.pop()) else{
+                    unreachable!()
+                };
+                results.push(match keep{
+                    true=>join(lower, node, upper)?,
+                    false=>concat(lower, upper)?
+                });
+            }
+        }
     }
+    Ok(results.pop().flatten())
 }
 
-fn left_rotate<T: Ord+Copy+Debug+Display>(root: &TreeRoot<T>){
+fn left_rotate<T: Debug, V, A: Augment<T>>(root: &TreeRoot<T, V, A>)->Shared<NodeCell<TreeNode<T, V, A>>>{
     let right = root.clone().unwrap().borrow().right.clone();
-    rotate(&root, &right);
+    rotate(root, &right)
 }
 
-fn right_rotate<T: Ord+Copy+Debug+Display>(root: &TreeRoot<T>){
+fn right_rotate<T: Debug, V, A: Augment<T>>(root: &TreeRoot<T, V, A>)->Shared<NodeCell<TreeNode<T, V, A>>>{
     let left = root.clone().unwrap().borrow().left.clone();
-    rotate(&root, &left);
+    rotate(root, &left)
 }
 
-fn left_left_rotate<T: Ord+Copy+Debug+Display>(root: &TreeRoot<T>){
-    left_rotate(&root);
+fn left_left_rotate<T: Debug, V, A: Augment<T>>(root: &TreeRoot<T, V, A>)->Shared<NodeCell<TreeNode<T, V, A>>>{
+    let top = left_rotate(root);
     root.clone().unwrap().borrow_mut().update_height();
-    root.clone().unwrap().borrow().parent.clone().unwrap().borrow_mut().update_height();
+    top.borrow_mut().update_height();
+    top
 }
 
-fn right_right_rotate<T: Ord+Copy+Debug+Display>(root: &TreeRoot<T>){
-    right_rotate(&root);
+fn right_right_rotate<T: Debug, V, A: Augment<T>>(root: &TreeRoot<T, V, A>)->Shared<NodeCell<TreeNode<T, V, A>>>{
+    let top = right_rotate(root);
     root.clone().unwrap().borrow_mut().update_height();
-    root.clone().unwrap().borrow().parent.clone().unwrap().borrow_mut().update_height();
+    top.borrow_mut().update_height();
+    top
 }
 
-fn left_right_rotate<T: Ord+Copy+Debug+Display>(root: &TreeRoot<T>){
+fn left_right_rotate<T: Debug, V, A: Augment<T>>(root: &TreeRoot<T, V, A>)->Shared<NodeCell<TreeNode<T, V, A>>>{
     let right = root.clone().unwrap().borrow().right();
     right_right_rotate(&right);
-    left_left_rotate(&root);
+    left_left_rotate(root)
 }
 
-fn right_left_rotate<T: Ord+Copy+Debug+Display>(root: &TreeRoot<T>){
+fn right_left_rotate<T: Debug, V, A: Augment<T>>(root: &TreeRoot<T, V, A>)->Shared<NodeCell<TreeNode<T, V, A>>>{
     let left = root.clone().unwrap().borrow().left.clone();
     left_left_rotate(&left);
-    right_right_rotate(&root);
+    right_right_rotate(root)
 }
 
 
 #[cfg(test)]
 mod test{
     use super::*;
-    fn check_valid_insert(tree: &AVLTree<i32>, value: i32, pre_delete_vec: &mut Vec<i32>){
+    use std::collections::{BTreeMap, BTreeSet};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn check_valid_insert(tree: &AVLTree<i32>, value: i32, pre_delete_vec: &[i32]){
         let mut vec = tree.in_order_traverse();
         assert!(tree.check_valid());
         assert!(vec.len()==pre_delete_vec.len()+1);
@@ -483,11 +1837,11 @@ mod test{
         assert!(vec.iter().zip(&mut pre_delete_vec.iter()).filter(|&(a, b)| a != b).count()==0);
     }
     fn check_valid_delete(tree: &AVLTree<i32>, expect: Option<i32>, result: Option<i32>, pre_delete_vec: &mut Vec<i32>){
-        let mut vec = tree.in_order_traverse();
+        let vec = tree.in_order_traverse();
         assert!(tree.check_valid());
         match expect{
             Some(value)=>{
-                assert!(result.is_some() && result.clone().unwrap() == value);
+                assert!(result.is_some() && result.unwrap() == value);
                 assert!(vec.len()==pre_delete_vec.len()-1);
                 pre_delete_vec.retain(|&x| x != value);
             },
@@ -501,7 +1855,7 @@ mod test{
     #[test]
     fn test_insert1(){
         // insert new
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         assert!(tree.insert(5));
         assert!(tree.root.is_some() && tree.root.clone().unwrap().borrow().value==5);
     }
@@ -509,71 +1863,71 @@ mod test{
     #[test]
     fn test_insert2(){
         // insert one
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         tree.insert(5);
-        let mut vec:Vec::<i32> = vec![5];
+        let vec:Vec::<i32> = vec![5];
         assert!(tree.insert(8));
-        check_valid_insert(&tree, 8, &mut vec);
+        check_valid_insert(&tree, 8, &vec);
     }
 
     #[test]
     fn test_insert3(){
         // RR
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         tree.insert(5);
         tree.insert(6);
-        let mut vec:Vec::<i32> = vec![5, 6];
+        let vec:Vec::<i32> = vec![5, 6];
         assert!(tree.insert(8));
-        check_valid_insert(&tree, 8, &mut vec);
+        check_valid_insert(&tree, 8, &vec);
     }
 
     #[test]
     fn test_insert4(){
         // LL
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         tree.insert(5);
         tree.insert(4);
-        let mut vec:Vec::<i32> = vec![4, 5];
+        let vec:Vec::<i32> = vec![4, 5];
         assert!(tree.insert(3));
-        check_valid_insert(&tree, 3, &mut vec);
+        check_valid_insert(&tree, 3, &vec);
     }
 
     #[test]
     fn test_insert5(){
         // LR
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         tree.insert(5);
         tree.insert(2);
-        let mut vec:Vec::<i32> = vec![2, 5];
+        let vec:Vec::<i32> = vec![2, 5];
         assert!(tree.insert(3));
-        check_valid_insert(&tree, 3, &mut vec);
+        check_valid_insert(&tree, 3, &vec);
     }
 
     #[test]
     fn test_insert6(){
         // RL
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         tree.insert(5);
         tree.insert(9);
-        let mut vec:Vec::<i32> = vec![5, 9];
+        let vec:Vec::<i32> = vec![5, 9];
         assert!(tree.insert(6));
-        check_valid_insert(&tree, 6, &mut vec);
+        check_valid_insert(&tree, 6, &vec);
     }
 
     #[test]
     fn test_insert7(){
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         tree.insert(5);
         tree.insert(2);
-        let mut vec:Vec::<i32> = vec![2, 5];
+        let vec:Vec::<i32> = vec![2, 5];
         assert!(tree.insert(8));
-        check_valid_insert(&tree, 8, &mut vec);
+        check_valid_insert(&tree, 8, &vec);
     }
 
 
     #[test]
     fn test_insert8(){
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         tree.insert(5);
         tree.insert(2);
         tree.insert(8);
@@ -584,65 +1938,67 @@ mod test{
     #[test]
     fn test_delete1(){
         // root
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         tree.insert(8);
-        let d = tree.delete(8);
-        assert!(d.is_some() && d.clone().unwrap() == 8);
+        let d = tree.delete(&8);
+        assert!(d.is_some() && d.unwrap() == 8);
         assert!(tree.is_empty());
     }
 
     #[test]
     fn test_delete2(){
         // LL
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         tree.insert(5);
         tree.insert(8);
         tree.insert(2);
         tree.insert(1);
         let mut vec = tree.in_order_traverse();
-        let d = tree.delete(1);
+        let d = tree.delete(&1);
         check_valid_delete(&tree, Some(1), d, &mut vec);
     }
 
     #[test]
     fn test_delete3(){
         // RR
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         tree.insert(5);
         tree.insert(8);
         tree.insert(2);
         tree.insert(10);
         let mut vec = tree.in_order_traverse();
-        let d = tree.delete(10);
+        let d = tree.delete(&10);
         check_valid_delete(&tree, Some(10), d, &mut vec);
     }
 
     #[test]
     fn test_delete4(){
         // LR
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         tree.insert(5);
         tree.insert(8);
         tree.insert(2);
         tree.insert(1);
         tree.insert(3);
         let mut vec = tree.in_order_traverse();
-        let d = tree.delete(1);
+        let d = tree.delete(&1);
         check_valid_delete(&tree, Some(1), d, &mut vec);
     }
 
     #[test]
     fn test_delete5(){
         // RL
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         tree.insert(5);
         tree.insert(8);
         tree.insert(2);
         tree.insert(6);
         tree.insert(10);
         let mut vec = tree.in_order_traverse();
+        #[cfg(feature = "std")]
         tree.print(true);
-        let d = tree.delete(10);
+        let d = tree.delete(&10);
+        #[cfg(feature = "std")]
         tree.print(true);
         check_valid_delete(&tree, Some(10), d, &mut vec);
     }
@@ -650,28 +2006,1055 @@ mod test{
     #[test]
     fn test_delete6(){
         // invalid
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         tree.insert(8);
         tree.insert(80);
-        assert!(tree.delete(25).is_none());
+        assert!(tree.delete(&25).is_none());
     }
 
     #[test]
     fn test_delete7(){
         // two children
-        let mut tree: AVLTree<i32> = AVLTree{root: None};
+        let mut tree: AVLTree<i32> = AVLTree{root: None, len: 0, comparator: NaturalOrder};
         tree.insert(5);
         tree.insert(8);
         tree.insert(2);
         tree.insert(6);
         tree.insert(10);
         let mut vec = tree.in_order_traverse();
+        #[cfg(feature = "std")]
         tree.print(true);
-        let d = tree.delete(5);
+        let d = tree.delete(&5);
+        #[cfg(feature = "std")]
         tree.print(true);
         check_valid_delete(&tree, Some(5), d, &mut vec);
     }
 
+    #[test]
+    fn test_random_insert_delete(){
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut tree = AVLTree::new();
+        let mut expect = BTreeSet::new();
+        for _ in 0..2000{
+            let v: i32 = rng.gen_range(0, 200);
+            if rng.gen_bool(0.6){
+                assert_eq!(tree.insert(v), expect.insert(v));
+            }
+            else{
+                assert_eq!(tree.delete(&v), expect.take(&v));
+            }
+            assert!(tree.check_valid());
+            assert_eq!(tree.len(), expect.len());
+        }
+        assert_eq!(tree.in_order_traverse(), expect.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_map(){
+        let mut map = AVLMap::new();
+        assert!(map.is_empty());
+        assert!(map.insert(5, String::from("five")).is_none());
+        assert!(map.insert(2, String::from("two")).is_none());
+        assert_eq!(map.insert(5, String::from("FIVE")), Some(String::from("five")));
+        assert_eq!(map.len(), 2);
+        assert_eq!(*map.get(&5).unwrap(), "FIVE");
+        assert!(map.get(&7).is_none());
+        map.get_mut(&2).unwrap().push('!');
+        assert_eq!(*map.get(&2).unwrap(), "two!");
+        assert!(map.contains_key(&2));
+        assert_eq!(map.remove(&2), Some(String::from("two!")));
+        assert!(!map.contains_key(&2));
+        assert!(map.remove(&2).is_none());
+        assert_eq!(map.len(), 1);
+        assert!(map.check_valid());
+    }
+
+    #[test]
+    fn test_map_random(){
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut map = AVLMap::new();
+        let mut expect = BTreeMap::new();
+        for i in 0..2000{
+            let k: i32 = rng.gen_range(0, 200);
+            if rng.gen_bool(0.6){
+                assert_eq!(map.insert(k, i), expect.insert(k, i));
+            }
+            else{
+                assert_eq!(map.remove(&k), expect.remove(&k));
+            }
+            assert!(map.check_valid());
+            assert_eq!(map.len(), expect.len());
+        }
+        for (k, v) in expect.iter(){
+            assert_eq!(*map.get(k).unwrap(), *v);
+        }
+    }
+
+    #[test]
+    fn test_owned_values(){
+        let mut tree = AVLTree::new();
+        for word in ["pear", "apple", "fig", "kiwi", "banana", "cherry"]{
+            assert!(tree.insert(word.to_string()));
+        }
+        assert!(!tree.insert(String::from("fig")));
+        assert_eq!(*tree.get(&String::from("kiwi")).unwrap(), "kiwi");
+        assert!(tree.get(&String::from("plum")).is_none());
+        assert_eq!(tree.delete(&String::from("apple")), Some(String::from("apple")));
+        assert_eq!(tree.delete(&String::from("pear")), Some(String::from("pear")));
+        assert!(tree.delete(&String::from("apple")).is_none());
+        assert!(tree.check_valid());
+        assert_eq!(tree.in_order_traverse(), vec!["banana", "cherry", "fig", "kiwi"]);
+
+        let mut bytes: AVLTree<Vec<u8>> = AVLTree::new();
+        for i in 0..50u8{
+            bytes.insert(vec![i % 7, i]);
+        }
+        for i in 0..25u8{
+            assert_eq!(bytes.delete(&vec![i % 7, i]), Some(vec![i % 7, i]));
+        }
+        assert!(bytes.check_valid());
+        assert!(bytes.search(&vec![0, 49]));
+    }
+
+    #[test]
+    fn test_iter(){
+        let mut tree = AVLTree::new();
+        assert!(tree.iter().next().is_none());
+        for v in [5, 3, 8, 1, 4, 7, 9, 2, 6]{
+            tree.insert(v);
+        }
+        let forward: Vec<i32> = tree.iter().map(|v| *v).collect();
+        assert_eq!(forward, (1..10).collect::<Vec<_>>());
+        let backward: Vec<i32> = tree.iter().rev().map(|v| *v).collect();
+        assert_eq!(backward, (1..10).rev().collect::<Vec<_>>());
+
+        let mut iter = tree.iter();
+        assert_eq!(iter.len(), 9);
+        assert_eq!(*iter.next().unwrap(), 1);
+        assert_eq!(*iter.next_back().unwrap(), 9);
+        assert_eq!(iter.len(), 7);
+        let middle: Vec<i32> = iter.map(|v| *v).collect();
+        assert_eq!(middle, (2..9).collect::<Vec<_>>());
+
+        let mut sum = 0;
+        for v in &tree{
+            sum += *v;
+        }
+        assert_eq!(sum, 45);
+    }
+
+    #[test]
+    fn test_range(){
+        use std::collections::BTreeSet;
+        use std::ops::Bound;
+        let mut tree = AVLTree::new();
+        let empty: Vec<i32> = tree.range(..).map(|v| *v).collect();
+        assert!(empty.is_empty());
+        let mut expected = BTreeSet::new();
+        for v in (0..200).map(|i| (i * 37) % 211){
+            tree.insert(v);
+            expected.insert(v);
+        }
+        let bounds = [Bound::Unbounded, Bound::Included(-5), Bound::Included(0), Bound::Excluded(0),
+            Bound::Included(50), Bound::Excluded(50), Bound::Included(74), Bound::Excluded(150),
+            Bound::Included(210), Bound::Excluded(210), Bound::Included(300)];
+        for lo in bounds.iter(){
+            for hi in bounds.iter(){
+                let (lo, hi) = (*lo, *hi);
+                let valid = match (lo, hi){
+                    (Bound::Included(a), Bound::Included(b))=>a <= b,
+                    (Bound::Included(a), Bound::Excluded(b)) | (Bound::Excluded(a), Bound::Included(b))
+                        | (Bound::Excluded(a), Bound::Excluded(b))=>a < b,
+                    _=>true
+                };
+                let forward: Vec<i32> = tree.range((lo, hi)).map(|v| *v).collect();
+                let backward: Vec<i32> = tree.range((lo, hi)).rev().map(|v| *v).collect();
+                let want: Vec<i32> = if valid { expected.range((lo, hi)).cloned().collect() } else { Vec::new() };
+                assert_eq!(forward, want);
+                assert_eq!(backward, want.iter().rev().cloned().collect::<Vec<_>>());
+            }
+        }
+        assert_eq!(tree.range((Bound::Included(60), Bound::Excluded(40))).count(), 0);
+        assert_eq!(tree.range(3..3).count(), 0);
+
+        let mut range = tree.range(10..=20);
+        assert_eq!(*range.next().unwrap(), 10);
+        assert_eq!(*range.next_back().unwrap(), 20);
+        let middle: Vec<i32> = range.map(|v| *v).collect();
+        assert_eq!(middle, (11..20).filter(|v| expected.contains(v)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_select_rank(){
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut tree = AVLTree::new();
+        let mut expected = BTreeSet::new();
+        assert!(tree.select(0).is_none());
+        assert_eq!(tree.rank(&0), 0);
+        for _ in 0..1000{
+            let v = rng.gen_range(0, 300);
+            if rng.gen_bool(0.6){
+                tree.insert(v);
+                expected.insert(v);
+            }
+            else{
+                tree.delete(&v);
+                expected.remove(&v);
+            }
+        }
+        assert!(tree.check_valid());
+        assert_eq!(tree.len(), expected.len());
+        for (k, v) in expected.iter().enumerate(){
+            assert_eq!(*tree.select(k).unwrap(), *v);
+            assert_eq!(tree.rank(v), k);
+        }
+        assert!(tree.select(expected.len()).is_none());
+        for v in -1..301{
+            assert_eq!(tree.rank(&v), expected.range(..v).count());
+        }
+    }
+
+    #[test]
+    fn test_clone(){
+        let mut tree: AVLTree<i32> = (0..100).collect();
+        let mut copy = tree.clone();
+        assert!(copy.check_valid());
+        assert_eq!(copy.in_order_traverse(), tree.in_order_traverse());
+        copy.insert(100);
+        copy.delete(&0);
+        tree.delete(&50);
+        assert_eq!(tree.len(), 99);
+        assert_eq!(copy.len(), 100);
+        assert_eq!(tree.rank(&60), 59);
+        assert_eq!(*tree.select(99 - 1).unwrap(), 99);
+        assert_eq!(copy.rank(&60), 59);
+        assert_eq!(*copy.select(99).unwrap(), 100);
+        assert!(tree.search(&0) && !copy.search(&0));
+        assert!(tree.check_valid() && copy.check_valid());
+    }
+
+    #[test]
+    fn test_neighbors(){
+        let mut rng = StdRng::seed_from_u64(6);
+        let mut tree = AVLTree::new();
+        let mut expected = BTreeSet::new();
+        assert!(tree.first().is_none() && tree.last().is_none());
+        assert!(tree.floor(&0).is_none() && tree.ceiling(&0).is_none());
+        assert_eq!(tree.pop_first(), None);
+        assert_eq!(tree.pop_last(), None);
+        for _ in 0..300{
+            let v = rng.gen_range(0, 500);
+            tree.insert(v);
+            expected.insert(v);
+        }
+        for v in -1..502{
+            assert_eq!(tree.floor(&v).map(|x| *x), expected.range(..=v).next_back().cloned());
+            assert_eq!(tree.ceiling(&v).map(|x| *x), expected.range(v..).next().cloned());
+            assert_eq!(tree.predecessor(&v).map(|x| *x), expected.range(..v).next_back().cloned());
+            assert_eq!(tree.successor(&v).map(|x| *x), expected.range(v+1..).next().cloned());
+        }
+        while !expected.is_empty(){
+            assert_eq!(tree.first().map(|x| *x), expected.iter().next().cloned());
+            assert_eq!(tree.last().map(|x| *x), expected.iter().next_back().cloned());
+            if rng.gen_bool(0.5){
+                assert_eq!(tree.pop_first(), expected.pop_first());
+            }
+            else{
+                assert_eq!(tree.pop_last(), expected.pop_last());
+            }
+            assert!(tree.check_valid());
+            assert_eq!(tree.len(), expected.len());
+        }
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_split_append(){
+        let mut rng = StdRng::seed_from_u64(7);
+        for round in 0..40{
+            let mut tree = AVLTree::new();
+            let mut expected = BTreeSet::new();
+            for _ in 0..rng.gen_range(0, 300){
+                let v = rng.gen_range(0, 1000);
+                tree.insert(v);
+                expected.insert(v);
+            }
+            let key = rng.gen_range(-10, 1010);
+            let mut upper = tree.split_off(&key);
+            let expected_upper = expected.split_off(&key);
+            assert!(tree.check_valid() && upper.check_valid());
+            assert_eq!(tree.in_order_traverse(), expected.iter().cloned().collect::<Vec<_>>());
+            assert_eq!(upper.in_order_traverse(), expected_upper.iter().cloned().collect::<Vec<_>>());
+            assert_eq!(tree.len(), expected.len());
+            assert_eq!(upper.len(), expected_upper.len());
+
+            // join back, from either side
+            if round % 2 == 0{
+                tree.append(&mut upper);
+            }
+            else{
+                upper.append(&mut tree);
+                mem::swap(&mut tree, &mut upper);
+            }
+            expected.extend(expected_upper);
+            assert!(upper.is_empty());
+            assert!(tree.check_valid());
+            assert_eq!(tree.len(), expected.len());
+            assert_eq!(tree.in_order_traverse(), expected.iter().cloned().collect::<Vec<_>>());
+        }
+
+        // overlapping trees
+        let mut tree = AVLTree::new();
+        let mut other = AVLTree::new();
+        for v in 0..50{
+            tree.insert(v * 2);
+            other.insert(v * 3);
+        }
+        tree.append(&mut other);
+        let expected: BTreeSet<i32> = (0..50).map(|v| v * 2).chain((0..50).map(|v| v * 3)).collect();
+        assert!(tree.check_valid() && other.is_empty());
+        assert_eq!(tree.len(), expected.len());
+        assert_eq!(tree.in_order_traverse(), expected.into_iter().collect::<Vec<_>>());
+    }
+
+    type BuildOperation = fn(AVLTree<i32>, AVLTree<i32>)->AVLTree<i32>;
+
+    #[test]
+    fn test_set_operations(){
+        let mut rng = StdRng::seed_from_u64(8);
+        let sizes = [(0, 0), (0, 30), (30, 0), (1, 200), (200, 1), (150, 150), (600, 40), (3, 3)];
+        for (n, m) in sizes.iter(){
+            let build = |rng: &mut StdRng, size: usize, span: i32|{
+                let mut tree = AVLTree::new();
+                let mut expected = BTreeSet::new();
+                for _ in 0..size{
+                    let v = rng.gen_range(0, span);
+                    tree.insert(v);
+                    expected.insert(v);
+                }
+                (tree, expected)
+            };
+            let span = (*n + *m) as i32 + 10;
+            let (a, a_set) = build(&mut rng, *n, span);
+            let (b, b_set) = build(&mut rng, *m, span);
+
+            let collect = |iter: SetIter<i32>| iter.map(|v| *v).collect::<Vec<i32>>();
+            assert_eq!(collect(a.union(&b)), a_set.union(&b_set).cloned().collect::<Vec<_>>());
+            assert_eq!(collect(a.intersection(&b)), a_set.intersection(&b_set).cloned().collect::<Vec<_>>());
+            assert_eq!(collect(a.difference(&b)), a_set.difference(&b_set).cloned().collect::<Vec<_>>());
+            assert_eq!(collect(a.symmetric_difference(&b)),
+                a_set.symmetric_difference(&b_set).cloned().collect::<Vec<_>>());
+
+            let from_set = |set: &BTreeSet<i32>|{
+                let mut tree = AVLTree::new();
+                for v in set.iter(){
+                    tree.insert(*v);
+                }
+                tree
+            };
+            let operations: [(BuildOperation, Vec<i32>); 4] = [
+                (AVLTree::into_union, a_set.union(&b_set).cloned().collect()),
+                (AVLTree::into_intersection, a_set.intersection(&b_set).cloned().collect()),
+                (AVLTree::into_difference, a_set.difference(&b_set).cloned().collect()),
+                (AVLTree::into_symmetric_difference, a_set.symmetric_difference(&b_set).cloned().collect()),
+            ];
+            for (operation, expected) in operations.iter(){
+                let result = operation(from_set(&a_set), from_set(&b_set));
+                assert!(result.check_valid());
+                assert_eq!(result.len(), expected.len());
+                assert_eq!(&result.in_order_traverse(), expected);
+            }
+        }
+
+        // disjoint trees are joined directly
+        let mut low = AVLTree::new();
+        let mut high = AVLTree::new();
+        for v in 0..100{
+            low.insert(v);
+            high.insert(v + 1000);
+        }
+        let joined = high.into_union(low);
+        assert!(joined.check_valid());
+        assert_eq!(joined.len(), 200);
+        assert_eq!(*joined.select(100).unwrap(), 1000);
+    }
+
+    #[test]
+    fn test_from_sorted_iter(){
+        for n in (0..70).chain([255, 256, 1000, 1023]){
+            let tree = AVLTree::from_sorted_iter(0..n);
+            assert!(tree.check_valid());
+            assert_eq!(tree.len(), n as usize);
+            assert_eq!(tree.in_order_traverse(), (0..n).collect::<Vec<_>>());
+        }
+        let tree = AVLTree::from_sorted_iter(vec![1, 1, 2, 3, 3, 3, 7]);
+        assert!(tree.check_valid());
+        assert_eq!(tree.in_order_traverse(), vec![1, 2, 3, 7]);
+
+        let mut tree = AVLTree::from_sorted_iter((0..10_000).map(|v| v * 2));
+        assert!(tree.check_valid());
+        for v in 0..5_000{
+            assert_eq!(tree.delete(&(v * 4)), Some(v * 4));
+        }
+        tree.insert(3);
+        assert!(tree.check_valid());
+        assert_eq!(tree.len(), 5_001);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_from_sorted_iter_unsorted(){
+        AVLTree::from_sorted_iter(vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn test_from_iter_extend(){
+        let mut rng = StdRng::seed_from_u64(10);
+        let values: Vec<i32> = (0..500).map(|_| rng.gen_range(0, 300)).collect();
+        let expected: BTreeSet<i32> = values.iter().cloned().collect();
+        let mut tree: AVLTree<i32> = values.iter().cloned().collect();
+        assert!(tree.check_valid());
+        assert_eq!(tree.len(), expected.len());
+        assert_eq!(tree.in_order_traverse(), expected.iter().cloned().collect::<Vec<_>>());
+
+        let mut expected = expected;
+        // sorted, overlapping and disjoint
+        for batch in [(250..400).collect::<Vec<i32>>(), (1000..1100).collect(), (-50..-10).collect(),
+            vec![5, 1, 999, 42, -7], Vec::new()]{
+            expected.extend(batch.iter().cloned());
+            tree.extend(batch);
+            assert!(tree.check_valid());
+            assert_eq!(tree.len(), expected.len());
+            assert_eq!(tree.in_order_traverse(), expected.iter().cloned().collect::<Vec<_>>());
+        }
+    }
+
+    #[derive(Debug, Default, Clone)]
+    struct CaseInsensitive;
+
+    impl Comparator<String> for CaseInsensitive{
+        fn compare(&self, a: &String, b: &String)->Ordering{
+            a.to_lowercase().cmp(&b.to_lowercase())
+        }
+    }
+
+    /// Has no Ord, ordered by the priority only
+    #[derive(Debug, Clone, PartialEq)]
+    struct Task{
+        priority: u32,
+        name: &'static str
+    }
+
+    type ByPriority = fn(&Task, &Task)->Ordering;
+
+    fn by_priority(a: &Task, b: &Task)->Ordering{
+        a.priority.cmp(&b.priority)
+    }
+
+    #[test]
+    fn test_comparators(){
+        // case-insensitive strings
+        let mut tree: AVLTree<String, CaseInsensitive> = ["banana", "Apple", "cherry"].iter().map(|s| s.to_string()).collect();
+        assert!(!tree.insert(String::from("APPLE")));
+        assert!(tree.insert(String::from("apricot")));
+        assert!(tree.check_valid());
+        assert_eq!(tree.in_order_traverse(), vec!["Apple", "apricot", "banana", "cherry"]);
+        assert!(tree.search(&String::from("BANANA")));
+        assert_eq!(*tree.ceiling(&String::from("B")).unwrap(), "banana");
+        assert_eq!(tree.rank(&String::from("CHERRY")), 3);
+        assert_eq!(tree.delete(&String::from("cHeRrY")), Some(String::from("cherry")));
+        assert_eq!(tree.len(), 3);
+
+        // reverse order with a closure
+        let mut rng = StdRng::seed_from_u64(10);
+        let mut reverse = AVLTree::with_comparator(|a: &i32, b: &i32| b.cmp(a));
+        let mut expected = BTreeSet::new();
+        for _ in 0..500{
+            let v = rng.gen_range(0, 300);
+            assert_eq!(reverse.insert(v), expected.insert(v));
+        }
+        for _ in 0..200{
+            let v = rng.gen_range(0, 300);
+            assert_eq!(reverse.delete(&v), expected.take(&v));
+        }
+        assert!(reverse.check_valid());
+        let descending: Vec<i32> = expected.iter().rev().cloned().collect();
+        assert_eq!(reverse.in_order_traverse(), descending);
+        assert_eq!(*reverse.first().unwrap(), *expected.last().unwrap());
+        let ranged: Vec<i32> = reverse.range((Bound::Included(200), Bound::Included(100))).map(|v| *v).collect();
+        assert_eq!(ranged, expected.range(100..=200).rev().cloned().collect::<Vec<_>>());
+        let floor = reverse.floor(&150).map(|v| *v);
+        assert_eq!(floor, expected.range(150..).next().cloned());
+        let upper = reverse.split_off(&150);
+        assert!(reverse.check_valid() && upper.check_valid());
+        assert!(reverse.iter().all(|v| *v > 150) && upper.iter().all(|v| *v <= 150));
+
+        // projected field of a struct without Ord
+        let task = |priority, name| Task{priority, name};
+        let a = AVLTree::from_sorted_iter_by(vec![task(1, "a"), task(3, "b"), task(5, "c")], by_priority as ByPriority);
+        let mut b = AVLTree::with_comparator(by_priority as ByPriority);
+        b.extend(vec![task(3, "x"), task(4, "y"), task(9, "z")]);
+        let names = |it: &mut dyn Iterator<Item = &'static str>| it.collect::<Vec<_>>();
+        assert_eq!(names(&mut a.union(&b).map(|t| t.name)), vec!["a", "b", "y", "c", "z"]);
+        assert_eq!(names(&mut a.intersection(&b).map(|t| t.name)), vec!["b"]);
+        assert_eq!(names(&mut a.difference(&b).map(|t| t.name)), vec!["a", "c"]);
+        let merged = a.into_union(b);
+        assert!(merged.check_valid());
+        assert_eq!(names(&mut merged.iter().map(|t| t.name)), vec!["a", "x", "y", "c", "z"]);
+        assert_eq!(merged.get(&task(4, "")).unwrap().name, "y");
+
+        let mut map = AVLMap::with_comparator(CaseInsensitive);
+        assert_eq!(map.insert(String::from("Key"), 1), None);
+        assert_eq!(map.insert(String::from("KEY"), 2), Some(1));
+        assert_eq!(*map.get(&String::from("key")).unwrap(), 2);
+        assert!(map.check_valid());
+    }
+
+    #[test]
+    fn test_multiset(){
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut multiset: AVLMultiSet<i32> = AVLMultiSet::new();
+        let mut expected: BTreeMap<i32, usize> = BTreeMap::new();
+        for _ in 0..2000{
+            let v = rng.gen_range(0, 100);
+            match rng.gen_range(0, 4){
+                0=>assert_eq!(multiset.remove_one(&v), expected.contains_key(&v).then_some(v)),
+                1 if rng.gen_bool(0.2)=>assert_eq!(multiset.remove_all(&v), expected.remove(&v).unwrap_or(0)),
+                _=>{
+                    *expected.entry(v).or_insert(0) += 1;
+                    assert_eq!(multiset.insert(v), expected[&v]);
+                    continue;
+                }
+            }
+            if let Some(count) = expected.get_mut(&v){
+                *count -= 1;
+                if *count == 0{
+                    expected.remove(&v);
+                }
+            }
+        }
+        assert!(multiset.check_valid());
+        assert_eq!(multiset.distinct_len(), expected.len());
+        for v in 0..100{
+            assert_eq!(multiset.count(&v), expected.get(&v).cloned().unwrap_or(0));
+            assert_eq!(multiset.contains(&v), expected.contains_key(&v));
+        }
+        let all: Vec<i32> = expected.iter().flat_map(|(v, count)| std::iter::repeat_n(*v, *count)).collect();
+        assert_eq!(multiset.len(), all.len());
+        assert_eq!(multiset.iter().map(|v| *v).collect::<Vec<_>>(), all);
+        assert_eq!(multiset.iter().rev().map(|v| *v).collect::<Vec<_>>(), all.iter().rev().cloned().collect::<Vec<_>>());
+        // both ends meeting inside the copies of one value
+        let mut iter = multiset.iter();
+        let mut mixed = Vec::new();
+        while let Some(v) = if mixed.len() % 2 == 0 {iter.next()} else {iter.next_back()}{
+            mixed.push(*v);
+        }
+        mixed.sort();
+        assert_eq!(mixed, all);
+
+        let mut words: AVLMultiSet<String, CaseInsensitive> = ["b", "A", "a", "B", "a"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(words.count(&String::from("A")), 3);
+        // each duplicate as it was inserted, in insertion order
+        assert_eq!(words.iter().map(|v| v.clone()).collect::<Vec<_>>(), vec!["A", "a", "a", "b", "B"]);
+        assert_eq!(words.iter().rev().map(|v| v.clone()).collect::<Vec<_>>(), vec!["B", "b", "a", "a", "A"]);
+        assert!(words.check_valid());
+        assert_eq!(words.remove_one(&String::from("b")), Some(String::from("B")));
+        assert_eq!(words.remove_one(&String::from("A")), Some(String::from("a")));
+        assert_eq!(words.iter().map(|v| v.clone()).collect::<Vec<_>>(), vec!["A", "a", "b"]);
+        assert_eq!(words.remove_all(&String::from("a")), 2);
+        assert_eq!(words.remove_one(&String::from("B")), Some(String::from("b")));
+        assert_eq!(words.remove_one(&String::from("B")), None);
+        assert!(words.is_empty());
+        assert!(words.check_valid());
+    }
+
+    /// Order-sensitive summary checking the aggregates against the values themselves
+    #[derive(Debug, PartialEq)]
+    struct Stats{
+        count: usize,
+        sum: i64,
+        min: Option<i32>,
+        values: Vec<i32>
+    }
+
+    impl Augment<i32> for Stats{
+        fn identity()->Stats{
+            Stats{count: 0, sum: 0, min: None, values: Vec::new()}
+        }
+        fn summarize(value: &i32)->Stats{
+            Stats{count: 1, sum: *value as i64, min: Some(*value), values: vec![*value]}
+        }
+        fn combine(&self, other: &Stats)->Stats{
+            let min = match (self.min, other.min){
+                (Some(a), Some(b))=>Some(a.min(b)),
+                (a, b)=>a.or(b)
+            };
+            let values = self.values.iter().chain(other.values.iter()).cloned().collect();
+            Stats{count: self.count + other.count, sum: self.sum + other.sum, min, values}
+        }
+    }
+
+    fn stats_of<'a>(values: impl Iterator<Item = &'a i32>)->Stats{
+        values.fold(Stats::identity(), |acc, v| acc.combine(&Stats::summarize(v)))
+    }
+
+    #[test]
+    fn test_aggregate(){
+        let mut rng = StdRng::seed_from_u64(13);
+        let mut tree: AVLTree<i32, NaturalOrder, Stats> = AVLTree::default();
+        let mut expected = BTreeSet::new();
+        for _ in 0..1500{
+            let v = rng.gen_range(0, 500);
+            if rng.gen_bool(0.6){
+                assert_eq!(tree.insert(v), expected.insert(v));
+            }
+            else{
+                assert_eq!(tree.delete(&v), expected.take(&v));
+            }
+        }
+        assert!(tree.check_valid());
+        let check = |tree: &AVLTree<i32, NaturalOrder, Stats>, expected: &BTreeSet<i32>, rng: &mut StdRng|{
+            assert_eq!(tree.aggregate(..), stats_of(expected.iter()));
+            for _ in 0..100{
+                let low = rng.gen_range(-10, 510);
+                let high = low + rng.gen_range(1, 200);
+                assert_eq!(tree.aggregate(low..high), stats_of(expected.range(low..high)));
+                assert_eq!(tree.aggregate(low..=high), stats_of(expected.range(low..=high)));
+                let bounds = (Bound::Excluded(low), Bound::Included(high));
+                assert_eq!(tree.aggregate(bounds), stats_of(expected.range(bounds)));
+                assert_eq!(tree.aggregate(low..), stats_of(expected.range(low..)));
+                assert_eq!(tree.aggregate(..high), stats_of(expected.range(..high)));
+            }
+            assert_eq!(tree.aggregate((Bound::Included(300), Bound::Excluded(100))), Stats::identity());
+        };
+        check(&tree, &expected, &mut rng);
+
+        // joins keep the summaries up to date as well
+        let mut upper = tree.split_off(&250);
+        let expected_upper = expected.split_off(&250);
+        check(&tree, &expected, &mut rng);
+        check(&upper, &expected_upper, &mut rng);
+        tree.append(&mut upper);
+        expected.extend(expected_upper);
+        check(&tree, &expected, &mut rng);
+        tree.extend(600..700);
+        expected.extend(600..700);
+        check(&tree, &expected, &mut rng);
+        let other: AVLTree<i32, NaturalOrder, Stats> = (0..800).step_by(3).collect();
+        let other_set: BTreeSet<i32> = (0..800).step_by(3).collect();
+        let both = tree.into_intersection(other);
+        assert!(both.check_valid());
+        check(&both, &expected.intersection(&other_set).cloned().collect(), &mut rng);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde(){
+        let tree: AVLTree<i32> = (0..100).rev().collect();
+        let json = serde_json::to_string(&tree).unwrap();
+        assert_eq!(json, serde_json::to_string(&(0..100).collect::<Vec<_>>()).unwrap());
+        let back: AVLTree<i32> = serde_json::from_str(&json).unwrap();
+        assert!(back.check_valid());
+        assert_eq!(back.len(), 100);
+        assert_eq!(back.in_order_traverse(), tree.in_order_traverse());
+
+        let empty: AVLTree<i32> = serde_json::from_str("[]").unwrap();
+        assert!(empty.is_empty() && empty.check_valid());
+
+        // out of order or repeated values are rejected instead of built into a broken tree
+        let err = serde_json::from_str::<AVLTree<i32>>("[1, 3, 2]").err().unwrap();
+        assert!(err.to_string().contains("index 2"));
+        assert!(serde_json::from_str::<AVLTree<i32>>("[1, 1]").is_err());
+        assert!(serde_json::from_str::<AVLTree<i32>>("{}").is_err());
+
+        // the order is the one of the comparator
+        let words: AVLTree<String, CaseInsensitive> = serde_json::from_str(r#"["apple", "Banana", "cherry"]"#).unwrap();
+        assert!(words.check_valid());
+        assert_eq!(serde_json::to_string(&words).unwrap(), r#"["apple","Banana","cherry"]"#);
+        assert!(serde_json::from_str::<AVLTree<String, CaseInsensitive>>(r#"["apple", "APPLE"]"#).is_err());
+    }
+
+    #[test]
+    fn test_to_dot(){
+        let tree = AVLTree::<i32>::new();
+        assert_eq!(tree.to_dot(), "digraph {\n    graph [ordering=out];\n    node [fontname=\"monospace\"];\n}\n");
 
+        let mut tree = AVLTree::<i32>::new();
+        for x in [2, 1, 3, 4]{
+            tree.insert(x);
+        }
+        assert_eq!(tree.to_dot(), "digraph {
+    graph [ordering=out];
+    node [fontname=\"monospace\"];
+    n0 [label=\"2\\nh=3 b=1\"];
+    n0 -> n1;
+    n0 -> n2;
+    n2 [label=\"3\\nh=2 b=1\"];
+    n3 [style=invis];
+    n2 -> n3 [style=invis];
+    n2 -> n4;
+    n4 [label=\"4\\nh=1 b=0\"];
+    n1 [label=\"1\\nh=1 b=0\"];
 }
+");
+
+        let tree: AVLTree<i32> = (0..100).collect();
+        let dot = tree.to_dot_with(true);
+        assert_eq!(dot.matches("\\nh=").count(), 100);
+        assert!(dot.contains("h=7"));
+        assert!(!dot.contains("b=2") && !dot.contains("b=-2"));
+        assert_eq!(dot.matches("label=\"NIL\"").count(), 101);
+        assert_eq!(dot.matches("->").count(), 200);
+
+        // values are escaped inside the quoted labels
+        let tree: AVLTree<String> = ["say \"hi\"".to_string()].into_iter().collect();
+        assert!(tree.to_dot().contains(r#"n0 [label="\"say \\\"hi\\\"\"\nh=1 b=0"];"#));
+    }
+
+    #[test]
+    fn test_check_valid_into(){
+        let mut map = AVLMap::new();
+        map.insert(2, 'b');
+        map.insert(1, 'a');
+        let mut report = String::new();
+        assert!(map.check_valid_into(&mut report));
+        assert!(report.is_empty());
+        map.root.as_ref().unwrap().borrow_mut().height = 5;
+        assert!(!map.check_valid_into(&mut report));
+        assert_eq!(report, "Height of 2 is 5 instead of 2\n");
+
+        // copies only stay equal to their key while the comparator looks at the number
+        let strict = std::cell::Cell::new(false);
+        let mut multiset = AVLMultiSet::with_comparator(|a: &(u32, char), b: &(u32, char)|
+            if strict.get() {a.cmp(b)} else {a.0.cmp(&b.0)});
+        multiset.extend([(8, 'a'), (8, 'b')]);
+        let mut report = String::new();
+        assert!(multiset.check_valid_into(&mut report));
+        strict.set(true);
+        assert!(!multiset.check_valid_into(&mut report));
+        assert_eq!(report, "Copy (8, 'b') is kept with (8, 'a')\n");
+    }
+
+    #[test]
+    fn test_validate(){
+        assert_eq!(AVLTree::<i32>::new().validate(), Ok(()));
+        let tree: AVLTree<i32> = (0..1000).collect();
+        assert_eq!(tree.validate(), Ok(()));
+
+        // 2 on top of 1 and 3, 3 on top of 4
+        let build = ||{
+            let mut tree = AVLTree::<i32>::new();
+            for x in [2, 1, 3, 4]{
+                tree.insert(x);
+            }
+            assert_eq!(tree.validate(), Ok(()));
+            tree
+        };
+        let node = |tree: &AVLTree<i32>, value: i32| search_node(tree.root.clone(), &value, &NaturalOrder).flatten().unwrap();
+
+        let tree = build();
+        node(&tree, 4).borrow_mut().height = 2;
+        let violation = tree.validate().unwrap_err();
+        assert_eq!(violation, InvariantViolation::StaleHeight{ node: 4, stored: 2, actual: 1 });
+        assert_eq!(violation.to_string(), "Height of 4 is 2 instead of 1");
+
+        let tree = build();
+        node(&tree, 1).borrow_mut().value = 5;
+        assert_eq!(tree.validate(), Err(InvariantViolation::Order{ prev: 5, next: 2 }));
+
+        let tree = build();
+        tree.root.as_ref().unwrap().borrow_mut().size = 5;
+        assert_eq!(tree.validate(), Err(InvariantViolation::Size{ node: 2, stored: 5, actual: 4 }));
+
+        let tree = build();
+        node(&tree, 4).borrow_mut().parent = None;
+        assert_eq!(tree.validate(), Err(InvariantViolation::ParentLink{ node: 4, parent: Some(3) }));
+
+        // a chain of three with correct heights and sizes is still unbalanced
+        let nodes: Vec<_> = (1..4).map(|v| TreeNode::<i32>::new_root(v, ()).unwrap()).collect();
+        for pair in nodes.windows(2).rev(){
+            link_children(&pair[0], None, Some(pair[1].clone()));
+            pair[0].borrow_mut().update_height();
+        }
+        let chain = AVLTree{root: Some(nodes[0].clone()), len: nodes.len(), comparator: NaturalOrder};
+        drop(nodes);
+        assert_eq!(chain.validate(), Err(InvariantViolation::Unbalanced{ node: 1, left: 0, right: 2 }));
+        let mut why = String::new();
+        assert!(!chain.check_valid_into(&mut why));
+        assert_eq!(why, "1 is unbalanced: height 0 on the left, 2 on the right\n");
+    }
+
+    #[test]
+    fn test_try_insert_delete(){
+        let mut tree = AVLTree::<i32>::new();
+        for x in [2, 1, 3, 4]{
+            assert_eq!(tree.try_insert(x), Ok(true));
+        }
+        assert_eq!(tree.try_insert(4), Ok(false));
+        assert_eq!(tree.try_delete(&5), Ok(None));
+        assert_eq!(tree.try_delete(&4), Ok(Some(4)));
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.validate(), Ok(()));
+
+        let held = search_node(tree.root.clone(), &3, &NaturalOrder).flatten().unwrap();
+        let error = tree.try_delete(&3).unwrap_err();
+        assert_eq!(error, Error::StillReferenced);
+        assert_eq!(error.to_string(), "Deleted node is still referenced");
+        assert_eq!(held.borrow().value, 3);
+        // found before anything changed
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.validate(), Ok(()));
+        drop(held);
+
+        let root = tree.root.clone().unwrap();
+        assert_eq!(root.borrow().get_child_delete_helper().unwrap_err(), Error::TwoChildren);
+        assert_eq!(root.borrow_mut().try_delete_node().unwrap_err(), Error::TwoChildren);
+        drop(root);
+        assert_eq!(tree.validate(), Ok(()));
+        assert_eq!(tree.try_delete(&3), Ok(Some(3)));
+
+        let mut dynamic: Box<dyn SimpleTreeTrait<i32>> = Box::new((0..10).collect::<AVLTree<i32>>());
+        assert_eq!(dynamic.try_insert(10), Ok(true));
+        assert_eq!(dynamic.try_delete(&0), Ok(Some(0)));
+    }
+
+    #[test]
+    fn test_render(){
+        let mut tree = AVLTree::<i32>::new();
+        assert_eq!(tree.to_string(), "Empty tree!\n");
+        assert_eq!(format!("{:?}", tree), "Empty tree!\n");
+        for x in [2, 1, 3]{
+            tree.insert(x);
+        }
+        assert_eq!(tree.to_string(), "   2   \n  / \\  \n 1   3 \n");
+        assert_eq!(format!("{:?}", tree), concat!("(Value: 2, Height: 2, Is Leaf: false)\n",
+            "  left: (Value: 1, Height: 1, Is Leaf: true)\n",
+            "  right: (Value: 3, Height: 1, Is Leaf: true)\n"));
+
+        let mut verbose = String::new();
+        tree.render(&mut verbose, true).unwrap();
+        assert_eq!(verbose, format!("{:?}{}", tree, tree));
+        #[cfg(feature = "std")]
+        {
+            let mut bytes = Vec::new();
+            tree.render_io(&mut bytes, true).unwrap();
+            assert_eq!(bytes, verbose.as_bytes());
+        }
+
+        // check_valid_into() tells why the tree is broken instead of printing it
+        tree.root.as_ref().unwrap().borrow_mut().value = 10;
+        let mut why = String::new();
+        assert!(!tree.check_valid_into(&mut why));
+        assert_eq!(why, "Order error: 3 comes after 10\n");
+    }
+
+    #[test]
+    fn test_small_stack(){
+        // every walk down or up the tree is a loop or an explicit stack, so a 64 KiB stack is plenty
+        let worker = std::thread::Builder::new().stack_size(64 * 1024).spawn(||{
+            let mut tree = AVLTree::new();
+            for v in 0..100_000{
+                tree.insert(v);
+            }
+            for v in (0..100_000).step_by(2){
+                tree.delete(&v);
+            }
+            assert!(tree.check_valid());
+            assert_eq!(tree.len(), 50_000);
+            assert!(tree.search(&99_999) && !tree.search(&0));
+            assert_eq!(tree.in_order_traverse(), (1..100_000).step_by(2).collect::<Vec<_>>());
+
+            // building, splitting, joining and the set operations keep their pending work on the heap
+            let sorted = |values: &dyn Fn(&i32)->bool| (0..100_000).filter(values).collect::<Vec<i32>>();
+            let built = |values: &dyn Fn(&i32)->bool| AVLTree::from_sorted_iter(sorted(values));
+            let mut upper = tree.split_off(&50_001);
+            assert!(tree.check_valid() && upper.check_valid());
+            assert_eq!((tree.len(), upper.len()), (25_000, 25_000));
+            tree.append(&mut upper);
+            assert!(tree.check_valid() && upper.is_empty());
+            assert_eq!(tree.in_order_traverse(), sorted(&|v| v % 2 == 1));
+            let evens = built(&|v| v % 2 == 0);
+            assert!(evens.check_valid());
+            assert_eq!(evens.len(), 50_000);
+            let union = tree.into_union(evens);
+            assert!(union.check_valid());
+            assert_eq!(union.in_order_traverse(), sorted(&|_| true));
+            let intersection = union.into_intersection(built(&|v| v % 3 == 0));
+            assert!(intersection.check_valid());
+            assert_eq!(intersection.in_order_traverse(), sorted(&|v| v % 3 == 0));
+            let difference = built(&|v| v % 2 == 0).into_difference(intersection);
+            assert!(difference.check_valid());
+            assert_eq!(difference.in_order_traverse(), sorted(&|v| v % 2 == 0 && v % 3 != 0));
+            let symmetric = difference.into_symmetric_difference(built(&|v| v % 5 == 0));
+            assert!(symmetric.check_valid());
+            assert_eq!(symmetric.in_order_traverse(), sorted(&|v| (v % 2 == 0 && v % 3 != 0) != (v % 5 == 0)));
+
+            // a chain is far from balanced, yet it is checked, walked and dropped without recursing
+            let nodes: Vec<_> = (0..100_000).map(|v| TreeNode::<i32>::new_root(v, ()).unwrap()).collect();
+            for pair in nodes.windows(2).rev(){
+                link_children(&pair[0], None, Some(pair[1].clone()));
+                pair[0].borrow_mut().update_height();
+            }
+            let chain = AVLTree{root: Some(nodes[0].clone()), len: nodes.len(), comparator: NaturalOrder};
+            drop(nodes);
+            assert!(!chain.check_valid());
+            assert_eq!(TreeTrait::height(&chain), 100_000);
+            assert_eq!(chain.count_leaves(), 1);
+            assert!(chain.search(&99_999));
+            assert_eq!(chain.in_order_traverse(), (0..100_000).collect::<Vec<_>>());
+        }).unwrap();
+        worker.join().unwrap();
+    }
 
+    /// Value holding a token, so the live copies can be counted
+    #[derive(Debug, Clone)]
+    struct Tracked(i32, #[allow(dead_code)] std::rc::Rc<()>);
+
+    impl PartialEq for Tracked{
+        fn eq(&self, other: &Self)->bool{
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for Tracked{}
+
+    impl PartialOrd for Tracked{
+        fn partial_cmp(&self, other: &Self)->Option<Ordering>{
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Tracked{
+        fn cmp(&self, other: &Self)->Ordering{
+            self.0.cmp(&other.0)
+        }
+    }
+
+    #[test]
+    fn test_no_leaks(){
+        let token = std::rc::Rc::new(());
+        let live = |token: &std::rc::Rc<()>| std::rc::Rc::strong_count(token) - 1;
+        let mut rng = StdRng::seed_from_u64(17);
+
+        // every node holds one token, a leaked node would keep it alive
+        let mut tree = AVLTree::new();
+        for _ in 0..5000{
+            let v = rng.gen_range(0, 500);
+            if rng.gen_bool(0.6){
+                tree.insert(Tracked(v, token.clone()));
+            }
+            else{
+                tree.delete(&Tracked(v, token.clone()));
+            }
+            assert_eq!(live(&token), tree.len());
+        }
+        assert!(tree.check_valid());
+        let mut upper = tree.split_off(&Tracked(250, token.clone()));
+        assert_eq!(live(&token), tree.len() + upper.len());
+        tree.append(&mut upper);
+        assert_eq!(live(&token), tree.len());
+        // a clone owns its own copies, dropping either one leaves the other intact
+        let copy = tree.clone();
+        assert_eq!(live(&token), 2 * tree.len());
+        drop(tree);
+        assert_eq!(live(&token), copy.len());
+        assert!(copy.check_valid());
+        let tree = copy;
+        drop(tree);
+        drop(upper);
+        assert_eq!(live(&token), 0);
+
+        let mut map = AVLMap::new();
+        for _ in 0..5000{
+            let k: i32 = rng.gen_range(0, 500);
+            if rng.gen_bool(0.6){
+                map.insert(k, token.clone());
+            }
+            else{
+                map.remove(&k);
+            }
+            assert_eq!(live(&token), map.len());
+        }
+        drop(map);
+        assert_eq!(live(&token), 0);
+
+        // a large tree is freed without recursing down to the leaves
+        let tree: AVLTree<Tracked> = (0..200_000).map(|v| Tracked(v, token.clone())).collect();
+        assert_eq!(live(&token), 200_000);
+        drop(tree);
+        assert_eq!(live(&token), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "sync")]
+    fn test_threads(){
+        use std::sync::{Arc, RwLock};
+        use std::thread;
+
+        fn assert_send_sync<X: Send+Sync>(){}
+        assert_send_sync::<AVLTree<i32>>();
+        assert_send_sync::<AVLMap<i32, String>>();
+        assert_send_sync::<AVLMultiSet<i32>>();
+
+        // a tree built on one thread can be read from several at once
+        let tree: AVLTree<i32> = thread::spawn(|| (0..1000).collect()).join().unwrap();
+        let tree = Arc::new(tree);
+        let readers: Vec<_> = (0..4).map(|i|{
+            let tree = Arc::clone(&tree);
+            thread::spawn(move ||{
+                for v in (i..1000).step_by(4){
+                    assert!(tree.search(&v));
+                    assert_eq!(tree.rank(&v), v as usize);
+                }
+                assert_eq!(tree.iter().count(), 1000);
+            })
+        }).collect();
+        for reader in readers{
+            reader.join().unwrap();
+        }
+
+        // one writer and several readers share the tree behind a lock
+        let tree = Arc::new(RwLock::new(AVLTree::new()));
+        let writer = {
+            let tree = Arc::clone(&tree);
+            thread::spawn(move ||{
+                for v in 0..2000{
+                    let mut tree = tree.write().unwrap();
+                    tree.insert(v);
+                    if v % 3 == 0{
+                        tree.delete(&(v / 2));
+                    }
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4).map(|_|{
+            let tree = Arc::clone(&tree);
+            thread::spawn(move ||{
+                for _ in 0..200{
+                    let tree = tree.read().unwrap();
+                    let values: Vec<i32> = tree.iter().map(|v| *v).collect();
+                    assert_eq!(values.len(), tree.len());
+                    assert!(values.windows(2).all(|w| w[0] < w[1]));
+                    if let Some(first) = tree.first(){
+                        assert!(tree.search(&first));
+                    };
+                }
+            })
+        }).collect();
+        writer.join().unwrap();
+        for reader in readers{
+            reader.join().unwrap();
+        }
+
+        let mut expected = BTreeSet::new();
+        for v in 0..2000{
+            expected.insert(v);
+            if v % 3 == 0{
+                expected.remove(&(v / 2));
+            }
+        }
+        let tree = tree.read().unwrap();
+        assert!(tree.check_valid());
+        assert_eq!(tree.in_order_traverse(), expected.into_iter().collect::<Vec<_>>());
+    }
+}
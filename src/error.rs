@@ -0,0 +1,63 @@
+//! Errors
+//!
+//! Returned by the fallible operations, e.g. `try_insert` and `try_delete`, when they
+//! find the tree corrupted part way through instead of panicking.
+//!
+//! StillReferenced, TwoChildren and BlackChildOfBlackNode, and a RedGrandParent right above
+//! the new leaf, are found before the tree changes and leave it as it was. After any other
+//! error the tree is poisoned: it still owns its nodes and drops cleanly, but its values,
+//! length and balance are unspecified; validate() tells what is wrong with it.
+
+use core::fmt;
+
+/// Corruption found by a fallible tree operation
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error{
+    /// A node has no parent where one is required
+    MissingParent,
+    /// A child promised by the heights or colors of the tree is missing
+    MissingChild,
+    /// A node with two children was unlinked as if it had at most one
+    TwoChildren,
+    /// A black node being deleted has a single black child
+    BlackChildOfBlackNode,
+    /// The red parent of an inserted node has a red parent of its own
+    RedGrandParent,
+    /// The sibling of the node being rebalanced after a deletion is red
+    RedSibling,
+    /// A deleted node is still referenced
+    StillReferenced,
+    /// A link of an arena tree leads to a vacant slot
+    DanglingLink,
+    /// An arena has no index left for another node
+    ArenaFull
+}
+
+impl Error{
+    /// Panic with the message of the error
+    ///
+    /// For the infallible operations, which wrap the fallible ones
+    pub(crate) fn raise<T>(self)->T{
+        panic!("Error! {}!", self)
+    }
+}
+
+impl fmt::Display for Error{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        let message = match self{
+            Error::MissingParent => "Node has no parent",
+            Error::MissingChild => "Child required by the balance is missing",
+            Error::TwoChildren => "Node to unlink has two children",
+            Error::BlackChildOfBlackNode => "If current node is black, its unique child cannot be black",
+            Error::RedGrandParent => "Red parent of the inserted node has a red parent",
+            Error::RedSibling => "Sibling of a doubly black node is red",
+            Error::StillReferenced => "Deleted node is still referenced",
+            Error::DanglingLink => "Link to a vacant arena slot",
+            Error::ArenaFull => "Arena has no index left"
+        };
+        f.write_str(message)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error{}
@@ -0,0 +1,414 @@
+//! Interval Tree
+//!
+//! An interval tree on the nodes and the rebalancing of RedBlackTree.
+//! Every node also keeps the largest high endpoint of its sub-tree,
+//! so sub-trees ending before a query can be skipped.
+
+use core::fmt;
+use core::fmt::Debug;
+use alloc::vec::Vec;
+
+pub use crate::tree::{TreeTrait, TreeNodeTrait, SimpleTreeTrait};
+use crate::tree;
+use crate::tree::{Augment, NodeCell, Shared, NaturalOrder, search_node};
+use crate::rbtree::{self, insert_entry, delete_node, check_valid_node};
+
+/// Largest high endpoint of the intervals in a sub-tree, `None` when empty
+#[derive(Clone, Debug, PartialEq)]
+pub struct MaxEnd<T>(Option<T>);
+
+impl<T: Ord+Clone> Augment<(T, T)> for MaxEnd<T>{
+    fn identity()->Self{
+        MaxEnd(None)
+    }
+    fn summarize(value: &(T, T))->Self{
+        MaxEnd(Some(value.1.clone()))
+    }
+    fn combine(&self, other: &Self)->Self{
+        MaxEnd(self.0.clone().max(other.0.clone()))
+    }
+}
+
+/// Node of the interval tree
+pub type TreeNode<T> = rbtree::TreeNode<(T, T), (), MaxEnd<T>>;
+
+type TreeRoot<T> = Option<Shared<NodeCell<TreeNode<T>>>>;
+
+/// Struct of the interval tree
+///
+/// Stores distinct closed intervals `(low, high)` ordered by `low`, then by `high`
+pub struct IntervalTree<T: Ord+Clone+Debug>{
+    root: TreeRoot<T>,
+    len: usize
+}
+
+/// In-order iterator over an IntervalTree
+pub type Iter<'a, T> = tree::Iter<'a, (T, T), TreeNode<T>>;
+
+impl<T: Ord+Clone+Debug> TreeTrait<(T, T), TreeNode<T>> for IntervalTree<T>{
+    type Order = NaturalOrder;
+
+    fn root(&self)->TreeRoot<T>{
+        self.root.clone()
+    }
+
+    fn comparator(&self)->&NaturalOrder{
+        &NaturalOrder
+    }
+
+    fn len(&self)->usize{
+        self.len
+    }
+
+    /// Check whether the interval tree is valid
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::intervaltree::IntervalTree;
+    /// let mut tree: IntervalTree<u32> = IntervalTree::new();
+    /// tree.insert((1, 5));
+    /// println!("{}", tree.check_valid());
+    /// ```
+    fn check_valid_into<W: fmt::Write+?Sized>(&self, out: &mut W)->bool{
+        if !check_valid_node(&self.root, &NaturalOrder, out){
+            return false;
+        }
+        if let Some(root) = &self.root{
+            if check_max_end(root).is_none(){
+                report!(out, "Max endpoints are out of date");
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Helper for count_leaves()
+    fn DEFAULT_LEAF_NUM(&self)->u32{
+        2
+    }
+    /// Helper for height()
+    fn DEFAULT_HEIGHT_NUM(&self)->u32{
+        1
+    }
+}
+
+impl<T: Ord+Clone+Debug> SimpleTreeTrait<(T, T)> for IntervalTree<T>{
+    fn insert(&mut self, value: (T, T))->bool{
+        IntervalTree::<T>::insert(self, value)
+    }
+    fn delete(&mut self, value: &(T, T))->Option<(T, T)>{
+        IntervalTree::<T>::delete(self, value)
+    }
+    fn count_leaves(&self)->u32{
+        TreeTrait::count_leaves(self)
+    }
+    fn is_empty(&self)->bool{
+        IntervalTree::<T>::is_empty(self)
+    }
+    #[cfg(feature = "std")]
+    fn print(&self, verbose: bool){
+        TreeTrait::print(self, verbose)
+    }
+    fn height(&self)->u32{
+        TreeTrait::height(self)
+    }
+    fn in_order_traverse(&self)->Vec<(T, T)>{
+        TreeTrait::in_order_traverse(self)
+    }
+}
+
+impl<'a, T: Ord+Clone+Debug> IntoIterator for &'a IntervalTree<T>{
+    type Item = tree::ValueRef<'a, (T, T)>;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self)->Iter<'a, T>{
+        self.iter()
+    }
+}
+
+impl<T: Ord+Clone+Debug> Default for IntervalTree<T>{
+    fn default()->Self{
+        Self::new()
+    }
+}
+
+impl<T: Ord+Clone+Debug> Drop for IntervalTree<T>{
+    fn drop(&mut self){
+        tree::drop_tree(self.root.take(), TreeNode::take_children);
+    }
+}
+
+impl<T: Ord+Clone+Debug> IntervalTree<T>{
+    /// Create a new IntervalTree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::intervaltree::IntervalTree;
+    /// let mut tree: IntervalTree<u32> = IntervalTree::new();
+    /// ```
+    pub fn new()->Self{
+        IntervalTree{root: None, len: 0}
+    }
+
+    /// Insert an interval to the IntervalTree
+    ///
+    /// Return false if the interval was already present
+    ///
+    /// # Panic
+    /// the interval ends before it starts
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::intervaltree::IntervalTree;
+    /// let mut tree: IntervalTree<u32> = IntervalTree::new();
+    /// assert!(tree.insert((1, 5)));
+    /// assert!(!tree.insert((1, 5)));
+    /// ```
+    pub fn insert(&mut self, interval: (T, T))->bool{
+        assert!(interval.0 <= interval.1, "Error! The interval ends before it starts!");
+        let (new_root, old) = insert_entry(self.root.clone(), interval, (), &NaturalOrder);
+        self.root = new_root;
+        if old.is_none(){
+            self.len += 1;
+        }
+        old.is_none()
+    }
+
+    /// Delete an interval from the IntervalTree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::intervaltree::IntervalTree;
+    /// let mut tree: IntervalTree<u32> = IntervalTree::new();
+    /// tree.insert((1, 5));
+    /// assert_eq!(tree.delete(&(1, 5)), Some((1, 5)));
+    /// assert_eq!(tree.delete(&(1, 5)), None);
+    /// ```
+    pub fn delete(&mut self, interval: &(T, T))->Option<(T, T)>{
+        let node = search_node(self.root.clone(), interval, &NaturalOrder).flatten()?;
+        let (deleted, _) = delete_node(&mut self.root, node);
+        self.len -= 1;
+        Some(deleted)
+    }
+
+    /// Get all intervals overlapping the closed interval `[low, high]`, ordered like the tree
+    ///
+    /// Sub-trees whose max endpoint is below `low` are skipped, and the walk
+    /// stops at the first interval starting after `high`
+    ///
+    /// # Panic
+    /// the query ends before it starts
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::intervaltree::IntervalTree;
+    /// let mut tree: IntervalTree<u32> = IntervalTree::new();
+    /// tree.insert((1, 3));
+    /// tree.insert((2, 8));
+    /// tree.insert((6, 7));
+    /// tree.insert((9, 12));
+    /// assert_eq!(tree.overlapping(&4, &6), vec![(2, 8), (6, 7)]);
+    /// ```
+    pub fn overlapping(&self, low: &T, high: &T)->Vec<(T, T)>{
+        assert!(low <= high, "Error! The interval ends before it starts!");
+        let mut result = Vec::new();
+        let mut stack = Vec::new();
+        let mut current = self.root.clone();
+        loop{
+            while let Some(nd) = current.take(){
+                if nd.borrow().augmentation().0.as_ref() < Some(low){
+                    break;
+                }
+                current = nd.borrow().left();
+                stack.push(nd);
+            }
+            let nd = match stack.pop(){
+                Some(nd)=>nd,
+                None=>break
+            };
+            let interval = nd.borrow().value().clone();
+            if interval.0 > *high{
+                break;
+            }
+            if interval.1 >= *low{
+                result.push(interval);
+            }
+            current = nd.borrow().right();
+        }
+        result
+    }
+
+    /// Get all intervals containing `point`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::intervaltree::IntervalTree;
+    /// let mut tree: IntervalTree<u32> = IntervalTree::new();
+    /// tree.insert((1, 3));
+    /// tree.insert((2, 8));
+    /// tree.insert((6, 7));
+    /// assert_eq!(tree.stabbing(&3), vec![(1, 3), (2, 8)]);
+    /// ```
+    pub fn stabbing(&self, point: &T)->Vec<(T, T)>{
+        self.overlapping(point, point)
+    }
+
+    /// Check whether the IntervalTree contains an interval
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::intervaltree::IntervalTree;
+    /// let mut tree: IntervalTree<u32> = IntervalTree::new();
+    /// tree.insert((1, 5));
+    /// assert!(tree.search(&(1, 5)));
+    /// ```
+    pub fn search(&self, interval: &(T, T))->bool{
+        TreeTrait::<(T, T), TreeNode<T>>::search(self, interval)
+    }
+
+    /// Get the number of intervals in the IntervalTree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::intervaltree::IntervalTree;
+    /// let mut tree: IntervalTree<u32> = IntervalTree::new();
+    /// tree.insert((1, 5));
+    /// assert_eq!(tree.len(), 1);
+    /// ```
+    pub fn len(&self)->usize{
+        self.len
+    }
+
+    /// Check if the IntervalTree is empty
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::intervaltree::IntervalTree;
+    /// let tree: IntervalTree<u32> = IntervalTree::new();
+    /// assert!(tree.is_empty());
+    /// ```
+    pub fn is_empty(&self)->bool{
+        self.len == 0
+    }
+
+    /// Iterate over the intervals in order
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::intervaltree::IntervalTree;
+    /// let mut tree: IntervalTree<u32> = IntervalTree::new();
+    /// tree.insert((4, 5));
+    /// tree.insert((1, 9));
+    /// assert_eq!(tree.iter().map(|v| *v).collect::<Vec<_>>(), vec![(1, 9), (4, 5)]);
+    /// ```
+    pub fn iter(&self)->Iter<'_, T>{
+        TreeTrait::<(T, T), TreeNode<T>>::iter(self)
+    }
+
+    /// Check whether the interval tree is valid
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::intervaltree::IntervalTree;
+    /// let mut tree: IntervalTree<u32> = IntervalTree::new();
+    /// tree.insert((1, 5));
+    /// assert!(tree.check_valid());
+    /// ```
+    pub fn check_valid(&self)->bool{
+        TreeTrait::<(T, T), TreeNode<T>>::check_valid(self)
+    }
+}
+
+/// Helper for check_valid()
+///
+/// Return the max endpoint of the sub-tree if all stored ones are up to date
+fn check_max_end<T: Ord+Clone+Debug>(node: &Shared<NodeCell<TreeNode<T>>>)->Option<T>{
+    let nd = node.borrow();
+    let mut max = nd.value().1.clone();
+    for child in [nd.left(), nd.right()].into_iter().flatten(){
+        let child_max = check_max_end(&child)?;
+        if child_max > max{
+            max = child_max;
+        }
+    }
+    if nd.augmentation().0.as_ref() != Some(&max){
+        return None;
+    }
+    Some(max)
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    fn brute_force(intervals: &[(i32, i32)], low: i32, high: i32)->Vec<(i32, i32)>{
+        let mut result: Vec<(i32, i32)> = intervals.iter().filter(|(l, h)| *l <= high && low <= *h).cloned().collect();
+        result.sort();
+        result
+    }
+
+    #[test]
+    fn test_overlapping(){
+        let mut rng = StdRng::seed_from_u64(12);
+        let mut tree = IntervalTree::new();
+        let mut intervals: Vec<(i32, i32)> = Vec::new();
+        for round in 0..3000{
+            let low = rng.gen_range(0, 1000);
+            let interval = (low, low + rng.gen_range(0, 60));
+            if round % 3 == 2 && !intervals.is_empty(){
+                let removed = intervals.swap_remove(rng.gen_range(0, intervals.len()));
+                assert_eq!(tree.delete(&removed), Some(removed));
+            }
+            else if !intervals.contains(&interval){
+                assert!(tree.insert(interval));
+                intervals.push(interval);
+            }
+            if round % 100 == 0{
+                assert!(tree.check_valid());
+            }
+        }
+        assert!(tree.check_valid());
+        assert_eq!(tree.len(), intervals.len());
+        for _ in 0..300{
+            let low = rng.gen_range(-10, 1100);
+            let high = low + rng.gen_range(0, 40);
+            assert_eq!(tree.overlapping(&low, &high), brute_force(&intervals, low, high));
+            assert_eq!(tree.stabbing(&low), brute_force(&intervals, low, low));
+        }
+    }
+
+    #[test]
+    fn test_endpoints(){
+        let mut tree = IntervalTree::new();
+        assert!(tree.overlapping(&0, &10).is_empty());
+        for interval in [(5, 10), (0, 5), (10, 10), (11, 20), (0, 100)]{
+            tree.insert(interval);
+        }
+        assert_eq!(tree.stabbing(&10), vec![(0, 100), (5, 10), (10, 10)]);
+        assert_eq!(tree.stabbing(&5), vec![(0, 5), (0, 100), (5, 10)]);
+        assert_eq!(tree.overlapping(&101, &200), Vec::new());
+        tree.delete(&(0, 100));
+        assert!(tree.check_valid());
+        assert_eq!(tree.overlapping(&21, &50), Vec::new());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_reversed_interval(){
+        let mut tree = IntervalTree::new();
+        tree.insert((3, 1));
+    }
+}
@@ -1,9 +1,48 @@
 //! A lib for binary tree implementations.
 //!
-//! AVL Tree and RedBlackTree are available
+//! AVL Tree and RedBlackTree are available, along with the AVLMap and RedBlackMap key-value variants,
+//! an IntervalTree built on the red black tree, an immutable PersistentRedBlackTree
+//! and the arena-backed ArenaRedBlackTree and ArenaAVLTree
+//!
+//! With the `sync` feature all trees are `Send` and `Sync` when their values are, so they can be
+//! shared between threads. The nodes then sit behind `Arc` and a read-write lock, which makes
+//! every operation slower than with the default `Rc` and `RefCell`. Any number of threads can
+//! read a shared `&Tree` at once; for a writer alongside them, put the tree behind an
+//! `Arc<RwLock<_>>`. The arena-backed trees hold no pointers and are `Send` and `Sync` either way.
+//!
+//! The default `std` feature can be turned off to build against `alloc` only, e.g. for embedded
+//! targets. The printing helpers are left out then and check_valid() fails without telling why,
+//! but render() and check_valid_into() still write into any `core::fmt::Write`.
+//!
+//! The `serde` feature serializes RedBlackTree and AVLTree as the sorted sequence of their values.
+//! Deserializing builds the tree in linear time and rejects input that is not strictly increasing.
+//!
+//! With `std` both trees can also be saved to and loaded from a compact binary snapshot,
+//! see the snapshot module.
 //!
 //! And you're free to extend to more structures.
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+#![allow(non_snake_case)]
+
+extern crate alloc;
+
+/// Write a line on why check_valid() failed into `out`, any `fmt::Write`
+macro_rules! report{
+    ($out:expr, $($arg:tt)*)=>{{
+        let _ = core::fmt::Write::write_fmt($out, format_args!("{}\n", format_args!($($arg)*)));
+    }};
+}
+
 pub mod rbtree;
 pub mod avltree;
+pub mod intervaltree;
+pub mod persistent;
+pub mod arena;
+pub mod multiset;
+#[cfg(feature = "std")]
+pub mod snapshot;
 pub mod tree;
+pub mod error;
 pub mod prelude;
+
+pub use error::Error;
@@ -1,9 +1,7 @@
 fn main(){
     //! main
-    /// It's an implementation of two binary trees, AVL tree and red-black tree.
-    ///
-    
-    use testing::*;
+    //!
+    //! It's an implementation of two binary trees, AVL tree and red-black tree.
     testing::main_loop();
 }
 
@@ -15,7 +13,7 @@ mod testing{
         tree: Option<Box<dyn SimpleTreeTrait<i32>>>
     }
 
-    const TREETYPES:[&str; 2] = ["A", "R"];
+    const TREETYPES:[&str; 3] = ["A", "R", "P"];
 
     fn menu(){
         println!("====== Tree Test ======");
@@ -41,7 +39,9 @@ mod testing{
             let choice = get_choice();
             match choice{
                 1 =>{
-                    tester = Tester::new();
+                    if let Some(chosen) = Tester::new(){
+                        tester = chosen;
+                    }
                 },
                 10 =>{
                     println!("ByeBye!");
@@ -89,7 +89,7 @@ mod testing{
                 }
             };
 
-            if choice < 0 || choice > 10{
+            if !(0..=10).contains(&choice){
                 println!("Please input a choice between 0 - 10");
                 continue;
             }
@@ -118,7 +118,7 @@ mod testing{
 
     fn get_tree_type()->String{
         loop{ 
-            println!("Input tree type (R or A, R is redblacktree, A is AVL tree): ");
+            println!("Input tree type (R, A or P, R is redblacktree, A is AVL tree, P is persistent redblacktree): ");
             let mut choice = String::new();
             io::stdin()
                 .read_line(&mut choice)
@@ -133,27 +133,25 @@ mod testing{
 
     
     impl Tester{
-        fn init()->Self{
-            let tmp = RedBlackTree::<i32>::new();
-            let mut tester = Tester{ tree: Some(Box::new(tmp)) };
-            tester.tree = None;
-            tester
-        }
-
-        pub fn new()->Self{
+        pub fn new()->Option<Self>{
             let choice = get_tree_type();
             println!("Choice: {}", choice);
             match choice.as_str(){
                 "A"=>{
                     println!("Current Tree is AVL Tree");
-                    Tester{tree: Some(Box::new(AVLTree::<i32>::new()))}
+                    Some(Tester{tree: Some(Box::new(AVLTree::<i32>::new()))})
                 },
                 "R"=>{
                     println!("Current Tree is Red Black Tree");
-                    Tester{tree: Some(Box::new(RedBlackTree::<i32>::new()))}
+                    Some(Tester{tree: Some(Box::new(RedBlackTree::<i32>::new()))})
+                },
+                "P"=>{
+                    println!("Current Tree is Persistent Red Black Tree");
+                    Some(Tester{tree: Some(Box::new(PersistentRedBlackTree::<i32>::new()))})
                 },
                 _=>{
-                    panic!("Invalid tree type {:?}", choice);
+                    println!("Invalid tree type {:?}", choice);
+                    None
                 }
             }
         }
@@ -161,10 +159,10 @@ mod testing{
         fn insert(&mut self){
             let node = get_node();
             if let Some(tree) = &mut self.tree{
-                if !tree.insert(node){
-                    println!("The node {} already exists in the tree!", node);
-                } else{
-                    println!("Insert node {} successfully", node);
+                match tree.try_insert(node){
+                    Ok(false) => println!("The node {} already exists in the tree!", node),
+                    Ok(true) => println!("Insert node {} successfully", node),
+                    Err(error) => println!("Failed to insert node {}: {}!", node, error)
                 }
             }
         }
@@ -172,10 +170,10 @@ mod testing{
         fn delete(&mut self){
             let node = get_node();
             if let Some(tree) = &mut self.tree{
-                if tree.delete(node).is_none(){
-                    println!("The node {} doesn't exist in the tree!", node);
-                } else{
-                    println!("Delete node {} successfully", node);
+                match tree.try_delete(&node){
+                    Ok(None) => println!("The node {} doesn't exist in the tree!", node),
+                    Ok(Some(_)) => println!("Delete node {} successfully", node),
+                    Err(error) => println!("Failed to delete node {}: {}!", node, error)
                 }
             }
         }
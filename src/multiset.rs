@@ -0,0 +1,318 @@
+//! Multisets
+//!
+//! An ordered multiset on top of RedBlackMap or AVLMap, see RedBlackMultiSet and AVLMultiSet.
+//! Values comparing equal share one node: the first one inserted is its key and the ones
+//! inserted after it are kept next to it, so every duplicate is yielded as it was inserted.
+
+use core::fmt::{self, Debug};
+use core::cmp::Ordering;
+use core::marker::PhantomData;
+use alloc::vec::Vec;
+
+use crate::tree::{self, TreeNodeTrait, Comparator, NaturalOrder, NodeCell, Shared, Stdout, ValueRef, ValueMut};
+
+/// Ordered map a MultiSet keeps its values in
+///
+/// Maps the first copy of every value to the copies inserted after it.
+/// Implemented by RedBlackMap and AVLMap.
+pub trait MultiSetMap<T: Debug>{
+    /// Comparator ordering the values
+    type Order: Comparator<T>;
+    /// Node of the underlying tree
+    type Node: TreeNodeTrait<T>;
+
+    /// Create an empty map ordered by `comparator`
+    fn with_comparator(comparator: Self::Order)->Self;
+    /// Get the comparator of the map
+    fn comparator(&self)->&Self::Order;
+    /// Get the root of the underlying tree
+    fn root(&self)->Option<Shared<NodeCell<Self::Node>>>;
+    /// Get the copies kept in a node after its key
+    fn copies(node: &Self::Node)->&[T];
+    /// Insert a key with its copies, see RedBlackMap::insert()
+    fn insert(&mut self, key: T, copies: Vec<T>)->Option<Vec<T>>;
+    /// Remove a key with its copies, see RedBlackMap::remove_entry()
+    fn remove_entry(&mut self, key: &T)->Option<(T, Vec<T>)>;
+    /// Get the copies of a key, see RedBlackMap::get()
+    fn get(&self, key: &T)->Option<ValueRef<'_, Vec<T>>>;
+    /// Get the copies of a key mutably, see RedBlackMap::get_mut()
+    fn get_mut(&mut self, key: &T)->Option<ValueMut<'_, Vec<T>>>;
+    /// Number of keys in the map
+    fn len(&self)->usize;
+    /// Check whether the map is empty
+    fn is_empty(&self)->bool{
+        self.len() == 0
+    }
+    /// Check whether the underlying tree is valid, writing why it isn't into `out`
+    fn check_valid_into<W: fmt::Write+?Sized>(&self, out: &mut W)->bool;
+}
+
+/// Ordered multiset over the map `M`
+///
+/// Use it as RedBlackMultiSet or AVLMultiSet.
+pub struct MultiSet<T, M>{
+    map: M,
+    /// Number of values counting the duplicates
+    len: usize,
+    _marker: PhantomData<T>
+}
+
+impl<T: Debug, M: MultiSetMap<T>> Default for MultiSet<T, M> where M::Order: Default{
+    fn default()->Self{
+        Self::with_comparator(M::Order::default())
+    }
+}
+
+impl<'a, T: Debug+'a, M: MultiSetMap<T>> IntoIterator for &'a MultiSet<T, M> where M::Node: 'a{
+    type Item = ValueRef<'a, T>;
+    type IntoIter = tree::MultiIter<'a, T, M::Node>;
+
+    fn into_iter(self)->Self::IntoIter{
+        self.iter()
+    }
+}
+
+impl<T: Debug, M: MultiSetMap<T>> FromIterator<T> for MultiSet<T, M> where M::Order: Default{
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I)->Self{
+        let mut multiset = Self::default();
+        multiset.extend(iter);
+        multiset
+    }
+}
+
+impl<T: Debug, M: MultiSetMap<T>> Extend<T> for MultiSet<T, M>{
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I){
+        for value in iter{
+            self.insert(value);
+        }
+    }
+}
+
+impl<T: Ord+Debug, M: MultiSetMap<T, Order = NaturalOrder>> MultiSet<T, M>{
+    /// Create a new MultiSet
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMultiSet;
+    /// let mut multiset: RedBlackMultiSet<u32> = RedBlackMultiSet::new();
+    /// ```
+    pub fn new()->Self{
+        Self::with_comparator(NaturalOrder)
+    }
+}
+
+impl<T: Debug, M: MultiSetMap<T>> MultiSet<T, M>{
+    /// Create a new MultiSet ordered by `comparator`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLMultiSet;
+    /// let mut multiset = AVLMultiSet::with_comparator(|a: &u32, b: &u32| b.cmp(a));
+    /// multiset.insert(3);
+    /// multiset.insert(8);
+    /// multiset.insert(3);
+    /// assert_eq!(multiset.iter().map(|v| *v).collect::<Vec<_>>(), vec![8, 3, 3]);
+    /// ```
+    pub fn with_comparator(comparator: M::Order)->Self{
+        MultiSet{map: M::with_comparator(comparator), len: 0, _marker: PhantomData}
+    }
+
+    /// Insert a value to the MultiSet
+    ///
+    /// A value equal to ones already present is kept after them;
+    /// Return the count of the value after insertion
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMultiSet;
+    /// let mut multiset: RedBlackMultiSet<u32> = RedBlackMultiSet::new();
+    /// assert_eq!(multiset.insert(8), 1);
+    /// assert_eq!(multiset.insert(8), 2);
+    /// ```
+    pub fn insert(&mut self, value: T)->usize{
+        self.len += 1;
+        if let Some(mut copies) = self.map.get_mut(&value){
+            copies.push(value);
+            return 1 + copies.len();
+        }
+        self.map.insert(value, Vec::new());
+        1
+    }
+
+    /// Count the copies of a value in the MultiSet
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMultiSet;
+    /// let multiset: RedBlackMultiSet<u32> = vec![1, 8, 8].into_iter().collect();
+    /// assert_eq!(multiset.count(&8), 2);
+    /// assert_eq!(multiset.count(&2), 0);
+    /// ```
+    pub fn count(&self, value: &T)->usize{
+        self.map.get(value).map_or(0, |copies| 1 + copies.len())
+    }
+
+    /// Remove one copy of a value from the MultiSet
+    ///
+    /// The copy inserted last goes first; Return it if the value was present
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::avltree::AVLMultiSet;
+    /// let mut multiset = AVLMultiSet::with_comparator(|a: &(u32, char), b: &(u32, char)| a.0.cmp(&b.0));
+    /// multiset.extend([(8, 'a'), (8, 'b')]);
+    /// assert_eq!(multiset.remove_one(&(8, '?')), Some((8, 'b')));
+    /// assert_eq!(multiset.count(&(8, '?')), 1);
+    /// assert_eq!(multiset.remove_one(&(8, '?')), Some((8, 'a')));
+    /// assert_eq!(multiset.remove_one(&(8, '?')), None);
+    /// ```
+    pub fn remove_one(&mut self, value: &T)->Option<T>{
+        let last = self.map.get_mut(value)?.pop();
+        let removed = match last{
+            Some(copy)=>copy,
+            None=>self.map.remove_entry(value)?.0
+        };
+        self.len -= 1;
+        Some(removed)
+    }
+
+    /// Remove all copies of a value from the MultiSet
+    ///
+    /// Return the number of copies removed
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMultiSet;
+    /// let mut multiset: RedBlackMultiSet<u32> = vec![1, 8, 8].into_iter().collect();
+    /// assert_eq!(multiset.remove_all(&8), 2);
+    /// assert_eq!(multiset.len(), 1);
+    /// ```
+    pub fn remove_all(&mut self, value: &T)->usize{
+        let removed = self.map.remove_entry(value).map_or(0, |(_, copies)| 1 + copies.len());
+        self.len -= removed;
+        removed
+    }
+
+    /// Check whether the MultiSet contains a value
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMultiSet;
+    /// let mut multiset: RedBlackMultiSet<u32> = RedBlackMultiSet::new();
+    /// multiset.insert(8);
+    /// assert!(multiset.contains(&8));
+    /// ```
+    pub fn contains(&self, value: &T)->bool{
+        self.map.get(value).is_some()
+    }
+
+    /// Get the number of values in the MultiSet, counting the duplicates
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMultiSet;
+    /// let multiset: RedBlackMultiSet<u32> = vec![1, 8, 8].into_iter().collect();
+    /// assert_eq!(multiset.len(), 3);
+    /// ```
+    pub fn len(&self)->usize{
+        self.len
+    }
+
+    /// Get the number of distinct values in the MultiSet
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMultiSet;
+    /// let multiset: RedBlackMultiSet<u32> = vec![1, 8, 8].into_iter().collect();
+    /// assert_eq!(multiset.distinct_len(), 2);
+    /// ```
+    pub fn distinct_len(&self)->usize{
+        self.map.len()
+    }
+
+    /// Check whether the MultiSet is empty
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMultiSet;
+    /// let multiset: RedBlackMultiSet<u32> = RedBlackMultiSet::new();
+    /// assert!(multiset.is_empty());
+    /// ```
+    pub fn is_empty(&self)->bool{
+        self.len == 0
+    }
+
+    /// Iterate over the values in ascending order, yielding each duplicate in insertion order
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMultiSet;
+    /// let multiset: RedBlackMultiSet<u32> = vec![8, 1, 8].into_iter().collect();
+    /// assert_eq!(multiset.iter().map(|v| *v).collect::<Vec<_>>(), vec![1, 8, 8]);
+    /// ```
+    pub fn iter(&self)->tree::MultiIter<'_, T, M::Node>{
+        tree::MultiIter::new(self.map.root(), self.len, M::copies)
+    }
+
+    /// Check whether the MultiSet is valid, writing why it isn't into `out`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMultiSet;
+    /// let multiset: RedBlackMultiSet<u32> = vec![8, 1, 8].into_iter().collect();
+    /// let mut report = String::new();
+    /// assert!(multiset.check_valid_into(&mut report));
+    /// assert!(report.is_empty());
+    /// ```
+    pub fn check_valid_into<W: fmt::Write+?Sized>(&self, out: &mut W)->bool{
+        if !self.map.check_valid_into(out){
+            return false;
+        }
+        let mut total = 0;
+        let mut node = self.map.root().map(tree::get_min_node);
+        while let Some(nd) = node{
+            {
+                let nd = nd.borrow();
+                let copies = M::copies(&nd);
+                if let Some(copy) = copies.iter().find(|copy| self.map.comparator().compare(copy, nd.value()) != Ordering::Equal){
+                    report!(out, "Copy {:?} is kept with {:?}", copy, nd.value());
+                    return false;
+                }
+                total += 1 + copies.len();
+            }
+            node = tree::get_next_node(&nd);
+        }
+        if total != self.len{
+            report!(out, "Length is {} instead of {}", self.len, total);
+            return false;
+        }
+        true
+    }
+
+    /// Check whether the MultiSet is valid
+    ///
+    /// With the `std` feature why it isn't is printed, see check_valid_into()
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMultiSet;
+    /// let multiset: RedBlackMultiSet<u32> = vec![8, 1, 8].into_iter().collect();
+    /// assert!(multiset.check_valid());
+    /// ```
+    pub fn check_valid(&self)->bool{
+        self.check_valid_into(&mut Stdout)
+    }
+}
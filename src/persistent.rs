@@ -0,0 +1,879 @@
+//! Persistent Red Black Tree
+//!
+//! An immutable red black tree. Updates copy the path from the root to the
+//! changed node and share every other node with the old version through reference
+//! counting, so old versions stay usable and snapshots cost O(1). With the `sync`
+//! feature snapshots are `Send` and `Sync`, so readers on other threads can keep
+//! one while a writer moves on.
+//!
+//! Nodes have no parent pointers; insertion follows Okasaki's balance and
+//! deletion follows Kahrs' algorithm.
+
+use core::fmt::{self, Debug};
+use core::cmp::{max, Ordering};
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub use crate::tree::SimpleTreeTrait;
+pub use crate::rbtree::NodeColor;
+use crate::tree::{Comparator, NaturalOrder, Stdout, Shared};
+
+/// Node of the persistent red black tree
+///
+/// Never changed once it is shared
+#[derive(Debug)]
+struct TreeNode<T>{
+    color: NodeColor,
+    value: T,
+    left: TreeRoot<T>,
+    right: TreeRoot<T>
+}
+
+type TreeRoot<T> = Option<Shared<TreeNode<T>>>;
+
+/// Struct of the persistent red black tree
+///
+/// Cloning a tree is O(1) and shares all nodes
+#[derive(Clone)]
+pub struct PersistentRedBlackTree<T: Debug+Clone, C=NaturalOrder>{
+    root: TreeRoot<T>,
+    len: usize,
+    /// Comparator ordering the values
+    comparator: C
+}
+
+/// In-order iterator over a PersistentRedBlackTree
+pub struct Iter<'a, T>{
+    /// Nodes whose value and right sub-tree are still to visit
+    stack: Vec<&'a TreeNode<T>>,
+    len: usize
+}
+
+impl<T: Debug+Clone, C: Comparator<T>+Clone> SimpleTreeTrait<T> for PersistentRedBlackTree<T, C>{
+    fn insert(&mut self, value: T)->bool{
+        let len = self.len;
+        *self = PersistentRedBlackTree::<T, C>::insert(self, value);
+        self.len != len
+    }
+    fn delete(&mut self, value: &T)->Option<T>{
+        let removed = self.get(value)?.clone();
+        *self = self.remove(value);
+        Some(removed)
+    }
+    fn count_leaves(&self)->u32{
+        PersistentRedBlackTree::<T, C>::count_leaves(self)
+    }
+    fn is_empty(&self)->bool{
+        PersistentRedBlackTree::<T, C>::is_empty(self)
+    }
+    #[cfg(feature = "std")]
+    fn print(&self, verbose: bool){
+        PersistentRedBlackTree::<T, C>::print(self, verbose)
+    }
+    fn height(&self)->u32{
+        PersistentRedBlackTree::<T, C>::height(self)
+    }
+    fn in_order_traverse(&self)->Vec<T>{
+        PersistentRedBlackTree::<T, C>::in_order_traverse(self)
+    }
+}
+
+impl<'a, T: Debug+Clone, C: Comparator<T>> IntoIterator for &'a PersistentRedBlackTree<T, C>{
+    type Item = &'a T;
+    type IntoIter = Iter<'a, T>;
+
+    fn into_iter(self)->Iter<'a, T>{
+        self.iter()
+    }
+}
+
+impl<T: Debug+Clone, C: Comparator<T>> fmt::Display for PersistentRedBlackTree<T, C>{
+    /// Draw the structure of the tree, as print(false) does
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        self.render(f, false)
+    }
+}
+
+impl<T: Debug+Clone, C> fmt::Debug for PersistentRedBlackTree<T, C>{
+    /// List every node under its parent, as print(true) does before drawing the structure
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        match self.root.as_deref(){
+            None=>writeln!(f, "Empty tree!"),
+            Some(root)=>root.write_tree_as_fmt(f)
+        }
+    }
+}
+
+impl<T: Debug+Clone, C: Comparator<T>+Default> Default for PersistentRedBlackTree<T, C>{
+    fn default()->Self{
+        Self::with_comparator(C::default())
+    }
+}
+
+impl<T: Debug+Clone, C: Comparator<T>+Clone+Default> FromIterator<T> for PersistentRedBlackTree<T, C>{
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I)->Self{
+        let mut tree = Self::default();
+        for value in iter{
+            tree = tree.insert(value);
+        }
+        tree
+    }
+}
+
+impl<T: Ord+Debug+Clone> PersistentRedBlackTree<T>{
+    /// Create a new PersistentRedBlackTree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree: PersistentRedBlackTree<u32> = PersistentRedBlackTree::new();
+    /// ```
+    pub fn new()->Self{
+        Self::with_comparator(NaturalOrder)
+    }
+}
+
+impl<T: Debug+Clone, C: Comparator<T>> PersistentRedBlackTree<T, C>{
+    /// Create a new PersistentRedBlackTree ordered by `comparator`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree = PersistentRedBlackTree::with_comparator(|a: &u32, b: &u32| b.cmp(a));
+    /// let tree = tree.insert(3).insert(8);
+    /// assert_eq!(tree.in_order_traverse(), vec![8, 3]);
+    /// ```
+    pub fn with_comparator(comparator: C)->Self{
+        PersistentRedBlackTree{root: None, len: 0, comparator}
+    }
+
+    /// Return a new version of the tree with `value` inserted, O(log n)
+    ///
+    /// The tree itself is left unchanged; an equal value already present is kept
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let empty: PersistentRedBlackTree<u32> = PersistentRedBlackTree::new();
+    /// let tree = empty.insert(8);
+    /// assert!(tree.search(&8));
+    /// assert!(empty.is_empty());
+    /// ```
+    pub fn insert(&self, value: T)->Self where C: Clone{
+        let (root, inserted) = insert_node(&self.root, value, &self.comparator);
+        if !inserted{
+            return self.clone();
+        }
+        PersistentRedBlackTree{root: blacken(root), len: self.len + 1, comparator: self.comparator.clone()}
+    }
+
+    /// Return a new version of the tree without `value`, O(log n)
+    ///
+    /// The tree itself is left unchanged
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree: PersistentRedBlackTree<u32> = (1..10).collect();
+    /// let smaller = tree.remove(&5);
+    /// assert!(!smaller.search(&5));
+    /// assert!(tree.search(&5));
+    /// ```
+    pub fn remove(&self, value: &T)->Self where C: Clone{
+        if !self.search(value){
+            return self.clone();
+        }
+        let root = delete_node(&self.root, value, &self.comparator);
+        PersistentRedBlackTree{root: blacken(root), len: self.len - 1, comparator: self.comparator.clone()}
+    }
+
+    /// Get a reference to the value in the tree equal to `value`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree = PersistentRedBlackTree::new().insert(String::from("a"));
+    /// assert_eq!(tree.get(&String::from("a")).unwrap(), "a");
+    /// ```
+    pub fn get(&self, value: &T)->Option<&T>{
+        let mut current = self.root.as_ref();
+        while let Some(node) = current{
+            current = match self.comparator.compare(value, &node.value){
+                Ordering::Less=>node.left.as_ref(),
+                Ordering::Greater=>node.right.as_ref(),
+                Ordering::Equal=>return Some(&node.value)
+            };
+        }
+        None
+    }
+
+    /// Search a value in the tree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree: PersistentRedBlackTree<u32> = PersistentRedBlackTree::new().insert(8);
+    /// assert!(tree.search(&8));
+    /// ```
+    pub fn search(&self, value: &T)->bool{
+        self.get(value).is_some()
+    }
+
+    /// Get the number of values in the tree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree: PersistentRedBlackTree<u32> = (1..10).collect();
+    /// assert_eq!(tree.len(), 9);
+    /// ```
+    pub fn len(&self)->usize{
+        self.len
+    }
+
+    /// Check if the tree is empty
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree: PersistentRedBlackTree<u32> = PersistentRedBlackTree::new();
+    /// assert!(tree.is_empty());
+    /// ```
+    pub fn is_empty(&self)->bool{
+        self.root.is_none()
+    }
+
+    /// Iterate over the values in order
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree: PersistentRedBlackTree<u32> = vec![3, 1, 2].into_iter().collect();
+    /// assert_eq!(tree.iter().cloned().collect::<Vec<_>>(), vec![1, 2, 3]);
+    /// ```
+    pub fn iter(&self)->Iter<'_, T>{
+        let mut iter = Iter{stack: Vec::new(), len: self.len};
+        iter.push_left(self.root.as_deref());
+        iter
+    }
+
+    /// In-order traverse of the tree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree: PersistentRedBlackTree<u32> = vec![3, 1, 2].into_iter().collect();
+    /// assert_eq!(tree.in_order_traverse(), vec![1, 2, 3]);
+    /// ```
+    pub fn in_order_traverse(&self)->Vec<T>{
+        self.iter().cloned().collect()
+    }
+
+    /// Count number of leaves in the tree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree: PersistentRedBlackTree<u32> = (1..4).collect();
+    /// assert_eq!(tree.count_leaves(), 2);
+    /// ```
+    pub fn count_leaves(&self)->u32{
+        match &self.root{
+            // same as RedBlackTree
+            None=>2,
+            Some(root)=>root.count_leaves()
+        }
+    }
+
+    /// Get height of the tree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree: PersistentRedBlackTree<u32> = (1..4).collect();
+    /// assert_eq!(tree.height(), 2);
+    /// ```
+    pub fn height(&self)->u32{
+        match &self.root{
+            // same as RedBlackTree
+            None=>1,
+            Some(root)=>root.height()
+        }
+    }
+
+    /// Render the tree into `out`
+    ///
+    /// Write the structure sideways, the right sub-trees above, with `b` marking black nodes;
+    ///
+    /// Additional verbose information of the nodes if verbose is true
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree: PersistentRedBlackTree<u32> = (1..4).collect();
+    /// let mut text = String::new();
+    /// tree.render(&mut text, false).unwrap();
+    /// assert_eq!(text, "    3b\n2b\n    1b\n");
+    /// ```
+    pub fn render<W: fmt::Write+?Sized>(&self, out: &mut W, verbose: bool)->fmt::Result{
+        let Some(root) = self.root.as_deref() else{
+            return writeln!(out, "Empty tree!");
+        };
+        if verbose{
+            root.write_tree_as_fmt(out)?;
+        }
+        // reverse in-order walk, the right-most node first
+        let mut stack = Vec::new();
+        let mut current = Some((root, 0));
+        loop{
+            while let Some((node, depth)) = current{
+                stack.push((node, depth));
+                current = node.right.as_deref().map(|right| (right, depth + 1));
+            }
+            let Some((node, depth)) = stack.pop() else{
+                return Ok(());
+            };
+            let color = match node.color{
+                NodeColor::Red=>"",
+                NodeColor::Black=>"b"
+            };
+            writeln!(out, "{}{:?}{}", "    ".repeat(depth), node.value, color)?;
+            current = node.left.as_deref().map(|left| (left, depth + 1));
+        }
+    }
+
+    /// Print the tree, see render()
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree: PersistentRedBlackTree<u32> = (1..4).collect();
+    /// tree.print(true);
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn print(&self, verbose: bool){
+        let _ = self.render(&mut Stdout, verbose);
+    }
+
+    /// Check whether the tree is a valid red black tree
+    ///
+    /// With the `std` feature why it isn't is printed, see check_valid_into()
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree: PersistentRedBlackTree<u32> = (1..100).collect();
+    /// assert!(tree.check_valid());
+    /// ```
+    pub fn check_valid(&self)->bool{
+        self.check_valid_into(&mut Stdout)
+    }
+
+    /// Check whether the tree is a valid red black tree, writing why it isn't into `out`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::persistent::PersistentRedBlackTree;
+    /// let tree: PersistentRedBlackTree<u32> = (1..100).collect();
+    /// let mut report = String::new();
+    /// assert!(tree.check_valid_into(&mut report));
+    /// assert!(report.is_empty());
+    /// ```
+    pub fn check_valid_into<W: fmt::Write+?Sized>(&self, out: &mut W)->bool{
+        let root = match &self.root{
+            None if self.len == 0=>return true,
+            None=>{
+                report!(out, "Length is {} instead of 0", self.len);
+                return false;
+            },
+            Some(root)=>root
+        };
+        if root.color == NodeColor::Red{
+            report!(out, "Root node {:?} should be black", root.value);
+            return false;
+        }
+        if root.check_color().is_none(){
+            report!(out, "Red nodes have red children or black heights differ");
+            return false;
+        }
+        if !root.check_order(&self.comparator, None, None){
+            report!(out, "Values are not in order");
+            return false;
+        }
+        if root.size() != self.len{
+            report!(out, "Length is {} instead of {}", self.len, root.size());
+            return false;
+        }
+        true
+    }
+}
+
+impl<'a, T> Iter<'a, T>{
+    /// Push `node` and its chain of left children
+    fn push_left(&mut self, node: Option<&'a TreeNode<T>>){
+        let mut current = node;
+        while let Some(nd) = current{
+            self.stack.push(nd);
+            current = nd.left.as_deref();
+        }
+    }
+}
+
+impl<'a, T> Iterator for Iter<'a, T>{
+    type Item = &'a T;
+
+    fn next(&mut self)->Option<&'a T>{
+        let node = self.stack.pop()?;
+        self.len -= 1;
+        self.push_left(node.right.as_deref());
+        Some(&node.value)
+    }
+
+    fn size_hint(&self)->(usize, Option<usize>){
+        (self.len, Some(self.len))
+    }
+}
+
+impl<T> ExactSizeIterator for Iter<'_, T>{}
+
+impl<T: Debug> TreeNode<T>{
+    /// Helper of count_leaves()
+    fn count_leaves(&self)->u32{
+        if self.left.is_none() && self.right.is_none(){
+            return 1;
+        }
+        self.left.as_ref().map_or(0, |l| l.count_leaves()) + self.right.as_ref().map_or(0, |r| r.count_leaves())
+    }
+
+    /// Helper of height()
+    fn height(&self)->u32{
+        1 + max(self.left.as_ref().map_or(0, |l| l.height()), self.right.as_ref().map_or(0, |r| r.height()))
+    }
+
+    /// Helper of check_valid()
+    fn size(&self)->usize{
+        1 + self.left.as_ref().map_or(0, |l| l.size()) + self.right.as_ref().map_or(0, |r| r.size())
+    }
+
+    /// Helper of check_valid()
+    ///
+    /// Return the black height of the sub-tree if no red node has a red child
+    /// and all paths have the same number of black nodes
+    fn check_color(&self)->Option<usize>{
+        let mut heights = [0, 0];
+        for (height, child) in heights.iter_mut().zip([&self.left, &self.right]){
+            if let Some(child) = child{
+                if self.color == NodeColor::Red && child.color == NodeColor::Red{
+                    return None;
+                }
+                *height = child.check_color()?;
+            }
+        }
+        if heights[0] != heights[1]{
+            return None;
+        }
+        Some(heights[0] + (self.color == NodeColor::Black) as usize)
+    }
+
+    /// Helper of check_valid()
+    ///
+    /// Check the values of the sub-tree are strictly increasing
+    /// and lie between `lower` and `upper`
+    fn check_order<C: Comparator<T>>(&self, cmp: &C, lower: Option<&T>, upper: Option<&T>)->bool{
+        if lower.is_some_and(|l| cmp.compare(l, &self.value) != Ordering::Less)
+            || upper.is_some_and(|u| cmp.compare(u, &self.value) != Ordering::Greater){
+            return false;
+        }
+        self.left.as_ref().is_none_or(|l| l.check_order(cmp, lower, Some(&self.value)))
+            && self.right.as_ref().is_none_or(|r| r.check_order(cmp, Some(&self.value), upper))
+    }
+
+    /// Write the verbose information of the sub-tree, each child under its parent
+    ///
+    /// Helper of render()
+    fn write_tree_as_fmt<W: fmt::Write+?Sized>(&self, out: &mut W)->fmt::Result{
+        let mut stack = vec![(self, 0, "")];
+        while let Some((node, depth, side)) = stack.pop(){
+            writeln!(out, "{}{}(Color: {:?}, Value: {:?}, Is Leaf: {:?})", "  ".repeat(depth), side, node.color,
+                node.value, node.left.is_none() && node.right.is_none())?;
+            // the left child is written first
+            stack.extend(node.right.as_deref().map(|right| (right, depth + 1, "right: ")));
+            stack.extend(node.left.as_deref().map(|left| (left, depth + 1, "left: ")));
+        }
+        Ok(())
+    }
+}
+
+/// Create a node
+fn new_node<T>(color: NodeColor, left: TreeRoot<T>, value: T, right: TreeRoot<T>)->TreeRoot<T>{
+    Some(Shared::new(TreeNode{color, value, left, right}))
+}
+
+fn red<T>(left: TreeRoot<T>, value: T, right: TreeRoot<T>)->TreeRoot<T>{
+    new_node(NodeColor::Red, left, value, right)
+}
+
+fn black<T>(left: TreeRoot<T>, value: T, right: TreeRoot<T>)->TreeRoot<T>{
+    new_node(NodeColor::Black, left, value, right)
+}
+
+/// Check whether `root` is a black node, NIL leaves excluded
+fn is_black_node<T>(root: &TreeRoot<T>)->bool{
+    root.as_ref().is_some_and(|nd| nd.color == NodeColor::Black)
+}
+
+/// Copy of `node` with another color
+fn recolor<T: Clone>(node: &TreeNode<T>, color: NodeColor)->TreeRoot<T>{
+    new_node(color, node.left.clone(), node.value.clone(), node.right.clone())
+}
+
+/// Make the root black, copying it only if it is red
+fn blacken<T: Clone>(root: TreeRoot<T>)->TreeRoot<T>{
+    match &root{
+        Some(nd) if nd.color == NodeColor::Red=>recolor(nd, NodeColor::Black),
+        _=>root
+    }
+}
+
+/// Build a black node over `left`, `value` and `right`, resolving a red child with a red child
+///
+/// The red grandchild, its red parent and the new node are rearranged into a red node with two black children
+fn balance<T: Clone>(left: TreeRoot<T>, value: T, right: TreeRoot<T>)->TreeRoot<T>{
+    if let (Some(l), Some(r)) = (&left, &right){
+        if l.color == NodeColor::Red && r.color == NodeColor::Red{
+            return red(recolor(l, NodeColor::Black), value, recolor(r, NodeColor::Black));
+        }
+    }
+    if let Some(l) = left.as_ref().filter(|nd| nd.color == NodeColor::Red){
+        if let Some(ll) = l.left.as_ref().filter(|nd| nd.color == NodeColor::Red){
+            return red(recolor(ll, NodeColor::Black), l.value.clone(), black(l.right.clone(), value, right));
+        }
+        if let Some(lr) = l.right.as_ref().filter(|nd| nd.color == NodeColor::Red){
+            return red(black(l.left.clone(), l.value.clone(), lr.left.clone()), lr.value.clone(),
+                black(lr.right.clone(), value, right));
+        }
+    }
+    if let Some(r) = right.as_ref().filter(|nd| nd.color == NodeColor::Red){
+        if let Some(rr) = r.right.as_ref().filter(|nd| nd.color == NodeColor::Red){
+            return red(black(left, value, r.left.clone()), r.value.clone(), recolor(rr, NodeColor::Black));
+        }
+        if let Some(rl) = r.left.as_ref().filter(|nd| nd.color == NodeColor::Red){
+            return red(black(left, value, rl.left.clone()), rl.value.clone(),
+                black(rl.right.clone(), r.value.clone(), r.right.clone()));
+        }
+    }
+    black(left, value, right)
+}
+
+/// Helper for insert()
+///
+/// Copy the search path for `value` and insert it at the end;
+/// Return the new sub-tree and whether the value was inserted
+fn insert_node<T: Clone, C: Comparator<T>>(root: &TreeRoot<T>, value: T, cmp: &C)->(TreeRoot<T>, bool){
+    let node = match root{
+        None=>return (red(None, value, None), true),
+        Some(node)=>node
+    };
+    let rebuild = if node.color == NodeColor::Black {balance} else {red};
+    match cmp.compare(&value, &node.value){
+        Ordering::Equal=>(root.clone(), false),
+        Ordering::Less=>{
+            let (left, inserted) = insert_node(&node.left, value, cmp);
+            if !inserted{
+                return (root.clone(), false);
+            }
+            (rebuild(left, node.value.clone(), node.right.clone()), true)
+        },
+        Ordering::Greater=>{
+            let (right, inserted) = insert_node(&node.right, value, cmp);
+            if !inserted{
+                return (root.clone(), false);
+            }
+            (rebuild(node.left.clone(), node.value.clone(), right), true)
+        }
+    }
+}
+
+/// Helper for remove()
+///
+/// Copy the search path for `value`, which must be in the sub-tree, and delete it
+fn delete_node<T: Clone, C: Comparator<T>>(root: &TreeRoot<T>, value: &T, cmp: &C)->TreeRoot<T>{
+    let node = match root{
+        None=>return None,
+        Some(node)=>node
+    };
+    match cmp.compare(value, &node.value){
+        Ordering::Less=>{
+            let left = delete_node(&node.left, value, cmp);
+            // a black sub-tree lost one black node
+            if is_black_node(&node.left){
+                return balance_left(left, node.value.clone(), node.right.clone());
+            }
+            red(left, node.value.clone(), node.right.clone())
+        },
+        Ordering::Greater=>{
+            let right = delete_node(&node.right, value, cmp);
+            if is_black_node(&node.right){
+                return balance_right(node.left.clone(), node.value.clone(), right);
+            }
+            red(node.left.clone(), node.value.clone(), right)
+        },
+        Ordering::Equal=>append(&node.left, &node.right)
+    }
+}
+
+/// Rebuild a node whose left sub-tree has one black node less than the right one
+fn balance_left<T: Clone>(left: TreeRoot<T>, value: T, right: TreeRoot<T>)->TreeRoot<T>{
+    if let Some(l) = left.as_ref().filter(|nd| nd.color == NodeColor::Red){
+        return red(recolor(l, NodeColor::Black), value, right);
+    }
+    let r = right.as_ref().expect("Error! Black heights of the sub-trees are broken!");
+    if r.color == NodeColor::Black{
+        return balance(left, value, recolor(r, NodeColor::Red));
+    }
+    let rl = r.left.as_ref().filter(|nd| nd.color == NodeColor::Black)
+        .expect("Error! Black heights of the sub-trees are broken!");
+    red(black(left, value, rl.left.clone()), rl.value.clone(),
+        balance(rl.right.clone(), r.value.clone(), redden(&r.right)))
+}
+
+/// Rebuild a node whose right sub-tree has one black node less than the left one
+fn balance_right<T: Clone>(left: TreeRoot<T>, value: T, right: TreeRoot<T>)->TreeRoot<T>{
+    if let Some(r) = right.as_ref().filter(|nd| nd.color == NodeColor::Red){
+        return red(left, value, recolor(r, NodeColor::Black));
+    }
+    let l = left.as_ref().expect("Error! Black heights of the sub-trees are broken!");
+    if l.color == NodeColor::Black{
+        return balance(recolor(l, NodeColor::Red), value, right);
+    }
+    let lr = l.right.as_ref().filter(|nd| nd.color == NodeColor::Black)
+        .expect("Error! Black heights of the sub-trees are broken!");
+    red(balance(redden(&l.left), l.value.clone(), lr.left.clone()), lr.value.clone(),
+        black(lr.right.clone(), value, right))
+}
+
+/// Copy of a black node turned red, lowering its black height by one
+fn redden<T: Clone>(root: &TreeRoot<T>)->TreeRoot<T>{
+    match root{
+        Some(nd) if nd.color == NodeColor::Black=>recolor(nd, NodeColor::Red),
+        _=>panic!("Error! Black heights of the sub-trees are broken!")
+    }
+}
+
+/// Join the sub-trees of a deleted node, all values of `left` being smaller than those of `right`
+fn append<T: Clone>(left: &TreeRoot<T>, right: &TreeRoot<T>)->TreeRoot<T>{
+    let (l, r) = match (left, right){
+        (None, _)=>return right.clone(),
+        (_, None)=>return left.clone(),
+        (Some(l), Some(r))=>(l, r)
+    };
+    match (l.color, r.color){
+        (NodeColor::Red, NodeColor::Red)=>{
+            let middle = append(&l.right, &r.left);
+            if let Some(m) = middle.as_ref().filter(|nd| nd.color == NodeColor::Red){
+                return red(red(l.left.clone(), l.value.clone(), m.left.clone()), m.value.clone(),
+                    red(m.right.clone(), r.value.clone(), r.right.clone()));
+            }
+            red(l.left.clone(), l.value.clone(), red(middle, r.value.clone(), r.right.clone()))
+        },
+        (NodeColor::Black, NodeColor::Black)=>{
+            let middle = append(&l.right, &r.left);
+            if let Some(m) = middle.as_ref().filter(|nd| nd.color == NodeColor::Red){
+                return red(black(l.left.clone(), l.value.clone(), m.left.clone()), m.value.clone(),
+                    black(m.right.clone(), r.value.clone(), r.right.clone()));
+            }
+            balance_left(l.left.clone(), l.value.clone(), black(middle, r.value.clone(), r.right.clone()))
+        },
+        (NodeColor::Black, NodeColor::Red)=>red(append(left, &r.left), r.value.clone(), r.right.clone()),
+        (NodeColor::Red, NodeColor::Black)=>red(l.left.clone(), l.value.clone(), append(&l.right, right))
+    }
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use std::collections::BTreeSet;
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
+    #[test]
+    fn test_insert_remove(){
+        let mut rng = StdRng::seed_from_u64(14);
+        let mut tree = PersistentRedBlackTree::new();
+        let mut expected = BTreeSet::new();
+        for _ in 0..3000{
+            let v = rng.gen_range(0, 800);
+            if rng.gen_bool(0.6){
+                tree = tree.insert(v);
+                expected.insert(v);
+            }
+            else{
+                tree = tree.remove(&v);
+                expected.remove(&v);
+            }
+            assert_eq!(tree.len(), expected.len());
+        }
+        assert!(tree.check_valid());
+        assert_eq!(tree.in_order_traverse(), expected.iter().cloned().collect::<Vec<_>>());
+        for v in expected.clone(){
+            tree = tree.remove(&v);
+            assert!(tree.check_valid());
+        }
+        assert!(tree.is_empty());
+    }
+
+    #[test]
+    fn test_snapshots(){
+        let mut rng = StdRng::seed_from_u64(15);
+        let mut versions = vec![PersistentRedBlackTree::new()];
+        let mut expected = vec![BTreeSet::new()];
+        for _ in 0..500{
+            // branch off a random old version
+            let k = rng.gen_range(0, versions.len());
+            let v = rng.gen_range(0, 200);
+            let (tree, mut set) = (versions[k].clone(), expected[k].clone());
+            if rng.gen_bool(0.7){
+                versions.push(tree.insert(v));
+                set.insert(v);
+            }
+            else{
+                versions.push(tree.remove(&v));
+                set.remove(&v);
+            }
+            expected.push(set);
+        }
+        for (tree, set) in versions.iter().zip(expected.iter()){
+            assert!(tree.check_valid());
+            assert_eq!(tree.iter().cloned().collect::<Vec<_>>(), set.iter().cloned().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_sharing(){
+        let tree: PersistentRedBlackTree<i32> = (0..1000).collect();
+        let root = tree.root.clone().unwrap();
+        // only the path to the new value is copied
+        let larger = tree.insert(1000);
+        assert!(Shared::ptr_eq(root.left.as_ref().unwrap(), larger.root.as_ref().unwrap().left.as_ref().unwrap()));
+        let same = tree.insert(5);
+        assert!(Shared::ptr_eq(&root, same.root.as_ref().unwrap()));
+        let same = tree.remove(&-1);
+        assert!(Shared::ptr_eq(&root, same.root.as_ref().unwrap()));
+    }
+
+    #[test]
+    fn test_check_valid_into(){
+        let mut tree: PersistentRedBlackTree<i32> = (0..10).collect();
+        let mut report = String::new();
+        assert!(tree.check_valid_into(&mut report));
+        assert!(report.is_empty());
+        tree.len = 11;
+        assert!(!tree.check_valid_into(&mut report));
+        assert_eq!(report, "Length is 11 instead of 10\n");
+
+        // an empty root with a length is reported too
+        let mut empty: PersistentRedBlackTree<i32> = PersistentRedBlackTree::new();
+        empty.len = 2;
+        report.clear();
+        assert!(!empty.check_valid_into(&mut report));
+        assert_eq!(report, "Length is 2 instead of 0\n");
+    }
+
+    #[test]
+    fn test_render(){
+        let tree: PersistentRedBlackTree<i32> = (1..4).collect();
+        let verbose = "(Color: Black, Value: 2, Is Leaf: false)\n\
+            \x20 left: (Color: Black, Value: 1, Is Leaf: true)\n\
+            \x20 right: (Color: Black, Value: 3, Is Leaf: true)\n";
+        let mut text = String::new();
+        tree.render(&mut text, true).unwrap();
+        assert_eq!(text, format!("{}    3b\n2b\n    1b\n", verbose));
+        assert_eq!(format!("{}", tree), "    3b\n2b\n    1b\n");
+        assert_eq!(format!("{:?}", tree), verbose);
+        assert_eq!(format!("{}", PersistentRedBlackTree::<i32>::new()), "Empty tree!\n");
+
+        // a deep tree is rendered without recursing; the indentation grows with the depth, so keep it moderate
+        let worker = std::thread::Builder::new().stack_size(64 * 1024).spawn(||{
+            let mut chain = None;
+            for v in (0..5_000).rev(){
+                chain = black(None, v, chain);
+            }
+            let chain = PersistentRedBlackTree{root: chain, len: 5_000, comparator: NaturalOrder};
+            let mut text = String::new();
+            chain.render(&mut text, true).unwrap();
+            assert_eq!(text.lines().count(), 10_000);
+            // the nodes are shared, unlink them one by one
+            let mut current = chain.root.clone();
+            drop(chain);
+            while let Some(node) = current{
+                current = Shared::try_unwrap(node).ok().and_then(|node| node.right);
+            }
+        }).unwrap();
+        worker.join().unwrap();
+    }
+
+    #[test]
+    fn test_simple_tree_trait(){
+        let mut tree: Box<dyn SimpleTreeTrait<i32>> = Box::new(PersistentRedBlackTree::new());
+        assert!(tree.insert(3));
+        assert!(tree.insert(1));
+        assert!(!tree.insert(3));
+        assert_eq!(tree.delete(&3), Some(3));
+        assert_eq!(tree.delete(&3), None);
+        assert_eq!(tree.in_order_traverse(), vec![1]);
+        assert_eq!(tree.height(), 1);
+        assert_eq!(tree.count_leaves(), 1);
+        assert!(!tree.is_empty());
+    }
+
+    #[test]
+    #[cfg(feature = "sync")]
+    fn test_threads(){
+        use std::sync::{Arc, RwLock};
+        use std::thread;
+
+        // readers take O(1) snapshots and never block the writer while they read
+        let current = Arc::new(RwLock::new(PersistentRedBlackTree::new()));
+        let writer = {
+            let current = Arc::clone(&current);
+            thread::spawn(move ||{
+                for v in 0..2000{
+                    let next = current.read().unwrap().insert(v).remove(&(v / 2));
+                    *current.write().unwrap() = next;
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4).map(|_|{
+            let current = Arc::clone(&current);
+            thread::spawn(move ||{
+                for _ in 0..200{
+                    let snapshot = current.read().unwrap().clone();
+                    assert!(snapshot.check_valid());
+                    assert_eq!(snapshot.iter().count(), snapshot.len());
+                }
+            })
+        }).collect();
+        writer.join().unwrap();
+        for reader in readers{
+            reader.join().unwrap();
+        }
+        let tree = current.read().unwrap();
+        assert_eq!(tree.in_order_traverse(), (1000..2000).collect::<Vec<_>>());
+    }
+}
@@ -1,6 +1,9 @@
 //! Prelude imports
 //!
-//! Use rbtree::RedBlackTree, avltree::AVLTree and tree::{TreeTrait, TreeNodeTrait, SimpleTreeTrait}
-pub use crate::rbtree::RedBlackTree;
-pub use crate::avltree::AVLTree;
-pub use crate::tree::{TreeTrait, TreeNodeTrait, SimpleTreeTrait};
+//! Use rbtree::{RedBlackTree, RedBlackMap, RedBlackMultiSet}, avltree::{AVLTree, AVLMap, AVLMultiSet}, intervaltree::IntervalTree, persistent::PersistentRedBlackTree, arena::{ArenaRedBlackTree, ArenaAVLTree} and tree::{TreeTrait, TreeNodeTrait, SimpleTreeTrait, Comparator, NaturalOrder, Augment, InvariantViolation}
+pub use crate::rbtree::{RedBlackTree, RedBlackMap, RedBlackMultiSet};
+pub use crate::avltree::{AVLTree, AVLMap, AVLMultiSet};
+pub use crate::intervaltree::IntervalTree;
+pub use crate::persistent::PersistentRedBlackTree;
+pub use crate::arena::{ArenaRedBlackTree, ArenaAVLTree};
+pub use crate::tree::{TreeTrait, TreeNodeTrait, SimpleTreeTrait, Comparator, NaturalOrder, Augment, InvariantViolation};
@@ -2,11 +2,25 @@
 //!
 //! An implementation of red black tree
 
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::fmt::{Debug, Display};
+use core::fmt;
+use core::fmt::Debug;
+use core::mem;
+use core::cmp::Ordering;
+use core::ops::{Bound, RangeBounds};
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::string::{String, ToString};
+use alloc::format;
 
 pub use crate::tree::{TreeTrait, TreeNodeTrait, Direction, SimpleTreeTrait, rotate, search_node};
+use crate::tree;
+use crate::multiset::{MultiSet, MultiSetMap};
+use crate::error::Error;
+use crate::tree::{Augment, SetOperation, Stdout, InvariantViolation, NodeCell, Shared, Weak, Comparator, NaturalOrder, aggregate_range, get_min_node, search_bound, precedes, update_size_to_root, detach_node, link_children, ValueRef, ValueMut};
+#[cfg(feature = "std")]
+use crate::snapshot::{self, SnapshotError, SnapshotValue, TreeKind};
+#[cfg(feature = "std")]
+use std::io::{Read, Write};
 
 /// Color of the nodes in red black tree
 #[derive(Clone, Debug, PartialEq, Copy)]
@@ -15,174 +29,461 @@ pub enum NodeColor {
     Black, 
 }
 
-#[derive(Clone, Debug, PartialEq)]
-struct TreeNode<T: Ord+Copy+Debug+Display> {
-    pub color: NodeColor,
-    pub value: T,
-    pub parent: TreeRoot<T>,
-    left: TreeRoot<T>, 
-    right: TreeRoot<T>
+/// Node of the red black tree
+#[derive(Clone, Debug)]
+pub struct TreeNode<T: Debug, V=(), A=()> {
+    color: NodeColor,
+    value: T,
+    /// Data stored next to the value, `()` for RedBlackTree
+    data: V,
+    parent: ParentLink<T, V, A>,
+    left: TreeRoot<T, V, A>, 
+    right: TreeRoot<T, V, A>,
+    /// Number of nodes in the sub-tree rooted here
+    size: usize,
+    /// Summary of the sub-tree rooted here, `()` unless augmented
+    augmentation: A
+}
+type TreeRoot<T, V=(), A=()> = Option<Shared<NodeCell<TreeNode<T, V, A>>>>;
+/// Parent link of a node, which doesn't keep the parent alive
+type ParentLink<T, V=(), A=()> = Option<Weak<NodeCell<TreeNode<T, V, A>>>>;
+
+// the parent is left out, it follows from the children of the parent
+impl<T: Debug+PartialEq, V: PartialEq, A: PartialEq> PartialEq for TreeNode<T, V, A>{
+    fn eq(&self, other: &Self)->bool{
+        self.color == other.color
+            && self.value == other.value
+            && self.data == other.data
+            && self.left == other.left
+            && self.right == other.right
+            && self.size == other.size
+            && self.augmentation == other.augmentation
+    }
+}
+
+impl<T: Debug, V, A> TreeNode<T, V, A>{
+    /// Unlink both children, used by tree::drop_tree
+    pub(crate) fn take_children(&mut self)->[TreeRoot<T, V, A>; 2]{
+        [self.left.take(), self.right.take()]
+    }
 }
-type TreeRoot<T> = Option<Rc<RefCell<TreeNode<T>>>>;
 
 /// Struct of the red black tree
-pub struct RedBlackTree<T: Ord+Copy+Debug+Display>{
-    root: TreeRoot<T>
+pub struct RedBlackTree<T: Debug, C=NaturalOrder, A=()>{
+    root: TreeRoot<T, (), A>,
+    len: usize,
+    /// Comparator ordering the values
+    comparator: C
+}
+
+/// In-order iterator over a RedBlackTree
+pub type Iter<'a, T, A=()> = tree::Iter<'a, T, TreeNode<T, (), A>>;
+
+/// In-order iterator over a range of a RedBlackTree
+pub type Range<'a, T, A=()> = tree::Range<'a, T, TreeNode<T, (), A>>;
+
+/// Lazy set operation over two RedBlackTrees
+pub type SetIter<'a, T, C=NaturalOrder, A=()> = tree::SetIter<'a, T, TreeNode<T, (), A>, C>;
+
+/// Struct of the red black tree map
+///
+/// An ordered map sharing the nodes and the rebalancing of RedBlackTree
+pub struct RedBlackMap<K: Debug, V, C=NaturalOrder>{
+    root: TreeRoot<K, V>,
+    len: usize,
+    /// Comparator ordering the keys
+    comparator: C
 }
 
+/// Ordered multiset on the nodes and the rebalancing of RedBlackMap, see MultiSet
+pub type RedBlackMultiSet<T, C=NaturalOrder> = MultiSet<T, RedBlackMap<T, Vec<T>, C>>;
+
+/// In-order iterator over a RedBlackMultiSet yielding every duplicate
+pub type MultiIter<'a, T> = tree::MultiIter<'a, T, TreeNode<T, Vec<T>>>;
+
 
-impl<T: Ord+Copy+Debug+Display> TreeTrait<T, TreeNode<T>> for RedBlackTree<T>{
-    fn root(&self)->TreeRoot<T>{
+impl<T: Debug, C: Comparator<T>, A: Augment<T>> TreeTrait<T, TreeNode<T, (), A>> for RedBlackTree<T, C, A>{
+    type Order = C;
+
+    fn root(&self)->TreeRoot<T, (), A>{
         self.root.clone()
     }
 
+    fn comparator(&self)->&C{
+        &self.comparator
+    }
+
+    fn len(&self)->usize{
+        self.len
+    }
+
     /// Check whether the red black tree is valid
     ///
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::rbtree::RedBlackTree;
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// use BinaryTrees::prelude::*;
     /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
     /// rbtree.insert(8);
-    /// println!("{}", rbtree.check_valid());
+    /// let mut why = String::new();
+    /// assert!(rbtree.check_valid_into(&mut why));
+    /// assert!(why.is_empty());
     /// ```
-    fn check_valid(&self)->bool{
-        if self.root.is_none(){
-            return true;
-        }
-
-        let root_nd = self.root.clone().unwrap();
-        if root_nd.borrow().color != NodeColor::Black{
-            println!("Root node should be black");
-            return false;
-        }
-        let vec = self.in_order_traverse();
-        let order = vec.iter().zip(vec.iter().skip(1))
-            .all(|(current, next)| current<next);
-        if !order{
-            println!("Order error");
-            return false;
-        }
-        if !root_nd.borrow().check_red_children(){
-            println!("Red node doesn't have two black children");
-            return false;
-        }
-        if !self.check_color(){
-            println!("Black nodes in the paths don't agree");
-            return false;
-        }
-        return true;
+    fn check_valid_into<W: fmt::Write+?Sized>(&self, out: &mut W)->bool{
+        check_valid_node(&self.root, &self.comparator, out)
     }
 
     /// Helper for count_leaves()
     fn DEFAULT_LEAF_NUM(&self)->u32{
-        2 as u32
+        2
     }
     /// Helper for height()
     fn DEFAULT_HEIGHT_NUM(&self)->u32{
-        1 as u32
+        1
     }
 
 }
 
-impl<T: Ord+Copy+Debug+Display> SimpleTreeTrait<T> for RedBlackTree<T>{
+impl<T: Debug, C: Comparator<T>, A: Augment<T>> SimpleTreeTrait<T> for RedBlackTree<T, C, A>{
     fn insert(&mut self, value: T)->bool{
-        RedBlackTree::<T>::insert(self, value)
+        RedBlackTree::<T, C, A>::insert(self, value)
+    }
+    fn delete(&mut self, value: &T)->Option<T>{
+        RedBlackTree::<T, C, A>::delete(self, value)
     }
-    fn delete(&mut self, value: T)->Option<T>{
-        RedBlackTree::<T>::delete(self, value)
+    fn try_insert(&mut self, value: T)->Result<bool, Error>{
+        RedBlackTree::<T, C, A>::try_insert(self, value)
+    }
+    fn try_delete(&mut self, value: &T)->Result<Option<T>, Error>{
+        RedBlackTree::<T, C, A>::try_delete(self, value)
     }
     fn count_leaves(&self)->u32{
-        RedBlackTree::<T>::count_leaves(self)
+        RedBlackTree::<T, C, A>::count_leaves(self)
     }
     fn is_empty(&self)->bool{
-        RedBlackTree::<T>::is_empty(self)
+        RedBlackTree::<T, C, A>::is_empty(self)
     }
+    #[cfg(feature = "std")]
     fn print(&self, verbose: bool){
-        RedBlackTree::<T>::print(self, verbose)
+        RedBlackTree::<T, C, A>::print(self, verbose)
     }
     fn height(&self)->u32{
-        RedBlackTree::<T>::height(self)
+        RedBlackTree::<T, C, A>::height(self)
+    }
+    fn in_order_traverse(&self)->Vec<T> where T: Clone{
+        RedBlackTree::<T, C, A>::in_order_traverse(self)
+    }
+}
+
+impl<'a, T: Debug, C: Comparator<T>, A: Augment<T>> IntoIterator for &'a RedBlackTree<T, C, A>{
+    type Item = tree::ValueRef<'a, T>;
+    type IntoIter = Iter<'a, T, A>;
+
+    fn into_iter(self)->Iter<'a, T, A>{
+        self.iter()
+    }
+}
+
+impl<T: Debug, C: Comparator<T>+Default, A: Augment<T>> Default for RedBlackTree<T, C, A>{
+    fn default()->Self{
+        Self::with_augment(C::default())
+    }
+}
+
+impl<T: Debug, C, A> Drop for RedBlackTree<T, C, A>{
+    fn drop(&mut self){
+        tree::drop_tree(self.root.take(), TreeNode::take_children);
+    }
+}
+
+impl<T: Debug, C: Comparator<T>, A: Augment<T>> fmt::Display for RedBlackTree<T, C, A>{
+    /// Draw the structure of the tree, as print(false) does
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        self.render(f, false)
+    }
+}
+
+impl<T: Debug, C, A: Augment<T>> fmt::Debug for RedBlackTree<T, C, A>{
+    /// List every node under its parent, as print(true) does before drawing the structure
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        match &self.root{
+            None => writeln!(f, "Empty tree!"),
+            Some(root) => root.borrow().write_tree_as_fmt(f, "  ")
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T: Debug+serde::Serialize, C: Comparator<T>, A: Augment<T>> serde::Serialize for RedBlackTree<T, C, A>{
+    /// Serialize the values in order as a sequence
+    fn serialize<S: serde::Serializer>(&self, serializer: S)->Result<S::Ok, S::Error>{
+        tree::serialize_sorted(self.iter(), serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, T: Debug+serde::Deserialize<'de>, C: Comparator<T>+Default, A: Augment<T>> serde::Deserialize<'de> for RedBlackTree<T, C, A>{
+    /// Build the tree in linear time from a sequence of strictly increasing values
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D)->Result<Self, D::Error>{
+        let comparator = C::default();
+        let values = tree::deserialize_sorted(deserializer, &comparator)?;
+        Ok(Self::build_sorted_by(values, comparator))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Debug+SnapshotValue, C: Comparator<T>, A: Augment<T>> RedBlackTree<T, C, A>{
+    /// Write the tree as a binary snapshot, see the snapshot module for the format
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let rbtree: RedBlackTree<u32> = (0..1000).collect();
+    /// let mut bytes = Vec::new();
+    /// rbtree.write_snapshot(&mut bytes).unwrap();
+    /// println!("{} bytes", bytes.len());
+    /// ```
+    pub fn write_snapshot<W: Write>(&self, out: W)->Result<(), SnapshotError>{
+        snapshot::write_values(TreeKind::RedBlack, self.iter(), out)
     }
-    fn in_order_traverse(&self)->Vec<T>{
-        RedBlackTree::<T>::in_order_traverse(self)
+
+    /// Read a tree written by write_snapshot, building it in linear time
+    ///
+    /// Fails on truncated or corrupt data, snapshots of another kind of tree and
+    /// values that are not strictly increasing under the comparator.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let rbtree: RedBlackTree<u32> = (0..1000).collect();
+    /// let mut bytes = Vec::new();
+    /// rbtree.write_snapshot(&mut bytes).unwrap();
+    /// let copy: RedBlackTree<u32> = RedBlackTree::read_snapshot(bytes.as_slice()).unwrap();
+    /// assert_eq!(copy.len(), 1000);
+    /// ```
+    pub fn read_snapshot<R: Read>(input: R)->Result<Self, SnapshotError> where C: Default{
+        let comparator = C::default();
+        let values = snapshot::read_values(TreeKind::RedBlack, input, &comparator)?;
+        Ok(Self::build_sorted_by(values, comparator))
     }
 }
 
-impl<T: Ord+Copy+Debug+Display> RedBlackTree <T>{
+impl<T: Debug, C: Comparator<T>+Default, A: Augment<T>> FromIterator<T> for RedBlackTree<T, C, A>{
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I)->Self{
+        let comparator = C::default();
+        let mut values: Vec<T> = iter.into_iter().collect();
+        // sorted input skips straight to the linear build
+        if !values.is_sorted_by(|a, b| comparator.compare(a, b) != Ordering::Greater){
+            values.sort_by(|a, b| comparator.compare(a, b));
+        }
+        Self::build_sorted_by(values, comparator)
+    }
+}
 
-    fn check_color(&self)->bool{
-        if self.root.is_none(){
-            return true;
+impl<T: Debug, C: Comparator<T>, A: Augment<T>> Extend<T> for RedBlackTree<T, C, A>{
+    fn extend<I: IntoIterator<Item = T>>(&mut self, iter: I){
+        let mut values: Vec<T> = iter.into_iter().collect();
+        if !values.is_sorted_by(|a, b| self.comparator.compare(a, b) != Ordering::Greater){
+            for value in values{
+                self.insert(value);
+            }
+            return;
         }
-        let height_option = self.root.clone().unwrap().borrow().check_color();
-        return height_option.is_some();
+        // sorted input is built in linear time and joined in, keeping the values already present
+        values.dedup_by(|a, b| self.comparator.compare(a, b) == Ordering::Equal);
+        let other = build_subtree(values.into_iter().map(|value| (value, ())));
+        let tree = merge(other, self.take_subtree(), &self.comparator).unwrap_or_else(Error::raise);
+        self.set_subtree(tree);
     }
+}
+
+impl<T: Ord+Debug> RedBlackTree<T>{
 
     /// Create a new RedBlackTree
     ///
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::rbtree::RedBlackTree;
+    /// use BinaryTrees::rbtree::RedBlackTree;
     /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
     /// ```
     pub fn new()->Self{
-        RedBlackTree{root: None}
+        Self::with_comparator(NaturalOrder)
+    }
+
+    /// Build a RedBlackTree from values in ascending order in O(n)
+    ///
+    /// Repeated values are kept once
+    ///
+    /// # Panic
+    /// the values are not sorted
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let rbtree = RedBlackTree::from_sorted_iter(1..=100);
+    /// assert_eq!(rbtree.len(), 100);
+    /// assert!(rbtree.check_valid());
+    /// ```
+    pub fn from_sorted_iter<I: IntoIterator<Item = T>>(iter: I)->Self{
+        Self::from_sorted_iter_by(iter, NaturalOrder)
+    }
+}
+
+impl<T: Debug, C: Comparator<T>> RedBlackTree<T, C>{
+    /// Create a new RedBlackTree ordered by `comparator`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree = RedBlackTree::with_comparator(|a: &u32, b: &u32| b.cmp(a));
+    /// rbtree.insert(3);
+    /// rbtree.insert(8);
+    /// assert_eq!(rbtree.in_order_traverse(), vec![8, 3]);
+    /// ```
+    pub fn with_comparator(comparator: C)->Self{
+        Self::with_augment(comparator)
+    }
+
+    /// Build a RedBlackTree from values sorted by `comparator` in O(n)
+    ///
+    /// Repeated values are kept once
+    ///
+    /// # Panic
+    /// the values are not sorted
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let rbtree = RedBlackTree::from_sorted_iter_by((1..=100).rev(), |a: &u32, b: &u32| b.cmp(a));
+    /// assert_eq!(*rbtree.first().unwrap(), 100);
+    /// assert!(rbtree.check_valid());
+    /// ```
+    pub fn from_sorted_iter_by<I: IntoIterator<Item = T>>(iter: I, comparator: C)->Self{
+        Self::build_sorted_by(iter, comparator)
+    }
+}
+
+impl<T: Debug, C: Comparator<T>, A: Augment<T>> RedBlackTree<T, C, A>{
+    /// Create a new RedBlackTree ordered by `comparator`, keeping the summaries `A` in its nodes
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// use BinaryTrees::tree::{Augment, NaturalOrder};
+    /// struct Count(usize);
+    /// impl Augment<u32> for Count{
+    ///     fn identity()->Count{
+    ///         Count(0)
+    ///     }
+    ///     fn summarize(_value: &u32)->Count{
+    ///         Count(1)
+    ///     }
+    ///     fn combine(&self, other: &Count)->Count{
+    ///         Count(self.0 + other.0)
+    ///     }
+    /// }
+    /// let mut rbtree: RedBlackTree<u32, NaturalOrder, Count> = RedBlackTree::with_augment(NaturalOrder);
+    /// rbtree.insert(3);
+    /// rbtree.insert(8);
+    /// assert_eq!(rbtree.aggregate(..5).0, 1);
+    /// ```
+    pub fn with_augment(comparator: C)->Self{
+        RedBlackTree{root: None, len: 0, comparator}
+    }
+
+    /// Helper for from_sorted_iter_by() and from_iter()
+    fn build_sorted_by<I: IntoIterator<Item = T>>(iter: I, comparator: C)->Self{
+        let mut values: Vec<T> = iter.into_iter().collect();
+        values.dedup_by(|a, b| comparator.compare(a, b) == Ordering::Equal);
+        assert!(values.windows(2).all(|w| comparator.compare(&w[0], &w[1]) == Ordering::Less),
+            "Error! Values are not sorted!");
+        let mut tree = Self::with_augment(comparator);
+        tree.set_subtree(build_subtree(values.into_iter().map(|value| (value, ()))));
+        tree
     }
 
     /// Delete a node in the RedBlackTree
     ///
+    /// # Panic
+    /// Illegal cases for rotation, see try_delete()
+    ///
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::rbtree::RedBlackTree;
+    /// use BinaryTrees::rbtree::RedBlackTree;
     /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
-    /// let deleted = rbtree.delete(8);
+    /// let deleted = rbtree.delete(&8);
     /// println!("{:?}", deleted.is_none());
     /// ```
-    pub fn delete(&mut self, value: T)->Option<T>{
-        let node = search_node(self.root.clone(), value);
-        if node.is_none(){
-            return None;
-        }
-        let new_root = delete_node(node.clone().unwrap(), value);
-        if new_root.is_some(){
-            self.root = new_root.unwrap().clone();
-        }
-        return Some(value);
+    pub fn delete(&mut self, value: &T)->Option<T>{
+        self.try_delete(value).unwrap_or_else(Error::raise)
+    }
+
+    /// Delete a node in the RedBlackTree, reporting a corrupted tree instead of panicking
+    ///
+    /// The tree may be poisoned after an error, see Error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = (0..10).collect();
+    /// assert_eq!(rbtree.try_delete(&8), Ok(Some(8)));
+    /// assert_eq!(rbtree.try_delete(&8), Ok(None));
+    /// ```
+    pub fn try_delete(&mut self, value: &T)->Result<Option<T>, Error>{
+        let Some(node) = search_node(self.root.clone(), value, &self.comparator).flatten() else{
+            return Ok(None);
+        };
+        let (deleted, _) = try_delete_node(&mut self.root, node)?;
+        self.len -= 1;
+        Ok(Some(deleted))
     }
 
-    /// Insert a node to the AVLTree
+    /// Insert a node to the RedBlackTree
     ///
     /// # Panic
-    /// Illegal cases for rotation
+    /// Illegal cases for rotation, see try_insert()
     ///
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::rbtree::RedBlackTree;
+    /// use BinaryTrees::rbtree::RedBlackTree;
     /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
     /// let inserted = rbtree.insert(8);
-    /// println!("{}", rbtree.search(8));
+    /// println!("{}", rbtree.search(&8));
     /// ```
     pub fn insert(&mut self, value:T)->bool{
-        let root=self.root.clone();
-        let mut res=false;
-        self.root=match root {
-            Some(root) => {
-                let (new_root,res)=insert_node(root,value);
-                new_root
-            }
-            None => {
-                let mut new_node=TreeNode::new(value);
-                new_node.color=NodeColor::Black;
-                let new_root=Rc::new(RefCell::new(new_node));
-                res=true;
-                Some(new_root)
-            },
-        };
-        res 
+        self.try_insert(value).unwrap_or_else(Error::raise)
+    }
+
+    /// Insert a node to the RedBlackTree, reporting a corrupted tree instead of panicking
+    ///
+    /// The tree may be poisoned after an error, see Error.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// assert_eq!(rbtree.try_insert(8), Ok(true));
+    /// assert_eq!(rbtree.try_insert(8), Ok(false));
+    /// ```
+    pub fn try_insert(&mut self, value:T)->Result<bool, Error>{
+        let (new_root, old) = try_insert_entry(self.root.clone(), value, (), &self.comparator)?;
+        self.root = new_root;
+        if old.is_none(){
+            self.len += 1;
+        }
+        Ok(old.is_none())
     }
 
     // repeating
@@ -191,13 +492,13 @@ impl<T: Ord+Copy+Debug+Display> RedBlackTree <T>{
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::rbtree::RedBlackTree;
+    /// use BinaryTrees::rbtree::RedBlackTree;
     /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
     /// rbtree.insert(8);
     /// println!("{}", rbtree.is_empty());
     /// ```
-    fn is_empty(&self)->bool{
-        TreeTrait::<T, TreeNode<T>>::is_empty(self)
+    pub fn is_empty(&self)->bool{
+        TreeTrait::<T, TreeNode<T, (), A>>::is_empty(self)
     }
 
     /// Count number of leaves in the RedBlackTree
@@ -205,13 +506,13 @@ impl<T: Ord+Copy+Debug+Display> RedBlackTree <T>{
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::rbtree::RedBlackTree;
+    /// use BinaryTrees::rbtree::RedBlackTree;
     /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
     /// rbtree.insert(8);
     /// println!("{}", rbtree.count_leaves());
     /// ```
-    fn count_leaves(&self)->u32{
-        TreeTrait::<T, TreeNode<T>>::count_leaves(self)
+    pub fn count_leaves(&self)->u32{
+        TreeTrait::<T, TreeNode<T, (), A>>::count_leaves(self)
     }
     /// Print the information of the tree
     ///
@@ -222,13 +523,14 @@ impl<T: Ord+Copy+Debug+Display> RedBlackTree <T>{
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::rbtree::RedBlackTree;
+    /// use BinaryTrees::rbtree::RedBlackTree;
     /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
     /// rbtree.insert(8);
-    /// println!("{}", rbtree.print(true));
+    /// rbtree.print(true);
     /// ```
-    fn print(&self, verbose: bool){
-        TreeTrait::<T, TreeNode<T>>::print(self, verbose)
+    #[cfg(feature = "std")]
+    pub fn print(&self, verbose: bool){
+        TreeTrait::<T, TreeNode<T, (), A>>::print(self, verbose)
     }
 
     /// Get height of the RedBlackTree
@@ -236,13 +538,13 @@ impl<T: Ord+Copy+Debug+Display> RedBlackTree <T>{
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::rbtree::RedBlackTree;
+    /// use BinaryTrees::rbtree::RedBlackTree;
     /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
     /// rbtree.insert(8);
     /// println!("{}", rbtree.height());
     /// ```
-    fn height(&self)->u32{
-        TreeTrait::<T, TreeNode<T>>::height(self)
+    pub fn height(&self)->u32{
+        TreeTrait::<T, TreeNode<T, (), A>>::height(self)
     }
     /// In-order traverse of the tree
     ///
@@ -251,369 +553,1191 @@ impl<T: Ord+Copy+Debug+Display> RedBlackTree <T>{
     /// # Example
     ///
     /// ```
-    /// use BinaryTress::rbtree::RedBlackTree;
+    /// use BinaryTrees::rbtree::RedBlackTree;
     /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
     /// rbtree.insert(8);
     /// rbtree.insert(10);
-    /// println!("{}", rbtree.in_order_traverse());
+    /// println!("{:?}", rbtree.in_order_traverse());
     /// ```
-    fn in_order_traverse(&self)->Vec<T>{
-        TreeTrait::<T, TreeNode<T>>::in_order_traverse(self)
+    pub fn in_order_traverse(&self)->Vec<T> where T: Clone{
+        TreeTrait::<T, TreeNode<T, (), A>>::in_order_traverse(self)
     }
-}
-
 
-
-
-impl<T: Ord+Copy+Debug+Display> TreeNodeTrait<T> for TreeNode <T>{
-
-    fn left(&self)->TreeRoot<T>{
-        self.left.clone()
-    }
-    fn right(&self)->TreeRoot<T>{
-        self.right.clone()
-    }
-    fn parent(&self)->TreeRoot<T>{
-        self.parent.clone()
-    }
-    fn value(&self)->T{
-        self.value
+    /// Number of values in the RedBlackTree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(8);
+    /// assert_eq!(rbtree.len(), 1);
+    /// ```
+    pub fn len(&self)->usize{
+        TreeTrait::<T, TreeNode<T, (), A>>::len(self)
     }
 
-    fn set_left(&mut self, v: TreeRoot<T>){
-        self.left = v
-    }
-    fn set_right(&mut self, v: TreeRoot<T>){
-        self.right = v
-    }
-    fn set_parent(&mut self, v: TreeRoot<T>){
-        self.parent = v
-    }
-    fn set_value(&mut self, v: T){
-        self.value = v;
+    /// Lazy in-order iterator over the values of the RedBlackTree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(8);
+    /// rbtree.insert(3);
+    /// let first = rbtree.iter().next().unwrap();
+    /// assert_eq!(*first, 3);
+    /// ```
+    pub fn iter(&self)->Iter<'_, T, A>{
+        TreeTrait::<T, TreeNode<T, (), A>>::iter(self)
     }
 
-    fn structure_info(&self)->String{
-        let val = self.value.to_string();
-        let cl = match self.color{
-            NodeColor::Red=>"",
-            NodeColor::Black=>"b"
-        }.to_string();
-        return val+&cl;
+    /// Iterate over the values within `range` in order
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// for v in 1..10{
+    ///     rbtree.insert(v);
+    /// }
+    /// let inner: Vec<u32> = rbtree.range(3..6).map(|v| *v).collect();
+    /// assert_eq!(inner, vec![3, 4, 5]);
+    /// ```
+    pub fn range<R: RangeBounds<T>>(&self, range: R)->Range<'_, T, A>{
+        TreeTrait::<T, TreeNode<T, (), A>>::range(self, range)
     }
 
-    fn fmt_info(&self)->String{
-        format!(
-            "(Color: {:?}, Value: {:?}, Is Leaf: {:?})",
-            self.color,
-            self.value,
-            self.is_leaf(),
-        )
+    /// Combine the summaries of the values within `range` in order
+    ///
+    /// Uses the summaries kept in the nodes, O(log n)
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// use BinaryTrees::tree::{Augment, NaturalOrder};
+    /// struct Sum(u64);
+    /// impl Augment<u64> for Sum{
+    ///     fn identity()->Sum{
+    ///         Sum(0)
+    ///     }
+    ///     fn summarize(value: &u64)->Sum{
+    ///         Sum(*value)
+    ///     }
+    ///     fn combine(&self, other: &Sum)->Sum{
+    ///         Sum(self.0 + other.0)
+    ///     }
+    /// }
+    /// let rbtree: RedBlackTree<u64, NaturalOrder, Sum> = (1..=100).collect();
+    /// assert_eq!(rbtree.aggregate(10..=20).0, 165);
+    /// assert_eq!(rbtree.aggregate(..).0, 5050);
+    /// ```
+    pub fn aggregate<R: RangeBounds<T>>(&self, range: R)->A{
+        aggregate_range(self.root.clone(), range, &self.comparator, TreeNode::augmentation)
     }
 
-}
-
-impl<T: Ord+Copy+Debug+Display> TreeNode <T>{
-    fn new(value: T) -> Self {
-        TreeNode {
-            color: NodeColor::Red,
-            value: value,
-            parent: None,
-            left: None,
-            right: None,
-        }
+    /// Get the `k`-th smallest value, counting from 0
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(8);
+    /// rbtree.insert(3);
+    /// assert_eq!(*rbtree.select(1).unwrap(), 8);
+    /// assert!(rbtree.select(2).is_none());
+    /// ```
+    pub fn select(&self, k: usize)->Option<ValueRef<'_, T>>{
+        TreeTrait::<T, TreeNode<T, (), A>>::select(self, k)
     }
 
-    fn new_with_parent(value: T, parent: Rc<RefCell<TreeNode<T>>>) -> Self {
-        TreeNode {
-            color: NodeColor::Red,
-            value: value,
-            parent: Some(parent),
-            left: None,
-            right: None,
-        }
+    /// Count the values smaller than `value`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(8);
+    /// rbtree.insert(3);
+    /// assert_eq!(rbtree.rank(&8), 1);
+    /// assert_eq!(rbtree.rank(&9), 2);
+    /// ```
+    pub fn rank(&self, value: &T)->usize{
+        TreeTrait::<T, TreeNode<T, (), A>>::rank(self, value)
     }
 
-    fn check_color(&self)->Option<usize>{
-        let left_height = if let Some(ln)=self.left.clone(){
-            ln.borrow().check_color()
-        }
-        else{
-            Some(0)
-        };
-        let right_height = if let Some(rn)=self.right.clone(){
-            rn.borrow().check_color()
-        }
-        else{
-            Some(0)
-        };
-        match (left_height, right_height){
-            (Some(lh), Some(rh))=>{
-                if lh != rh{
-                    return None;
-                }
-                match &self.color{
-                    NodeColor::Red => Some(lh),
-                    NodeColor::Black => Some(lh+1),
-                } 
-            },
-            _=>None
-        }
+    /// Get the greatest value not greater than `value`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(8);
+    /// rbtree.insert(3);
+    /// assert_eq!(*rbtree.floor(&5).unwrap(), 3);
+    /// ```
+    pub fn floor(&self, value: &T)->Option<ValueRef<'_, T>>{
+        TreeTrait::<T, TreeNode<T, (), A>>::floor(self, value)
     }
 
-    fn check_red_children(&self)->bool{
-        let current = match &self.color{
-            NodeColor::Red=>
-            match (self.left.is_some(), self.right.is_some()){
-                (false, false)=>true,
-                (true, true)=>{
-                    let lc = self.left.clone().unwrap().borrow().color;
-                    let rc = self.right.clone().unwrap().borrow().color;
-                    lc == NodeColor::Black && rc == NodeColor::Black
-                },
-                _=>false
-            },
-            NodeColor::Black=>true
-        };
-        if !current{
-            return false;
-        }
-        if self.left.is_some(){
-            if !self.left.clone().unwrap().borrow().check_red_children(){
-                return false;
-            }
-        }
-        if self.right.is_some(){
-            if !self.right.clone().unwrap().borrow().check_red_children(){
-                return false;
-            }
-        }
-        return true;
+    /// Get the smallest value not smaller than `value`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(8);
+    /// rbtree.insert(3);
+    /// assert_eq!(*rbtree.ceiling(&5).unwrap(), 8);
+    /// ```
+    pub fn ceiling(&self, value: &T)->Option<ValueRef<'_, T>>{
+        TreeTrait::<T, TreeNode<T, (), A>>::ceiling(self, value)
     }
 
-    pub fn is_red(node:TreeRoot<T>)->bool{
-        if node.is_none(){
-            //println!("uncle is none");
-            return false;
-        }else{
-            let unwraped_node=node.clone().unwrap();
-            if unwraped_node.borrow().color==NodeColor::Red{
-                return true;
-            }else{
-                return false;
-            }
+    /// Get the greatest value smaller than `value`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(8);
+    /// rbtree.insert(3);
+    /// assert_eq!(*rbtree.predecessor(&8).unwrap(), 3);
+    /// ```
+    pub fn predecessor(&self, value: &T)->Option<ValueRef<'_, T>>{
+        TreeTrait::<T, TreeNode<T, (), A>>::predecessor(self, value)
+    }
+
+    /// Get the smallest value greater than `value`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(8);
+    /// rbtree.insert(3);
+    /// assert_eq!(*rbtree.successor(&3).unwrap(), 8);
+    /// ```
+    pub fn successor(&self, value: &T)->Option<ValueRef<'_, T>>{
+        TreeTrait::<T, TreeNode<T, (), A>>::successor(self, value)
+    }
+
+    /// Get the minimum value
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(8);
+    /// rbtree.insert(3);
+    /// assert_eq!(*rbtree.first().unwrap(), 3);
+    /// ```
+    pub fn first(&self)->Option<ValueRef<'_, T>>{
+        TreeTrait::<T, TreeNode<T, (), A>>::first(self)
+    }
+
+    /// Get the maximum value
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(8);
+    /// rbtree.insert(3);
+    /// assert_eq!(*rbtree.last().unwrap(), 8);
+    /// ```
+    pub fn last(&self)->Option<ValueRef<'_, T>>{
+        TreeTrait::<T, TreeNode<T, (), A>>::last(self)
+    }
+
+    /// Remove and return the minimum value
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(8);
+    /// rbtree.insert(3);
+    /// assert_eq!(rbtree.pop_first(), Some(3));
+    /// assert_eq!(rbtree.pop_first(), Some(8));
+    /// assert_eq!(rbtree.pop_first(), None);
+    /// ```
+    pub fn pop_first(&mut self)->Option<T>{
+        let node = search_bound(self.root.clone(), Bound::Unbounded, &Direction::Right, &self.comparator)?;
+        let (deleted, _) = delete_node(&mut self.root, node);
+        self.len -= 1;
+        Some(deleted)
+    }
+
+    /// Remove and return the maximum value
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(8);
+    /// rbtree.insert(3);
+    /// assert_eq!(rbtree.pop_last(), Some(8));
+    /// assert_eq!(rbtree.pop_last(), Some(3));
+    /// assert_eq!(rbtree.pop_last(), None);
+    /// ```
+    pub fn pop_last(&mut self)->Option<T>{
+        let node = search_bound(self.root.clone(), Bound::Unbounded, &Direction::Left, &self.comparator)?;
+        let (deleted, _) = delete_node(&mut self.root, node);
+        self.len -= 1;
+        Some(deleted)
+    }
+
+    /// Move the values not smaller than `key` into a new RedBlackTree
+    ///
+    /// Splits along the search path for `key` in O(log n)
+    ///
+    /// # Panic
+    /// A corrupted tree, see Error
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// for v in 1..10{
+    ///     rbtree.insert(v);
+    /// }
+    /// let upper = rbtree.split_off(&6);
+    /// assert_eq!(rbtree.in_order_traverse(), vec![1, 2, 3, 4, 5]);
+    /// assert_eq!(upper.in_order_traverse(), vec![6, 7, 8, 9]);
+    /// ```
+    pub fn split_off(&mut self, key: &T)->Self where C: Clone{
+        let (less, found, rest) = split(self.take_subtree(), key, &self.comparator).unwrap_or_else(Error::raise);
+        let rest = match found{
+            Some(found)=>join((None, 0), found, rest).unwrap_or_else(Error::raise),
+            None=>rest
+        };
+        let mut upper = Self::with_augment(self.comparator.clone());
+        upper.set_subtree(rest);
+        self.set_subtree(less);
+        upper
+    }
+
+    /// Move all values of `other` into the RedBlackTree, leaving `other` empty
+    ///
+    /// When all values of one tree are smaller than those of the other
+    /// the trees are joined in O(log n); otherwise they are merged like into_union
+    ///
+    /// # Panic
+    /// A corrupted tree, see Error
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// let mut other: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(1);
+    /// other.insert(2);
+    /// rbtree.append(&mut other);
+    /// assert_eq!(rbtree.in_order_traverse(), vec![1, 2]);
+    /// assert!(other.is_empty());
+    /// ```
+    pub fn append(&mut self, other: &mut Self){
+        let tree = merge(self.take_subtree(), other.take_subtree(), &self.comparator).unwrap_or_else(Error::raise);
+        self.set_subtree(tree);
+    }
+
+    /// Union with `other`, built by joining instead of re-inserting
+    ///
+    /// Trees whose values do not overlap are joined in O(log n),
+    /// otherwise the cost is O(m log(n/m + 1)) for sizes m <= n
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut a: RedBlackTree<u32> = RedBlackTree::new();
+    /// let mut b: RedBlackTree<u32> = RedBlackTree::new();
+    /// a.insert(1);
+    /// a.insert(2);
+    /// b.insert(2);
+    /// b.insert(3);
+    /// assert_eq!(a.into_union(b).in_order_traverse(), vec![1, 2, 3]);
+    /// ```
+    pub fn into_union(mut self, mut other: Self)->Self{
+        self.append(&mut other);
+        self
+    }
+
+    /// Intersection with `other`, built by joining instead of re-inserting
+    ///
+    /// # Panic
+    /// A corrupted tree, see Error
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut a: RedBlackTree<u32> = RedBlackTree::new();
+    /// let mut b: RedBlackTree<u32> = RedBlackTree::new();
+    /// a.insert(1);
+    /// a.insert(2);
+    /// b.insert(2);
+    /// b.insert(3);
+    /// assert_eq!(a.into_intersection(b).in_order_traverse(), vec![2]);
+    /// ```
+    pub fn into_intersection(mut self, mut other: Self)->Self{
+        let tree = combine(self.take_subtree(), other.take_subtree(), &self.comparator, SetOperation::Intersection)
+            .unwrap_or_else(Error::raise);
+        self.set_subtree(tree);
+        self
+    }
+
+    /// Values not in `other`, built by joining instead of re-inserting
+    ///
+    /// # Panic
+    /// A corrupted tree, see Error
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut a: RedBlackTree<u32> = RedBlackTree::new();
+    /// let mut b: RedBlackTree<u32> = RedBlackTree::new();
+    /// a.insert(1);
+    /// a.insert(2);
+    /// b.insert(2);
+    /// b.insert(3);
+    /// assert_eq!(a.into_difference(b).in_order_traverse(), vec![1]);
+    /// ```
+    pub fn into_difference(mut self, mut other: Self)->Self{
+        let tree = combine(self.take_subtree(), other.take_subtree(), &self.comparator, SetOperation::Difference)
+            .unwrap_or_else(Error::raise);
+        self.set_subtree(tree);
+        self
+    }
+
+    /// Values in exactly one of the trees, built by joining instead of re-inserting
+    ///
+    /// # Panic
+    /// A corrupted tree, see Error
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut a: RedBlackTree<u32> = RedBlackTree::new();
+    /// let mut b: RedBlackTree<u32> = RedBlackTree::new();
+    /// a.insert(1);
+    /// a.insert(2);
+    /// b.insert(2);
+    /// b.insert(3);
+    /// assert_eq!(a.into_symmetric_difference(b).in_order_traverse(), vec![1, 3]);
+    /// ```
+    pub fn into_symmetric_difference(mut self, mut other: Self)->Self{
+        let tree = combine(self.take_subtree(), other.take_subtree(), &self.comparator, SetOperation::SymmetricDifference)
+            .unwrap_or_else(Error::raise);
+        self.set_subtree(tree);
+        self
+    }
+
+    /// Lazily iterate over the values in either tree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut a: RedBlackTree<u32> = RedBlackTree::new();
+    /// let mut b: RedBlackTree<u32> = RedBlackTree::new();
+    /// a.insert(1);
+    /// b.insert(2);
+    /// let union: Vec<u32> = a.union(&b).map(|v| *v).collect();
+    /// assert_eq!(union, vec![1, 2]);
+    /// ```
+    pub fn union<'a>(&'a self, other: &'a Self)->SetIter<'a, T, C, A>{
+        TreeTrait::<T, TreeNode<T, (), A>>::union(self, other)
+    }
+
+    /// Lazily iterate over the values in both trees
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut a: RedBlackTree<u32> = RedBlackTree::new();
+    /// let mut b: RedBlackTree<u32> = RedBlackTree::new();
+    /// a.insert(1);
+    /// a.insert(2);
+    /// b.insert(2);
+    /// let both: Vec<u32> = a.intersection(&b).map(|v| *v).collect();
+    /// assert_eq!(both, vec![2]);
+    /// ```
+    pub fn intersection<'a>(&'a self, other: &'a Self)->SetIter<'a, T, C, A>{
+        TreeTrait::<T, TreeNode<T, (), A>>::intersection(self, other)
+    }
+
+    /// Lazily iterate over the values not in `other`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut a: RedBlackTree<u32> = RedBlackTree::new();
+    /// let mut b: RedBlackTree<u32> = RedBlackTree::new();
+    /// a.insert(1);
+    /// a.insert(2);
+    /// b.insert(2);
+    /// let only: Vec<u32> = a.difference(&b).map(|v| *v).collect();
+    /// assert_eq!(only, vec![1]);
+    /// ```
+    pub fn difference<'a>(&'a self, other: &'a Self)->SetIter<'a, T, C, A>{
+        TreeTrait::<T, TreeNode<T, (), A>>::difference(self, other)
+    }
+
+    /// Lazily iterate over the values in exactly one of the trees
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut a: RedBlackTree<u32> = RedBlackTree::new();
+    /// let mut b: RedBlackTree<u32> = RedBlackTree::new();
+    /// a.insert(1);
+    /// a.insert(2);
+    /// b.insert(2);
+    /// b.insert(3);
+    /// let either: Vec<u32> = a.symmetric_difference(&b).map(|v| *v).collect();
+    /// assert_eq!(either, vec![1, 3]);
+    /// ```
+    pub fn symmetric_difference<'a>(&'a self, other: &'a Self)->SetIter<'a, T, C, A>{
+        TreeTrait::<T, TreeNode<T, (), A>>::symmetric_difference(self, other)
+    }
+
+    /// Take the nodes out of the tree for join and split
+    fn take_subtree(&mut self)->Subtree<T, (), A>{
+        let bh = black_height(&self.root);
+        self.len = 0;
+        (self.root.take(), bh)
+    }
+
+    /// Put the result of join and split into the tree
+    fn set_subtree(&mut self, (root, _): Subtree<T, (), A>){
+        if let Some(nd) = &root{
+            nd.borrow_mut().color = NodeColor::Black;
         }
+        self.len = root.as_ref().map_or(0, |nd| nd.borrow().size);
+        self.root = root;
     }
-    fn set_red(node:Rc<RefCell<TreeNode<T>>>) -> Rc<RefCell<TreeNode<T>>> {
-        node.borrow_mut().color = NodeColor::Red;
-        return node;
+
+    /// Search a node in the RedBlackTree
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(8);
+    /// assert!(rbtree.search(&8));
+    /// ```
+    pub fn search(&self, value: &T)->bool{
+        TreeTrait::<T, TreeNode<T, (), A>>::search(self, value)
     }
 
-    fn set_black(node:Rc<RefCell<TreeNode<T>>>) -> Rc<RefCell<TreeNode<T>>> {
-        node.borrow_mut().color = NodeColor::Black;
-        return node;
+    /// Get a reference to the value in the RedBlackTree equal to `value`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<String> = RedBlackTree::new();
+    /// rbtree.insert(String::from("a"));
+    /// assert_eq!(*rbtree.get(&String::from("a")).unwrap(), "a");
+    /// ```
+    pub fn get(&self, value: &T)->Option<ValueRef<'_, T>>{
+        let node = search_node(self.root.clone(), value, &self.comparator)?.unwrap();
+        Some(ValueRef::new(node, |nd: &TreeNode<T, (), A>| &nd.value))
     }
 
-    pub fn get_root(node:Rc<RefCell<TreeNode<T>>>)-> TreeRoot<T>{
-        let parent=node.borrow().parent.clone();
-        match parent {
-            Some(p) => {
-                Self::get_root(p)
-            },
-            None => Some(node),                                                                  
+    /// Check whether the red black tree is valid
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let mut rbtree: RedBlackTree<u32> = RedBlackTree::new();
+    /// rbtree.insert(8);
+    /// assert!(rbtree.check_valid());
+    /// ```
+    pub fn check_valid(&self)->bool{
+        TreeTrait::<T, TreeNode<T, (), A>>::check_valid(self)
+    }
+
+    /// Check every invariant of the tree, telling which one broke and at which values
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackTree;
+    /// let rbtree: RedBlackTree<u32> = (0..100).collect();
+    /// assert_eq!(rbtree.validate(), Ok(()));
+    /// ```
+    pub fn validate(&self)->Result<(), InvariantViolation<T>> where T: Clone{
+        validate_node(&self.root, &self.comparator, &T::clone)
+    }
+}
+
+impl<K: Debug, V, C: Comparator<K>+Default> Default for RedBlackMap<K, V, C>{
+    fn default()->Self{
+        Self::with_comparator(C::default())
+    }
+}
+
+impl<K: Debug, V, C> Drop for RedBlackMap<K, V, C>{
+    fn drop(&mut self){
+        tree::drop_tree(self.root.take(), TreeNode::take_children);
+    }
+}
+
+impl<K: Ord+Debug, V> RedBlackMap<K, V>{
+    /// Create a new RedBlackMap
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMap;
+    /// let mut map: RedBlackMap<u32, String> = RedBlackMap::new();
+    /// ```
+    pub fn new()->Self{
+        Self::with_comparator(NaturalOrder)
+    }
+}
+
+impl<K: Debug, V, C: Comparator<K>> RedBlackMap<K, V, C>{
+    /// Create a new RedBlackMap ordered by `comparator`
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMap;
+    /// let mut map = RedBlackMap::with_comparator(|a: &String, b: &String| a.to_lowercase().cmp(&b.to_lowercase()));
+    /// map.insert(String::from("Key"), 1);
+    /// assert_eq!(*map.get(&String::from("KEY")).unwrap(), 1);
+    /// ```
+    pub fn with_comparator(comparator: C)->Self{
+        RedBlackMap{root: None, len: 0, comparator}
+    }
+
+    /// Insert a key-value pair to the RedBlackMap
+    ///
+    /// Return the old value if the key was already present
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMap;
+    /// let mut map = RedBlackMap::new();
+    /// assert!(map.insert(8, "a").is_none());
+    /// assert_eq!(map.insert(8, "b"), Some("a"));
+    /// ```
+    pub fn insert(&mut self, key: K, value: V)->Option<V>{
+        let (new_root, old) = insert_entry(self.root.clone(), key, value, &self.comparator);
+        self.root = new_root;
+        if old.is_none(){
+            self.len += 1;
         }
+        old
+    }
+
+    /// Remove a key from the RedBlackMap
+    ///
+    /// Return the value of the key if it was present
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMap;
+    /// let mut map = RedBlackMap::new();
+    /// map.insert(8, "a");
+    /// assert_eq!(map.remove(&8), Some("a"));
+    /// assert_eq!(map.remove(&8), None);
+    /// ```
+    pub fn remove(&mut self, key: &K)->Option<V>{
+        self.remove_entry(key).map(|(_, value)| value)
+    }
+
+    /// Remove a key from the RedBlackMap
+    ///
+    /// Return the stored key and its value if it was present
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMap;
+    /// let mut map = RedBlackMap::with_comparator(|a: &String, b: &String| a.to_lowercase().cmp(&b.to_lowercase()));
+    /// map.insert(String::from("Key"), 1);
+    /// assert_eq!(map.remove_entry(&String::from("KEY")), Some((String::from("Key"), 1)));
+    /// assert_eq!(map.remove_entry(&String::from("KEY")), None);
+    /// ```
+    pub fn remove_entry(&mut self, key: &K)->Option<(K, V)>{
+        let node = search_node(self.root.clone(), key, &self.comparator).flatten()?;
+        let entry = delete_node(&mut self.root, node);
+        self.len -= 1;
+        Some(entry)
+    }
+
+    /// Get a reference to the value of a key
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMap;
+    /// let mut map = RedBlackMap::new();
+    /// map.insert(8, "a");
+    /// assert_eq!(*map.get(&8).unwrap(), "a");
+    /// ```
+    pub fn get(&self, key: &K)->Option<ValueRef<'_, V>>{
+        let node = search_node(self.root.clone(), key, &self.comparator)?.unwrap();
+        Some(ValueRef::new(node, |nd: &TreeNode<K, V>| &nd.data))
+    }
+
+    /// Get a mutable reference to the value of a key
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMap;
+    /// let mut map = RedBlackMap::new();
+    /// map.insert(8, 1);
+    /// *map.get_mut(&8).unwrap() += 1;
+    /// assert_eq!(*map.get(&8).unwrap(), 2);
+    /// ```
+    pub fn get_mut(&mut self, key: &K)->Option<ValueMut<'_, V>>{
+        let node = search_node(self.root.clone(), key, &self.comparator)?.unwrap();
+        Some(ValueMut::new(node, |nd: &mut TreeNode<K, V>| &mut nd.data))
     }
 
+    /// Check whether the RedBlackMap contains a key
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// use BinaryTrees::rbtree::RedBlackMap;
+    /// let mut map = RedBlackMap::new();
+    /// map.insert(8, "a");
+    /// assert!(map.contains_key(&8));
+    /// ```
+    pub fn contains_key(&self, key: &K)->bool{
+        search_node(self.root.clone(), key, &self.comparator).is_some()
+    }
+
+    /// Number of entries in the RedBlackMap
+    pub fn len(&self)->usize{
+        self.len
+    }
+
+    /// Check if the RedBlackMap is empty
+    pub fn is_empty(&self)->bool{
+        self.root.is_none()
+    }
+
+    /// Check whether the underlying red black tree is valid, writing why it isn't into `out`
+    pub fn check_valid_into<W: fmt::Write+?Sized>(&self, out: &mut W)->bool{
+        check_valid_node(&self.root, &self.comparator, out)
+    }
 
+    /// Check whether the underlying red black tree is valid
+    ///
+    /// With the `std` feature why it isn't is printed, see check_valid_into()
+    pub fn check_valid(&self)->bool{
+        self.check_valid_into(&mut Stdout)
+    }
 }
-fn insert_node<T: Ord+Copy+Debug+Display>(node:Rc<RefCell<TreeNode<T>>>, value: T) -> (TreeRoot<T>,bool){
-    //println!("insert {:?}",value);
-    if node.borrow().value ==value{
-        return (Some(node),false);
-    }else if node.borrow().value >value{
-        let left=node.borrow().left.clone();
-        match left {
-            Some(left_node) => {
-                insert_node(left_node,value);
-            }
-            None => {
-                node.borrow_mut().left= Some(Rc::new(RefCell::new(TreeNode::new_with_parent(value, node.clone()))));
-                let left=node.borrow().left.clone().unwrap();
-                insert_recolor(left);
-            },
+
+impl<T: Debug, C: Comparator<T>> MultiSetMap<T> for RedBlackMap<T, Vec<T>, C>{
+    type Order = C;
+    type Node = TreeNode<T, Vec<T>>;
+
+    fn with_comparator(comparator: C)->Self{
+        RedBlackMap::with_comparator(comparator)
+    }
+    fn comparator(&self)->&C{
+        &self.comparator
+    }
+    fn root(&self)->TreeRoot<T, Vec<T>>{
+        self.root.clone()
+    }
+    fn copies(node: &TreeNode<T, Vec<T>>)->&[T]{
+        &node.data
+    }
+    fn insert(&mut self, key: T, copies: Vec<T>)->Option<Vec<T>>{
+        RedBlackMap::insert(self, key, copies)
+    }
+    fn remove_entry(&mut self, key: &T)->Option<(T, Vec<T>)>{
+        RedBlackMap::remove_entry(self, key)
+    }
+    fn get(&self, key: &T)->Option<ValueRef<'_, Vec<T>>>{
+        RedBlackMap::get(self, key)
+    }
+    fn get_mut(&mut self, key: &T)->Option<ValueMut<'_, Vec<T>>>{
+        RedBlackMap::get_mut(self, key)
+    }
+    fn len(&self)->usize{
+        RedBlackMap::len(self)
+    }
+    fn check_valid_into<W: fmt::Write+?Sized>(&self, out: &mut W)->bool{
+        RedBlackMap::check_valid_into(self, out)
+    }
+}
+
+/// Helper for check_valid()
+pub(crate) fn check_valid_node<T: Debug, V, A: Augment<T>, C: Comparator<T>, W: fmt::Write+?Sized>(root: &TreeRoot<T, V, A>, cmp: &C,
+    out: &mut W)->bool{
+    match validate_node(root, cmp, &tree::debug_text){
+        Ok(()) => true,
+        Err(violation) => {
+            report!(out, "{}", violation);
+            false
         }
-    }else {
-        let right=node.borrow().right.clone();
-        match right {
-            Some(right_node) => {
-                insert_node(right_node,value);
+    }
+}
+
+/// Helper for validate() and check_valid()
+///
+/// Check every invariant of the red black tree rooted at `root`, naming the values involved by `capture`
+fn validate_node<T: Debug, V, A: Augment<T>, C: Comparator<T>, U>(root: &TreeRoot<T, V, A>, cmp: &C,
+    capture: &impl Fn(&T)->U)->Result<(), InvariantViolation<U>>{
+    tree::validate_links_and_order(root.clone(), cmp, capture)?;
+    let Some(root_nd) = root else{
+        return Ok(());
+    };
+    if root_nd.borrow().color != NodeColor::Black{
+        return Err(InvariantViolation::RedRoot(capture(&root_nd.borrow().value)));
+    }
+    // black height and size of every sub-tree, from the leaves up
+    tree::fold_post_order(root.clone(), &mut |nd: &TreeNode<T, V, A>, left: Option<(usize, usize)>, right|{
+        if nd.color == NodeColor::Red{
+            for child in nd.left.iter().chain(&nd.right){
+                if child.borrow().color == NodeColor::Red{
+                    return Err(InvariantViolation::RedRed{ parent: capture(&nd.value), child: capture(&child.borrow().value) });
+                }
             }
-            None => {
-                node.borrow_mut().right = Some(Rc::new(RefCell::new(TreeNode::new_with_parent(value, node.clone()))));
-                let right=node.borrow().right.clone().unwrap();
-                insert_recolor(right);
-            },
         }
+        let ((left_black, left_size), (right_black, right_size)) = (left.unwrap_or((0, 0)), right.unwrap_or((0, 0)));
+        if left_black != right_black{
+            return Err(InvariantViolation::BlackHeight{ node: capture(&nd.value), left: left_black, right: right_black });
+        }
+        let size = left_size + right_size + 1;
+        if nd.size != size{
+            return Err(InvariantViolation::Size{ node: capture(&nd.value), stored: nd.size, actual: size });
+        }
+        Ok((left_black + (nd.color == NodeColor::Black) as usize, size))
+    })?;
+    Ok(())
+}
+
+impl<T: Debug, V, A: Augment<T>> TreeNodeTrait<T> for TreeNode<T, V, A>{
+
+    fn left(&self)->TreeRoot<T, V, A>{
+        self.left.clone()
+    }
+    fn right(&self)->TreeRoot<T, V, A>{
+        self.right.clone()
+    }
+    fn parent(&self)->TreeRoot<T, V, A>{
+        self.parent.as_ref().and_then(Weak::upgrade)
+    }
+    fn value(&self)->&T{
+        &self.value
+    }
+
+    fn set_left(&mut self, v: TreeRoot<T, V, A>){
+        self.left = v
+    }
+    fn set_right(&mut self, v: TreeRoot<T, V, A>){
+        self.right = v
+    }
+    fn set_parent(&mut self, v: TreeRoot<T, V, A>){
+        self.parent = v.as_ref().map(Shared::downgrade)
+    }
+    fn set_value(&mut self, v: T){
+        self.value = v;
+    }
+    fn size(&self)->usize{
+        self.size
+    }
+    fn set_size(&mut self, v: usize){
+        self.size = v;
+    }
+    fn update_augmentation(&mut self){
+        let left = self.left.as_ref().map(|nd| nd.borrow());
+        let right = self.right.as_ref().map(|nd| nd.borrow());
+        self.augmentation = A::refresh(&self.value, left.as_ref().map(|nd| &nd.augmentation),
+            right.as_ref().map(|nd| &nd.augmentation));
+    }
+
+    fn structure_info(&self)->String{
+        let val = format!("{:?}", self.value);
+        let cl = match self.color{
+            NodeColor::Red=>"",
+            NodeColor::Black=>"b"
+        }.to_string();
+        val+&cl
+    }
+
+    fn fmt_info(&self)->String{
+        format!(
+            "(Color: {:?}, Value: {:?}, Is Leaf: {:?})",
+            self.color,
+            self.value,
+            self.is_leaf(),
+        )
+    }
+
+    fn dot_info(&self)->String{
+        let (fill, font) = match self.color{
+            NodeColor::Red=>("red", "black"),
+            NodeColor::Black=>("black", "white")
+        };
+        format!("label=\"{}\", style=filled, fillcolor={}, fontcolor={}",
+            tree::dot_escape(&format!("{:?}", self.value)), fill, font)
     }
-    return (TreeNode::get_root(node),true);
 }
-fn insert_recolor<T: Ord+Copy+Debug+Display>(node:Rc<RefCell<TreeNode<T>>>){
-
-    let parent=node.borrow().parent.clone();
-    match parent {
-        Some(parent) =>{
-            //1.if parent is black, no need to change
-            if(parent.borrow().color==NodeColor::Black){
-                ();
+
+impl<T: Debug, V, A: Augment<T>> TreeNode<T, V, A>{
+    fn new(value: T, data: V) -> Self {
+        TreeNode {
+            color: NodeColor::Red,
+            augmentation: A::refresh(&value, None, None),
+            value,
+            data,
+            parent: None,
+            left: None,
+            right: None,
+            size: 1,
+        }
+    }
+
+    fn new_with_parent(value: T, data: V, parent: Shared<NodeCell<TreeNode<T, V, A>>>) -> Self {
+        TreeNode {
+            color: NodeColor::Red,
+            augmentation: A::refresh(&value, None, None),
+            value,
+            data,
+            parent: Some(Shared::downgrade(&parent)),
+            left: None,
+            right: None,
+            size: 1,
+        }
+    }
+
+    pub fn is_red(node:TreeRoot<T, V, A>)->bool{
+        if node.is_none(){
+            //println!("uncle is none");
+            false
+        }else{
+            let unwraped_node=node.clone().unwrap();
+            return unwraped_node.borrow().color==NodeColor::Red;
+        }
+    }
+    fn set_red(node:Shared<NodeCell<TreeNode<T, V, A>>>) -> Shared<NodeCell<TreeNode<T, V, A>>> {
+        node.borrow_mut().color = NodeColor::Red;
+        node
+    }
+
+    fn set_black(node:Shared<NodeCell<TreeNode<T, V, A>>>) -> Shared<NodeCell<TreeNode<T, V, A>>> {
+        node.borrow_mut().color = NodeColor::Black;
+        node
+    }
+
+    pub fn get_root(node:Shared<NodeCell<TreeNode<T, V, A>>>)-> TreeRoot<T, V, A>{
+        let mut node = node;
+        loop{
+            let parent=node.borrow().parent();
+            match parent {
+                Some(p) => node = p,
+                None => return Some(node),
             }
-            //2.if parent is red
-            else{
-                let grand_parent=parent.borrow().parent.clone();
-                match grand_parent {
-                    Some(grand_parent) => {
-                        if(grand_parent.borrow().color==NodeColor::Red){
-                            panic!("insert into tree, red parent node have red grand parent.");
-                        }
-                        let parent_dir=parent.borrow().get_direction_to_parent();
-                        let node_dir=node.borrow().get_direction_to_parent();
-                        //2.1 RR
-                        if parent_dir==Direction::Right&&node_dir==Direction::Right{
-                            //println!("RR");
-                            let uncle=grand_parent.borrow().left.clone();
-                            //2.1.1 uncle=none||black               
-                            if !TreeNode::is_red(uncle.clone()){                             
-                                //grand parent node perform left rotation                              
-                                rotate(&Some(grand_parent.clone()),&Some(parent.clone()));
-                                //recolor parent to black and left sibling to red                                                               
-                                let parent=node.borrow().parent.clone().unwrap();
-                                TreeNode::set_black(parent.clone());
-                                let left_sibling=parent.borrow().left.clone().unwrap();                                
-                                TreeNode::set_red(left_sibling.clone());
+        }
+    }
+
+    /// Get the summary of the sub-tree rooted at the node
+    pub(crate) fn augmentation(&self)->&A{
+        &self.augmentation
+    }
+
+    /// Exchange value and data with another node
+    ///
+    /// Used for deletion
+    fn swap_entry(&mut self, other: &mut Self){
+        mem::swap(&mut self.value, &mut other.value);
+        mem::swap(&mut self.data, &mut other.data);
+    }
+}
+
+/// Helper for insert()
+///
+/// Insert the value or replace the data of an existing value;
+/// Return the new root and the replaced data
+pub(crate) fn insert_entry<T: Debug, V, A: Augment<T>, C: Comparator<T>>(root: TreeRoot<T, V, A>, value: T, data: V, cmp: &C)->
+    (TreeRoot<T, V, A>, Option<V>){
+    try_insert_entry(root, value, data, cmp).unwrap_or_else(Error::raise)
+}
+
+/// New root of the tree and the data replaced by an insertion
+type Inserted<T, V, A> = (TreeRoot<T, V, A>, Option<V>);
+
+/// Helper for try_insert(), see insert_entry()
+///
+/// Fails when the recoloring finds the tree corrupted
+fn try_insert_entry<T: Debug, V, A: Augment<T>, C: Comparator<T>>(root: TreeRoot<T, V, A>, value: T, data: V, cmp: &C)->
+    Result<Inserted<T, V, A>, Error>{
+    match root {
+        Some(node) => {
+            let mut root = Some(node.clone());
+            let old = insert_node(&mut root, node, value, data, cmp)?;
+            Ok((root, old))
+        },
+        None => {
+            let mut new_node=TreeNode::new(value, data);
+            new_node.color=NodeColor::Black;
+            Ok((Some(Shared::new(NodeCell::new(new_node))), None))
+        },
+    }
+}
+
+fn insert_node<T: Debug, V, A: Augment<T>, C: Comparator<T>>(root: &mut TreeRoot<T, V, A>, node:Shared<NodeCell<TreeNode<T, V, A>>>,
+    value: T, data: V, cmp: &C) -> Result<Option<V>, Error>{
+    let mut node = node;
+    // descend to the node holding the value or to the parent of the new leaf
+    let direction = loop{
+        let ordering = cmp.compare(&node.borrow().value, &value);
+        let (child, direction) = match ordering{
+            Ordering::Equal=>{
+                let old = mem::replace(&mut node.borrow_mut().data, data);
+                return Ok(Some(old));
+            },
+            Ordering::Greater=>(node.borrow().left.clone(), Direction::Left),
+            Ordering::Less=>(node.borrow().right.clone(), Direction::Right)
+        };
+        match child{
+            Some(child)=>node = child,
+            None=>break direction
+        }
+    };
+    // a red parent of the leaf with a red parent of its own is caught before the tree changes
+    if TreeNode::is_red(Some(node.clone())) && TreeNode::is_red(node.borrow().parent()){
+        return Err(Error::RedGrandParent);
+    }
+    let leaf = Shared::new(NodeCell::new(TreeNode::new_with_parent(value, data, node.clone())));
+    match direction{
+        Direction::Left=>node.borrow_mut().left = Some(leaf.clone()),
+        Direction::Right=>node.borrow_mut().right = Some(leaf.clone())
+    }
+    // the rotations leave the sizes above the leaf to this update
+    let recolored = insert_recolor(root, leaf.clone());
+    update_size_to_root(Some(leaf));
+    recolored?;
+    Ok(None)
+}
+
+/// Restore the colors after linking the red `node`
+///
+/// Return whether a red root was turned black, i.e. the black height of the tree grew;
+/// Fails when a red grand parent or a missing link shows the tree was corrupted.
+/// The sizes above `node` are left to the caller
+fn insert_recolor<T: Debug, V, A: Augment<T>>(root: &mut TreeRoot<T, V, A>, node:Shared<NodeCell<TreeNode<T, V, A>>>)->Result<bool, Error>{
+    let mut node = node;
+    // climb while the recoloring moves the red violation up
+    loop{
+        let parent=node.borrow().parent();
+        match parent {
+            Some(parent) =>{
+                //1.if parent is black, no need to change
+                //2.if parent is red
+                if parent.borrow().color==NodeColor::Red {
+                    let grand_parent=parent.borrow().parent();
+                    match grand_parent {
+                        Some(grand_parent) => {
+                            if grand_parent.borrow().color==NodeColor::Red {
+                                return Err(Error::RedGrandParent);
                             }
-                            //2.1.2 uncle=red
-                            else{
-                                //set parent and uncel to black
-                                TreeNode::set_black(parent.clone());
-                                let unwraped_uncle=uncle.clone().unwrap();
-                                TreeNode::set_black(unwraped_uncle.clone());
-                                //set grand to red and recolor
-                                TreeNode::set_red(grand_parent.clone());
-                                insert_recolor(grand_parent.clone());
+                            let parent_dir=parent.borrow().try_get_direction_to_parent()?;
+                            let node_dir=node.borrow().try_get_direction_to_parent()?;
+                            //2.1 RR
+                            if parent_dir==Direction::Right&&node_dir==Direction::Right{
+                                //println!("RR");
+                                let uncle=grand_parent.borrow().left.clone();
+                                //2.1.1 uncle=none||black               
+                                if !TreeNode::is_red(uncle.clone()){                             
+                                    //grand parent node perform left rotation                              
+                                    rotate_root(root, &Some(grand_parent.clone()),&Some(parent.clone()));
+                                    //recolor parent to black and left sibling to red                                                               
+                                    let parent=node.borrow().parent().ok_or(Error::MissingParent)?;
+                                    TreeNode::set_black(parent.clone());
+                                    let left_sibling=parent.borrow().left.clone().ok_or(Error::MissingChild)?;                                
+                                    TreeNode::set_red(left_sibling.clone());
+                                }
+                                //2.1.2 uncle=red
+                                else{
+                                    //set parent and uncel to black
+                                    TreeNode::set_black(parent.clone());
+                                    let unwraped_uncle=uncle.clone().ok_or(Error::MissingChild)?;
+                                    TreeNode::set_black(unwraped_uncle.clone());
+                                    //set grand to red and recolor
+                                    TreeNode::set_red(grand_parent.clone());
+                                    node = grand_parent.clone();
+                                    continue;
+                                }
                             }
-                        }
-                        //2.2 LL
-                        else if node_dir==Direction::Left&&parent_dir==Direction::Left{
-                            //println!("LL");
-                            let uncle=grand_parent.borrow().right.clone();
-                            //2.2.1 uncle=none||black
-                            if !TreeNode::is_red(uncle.clone()){
-                                //grand parent node perform right rotation
-                                rotate(&Some(grand_parent.clone()),&Some(parent.clone()));
-                                //recolor parent to black and right sibling to red                                
-                                let parent=node.borrow().parent.clone().unwrap();
-                                TreeNode::set_black(parent.clone());
-                                let right_sibling=parent.borrow().right.clone().unwrap();                                
-                                TreeNode::set_red(right_sibling.clone());
+                            //2.2 LL
+                            else if node_dir==Direction::Left&&parent_dir==Direction::Left{
+                                //println!("LL");
+                                let uncle=grand_parent.borrow().right.clone();
+                                //2.2.1 uncle=none||black
+                                if !TreeNode::is_red(uncle.clone()){
+                                    //grand parent node perform right rotation
+                                    rotate_root(root, &Some(grand_parent.clone()),&Some(parent.clone()));
+                                    //recolor parent to black and right sibling to red                                
+                                    let parent=node.borrow().parent().ok_or(Error::MissingParent)?;
+                                    TreeNode::set_black(parent.clone());
+                                    let right_sibling=parent.borrow().right.clone().ok_or(Error::MissingChild)?;                                
+                                    TreeNode::set_red(right_sibling.clone());
+                                }
+                                //2.2.2 uncle=red
+                                else{
+                                    //set parent and uncel to black
+                                    TreeNode::set_black(parent.clone());
+                                    let unwraped_uncle=uncle.clone().ok_or(Error::MissingChild)?;
+                                    TreeNode::set_black(unwraped_uncle.clone());
+                                    //set grand to red and recolor
+                                    TreeNode::set_red(grand_parent.clone());
+                                    node = grand_parent.clone();
+                                    continue;
+                                }
                             }
-                            //2.2.2 uncle=red
-                            else{
-                                //set parent and uncel to black
-                                TreeNode::set_black(parent.clone());
-                                let unwraped_uncle=uncle.clone().unwrap();
-                                TreeNode::set_black(unwraped_uncle.clone());
-                                //set grand to red and recolor
-                                TreeNode::set_red(grand_parent.clone());
-                                insert_recolor(grand_parent.clone());
+                            //2.3 LR
+                            else if parent_dir==Direction::Left&&node_dir==Direction::Right{
+                                //println!("LR");
+                                let uncle=grand_parent.borrow().right.clone();
+                                //2.3.1 uncle=none||black
+                                if !TreeNode::is_red(uncle.clone()){
+                                    //left rotate parent to change LR condition into LL
+                                    rotate_root(root, &Some(parent.clone()),&Some(node.clone()));
+                                    //now node is the parent and we take the original parent, which is the left child now as a new inserted node
+                                    let left_child=node.borrow().left.clone().ok_or(Error::MissingChild)?;
+                                    node = left_child.clone();
+                                    continue;
+                                }
+                                //2.3.2 uncle=red
+                                else{
+                                    //set parent and uncel to black
+                                    TreeNode::set_black(parent.clone());
+                                    let unwraped_uncle=uncle.clone().ok_or(Error::MissingChild)?;
+                                    TreeNode::set_black(unwraped_uncle.clone());
+                                    //set grand to red and recolor
+                                    TreeNode::set_red(grand_parent.clone());
+                                    node = grand_parent.clone();
+                                    continue;
+                                }
                             }
+                            //2.4 RL
+                            else if parent_dir==Direction::Right&&node_dir==Direction::Left{
+                                //println!("RL");
+                                let uncle=grand_parent.borrow().left.clone();
+                                //2.4.1 uncle=none||black
+                                if !TreeNode::is_red(uncle.clone()){
+                                    //right rotate parent to change LR condition into LL
+                                    rotate_root(root, &Some(parent.clone()),&Some(node.clone()));
+                                    //now node is the parent and we take the original parent, which is the right child now as a new inserted node
+                                    let right_child=node.borrow().right.clone().ok_or(Error::MissingChild)?;
+                                    node = right_child.clone();
+                                    continue;
+                                }
+                                //2.4.2 uncle=red
+                                else{
+                                    //set parent and uncel to black
+                                    TreeNode::set_black(parent.clone());
+                                    let unwraped_uncle=uncle.clone().ok_or(Error::MissingChild)?;
+                                    TreeNode::set_black(unwraped_uncle.clone());
+                                    //set grand to red and recolor
+                                    TreeNode::set_red(grand_parent.clone());
+                                    node = grand_parent.clone();
+                                    continue;
+                                }
+                            } 
                         }
-                        //2.3 LR
-                        else if parent_dir==Direction::Left&&node_dir==Direction::Right{
-                            //println!("LR");
-                            let uncle=grand_parent.borrow().right.clone();
-                            //2.3.1 uncle=none||black
-                            if !TreeNode::is_red(uncle.clone()){
-                                //left rotate parent to change LR condition into LL
-                                rotate(&Some(parent.clone()),&Some(node.clone()));
-                                //now node is the parent and we take the original parent, which is the left child now as a new inserted node
-                                let left_child=node.borrow().left.clone().unwrap();
-                                insert_recolor(left_child.clone());
-                            }
-                            //2.3.2 uncle=red
-                            else{
-                                //set parent and uncel to black
-                                TreeNode::set_black(parent.clone());
-                                let unwraped_uncle=uncle.clone().unwrap();
-                                TreeNode::set_black(unwraped_uncle.clone());
-                                //set grand to red and recolor
-                                TreeNode::set_red(grand_parent.clone());
-                                insert_recolor(grand_parent.clone());
-                            }
+                        None => {
+                            //2.5 parent is root, set parent to black
+                            TreeNode::set_black(parent);
+                            return Ok(true);
                         }
-                        //2.4 RL
-                        else if parent_dir==Direction::Right&&node_dir==Direction::Left{
-                            //println!("RL");
-                            let uncle=grand_parent.borrow().left.clone();
-                            //2.4.1 uncle=none||black
-                            if !TreeNode::is_red(uncle.clone()){
-                                //right rotate parent to change LR condition into LL
-                                rotate(&Some(parent.clone()),&Some(node.clone()));
-                                //now node is the parent and we take the original parent, which is the right child now as a new inserted node
-                                let right_child=node.borrow().right.clone().unwrap();
-                                insert_recolor(right_child.clone());
-                            }
-                            //2.4.2 uncle=red
-                            else{
-                                //set parent and uncel to black
-                                TreeNode::set_black(parent.clone());
-                                let unwraped_uncle=uncle.clone().unwrap();
-                                TreeNode::set_black(unwraped_uncle.clone());
-                                //set grand to red and recolor
-                                TreeNode::set_red(grand_parent.clone());
-                                insert_recolor(grand_parent.clone());
-                            }
-                        } 
-                    }
-                    None => {
-                        //2.5 parent is root, set parent to black
-                        TreeNode::set_black(parent);
                     }
                 }
-            }
-        },
-        //3. node is root
-        None => {
-            TreeNode::set_black(node);
-        },
+            },
+            //3. node is root
+            None => {
+                let was_red = TreeNode::is_red(Some(node.clone()));
+                TreeNode::set_black(node);
+                return Ok(was_red);
+            },
+        }
+        return Ok(false);
     }
 }
 
-fn delete_node<T: Ord+Copy+Debug+Display>(
-    root: TreeRoot<T>, value: T)->Option<TreeRoot<T>>{
-    if root.is_none(){
-        return None;
-    }
+/// Helper for delete()
+///
+/// Remove `node` from the tree and update its `root`;
+/// Return the removed value and data
+pub(crate) fn delete_node<T: Debug, V, A: Augment<T>>(
+    root: &mut TreeRoot<T, V, A>, node: Shared<NodeCell<TreeNode<T, V, A>>>)->(T, V){
+    try_delete_node(root, node).unwrap_or_else(Error::raise)
+}
 
-    let node = root.clone().unwrap();
+/// Helper for try_delete(), see delete_node()
+///
+/// Fails when the rebalancing finds the tree corrupted. StillReferenced, TwoChildren and
+/// BlackChildOfBlackNode are found before anything changes; after any other error the tree is poisoned
+fn try_delete_node<T: Debug, V, A: Augment<T>>(
+    root: &mut TreeRoot<T, V, A>, node: Shared<NodeCell<TreeNode<T, V, A>>>)->Result<(T, V), Error>{
+    let mut node = node;
+    // the node taken out of the tree, the right minimum of a node with two children
+    let right = node.borrow().right.clone();
+    let spliced = match (&node.borrow().left, right){
+        (Some(_), Some(right))=>get_min_node(right),
+        _=>node.clone()
+    };
+    // only its parent or `root` and the locals here may hold it
+    let holders = if Shared::ptr_eq(&spliced, &node) {3} else {2};
+    if Shared::strong_count(&spliced) > holders{
+        return Err(Error::StillReferenced);
+    }
+    // no child; one child
+    let (child, _direction) = spliced.borrow().get_child_delete_helper()?;
+    // current black && unique child black=>invalid case;
+    if spliced.borrow().color == NodeColor::Black && child.as_ref().is_some_and(|c| c.borrow().color == NodeColor::Black){
+        return Err(Error::BlackChildOfBlackNode);
+    }
 
     // Case0.1: Two children
-    // => like BSTree
-    if node.borrow().left.is_some() && node.borrow().right.is_some(){
-        let right_min = node.borrow().right.clone().unwrap().borrow().get_min();
-        let rchild = node.borrow().right.clone();
-        let r = delete_node(rchild, right_min);
-        node.borrow_mut().value = right_min;
-        return r;
-    }
-    // else: no child; one child
-    
+    // => like BSTree, take the place of the right minimum and delete that node
+    if !Shared::ptr_eq(&spliced, &node){
+        node.borrow_mut().swap_entry(&mut spliced.borrow_mut());
+    }
+    node = spliced;
 
     // Case0.2: No child
     // red=>just delete it
-    if node.borrow().left.is_none() && node.borrow().right.is_none() && node.borrow().color == NodeColor::Red{
-        let ret = node.borrow_mut().delete_node();
-        return ret;
-    }
-    //else: one child&&black; one child
 
     // Case1: current node is red
     // Red case ends
@@ -623,165 +1747,429 @@ fn delete_node<T: Ord+Copy+Debug+Display>(
     // Case2: current black && one child
     // Case2.1: current is black && unique child is red
     // => Replace it with its red child
-    let (child, _direction) = node.borrow().get_child_delete_helper();
-    if child.is_some(){
-        if child.clone().unwrap().borrow().color == NodeColor::Red{
-            child.clone().unwrap().borrow_mut().color = NodeColor::Black;
-            let ret = node.borrow_mut().delete_node();
-            return ret;
-        }
-        else{
-            // current black && unique child black=>invalid case;
-            panic!("Error! If current node is black, its unique child cannot be black!");
+    if node.borrow().color == NodeColor::Black{
+        match &child{
+            Some(child)=>child.borrow_mut().color = NodeColor::Black,
+            // Case3: current black && no child
+            None=>delete_rebalance_helper(root, Some(node.clone()))?
         }
     }
-    // else: 
-    // current black && no child
 
-    // Case3: 
-    let ret0 = delete_rebalance_helper(root.clone());
-    let ret = node.borrow_mut().delete_node();
-    match &ret{
-        None=>ret0,
-        _=> ret
+    let parent = node.borrow().parent();
+    node.borrow_mut().try_delete_node()?;
+    // rotations already moved any new root into `root`
+    if parent.is_none(){
+        *root = child;
     }
+    update_size_to_root(parent);
+    // checked above, nothing else holds the node
+    let removed = Shared::try_unwrap(node).map_err(|_| Error::StillReferenced)?.into_inner();
+    Ok((removed.value, removed.data))
 }
 
-fn delete_rebalance_helper<T: Ord+Copy+Debug+Display>(root: TreeRoot<T>)->Option<TreeRoot<T>> {
-    if root.is_none(){
-        return None;
+/// Rotate `child` into the place of `parent`
+///
+/// The parent links are weak, so a child rotated to the top is kept alive in `root`
+fn rotate_root<T: Debug, V, A: Augment<T>>(root: &mut TreeRoot<T, V, A>, parent: &TreeRoot<T, V, A>, child: &TreeRoot<T, V, A>){
+    let top = rotate(parent, child);
+    if top.borrow().parent.is_none(){
+        *root = Some(top);
     }
+}
 
-    let node = root.clone().unwrap();
+fn delete_rebalance_helper<T: Debug, V, A: Augment<T>>(root: &mut TreeRoot<T, V, A>, node: TreeRoot<T, V, A>)->Result<(), Error>{
+    let Some(mut node) = node else{
+        return Ok(());
+    };
+    // climb while the missing black moves up
+    loop{
+        // Case3: current black && no child
+        // Case3.1: child is new root
+        // => node is root => finished 
+        if node.borrow().parent.is_none(){
+            return Ok(());
+        }
+        let direction = node.borrow().try_get_direction_to_parent()?;
+        // the rotations below keep `parent` the parent of `node`
+        let parent = node.borrow().parent().ok_or(Error::MissingParent)?;
+        // First replace current node with its child N
+        // Case3.2: sibling is red
+        // =>sibling to black; parent to red; rotate 
+        let sibling_of = |parent: &Shared<NodeCell<TreeNode<T, V, A>>>| match direction{
+            Direction::Left=>parent.borrow().right.clone().ok_or(Error::MissingChild),
+            Direction::Right=>parent.borrow().left.clone().ok_or(Error::MissingChild),
+        };
+        let mut sibling = sibling_of(&parent)?;
+        let sib_direction = direction.opposite();
+
+        if sibling.borrow().color==NodeColor::Red{
+            sibling.borrow_mut().color = NodeColor::Black;
+            parent.borrow_mut().color = NodeColor::Red;
+            rotate_root(root, &Some(parent.clone()), &Some(sibling.clone()));
+
+            // sibling changed due to rotation
+            sibling = sibling_of(&parent)?;
+        }
+
+        // continue in Case3.4, 3.5, 3.6
+        // else: sibling is black
+        if sibling.borrow().color != NodeColor::Black{
+            return Err(Error::RedSibling);
+        }
+        // Case3.3&3.4: sibling black and black children or no child;
+        let sib_left = sibling.borrow().left.clone();
+        let sib_right = sibling.borrow().right.clone();
+        if !TreeNode::is_red(sib_left) && !TreeNode::is_red(sib_right){
+            sibling.borrow_mut().color = NodeColor::Red;
+            let par_color = parent.borrow().color;
+            match par_color{
+                // Case 3.3
+                NodeColor::Black=>{
+                    node = parent;
+                    continue;
+                },
+                // Case 3.4
+                NodeColor::Red=>{
+                    parent.borrow_mut().color = NodeColor::Black;
+                    return Ok(());
+                }
+            }
+        }
+        //else: sibling black && either child is red 
+        // (both children red is handled by Case3.6 directly)
+
+        // Case3.5: sibling close child is red
+        // => rotate, change color
+        let sib_close_child = match sib_direction{
+            Direction::Left=>sibling.borrow().right.clone(),
+            Direction::Right=>sibling.borrow().left.clone(),
+        };
+
+        if TreeNode::is_red(sib_close_child.clone()){
+            rotate_root(root, &Some(sibling.clone()), &sib_close_child);
+            let sibling_parent = sibling.borrow().parent().ok_or(Error::MissingParent)?;
+            let sp_cl = sibling_parent.borrow().color;
+            sibling.borrow_mut().color = sp_cl; 
+            sibling_parent.borrow_mut().color = NodeColor::Black;
+            // sibling changed due to rotation
+            sibling = sibling_of(&parent)?;
+        }
 
-    let mut new_root_ret: Option<TreeRoot<T>> = None;
-    // Case3: current black && no child
-    // Case3.1: child is new root
-    // => node is root => finished 
-    if node.borrow().parent.is_none(){
-        return None; 
+
+        // Case3.6: sibling distant child is red 
+        // =>  rotate, change color
+        let sib_dist_child = match sib_direction{
+            Direction::Left=>sibling.borrow().left.clone(),
+            Direction::Right=>sibling.borrow().right.clone(),
+        };
+        if let Some(dist_child) = sib_dist_child.filter(|c| c.borrow().color == NodeColor::Red){
+            dist_child.borrow_mut().color = NodeColor::Black;
+            let par_color = parent.borrow().color;
+            sibling.borrow_mut().color = par_color;
+            parent.borrow_mut().color = NodeColor::Black;
+            rotate_root(root, &Some(parent), &Some(sibling));
+        }
+        return Ok(());
     }
-    let direction = node.borrow().get_direction_to_parent();
-    // First replace current node with its child N
-    // Case3.2: sibling is red
-    // =>sibling to black; parent to red; rotate 
-    let mut sibling = match direction{
-        Direction::Left=>node.borrow().parent.clone().unwrap().borrow_mut().right.clone().unwrap(),
-        Direction::Right=>node.borrow().parent.clone().unwrap().borrow_mut().left.clone().unwrap(),
-    };
-    let sib_direction = direction.opposite();
+}
+
 
-    if sibling.borrow().color==NodeColor::Red{
-        sibling.borrow_mut().color = NodeColor::Black;
-        node.borrow().parent.clone().unwrap().borrow_mut().color = NodeColor::Red;
-        rotate(&node.borrow().parent, &Some(sibling.clone()));
-        if sibling.borrow().parent.is_none(){
-            new_root_ret = Some(Some(sibling.clone()));
+/// Build a tree from the next `len` entries of the sorted `entries`
+///
+/// Linked by link_sorted, so every level above `red_depth` is full; the nodes on
+/// the incomplete level `red_depth` are red and all others black
+fn build_sorted<T: Debug, V, A: Augment<T>, I: Iterator<Item = (T, V)>>(entries: &mut I, len: usize,
+    red_depth: usize)->TreeRoot<T, V, A>{
+    let nodes = entries.take(len).map(|(value, data)| Shared::new(NodeCell::new(TreeNode::new(value, data)))).collect();
+    tree::link_sorted(nodes, |node, depth|{
+        if depth != red_depth{
+            node.borrow_mut().color = NodeColor::Black;
         }
+    })
+}
+
+/// Build a tree from the sorted `entries` with build_sorted
+///
+/// Its black height is the number of full levels
+fn build_subtree<T: Debug, V, A: Augment<T>, I: ExactSizeIterator<Item = (T, V)>>(entries: I)->Subtree<T, V, A>{
+    let mut entries = entries;
+    let len = entries.len();
+    let red_depth = (len + 1).ilog2() as usize;
+    (build_sorted(&mut entries, len, red_depth), red_depth)
+}
+
+/// Count the black nodes on a path from `root` down to a leaf
+fn black_height<T: Debug, V, A: Augment<T>>(root: &TreeRoot<T, V, A>)->usize{
+    let mut height = 0;
+    let mut current = root.clone();
+    while let Some(nd) = current{
+        if nd.borrow().color == NodeColor::Black{
+            height += 1;
+        }
+        current = nd.borrow().left.clone();
+    }
+    height
+}
+
+/// A sub-tree together with its black height
+///
+/// Passed around by join and split so the black heights never have to be recounted
+type Subtree<T, V=(), A=()> = (TreeRoot<T, V, A>, usize);
+
+/// Parts of a split: the smaller values, the node holding the key and the greater values
+type SplitParts<T, V, A> = (Subtree<T, V, A>, TreeRoot<T, V, A>, Subtree<T, V, A>);
 
-        // sibling changed due to rotation
-        sibling = match direction{
-            Direction::Left=>node.borrow().parent.clone().unwrap().borrow().right.clone().unwrap(),
-            Direction::Right=>node.borrow().parent.clone().unwrap().borrow().left.clone().unwrap(),
+/// The detached node of the minimum of a tree and the rest of it
+type SplitFirst<T, V, A> = (TreeRoot<T, V, A>, Subtree<T, V, A>);
+
+/// Get the black height of the children of `node`
+fn child_black_height<T: Debug, V, A: Augment<T>>(node: &Shared<NodeCell<TreeNode<T, V, A>>>, bh: usize)->usize{
+    match node.borrow().color{
+        NodeColor::Black=>bh - 1,
+        NodeColor::Red=>bh
+    }
+}
+
+/// Join `left`, the detached node `mid` and `right` into one tree
+///
+/// All values of `left` must be smaller than `mid` and all values of `right` greater.
+/// Descends the spine of the taller tree to a black node of the other's black height,
+/// hangs the red `mid` there and recolors like insert, O(|bh(left) - bh(right)| + 1);
+/// Fails when the black heights or colors show a tree was corrupted
+fn join<T: Debug, V, A: Augment<T>>(left: Subtree<T, V, A>, mid: Shared<NodeCell<TreeNode<T, V, A>>>, right: Subtree<T, V, A>)->
+    Result<Subtree<T, V, A>, Error>{
+    // pieces of a split may have red roots
+    let blacken = |(nd, bh): Subtree<T, V, A>| match nd{
+        Some(root) if root.borrow().color == NodeColor::Red=>{
+            root.borrow_mut().color = NodeColor::Black;
+            (Some(root), bh + 1)
+        },
+        _=>(nd, bh)
+    };
+    let (left, left_bh) = blacken(left);
+    let (right, right_bh) = blacken(right);
+    if left_bh == right_bh{
+        link_children(&mid, left, right);
+        mid.borrow_mut().color = NodeColor::Black;
+        return Ok((Some(mid), left_bh + 1));
+    }
+    let (taller, taller_bh, shorter, shorter_bh, direction) = match left_bh > right_bh{
+        true=>(left, left_bh, right, right_bh, Direction::Right),
+        false=>(right, right_bh, left, left_bh, Direction::Left)
+    };
+    let mut parent = None;
+    // the parent links are weak, the root has to be held while descending
+    let mut root = taller.clone();
+    let mut current = taller;
+    let mut bh = taller_bh;
+    while bh > shorter_bh || TreeNode::is_red(current.clone()){
+        // the black heights promise a node down to `shorter_bh`
+        let nd = current.ok_or(Error::MissingChild)?;
+        if nd.borrow().color == NodeColor::Black{
+            bh -= 1;
+        }
+        current = match direction{
+            Direction::Left=>nd.borrow().left.clone(),
+            Direction::Right=>nd.borrow().right.clone()
         };
+        parent = Some(nd);
+    }
+    let parent = parent.ok_or(Error::MissingParent)?;
+    match direction{
+        Direction::Left=>{
+            link_children(&mid, shorter, current);
+            parent.borrow_mut().left = Some(mid.clone());
+        },
+        Direction::Right=>{
+            link_children(&mid, current, shorter);
+            parent.borrow_mut().right = Some(mid.clone());
+        }
     }
+    mid.borrow_mut().color = NodeColor::Red;
+    mid.borrow_mut().parent = Some(Shared::downgrade(&parent));
+    let recolored = insert_recolor(&mut root, mid.clone());
+    update_size_to_root(Some(mid));
+    Ok((root, taller_bh + recolored? as usize))
+}
 
-    // continue in Case3.4, 3.5, 3.6
-    // else: sibling is black
-    assert!(sibling.borrow().color == NodeColor::Black);
-    // Case3.3&3.4: sibling black and black children or no child;
-    let sib_left = sibling.borrow().left.clone();
-    let sib_right = sibling.borrow().right.clone();
-    if sib_left.is_some()&&sib_right.is_some()&&sib_left.clone().unwrap().borrow().color == NodeColor::Black && sib_right.clone().unwrap().borrow().color == NodeColor::Black ||
-    sib_left.is_none()&&sib_right.is_none(){
-        sibling.borrow_mut().color = NodeColor::Red;
-        let par_color = node.borrow().parent.clone().unwrap().borrow().color;
-        match par_color{
-            // Case 3.3
-            NodeColor::Black=>{
-                let parent = node.borrow().parent.clone();
-                let r = delete_rebalance_helper(parent);
-                return r;
+/// Split `tree` into the values smaller than `key`, the detached node holding `key`
+/// and the values greater than `key`
+///
+/// Walks down the search path for `key`, then joins the detached nodes back
+/// from the bottom up, O(log n)
+fn split<T: Debug, V, A: Augment<T>, C: Comparator<T>>(tree: Subtree<T, V, A>, key: &T, cmp: &C)->Result<SplitParts<T, V, A>, Error>{
+    // the nodes on the path, each with the side it was left by and the other sub-tree
+    let mut path = Vec::new();
+    let (mut current, mut bh) = tree;
+    let (mut less, found, mut greater) = loop{
+        let Some(node) = current else{
+            break ((None, 0), None, (None, 0));
+        };
+        let child_bh = child_black_height(&node, bh);
+        let (left, right) = detach_node(&node);
+        let ordering = cmp.compare(key, &node.borrow().value);
+        match ordering{
+            Ordering::Equal=>break ((left, child_bh), Some(node), (right, child_bh)),
+            Ordering::Less=>{
+                current = left;
+                path.push((node, Direction::Left, (right, child_bh)));
             },
-            // Case 3.4
-            NodeColor::Red=>{
-                node.borrow().parent.clone().unwrap().borrow_mut().color = NodeColor::Black;
-                return new_root_ret;
+            Ordering::Greater=>{
+                current = right;
+                path.push((node, Direction::Right, (left, child_bh)));
             }
         }
+        bh = child_bh;
+    };
+    while let Some((node, direction, other)) = path.pop(){
+        match direction{
+            Direction::Left=>greater = join(greater, node, other)?,
+            Direction::Right=>less = join(other, node, less)?
+        }
     }
-    //else: sibling black && either child is red 
+    Ok((less, found, greater))
+}
 
-    if sibling.borrow().left.is_some() && sibling.borrow().right.is_some() &&
-        sibling.borrow().left.clone().unwrap().borrow().color == NodeColor::Red && 
-        sibling.borrow().right.clone().unwrap().borrow().color == NodeColor::Red {
-        //panic!("not implemented");
-        println!("unsafe case");
+/// Split the minimum off `tree`
+///
+/// Walks down the left spine, then joins the detached nodes back from the bottom up;
+/// Return the detached node of the minimum and the rest of the tree
+fn split_first<T: Debug, V, A: Augment<T>>(tree: Subtree<T, V, A>)->Result<SplitFirst<T, V, A>, Error>{
+    // the nodes on the left spine, each with its right sub-tree
+    let mut spine = Vec::new();
+    let (mut current, mut bh) = tree;
+    let (first, mut rest) = loop{
+        let Some(node) = current else{
+            break (None, (None, 0));
+        };
+        let child_bh = child_black_height(&node, bh);
+        let (left, right) = detach_node(&node);
+        if left.is_none(){
+            break (Some(node), (right, child_bh));
+        }
+        current = left;
+        spine.push((node, (right, child_bh)));
+        bh = child_bh;
+    };
+    while let Some((node, right)) = spine.pop(){
+        rest = join(rest, node, right)?;
     }
+    Ok((first, rest))
+}
 
-    // Case3.5: sibling close child is red
-    // => rotate, change color
-    let sib_close_child = match sib_direction{
-        Direction::Left=>sibling.borrow().right.clone(),
-        Direction::Right=>sibling.borrow().left.clone(),
-    };
+/// Join two trees where all values of `left` are smaller than those of `right`
+///
+/// The minimum of `right` is split off to join them
+fn concat<T: Debug, V, A: Augment<T>>(left: Subtree<T, V, A>, right: Subtree<T, V, A>)->Result<Subtree<T, V, A>, Error>{
+    match split_first(right)?{
+        (None, _)=>Ok(left),
+        (Some(first), rest)=>join(left, first, rest)
+    }
+}
 
-    if sib_close_child.is_some() && sib_close_child.clone().unwrap().borrow().color == NodeColor::Red{
-        rotate(&Some(sibling.clone()), &sib_close_child);
-        if sib_close_child.clone().unwrap().borrow().parent.is_none(){
-            new_root_ret = Some(sib_close_child.clone());
-        }
-        let sp_cl = sibling.borrow().parent.clone().unwrap().borrow().color;
-        sibling.borrow_mut().color = sp_cl; 
-        sibling.borrow().parent.clone().unwrap().borrow_mut().color = NodeColor::Black;
-        // sibling changed due to rotation
-        sibling = match direction{
-            Direction::Left=>node.borrow().parent.clone().unwrap().borrow().right.clone().unwrap(),
-            Direction::Right=>node.borrow().parent.clone().unwrap().borrow().left.clone().unwrap(),
-        };
+/// Union of two trees, joined directly when their values do not overlap
+fn merge<T: Debug, V, A: Augment<T>, C: Comparator<T>>(left: Subtree<T, V, A>, right: Subtree<T, V, A>, cmp: &C)->
+    Result<Subtree<T, V, A>, Error>{
+    if precedes(&left.0, &right.0, cmp){
+        return concat(left, right);
     }
+    if precedes(&right.0, &left.0, cmp){
+        return concat(right, left);
+    }
+    combine(left, right, cmp, SetOperation::Union)
+}
 
+/// Pending work of combine
+enum CombineStep<T: Debug, V, A>{
+    /// Combine the two sub-trees and push the result
+    Visit(Subtree<T, V, A>, Subtree<T, V, A>),
+    /// Pop the combined lower and upper parts and join them, through the node when it is kept
+    Join(Shared<NodeCell<TreeNode<T, V, A>>>, bool)
+}
 
-    // Case3.6: sibling distant child is red 
-    // =>  rotate, change color
-    let sib_dist_child = match sib_direction{
-        Direction::Left=>sibling.borrow().left.clone(),
-        Direction::Right=>sibling.borrow().right.clone(),
-    };
-    if sib_dist_child.is_some() && sib_dist_child.clone().unwrap().borrow().color == NodeColor::Red{
-        sib_dist_child.clone().unwrap().borrow_mut().color = NodeColor::Black;
-        sibling.borrow_mut().color = node.borrow().parent.clone().unwrap().borrow().color;
-        node.borrow().parent.clone().unwrap().borrow_mut().color = NodeColor::Black;
-        rotate(&node.borrow().parent, &Some(sibling.clone()));
-        if sibling.borrow().parent.is_none(){
-            new_root_ret = Some(Some(sibling.clone()));
+/// Set `operation` of two trees, keeping the entries of `right` on equal values
+///
+/// Splits `left` by the root of `right` and joins the results for both sides,
+/// O(m log(n/m + 1)) for trees of sizes m <= n; the halves wait on an explicit
+/// stack instead of recursing
+fn combine<T: Debug, V, A: Augment<T>, C: Comparator<T>>(left: Subtree<T, V, A>, right: Subtree<T, V, A>, cmp: &C,
+    operation: SetOperation)->Result<Subtree<T, V, A>, Error>{
+    let mut steps = vec![CombineStep::Visit(left, right)];
+    let mut results = Vec::new();
+    while let Some(step) = steps.pop(){
+        match step{
+            CombineStep::Visit((None, _), right)=>results.push(match operation{
+                SetOperation::Union | SetOperation::SymmetricDifference=>right,
+                SetOperation::Intersection | SetOperation::Difference=>(None, 0)
+            }),
+            CombineStep::Visit(left, (None, _))=>results.push(match operation{
+                SetOperation::Intersection=>(None, 0),
+                _=>left
+            }),
+            CombineStep::Visit(left, (Some(node), bh))=>{
+                let child_bh = child_black_height(&node, bh);
+                let (right_left, right_right) = detach_node(&node);
+                let (less, found, greater) = split(left, &node.borrow().value, cmp)?;
+                let keep = match operation{
+                    SetOperation::Union=>true,
+                    SetOperation::Intersection=>found.is_some(),
+                    SetOperation::Difference=>false,
+                    SetOperation::SymmetricDifference=>found.is_none()
+                };
+                steps.push(CombineStep::Join(node, keep));
+                steps.push(CombineStep::Visit(greater, (right_right, child_bh)));
+                steps.push(CombineStep::Visit(less, (right_left, child_bh)));
+            },
+            CombineStep::Join(node, keep)=>{
+                // both halves were visited after the Join was pushed, so their results are on top
+                let (Some(upper), Some(lower)) = (results.pop(), results.pop()) else{
+                    unreachable!()
+                };
+                results.push(match keep{
+                    true=>join(lower, node, upper)?,
+                    false=>concat(lower, upper)?
+                });
+            }
         }
     }
-   return  new_root_ret;
+    Ok(results.pop().unwrap_or((None, 0)))
 }
 
-
 #[cfg(test)]
 mod test{
-    use crate::tree;
-
     use super::*;
+    use std::collections::{BTreeMap, BTreeSet};
+    use rand::{Rng, SeedableRng};
+    use rand::rngs::StdRng;
+
     fn new_children(nd: &TreeRoot<i32>, lv:i32, rv:i32,lc: &str, rc:&str)-> (TreeRoot<i32>, TreeRoot<i32>){
         let f = |s|if s == "r" {NodeColor::Red} else {NodeColor::Black};
         let left: TreeNode<i32> = TreeNode{color: f(lc),
-        value:lv, parent: Some(Rc::clone(&nd.clone().unwrap())), left: None, right:None};
-        let left = Some(Rc::new(RefCell::new(left)));
+        value:lv, data: (), parent: Some(Shared::downgrade(&nd.clone().unwrap())), left: None, right:None, size: 1, augmentation: ()};
+        let left = Some(Shared::new(NodeCell::new(left)));
         let right: TreeNode<i32> = TreeNode{color: f(rc),
-        value:rv, parent: Some(Rc::clone(&nd.clone().unwrap())), left: None, right:None};
-        let right = Some(Rc::new(RefCell::new(right)));
-        return (left, right);
+        value:rv, data: (), parent: Some(Shared::downgrade(&nd.clone().unwrap())), left: None, right:None, size: 1, augmentation: ()};
+        let right = Some(Shared::new(NodeCell::new(right)));
+        (left, right)
+    }
+    /// Fill in the sizes of a hand-built tree
+    fn fix_sizes(nd: &TreeRoot<i32>)->usize{
+        let node = match nd{
+            None=>return 0,
+            Some(node)=>node
+        };
+        let (left, right) = (node.borrow().left.clone(), node.borrow().right.clone());
+        let size = fix_sizes(&left) + fix_sizes(&right) + 1;
+        node.borrow_mut().size = size;
+        size
     }
+
     fn check_valid_delete(tree: &RedBlackTree<i32>, expect: Option<i32>, result: Option<i32>, pre_delete_vec: &mut Vec<i32>){
-        let mut vec = tree.in_order_traverse();
+        let vec = tree.in_order_traverse();
         assert!(tree.check_valid());
         match expect{
             Some(value)=>{
-                assert!(result.is_some() && result.clone().unwrap() == value);
+                assert!(result.is_some() && result.unwrap() == value);
                 assert!(vec.len()==pre_delete_vec.len()-1);
                 pre_delete_vec.retain(|&x| x != value);
             },
@@ -791,7 +2179,7 @@ mod test{
         }
         assert!(vec.iter().zip(&mut pre_delete_vec.iter()).filter(|&(a, b)| a != b).count()==0);
     }
-    fn check_valid_insert(tree: &RedBlackTree<i32>, value: i32, pre_insert_vec: &mut Vec<i32>){
+    fn check_valid_insert(tree: &RedBlackTree<i32>, value: i32, pre_insert_vec: &[i32]){
         let mut vec = tree.in_order_traverse();
         assert!(tree.check_valid());
         assert!(vec.len()==pre_insert_vec.len()+1);
@@ -831,9 +2219,9 @@ mod test{
         tree.insert(-1);
         tree.insert(3);
 
-        let mut vec = tree.in_order_traverse();
+        let vec = tree.in_order_traverse();
         tree.insert(4);
-        check_valid_insert(&tree, 4, &mut vec); 
+        check_valid_insert(&tree, 4, &vec); 
     }
     #[test]
     fn test_insert_RR_uncle_black() {
@@ -844,9 +2232,9 @@ mod test{
         tree.insert(7);
         tree.insert(6);
 
-        let mut vec = tree.in_order_traverse();
+        let vec = tree.in_order_traverse();
         tree.insert(10);
-        check_valid_insert(&tree, 10, &mut vec); 
+        check_valid_insert(&tree, 10, &vec); 
     }
     #[test]
     fn test_insert_LL_uncle_red() {
@@ -855,9 +2243,9 @@ mod test{
         tree.insert(4);
         tree.insert(8);
 
-        let mut vec = tree.in_order_traverse();
+        let vec = tree.in_order_traverse();
         tree.insert(3);
-        check_valid_insert(&tree, 3, &mut vec); 
+        check_valid_insert(&tree, 3, &vec); 
     }
     #[test]
     fn test_insert_LL_uncle_black() {
@@ -870,9 +2258,9 @@ mod test{
         tree.insert(1);
         tree.insert(10);
 
-        let mut vec = tree.in_order_traverse();
+        let vec = tree.in_order_traverse();
         tree.insert(0);
-        check_valid_insert(&tree, 0, &mut vec); 
+        check_valid_insert(&tree, 0, &vec); 
     }
     #[test]
     fn test_insert_LR_uncle_red() {
@@ -881,9 +2269,9 @@ mod test{
         tree.insert(2);
         tree.insert(5);
          
-        let mut vec = tree.in_order_traverse();
+        let vec = tree.in_order_traverse();
         tree.insert(3);
-        check_valid_insert(&tree, 3, &mut vec); 
+        check_valid_insert(&tree, 3, &vec); 
         
     }
 
@@ -897,9 +2285,9 @@ mod test{
         tree.insert(8);
         tree.insert(11);
          
-        let mut vec = tree.in_order_traverse();
+        let vec = tree.in_order_traverse();
         tree.insert(9);
-        check_valid_insert(&tree, 9, &mut vec);         
+        check_valid_insert(&tree, 9, &vec);         
     }
 
     #[test]
@@ -913,9 +2301,9 @@ mod test{
         tree.insert(5);
         tree.insert(9);
         
-        let mut vec = tree.in_order_traverse();
+        let vec = tree.in_order_traverse();
         tree.insert(8);
-        check_valid_insert(&tree, 8, &mut vec);   
+        check_valid_insert(&tree, 8, &vec);   
     }
 
     #[test]
@@ -931,9 +2319,9 @@ mod test{
         tree.insert(7);
         tree.insert(10);
         
-        let mut vec = tree.in_order_traverse();
+        let vec = tree.in_order_traverse();
         tree.insert(9);
-        check_valid_insert(&tree, 9, &mut vec);   
+        check_valid_insert(&tree, 9, &vec);   
     }
 
     
@@ -942,172 +2330,1299 @@ mod test{
     #[test]
     fn test_delete1(){
         // root
-        let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
-            value: 8, parent: None, left: None, right: None};
-        let nd = Some(Rc::new(RefCell::new(nd)));
-        let mut tree: RedBlackTree<i32> = RedBlackTree{root: nd.clone()};
-        let d = tree.delete(8);
-        assert!(d.is_some() && d.clone().unwrap() == 8);
+        let mut tree: RedBlackTree<i32> = {
+            let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
+                value: 8, data: (), parent: None, left: None, right: None, size: 1, augmentation: ()};
+            let nd = Some(Shared::new(NodeCell::new(nd)));
+            fix_sizes(&nd);
+            RedBlackTree{root: nd, len: 1, comparator: NaturalOrder}
+        };
+        let d = tree.delete(&8);
+        assert!(d.is_some() && d.unwrap() == 8);
         assert!(tree.is_empty());
     }
 
     #[test]
     fn test_delete2(){
         // black+two children
-        let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
-        value: 8, parent: None, left: None, right: None};
-        let nd = Some(Rc::new(RefCell::new(nd)));
-        let (left, right) = new_children(&nd, 2, 12, "b", "b");
-        
-        let mut tree: RedBlackTree<i32> = RedBlackTree{root: nd.clone()};
-        let _nd = tree.root.clone().unwrap();
-        _nd.borrow_mut().left = left.clone();
-        _nd.borrow_mut().right = right.clone();
+        let mut tree: RedBlackTree<i32> = {
+            let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
+            value: 8, data: (), parent: None, left: None, right: None, size: 1, augmentation: ()};
+            let nd = Some(Shared::new(NodeCell::new(nd)));
+            let (left, right) = new_children(&nd, 2, 12, "b", "b");
+
+            let _nd = nd.clone().unwrap();
+            _nd.borrow_mut().left = left.clone();
+            _nd.borrow_mut().right = right.clone();
+            fix_sizes(&nd);
+            RedBlackTree{root: nd, len: 3, comparator: NaturalOrder}
+        };
         let mut vec = tree.in_order_traverse();
-        let d = tree.delete(8); 
+        let d = tree.delete(&8); 
         check_valid_delete(&tree, Some(8), d, &mut vec); 
     }
 
     #[test]
     fn test_delete3(){
         //  red leaf
-        let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
-        value: 8, parent: None, left: None, right: None};
-        let nd = Some(Rc::new(RefCell::new(nd)));
-        let (left, right) = new_children(&nd, 2, 12, "r", "b");
-        
-        let mut tree: RedBlackTree<i32> = RedBlackTree{root: nd.clone()};
-        let _nd = tree.root.clone().unwrap();
-        _nd.borrow_mut().left = left.clone();
-
+        let mut tree: RedBlackTree<i32> = {
+            let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
+            value: 8, data: (), parent: None, left: None, right: None, size: 1, augmentation: ()};
+            let nd = Some(Shared::new(NodeCell::new(nd)));
+            let (left, _right) = new_children(&nd, 2, 12, "r", "b");
+
+            let _nd = nd.clone().unwrap();
+            _nd.borrow_mut().left = left.clone();
+            fix_sizes(&nd);
+            RedBlackTree{root: nd, len: 2, comparator: NaturalOrder}
+        };
         let mut vec = tree.in_order_traverse();
-        let d = tree.delete(2); 
+        let d = tree.delete(&2); 
         check_valid_delete(&tree, Some(2), d, &mut vec); 
     }
 
     #[test]
     fn test_delete4(){
         // black + red sibling
-        let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
-        value: 8, parent: None, left: None, right: None};
-        let nd = Some(Rc::new(RefCell::new(nd)));
-        let (left, right) = new_children(&nd, 2, 12, "b", "b");
-        let (rl, rr) = new_children(&right, 10, 20, "r", "b");
-        let (rll, rlr) = new_children(&rl, 9, 11, "b", "b");
-        let (ll, lr) = new_children(&left, 1, 5, "b", "b");
-        
-        let mut tree: RedBlackTree<i32> = RedBlackTree{root: nd.clone()};
-        let _nd = tree.root.clone().unwrap();
-        _nd.borrow_mut().left = left.clone();
-        _nd.borrow_mut().right = right.clone();
-        
-        let _right = right.clone().unwrap();
-        _right.borrow_mut().left = rl.clone();
-        _right.borrow_mut().right = rr.clone();
-        let _left = left.clone().unwrap();
-        _left.borrow_mut().left = ll.clone();
-        _left.borrow_mut().right = lr.clone();
-        let _rl = rl.clone().unwrap();
-        _rl.borrow_mut().left = rll.clone();
-        _rl.borrow_mut().right = rlr.clone();
-
+        let mut tree: RedBlackTree<i32> = {
+            let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
+            value: 8, data: (), parent: None, left: None, right: None, size: 1, augmentation: ()};
+            let nd = Some(Shared::new(NodeCell::new(nd)));
+            let (left, right) = new_children(&nd, 2, 12, "b", "b");
+            let (rl, rr) = new_children(&right, 10, 20, "r", "b");
+            let (rll, rlr) = new_children(&rl, 9, 11, "b", "b");
+            let (ll, lr) = new_children(&left, 1, 5, "b", "b");
+
+            let _nd = nd.clone().unwrap();
+            _nd.borrow_mut().left = left.clone();
+            _nd.borrow_mut().right = right.clone();
+
+            let _right = right.clone().unwrap();
+            _right.borrow_mut().left = rl.clone();
+            _right.borrow_mut().right = rr.clone();
+            let _left = left.clone().unwrap();
+            _left.borrow_mut().left = ll.clone();
+            _left.borrow_mut().right = lr.clone();
+            let _rl = rl.clone().unwrap();
+            _rl.borrow_mut().left = rll.clone();
+            _rl.borrow_mut().right = rlr.clone();
+            fix_sizes(&nd);
+            RedBlackTree{root: nd, len: 9, comparator: NaturalOrder}
+        };
         let mut vec = tree.in_order_traverse();
-        let d = tree.delete(20);
+        let d = tree.delete(&20);
         check_valid_delete(&tree, Some(20), d, &mut vec);
     }
 
     #[test]
     fn test_delete5(){
         // black + black sibling + no nephew + red parent 
-        let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
-        value: 8, parent: None, left: None, right: None};
-        let nd = Some(Rc::new(RefCell::new(nd)));
-        let (left, right) = new_children(&nd, 2, 12, "b", "b");
-        let (rl, rr) = new_children(&right, 10, 20, "r", "b");
-        let (rll, rlr) = new_children(&rl, 9, 11, "b", "b");
-        let (ll, lr) = new_children(&left, 1, 5, "b", "b");
-        
-        let mut tree: RedBlackTree<i32> = RedBlackTree{root: nd.clone()};
-        let _nd = tree.root.clone().unwrap();
-        _nd.borrow_mut().left = left.clone();
-        _nd.borrow_mut().right = right.clone();
-        
-        let _right = right.clone().unwrap();
-        _right.borrow_mut().left = rl.clone();
-        _right.borrow_mut().right = rr.clone();
-        let _left = left.clone().unwrap();
-        _left.borrow_mut().left = ll.clone();
-        _left.borrow_mut().right = lr.clone();
-        let _rl = rl.clone().unwrap();
-        _rl.borrow_mut().left = rll.clone();
-        _rl.borrow_mut().right = rlr.clone();
-
-
+        let mut tree: RedBlackTree<i32> = {
+            let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
+            value: 8, data: (), parent: None, left: None, right: None, size: 1, augmentation: ()};
+            let nd = Some(Shared::new(NodeCell::new(nd)));
+            let (left, right) = new_children(&nd, 2, 12, "b", "b");
+            let (rl, rr) = new_children(&right, 10, 20, "r", "b");
+            let (rll, rlr) = new_children(&rl, 9, 11, "b", "b");
+            let (ll, lr) = new_children(&left, 1, 5, "b", "b");
+
+            let _nd = nd.clone().unwrap();
+            _nd.borrow_mut().left = left.clone();
+            _nd.borrow_mut().right = right.clone();
+
+            let _right = right.clone().unwrap();
+            _right.borrow_mut().left = rl.clone();
+            _right.borrow_mut().right = rr.clone();
+            let _left = left.clone().unwrap();
+            _left.borrow_mut().left = ll.clone();
+            _left.borrow_mut().right = lr.clone();
+            let _rl = rl.clone().unwrap();
+            _rl.borrow_mut().left = rll.clone();
+            _rl.borrow_mut().right = rlr.clone();
+            fix_sizes(&nd);
+            RedBlackTree{root: nd, len: 9, comparator: NaturalOrder}
+        };
         let mut vec = tree.in_order_traverse();
-        let d = tree.delete(9);
+        let d = tree.delete(&9);
         check_valid_delete(&tree, Some(9), d, &mut vec);
     }
 
     #[test]
     fn test_delete6(){
         // black + black sibling + no nephew + black parent 
-        let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
-        value: 8, parent: None, left: None, right: None};
-        let nd = Some(Rc::new(RefCell::new(nd)));
-        let (left, right) = new_children(&nd, 2, 12, "b", "b");
-        
-        let mut tree: RedBlackTree<i32> = RedBlackTree{root: nd.clone()};
-        let _nd = tree.root.clone().unwrap();
-        _nd.borrow_mut().left = left.clone();
-        _nd.borrow_mut().right = right.clone();
-
+        let mut tree: RedBlackTree<i32> = {
+            let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
+            value: 8, data: (), parent: None, left: None, right: None, size: 1, augmentation: ()};
+            let nd = Some(Shared::new(NodeCell::new(nd)));
+            let (left, right) = new_children(&nd, 2, 12, "b", "b");
+
+            let _nd = nd.clone().unwrap();
+            _nd.borrow_mut().left = left.clone();
+            _nd.borrow_mut().right = right.clone();
+            fix_sizes(&nd);
+            RedBlackTree{root: nd, len: 3, comparator: NaturalOrder}
+        };
         let mut vec = tree.in_order_traverse();
-        let d = tree.delete(2);
+        let d = tree.delete(&2);
         check_valid_delete(&tree, Some(2), d, &mut vec);
     }
     
     #[test]
     fn test_delete7(){
         // black + black sibling + close red nephew
-        let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
-        value: 8, parent: None, left: None, right: None};
-        let nd = Some(Rc::new(RefCell::new(nd)));
-        let (left, right) = new_children(&nd, 2, 12, "b", "b");
-        let (rl, rr) = new_children(&right, 10, 20, "r", "b");
-        
-        let mut tree: RedBlackTree<i32> = RedBlackTree{root: nd.clone()};
-        let _nd = tree.root.clone().unwrap();
-        _nd.borrow_mut().left = left.clone();
-        _nd.borrow_mut().right = right.clone();
-        
-        let _right = right.clone().unwrap();
-        _right.borrow_mut().left = rl.clone();
-
+        let mut tree: RedBlackTree<i32> = {
+            let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
+            value: 8, data: (), parent: None, left: None, right: None, size: 1, augmentation: ()};
+            let nd = Some(Shared::new(NodeCell::new(nd)));
+            let (left, right) = new_children(&nd, 2, 12, "b", "b");
+            let (rl, _rr) = new_children(&right, 10, 20, "r", "b");
+
+            let _nd = nd.clone().unwrap();
+            _nd.borrow_mut().left = left.clone();
+            _nd.borrow_mut().right = right.clone();
+
+            let _right = right.clone().unwrap();
+            _right.borrow_mut().left = rl.clone();
+            fix_sizes(&nd);
+            RedBlackTree{root: nd, len: 4, comparator: NaturalOrder}
+        };
         let mut vec = tree.in_order_traverse();
-        let d = tree.delete(2);
+        let d = tree.delete(&2);
         check_valid_delete(&tree, Some(2), d, &mut vec);
     }
 
     #[test]
     fn test_delete8(){
         // black + black sibling + distant red nephew
-        let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
-        value: 8, parent: None, left: None, right: None};
-        let nd = Some(Rc::new(RefCell::new(nd)));
-        let (left, right) = new_children(&nd, 2, 12, "b", "b");
-        let (rl, rr) = new_children(&right, 10, 20, "b", "r");
-        
-        let mut tree: RedBlackTree<i32> = RedBlackTree{root: nd.clone()};
-        let _nd = tree.root.clone().unwrap();
-        _nd.borrow_mut().left = left.clone();
-        _nd.borrow_mut().right = right.clone();
-        
-        let _right = right.clone().unwrap();
-        _right.borrow_mut().right = rr.clone();
-
+        let mut tree: RedBlackTree<i32> = {
+            let nd:TreeNode<i32> = TreeNode{color: NodeColor::Black,
+            value: 8, data: (), parent: None, left: None, right: None, size: 1, augmentation: ()};
+            let nd = Some(Shared::new(NodeCell::new(nd)));
+            let (left, right) = new_children(&nd, 2, 12, "b", "b");
+            let (_rl, rr) = new_children(&right, 10, 20, "b", "r");
+
+            let _nd = nd.clone().unwrap();
+            _nd.borrow_mut().left = left.clone();
+            _nd.borrow_mut().right = right.clone();
+
+            let _right = right.clone().unwrap();
+            _right.borrow_mut().right = rr.clone();
+            fix_sizes(&nd);
+            RedBlackTree{root: nd, len: 4, comparator: NaturalOrder}
+        };
         let mut vec = tree.in_order_traverse();
-        let d = tree.delete(2);
+        let d = tree.delete(&2);
         check_valid_delete(&tree, Some(2), d, &mut vec);
     }
 
+    #[test]
+    fn test_random_insert_delete(){
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut tree = RedBlackTree::new();
+        let mut expect = BTreeSet::new();
+        for _ in 0..2000{
+            let v: i32 = rng.gen_range(0, 200);
+            if rng.gen_bool(0.6){
+                assert_eq!(tree.insert(v), expect.insert(v));
+            }
+            else{
+                assert_eq!(tree.delete(&v), expect.take(&v));
+            }
+            assert!(tree.check_valid());
+            assert_eq!(tree.len(), expect.len());
+        }
+        assert_eq!(tree.in_order_traverse(), expect.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_map(){
+        let mut map = RedBlackMap::new();
+        assert!(map.is_empty());
+        assert!(map.insert(5, String::from("five")).is_none());
+        assert!(map.insert(2, String::from("two")).is_none());
+        assert_eq!(map.insert(5, String::from("FIVE")), Some(String::from("five")));
+        assert_eq!(map.len(), 2);
+        assert_eq!(*map.get(&5).unwrap(), "FIVE");
+        assert!(map.get(&7).is_none());
+        map.get_mut(&2).unwrap().push('!');
+        assert_eq!(*map.get(&2).unwrap(), "two!");
+        assert!(map.contains_key(&2));
+        assert_eq!(map.remove(&2), Some(String::from("two!")));
+        assert!(!map.contains_key(&2));
+        assert!(map.remove(&2).is_none());
+        assert_eq!(map.len(), 1);
+        assert!(map.check_valid());
+    }
+
+    #[test]
+    fn test_map_random(){
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut map = RedBlackMap::new();
+        let mut expect = BTreeMap::new();
+        for i in 0..2000{
+            let k: i32 = rng.gen_range(0, 200);
+            if rng.gen_bool(0.6){
+                assert_eq!(map.insert(k, i), expect.insert(k, i));
+            }
+            else{
+                assert_eq!(map.remove(&k), expect.remove(&k));
+            }
+            assert!(map.check_valid());
+            assert_eq!(map.len(), expect.len());
+        }
+        for (k, v) in expect.iter(){
+            assert_eq!(*map.get(k).unwrap(), *v);
+        }
+    }
+
+    #[test]
+    fn test_owned_values(){
+        let mut tree = RedBlackTree::new();
+        for word in ["pear", "apple", "fig", "kiwi", "banana", "cherry"]{
+            assert!(tree.insert(word.to_string()));
+        }
+        assert!(!tree.insert(String::from("fig")));
+        assert_eq!(*tree.get(&String::from("kiwi")).unwrap(), "kiwi");
+        assert!(tree.get(&String::from("plum")).is_none());
+        assert_eq!(tree.delete(&String::from("apple")), Some(String::from("apple")));
+        assert_eq!(tree.delete(&String::from("pear")), Some(String::from("pear")));
+        assert!(tree.delete(&String::from("apple")).is_none());
+        assert!(tree.check_valid());
+        assert_eq!(tree.in_order_traverse(), vec!["banana", "cherry", "fig", "kiwi"]);
+
+        let mut bytes: RedBlackTree<Vec<u8>> = RedBlackTree::new();
+        for i in 0..50u8{
+            bytes.insert(vec![i % 7, i]);
+        }
+        for i in 0..25u8{
+            assert_eq!(bytes.delete(&vec![i % 7, i]), Some(vec![i % 7, i]));
+        }
+        assert!(bytes.check_valid());
+        assert!(bytes.search(&vec![0, 49]));
+    }
+
+    #[test]
+    fn test_iter(){
+        let mut tree = RedBlackTree::new();
+        assert!(tree.iter().next().is_none());
+        for v in [5, 3, 8, 1, 4, 7, 9, 2, 6]{
+            tree.insert(v);
+        }
+        let forward: Vec<i32> = tree.iter().map(|v| *v).collect();
+        assert_eq!(forward, (1..10).collect::<Vec<_>>());
+        let backward: Vec<i32> = tree.iter().rev().map(|v| *v).collect();
+        assert_eq!(backward, (1..10).rev().collect::<Vec<_>>());
+
+        let mut iter = tree.iter();
+        assert_eq!(iter.len(), 9);
+        assert_eq!(*iter.next().unwrap(), 1);
+        assert_eq!(*iter.next_back().unwrap(), 9);
+        assert_eq!(iter.len(), 7);
+        let middle: Vec<i32> = iter.map(|v| *v).collect();
+        assert_eq!(middle, (2..9).collect::<Vec<_>>());
+
+        let mut sum = 0;
+        for v in &tree{
+            sum += *v;
+        }
+        assert_eq!(sum, 45);
+    }
+
+    #[test]
+    fn test_range(){
+        use std::collections::BTreeSet;
+        use std::ops::Bound;
+        let mut tree = RedBlackTree::new();
+        let empty: Vec<i32> = tree.range(..).map(|v| *v).collect();
+        assert!(empty.is_empty());
+        let mut expected = BTreeSet::new();
+        for v in (0..200).map(|i| (i * 37) % 211){
+            tree.insert(v);
+            expected.insert(v);
+        }
+        let bounds = [Bound::Unbounded, Bound::Included(-5), Bound::Included(0), Bound::Excluded(0),
+            Bound::Included(50), Bound::Excluded(50), Bound::Included(74), Bound::Excluded(150),
+            Bound::Included(210), Bound::Excluded(210), Bound::Included(300)];
+        for lo in bounds.iter(){
+            for hi in bounds.iter(){
+                let (lo, hi) = (*lo, *hi);
+                let valid = match (lo, hi){
+                    (Bound::Included(a), Bound::Included(b))=>a <= b,
+                    (Bound::Included(a), Bound::Excluded(b)) | (Bound::Excluded(a), Bound::Included(b))
+                        | (Bound::Excluded(a), Bound::Excluded(b))=>a < b,
+                    _=>true
+                };
+                let forward: Vec<i32> = tree.range((lo, hi)).map(|v| *v).collect();
+                let backward: Vec<i32> = tree.range((lo, hi)).rev().map(|v| *v).collect();
+                let want: Vec<i32> = if valid { expected.range((lo, hi)).cloned().collect() } else { Vec::new() };
+                assert_eq!(forward, want);
+                assert_eq!(backward, want.iter().rev().cloned().collect::<Vec<_>>());
+            }
+        }
+        assert_eq!(tree.range((Bound::Included(60), Bound::Excluded(40))).count(), 0);
+        assert_eq!(tree.range(3..3).count(), 0);
+
+        let mut range = tree.range(10..=20);
+        assert_eq!(*range.next().unwrap(), 10);
+        assert_eq!(*range.next_back().unwrap(), 20);
+        let middle: Vec<i32> = range.map(|v| *v).collect();
+        assert_eq!(middle, (11..20).filter(|v| expected.contains(v)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_select_rank(){
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut tree = RedBlackTree::new();
+        let mut expected = BTreeSet::new();
+        assert!(tree.select(0).is_none());
+        assert_eq!(tree.rank(&0), 0);
+        for _ in 0..1000{
+            let v = rng.gen_range(0, 300);
+            if rng.gen_bool(0.6){
+                tree.insert(v);
+                expected.insert(v);
+            }
+            else{
+                tree.delete(&v);
+                expected.remove(&v);
+            }
+        }
+        assert!(tree.check_valid());
+        assert_eq!(tree.len(), expected.len());
+        for (k, v) in expected.iter().enumerate(){
+            assert_eq!(*tree.select(k).unwrap(), *v);
+            assert_eq!(tree.rank(v), k);
+        }
+        assert!(tree.select(expected.len()).is_none());
+        for v in -1..301{
+            assert_eq!(tree.rank(&v), expected.range(..v).count());
+        }
+    }
+
+    #[test]
+    fn test_neighbors(){
+        let mut rng = StdRng::seed_from_u64(6);
+        let mut tree = RedBlackTree::new();
+        let mut expected = BTreeSet::new();
+        assert!(tree.first().is_none() && tree.last().is_none());
+        assert!(tree.floor(&0).is_none() && tree.ceiling(&0).is_none());
+        assert_eq!(tree.pop_first(), None);
+        assert_eq!(tree.pop_last(), None);
+        for _ in 0..300{
+            let v = rng.gen_range(0, 500);
+            tree.insert(v);
+            expected.insert(v);
+        }
+        for v in -1..502{
+            assert_eq!(tree.floor(&v).map(|x| *x), expected.range(..=v).next_back().cloned());
+            assert_eq!(tree.ceiling(&v).map(|x| *x), expected.range(v..).next().cloned());
+            assert_eq!(tree.predecessor(&v).map(|x| *x), expected.range(..v).next_back().cloned());
+            assert_eq!(tree.successor(&v).map(|x| *x), expected.range(v+1..).next().cloned());
+        }
+        while !expected.is_empty(){
+            assert_eq!(tree.first().map(|x| *x), expected.iter().next().cloned());
+            assert_eq!(tree.last().map(|x| *x), expected.iter().next_back().cloned());
+            if rng.gen_bool(0.5){
+                assert_eq!(tree.pop_first(), expected.pop_first());
+            }
+            else{
+                assert_eq!(tree.pop_last(), expected.pop_last());
+            }
+            assert!(tree.check_valid());
+            assert_eq!(tree.len(), expected.len());
+        }
+        assert!(tree.is_empty());
+    }
+    #[test]
+    fn test_split_append(){
+        let mut rng = StdRng::seed_from_u64(7);
+        for round in 0..40{
+            let mut tree = RedBlackTree::new();
+            let mut expected = BTreeSet::new();
+            for _ in 0..rng.gen_range(0, 300){
+                let v = rng.gen_range(0, 1000);
+                tree.insert(v);
+                expected.insert(v);
+            }
+            let key = rng.gen_range(-10, 1010);
+            let mut upper = tree.split_off(&key);
+            let expected_upper = expected.split_off(&key);
+            assert!(tree.check_valid() && upper.check_valid());
+            assert_eq!(tree.in_order_traverse(), expected.iter().cloned().collect::<Vec<_>>());
+            assert_eq!(upper.in_order_traverse(), expected_upper.iter().cloned().collect::<Vec<_>>());
+            assert_eq!(tree.len(), expected.len());
+            assert_eq!(upper.len(), expected_upper.len());
+
+            // join back, from either side
+            if round % 2 == 0{
+                tree.append(&mut upper);
+            }
+            else{
+                upper.append(&mut tree);
+                mem::swap(&mut tree, &mut upper);
+            }
+            expected.extend(expected_upper);
+            assert!(upper.is_empty());
+            assert!(tree.check_valid());
+            assert_eq!(tree.len(), expected.len());
+            assert_eq!(tree.in_order_traverse(), expected.iter().cloned().collect::<Vec<_>>());
+        }
+
+        // overlapping trees
+        let mut tree = RedBlackTree::new();
+        let mut other = RedBlackTree::new();
+        for v in 0..50{
+            tree.insert(v * 2);
+            other.insert(v * 3);
+        }
+        tree.append(&mut other);
+        let expected: BTreeSet<i32> = (0..50).map(|v| v * 2).chain((0..50).map(|v| v * 3)).collect();
+        assert!(tree.check_valid() && other.is_empty());
+        assert_eq!(tree.len(), expected.len());
+        assert_eq!(tree.in_order_traverse(), expected.into_iter().collect::<Vec<_>>());
+    }
+
+    type BuildOperation = fn(RedBlackTree<i32>, RedBlackTree<i32>)->RedBlackTree<i32>;
+
+    #[test]
+    fn test_set_operations(){
+        let mut rng = StdRng::seed_from_u64(8);
+        let sizes = [(0, 0), (0, 30), (30, 0), (1, 200), (200, 1), (150, 150), (600, 40), (3, 3)];
+        for (n, m) in sizes.iter(){
+            let build = |rng: &mut StdRng, size: usize, span: i32|{
+                let mut tree = RedBlackTree::new();
+                let mut expected = BTreeSet::new();
+                for _ in 0..size{
+                    let v = rng.gen_range(0, span);
+                    tree.insert(v);
+                    expected.insert(v);
+                }
+                (tree, expected)
+            };
+            let span = (*n + *m) as i32 + 10;
+            let (a, a_set) = build(&mut rng, *n, span);
+            let (b, b_set) = build(&mut rng, *m, span);
+
+            let collect = |iter: SetIter<i32>| iter.map(|v| *v).collect::<Vec<i32>>();
+            assert_eq!(collect(a.union(&b)), a_set.union(&b_set).cloned().collect::<Vec<_>>());
+            assert_eq!(collect(a.intersection(&b)), a_set.intersection(&b_set).cloned().collect::<Vec<_>>());
+            assert_eq!(collect(a.difference(&b)), a_set.difference(&b_set).cloned().collect::<Vec<_>>());
+            assert_eq!(collect(a.symmetric_difference(&b)),
+                a_set.symmetric_difference(&b_set).cloned().collect::<Vec<_>>());
+
+            let from_set = |set: &BTreeSet<i32>|{
+                let mut tree = RedBlackTree::new();
+                for v in set.iter(){
+                    tree.insert(*v);
+                }
+                tree
+            };
+            let operations: [(BuildOperation, Vec<i32>); 4] = [
+                (RedBlackTree::into_union, a_set.union(&b_set).cloned().collect()),
+                (RedBlackTree::into_intersection, a_set.intersection(&b_set).cloned().collect()),
+                (RedBlackTree::into_difference, a_set.difference(&b_set).cloned().collect()),
+                (RedBlackTree::into_symmetric_difference, a_set.symmetric_difference(&b_set).cloned().collect()),
+            ];
+            for (operation, expected) in operations.iter(){
+                let result = operation(from_set(&a_set), from_set(&b_set));
+                assert!(result.check_valid());
+                assert_eq!(result.len(), expected.len());
+                assert_eq!(&result.in_order_traverse(), expected);
+            }
+        }
+
+        // disjoint trees are joined directly
+        let mut low = RedBlackTree::new();
+        let mut high = RedBlackTree::new();
+        for v in 0..100{
+            low.insert(v);
+            high.insert(v + 1000);
+        }
+        let joined = high.into_union(low);
+        assert!(joined.check_valid());
+        assert_eq!(joined.len(), 200);
+        assert_eq!(*joined.select(100).unwrap(), 1000);
+    }
+
+    #[test]
+    fn test_from_sorted_iter(){
+        for n in (0..70).chain([255, 256, 1000, 1023]){
+            let tree = RedBlackTree::from_sorted_iter(0..n);
+            assert!(tree.check_valid());
+            assert_eq!(tree.len(), n as usize);
+            assert_eq!(tree.in_order_traverse(), (0..n).collect::<Vec<_>>());
+        }
+        let tree = RedBlackTree::from_sorted_iter(vec![1, 1, 2, 3, 3, 3, 7]);
+        assert!(tree.check_valid());
+        assert_eq!(tree.in_order_traverse(), vec![1, 2, 3, 7]);
+
+        let mut tree = RedBlackTree::from_sorted_iter((0..10_000).map(|v| v * 2));
+        assert!(tree.check_valid());
+        for v in 0..5_000{
+            assert_eq!(tree.delete(&(v * 4)), Some(v * 4));
+        }
+        tree.insert(3);
+        assert!(tree.check_valid());
+        assert_eq!(tree.len(), 5_001);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_from_sorted_iter_unsorted(){
+        RedBlackTree::from_sorted_iter(vec![1, 3, 2]);
+    }
+
+    #[test]
+    fn test_from_iter_extend(){
+        let mut rng = StdRng::seed_from_u64(10);
+        let values: Vec<i32> = (0..500).map(|_| rng.gen_range(0, 300)).collect();
+        let expected: BTreeSet<i32> = values.iter().cloned().collect();
+        let mut tree: RedBlackTree<i32> = values.iter().cloned().collect();
+        assert!(tree.check_valid());
+        assert_eq!(tree.len(), expected.len());
+        assert_eq!(tree.in_order_traverse(), expected.iter().cloned().collect::<Vec<_>>());
+
+        let mut expected = expected;
+        // sorted, overlapping and disjoint
+        for batch in [(250..400).collect::<Vec<i32>>(), (1000..1100).collect(), (-50..-10).collect(),
+            vec![5, 1, 999, 42, -7], Vec::new()]{
+            expected.extend(batch.iter().cloned());
+            tree.extend(batch);
+            assert!(tree.check_valid());
+            assert_eq!(tree.len(), expected.len());
+            assert_eq!(tree.in_order_traverse(), expected.iter().cloned().collect::<Vec<_>>());
+        }
+    }
+
+    #[derive(Debug, Default, Clone)]
+    struct CaseInsensitive;
+
+    impl Comparator<String> for CaseInsensitive{
+        fn compare(&self, a: &String, b: &String)->Ordering{
+            a.to_lowercase().cmp(&b.to_lowercase())
+        }
+    }
+
+    /// Has no Ord, ordered by the priority only
+    #[derive(Debug, Clone, PartialEq)]
+    struct Task{
+        priority: u32,
+        name: &'static str
+    }
+
+    type ByPriority = fn(&Task, &Task)->Ordering;
+
+    fn by_priority(a: &Task, b: &Task)->Ordering{
+        a.priority.cmp(&b.priority)
+    }
+
+    #[test]
+    fn test_comparators(){
+        // case-insensitive strings
+        let mut tree: RedBlackTree<String, CaseInsensitive> = ["banana", "Apple", "cherry"].iter().map(|s| s.to_string()).collect();
+        assert!(!tree.insert(String::from("APPLE")));
+        assert!(tree.insert(String::from("apricot")));
+        assert!(tree.check_valid());
+        assert_eq!(tree.in_order_traverse(), vec!["Apple", "apricot", "banana", "cherry"]);
+        assert!(tree.search(&String::from("BANANA")));
+        assert_eq!(*tree.ceiling(&String::from("B")).unwrap(), "banana");
+        assert_eq!(tree.rank(&String::from("CHERRY")), 3);
+        assert_eq!(tree.delete(&String::from("cHeRrY")), Some(String::from("cherry")));
+        assert_eq!(tree.len(), 3);
+
+        // reverse order with a closure
+        let mut rng = StdRng::seed_from_u64(10);
+        let mut reverse = RedBlackTree::with_comparator(|a: &i32, b: &i32| b.cmp(a));
+        let mut expected = BTreeSet::new();
+        for _ in 0..500{
+            let v = rng.gen_range(0, 300);
+            assert_eq!(reverse.insert(v), expected.insert(v));
+        }
+        for _ in 0..200{
+            let v = rng.gen_range(0, 300);
+            assert_eq!(reverse.delete(&v), expected.take(&v));
+        }
+        assert!(reverse.check_valid());
+        let descending: Vec<i32> = expected.iter().rev().cloned().collect();
+        assert_eq!(reverse.in_order_traverse(), descending);
+        assert_eq!(*reverse.first().unwrap(), *expected.last().unwrap());
+        let ranged: Vec<i32> = reverse.range((Bound::Included(200), Bound::Included(100))).map(|v| *v).collect();
+        assert_eq!(ranged, expected.range(100..=200).rev().cloned().collect::<Vec<_>>());
+        let floor = reverse.floor(&150).map(|v| *v);
+        assert_eq!(floor, expected.range(150..).next().cloned());
+        let upper = reverse.split_off(&150);
+        assert!(reverse.check_valid() && upper.check_valid());
+        assert!(reverse.iter().all(|v| *v > 150) && upper.iter().all(|v| *v <= 150));
+
+        // projected field of a struct without Ord
+        let task = |priority, name| Task{priority, name};
+        let a = RedBlackTree::from_sorted_iter_by(vec![task(1, "a"), task(3, "b"), task(5, "c")], by_priority as ByPriority);
+        let mut b = RedBlackTree::with_comparator(by_priority as ByPriority);
+        b.extend(vec![task(3, "x"), task(4, "y"), task(9, "z")]);
+        let names = |it: &mut dyn Iterator<Item = &'static str>| it.collect::<Vec<_>>();
+        assert_eq!(names(&mut a.union(&b).map(|t| t.name)), vec!["a", "b", "y", "c", "z"]);
+        assert_eq!(names(&mut a.intersection(&b).map(|t| t.name)), vec!["b"]);
+        assert_eq!(names(&mut a.difference(&b).map(|t| t.name)), vec!["a", "c"]);
+        let merged = a.into_union(b);
+        assert!(merged.check_valid());
+        assert_eq!(names(&mut merged.iter().map(|t| t.name)), vec!["a", "x", "y", "c", "z"]);
+        assert_eq!(merged.get(&task(4, "")).unwrap().name, "y");
+
+        let mut map = RedBlackMap::with_comparator(CaseInsensitive);
+        assert_eq!(map.insert(String::from("Key"), 1), None);
+        assert_eq!(map.insert(String::from("KEY"), 2), Some(1));
+        assert_eq!(*map.get(&String::from("key")).unwrap(), 2);
+        assert!(map.check_valid());
+    }
+
+    #[test]
+    fn test_multiset(){
+        let mut rng = StdRng::seed_from_u64(11);
+        let mut multiset: RedBlackMultiSet<i32> = RedBlackMultiSet::new();
+        let mut expected: BTreeMap<i32, usize> = BTreeMap::new();
+        for _ in 0..2000{
+            let v = rng.gen_range(0, 100);
+            match rng.gen_range(0, 4){
+                0=>assert_eq!(multiset.remove_one(&v), expected.contains_key(&v).then_some(v)),
+                1 if rng.gen_bool(0.2)=>assert_eq!(multiset.remove_all(&v), expected.remove(&v).unwrap_or(0)),
+                _=>{
+                    *expected.entry(v).or_insert(0) += 1;
+                    assert_eq!(multiset.insert(v), expected[&v]);
+                    continue;
+                }
+            }
+            if let Some(count) = expected.get_mut(&v){
+                *count -= 1;
+                if *count == 0{
+                    expected.remove(&v);
+                }
+            }
+        }
+        assert!(multiset.check_valid());
+        assert_eq!(multiset.distinct_len(), expected.len());
+        for v in 0..100{
+            assert_eq!(multiset.count(&v), expected.get(&v).cloned().unwrap_or(0));
+            assert_eq!(multiset.contains(&v), expected.contains_key(&v));
+        }
+        let all: Vec<i32> = expected.iter().flat_map(|(v, count)| std::iter::repeat_n(*v, *count)).collect();
+        assert_eq!(multiset.len(), all.len());
+        assert_eq!(multiset.iter().map(|v| *v).collect::<Vec<_>>(), all);
+        assert_eq!(multiset.iter().rev().map(|v| *v).collect::<Vec<_>>(), all.iter().rev().cloned().collect::<Vec<_>>());
+        // both ends meeting inside the copies of one value
+        let mut iter = multiset.iter();
+        let mut mixed = Vec::new();
+        while let Some(v) = if mixed.len() % 2 == 0 {iter.next()} else {iter.next_back()}{
+            mixed.push(*v);
+        }
+        mixed.sort();
+        assert_eq!(mixed, all);
+
+        let mut words: RedBlackMultiSet<String, CaseInsensitive> = ["b", "A", "a", "B", "a"].iter().map(|s| s.to_string()).collect();
+        assert_eq!(words.count(&String::from("A")), 3);
+        // each duplicate as it was inserted, in insertion order
+        assert_eq!(words.iter().map(|v| v.clone()).collect::<Vec<_>>(), vec!["A", "a", "a", "b", "B"]);
+        assert_eq!(words.iter().rev().map(|v| v.clone()).collect::<Vec<_>>(), vec!["B", "b", "a", "a", "A"]);
+        assert!(words.check_valid());
+        assert_eq!(words.remove_one(&String::from("b")), Some(String::from("B")));
+        assert_eq!(words.remove_one(&String::from("A")), Some(String::from("a")));
+        assert_eq!(words.iter().map(|v| v.clone()).collect::<Vec<_>>(), vec!["A", "a", "b"]);
+        assert_eq!(words.remove_all(&String::from("a")), 2);
+        assert_eq!(words.remove_one(&String::from("B")), Some(String::from("b")));
+        assert_eq!(words.remove_one(&String::from("B")), None);
+        assert!(words.is_empty());
+        assert!(words.check_valid());
+    }
+
+    /// Order-sensitive summary checking the aggregates against the values themselves
+    #[derive(Debug, PartialEq)]
+    struct Stats{
+        count: usize,
+        sum: i64,
+        min: Option<i32>,
+        values: Vec<i32>
+    }
+
+    impl Augment<i32> for Stats{
+        fn identity()->Stats{
+            Stats{count: 0, sum: 0, min: None, values: Vec::new()}
+        }
+        fn summarize(value: &i32)->Stats{
+            Stats{count: 1, sum: *value as i64, min: Some(*value), values: vec![*value]}
+        }
+        fn combine(&self, other: &Stats)->Stats{
+            let min = match (self.min, other.min){
+                (Some(a), Some(b))=>Some(a.min(b)),
+                (a, b)=>a.or(b)
+            };
+            let values = self.values.iter().chain(other.values.iter()).cloned().collect();
+            Stats{count: self.count + other.count, sum: self.sum + other.sum, min, values}
+        }
+    }
+
+    fn stats_of<'a>(values: impl Iterator<Item = &'a i32>)->Stats{
+        values.fold(Stats::identity(), |acc, v| acc.combine(&Stats::summarize(v)))
+    }
+
+    #[test]
+    fn test_aggregate(){
+        let mut rng = StdRng::seed_from_u64(13);
+        let mut tree: RedBlackTree<i32, NaturalOrder, Stats> = RedBlackTree::default();
+        let mut expected = BTreeSet::new();
+        for _ in 0..1500{
+            let v = rng.gen_range(0, 500);
+            if rng.gen_bool(0.6){
+                assert_eq!(tree.insert(v), expected.insert(v));
+            }
+            else{
+                assert_eq!(tree.delete(&v), expected.take(&v));
+            }
+        }
+        assert!(tree.check_valid());
+        let check = |tree: &RedBlackTree<i32, NaturalOrder, Stats>, expected: &BTreeSet<i32>, rng: &mut StdRng|{
+            assert_eq!(tree.aggregate(..), stats_of(expected.iter()));
+            for _ in 0..100{
+                let low = rng.gen_range(-10, 510);
+                let high = low + rng.gen_range(1, 200);
+                assert_eq!(tree.aggregate(low..high), stats_of(expected.range(low..high)));
+                assert_eq!(tree.aggregate(low..=high), stats_of(expected.range(low..=high)));
+                let bounds = (Bound::Excluded(low), Bound::Included(high));
+                assert_eq!(tree.aggregate(bounds), stats_of(expected.range(bounds)));
+                assert_eq!(tree.aggregate(low..), stats_of(expected.range(low..)));
+                assert_eq!(tree.aggregate(..high), stats_of(expected.range(..high)));
+            }
+            assert_eq!(tree.aggregate((Bound::Included(300), Bound::Excluded(100))), Stats::identity());
+        };
+        check(&tree, &expected, &mut rng);
+
+        // joins keep the summaries up to date as well
+        let mut upper = tree.split_off(&250);
+        let expected_upper = expected.split_off(&250);
+        check(&tree, &expected, &mut rng);
+        check(&upper, &expected_upper, &mut rng);
+        tree.append(&mut upper);
+        expected.extend(expected_upper);
+        check(&tree, &expected, &mut rng);
+        tree.extend(600..700);
+        expected.extend(600..700);
+        check(&tree, &expected, &mut rng);
+        let other: RedBlackTree<i32, NaturalOrder, Stats> = (0..800).step_by(3).collect();
+        let other_set: BTreeSet<i32> = (0..800).step_by(3).collect();
+        let both = tree.into_intersection(other);
+        assert!(both.check_valid());
+        check(&both, &expected.intersection(&other_set).cloned().collect(), &mut rng);
+    }
+
+    #[test]
+    #[cfg(feature = "serde")]
+    fn test_serde(){
+        let tree: RedBlackTree<i32> = (0..100).rev().collect();
+        let json = serde_json::to_string(&tree).unwrap();
+        assert_eq!(json, serde_json::to_string(&(0..100).collect::<Vec<_>>()).unwrap());
+        let back: RedBlackTree<i32> = serde_json::from_str(&json).unwrap();
+        assert!(back.check_valid());
+        assert_eq!(back.len(), 100);
+        assert_eq!(back.in_order_traverse(), tree.in_order_traverse());
+
+        let empty: RedBlackTree<i32> = serde_json::from_str("[]").unwrap();
+        assert!(empty.is_empty() && empty.check_valid());
+
+        // out of order or repeated values are rejected instead of built into a broken tree
+        let err = serde_json::from_str::<RedBlackTree<i32>>("[1, 3, 2]").err().unwrap();
+        assert!(err.to_string().contains("index 2"));
+        assert!(serde_json::from_str::<RedBlackTree<i32>>("[1, 1]").is_err());
+        assert!(serde_json::from_str::<RedBlackTree<i32>>("{}").is_err());
+
+        // the order is the one of the comparator
+        let words: RedBlackTree<String, CaseInsensitive> = serde_json::from_str(r#"["apple", "Banana", "cherry"]"#).unwrap();
+        assert!(words.check_valid());
+        assert_eq!(serde_json::to_string(&words).unwrap(), r#"["apple","Banana","cherry"]"#);
+        assert!(serde_json::from_str::<RedBlackTree<String, CaseInsensitive>>(r#"["apple", "APPLE"]"#).is_err());
+    }
+
+    #[test]
+    fn test_to_dot(){
+        let tree = RedBlackTree::<i32>::new();
+        assert_eq!(tree.to_dot(), "digraph {\n    graph [ordering=out];\n    node [fontname=\"monospace\"];\n}\n");
+
+        let mut tree = RedBlackTree::<i32>::new();
+        for x in [2, 1, 3, 4]{
+            tree.insert(x);
+        }
+        assert_eq!(tree.to_dot(), "digraph {
+    graph [ordering=out];
+    node [fontname=\"monospace\"];
+    n0 [label=\"2\", style=filled, fillcolor=black, fontcolor=white];
+    n0 -> n1;
+    n0 -> n2;
+    n2 [label=\"3\", style=filled, fillcolor=black, fontcolor=white];
+    n3 [style=invis];
+    n2 -> n3 [style=invis];
+    n2 -> n4;
+    n4 [label=\"4\", style=filled, fillcolor=red, fontcolor=black];
+    n1 [label=\"1\", style=filled, fillcolor=black, fontcolor=white];
+}
+");
+
+        let tree: RedBlackTree<i32> = (0..100).collect();
+        let dot = tree.to_dot_with(true);
+        assert_eq!(dot.matches("fillcolor=red").count() + dot.matches("fillcolor=black").count(), 100);
+        // a red black tree of n values has n + 1 NIL leaves
+        assert_eq!(dot.matches("label=\"NIL\"").count(), 101);
+        assert_eq!(dot.matches("->").count(), 200);
+
+        // values are escaped inside the quoted labels
+        let tree: RedBlackTree<String> = ["say \"hi\"".to_string()].into_iter().collect();
+        assert!(tree.to_dot().contains(r#"n0 [label="\"say \\\"hi\\\"\"", style=filled, fillcolor=black, fontcolor=white];"#));
+    }
+
+    #[test]
+    fn test_check_valid_into(){
+        let mut map = RedBlackMap::new();
+        map.insert(2, 'b');
+        map.insert(1, 'a');
+        let mut report = String::new();
+        assert!(map.check_valid_into(&mut report));
+        assert!(report.is_empty());
+        map.root.as_ref().unwrap().borrow_mut().color = NodeColor::Red;
+        assert!(!map.check_valid_into(&mut report));
+        assert_eq!(report, "Root node 2 should be black\n");
+
+        // copies only stay equal to their key while the comparator looks at the number
+        let strict = std::cell::Cell::new(false);
+        let mut multiset = RedBlackMultiSet::with_comparator(|a: &(u32, char), b: &(u32, char)|
+            if strict.get() {a.cmp(b)} else {a.0.cmp(&b.0)});
+        multiset.extend([(8, 'a'), (8, 'b')]);
+        let mut report = String::new();
+        assert!(multiset.check_valid_into(&mut report));
+        strict.set(true);
+        assert!(!multiset.check_valid_into(&mut report));
+        assert_eq!(report, "Copy (8, 'b') is kept with (8, 'a')\n");
+    }
+
+    #[test]
+    fn test_validate(){
+        assert_eq!(RedBlackTree::<i32>::new().validate(), Ok(()));
+        let tree: RedBlackTree<i32> = (0..1000).collect();
+        assert_eq!(tree.validate(), Ok(()));
+
+        // 2 and 3 black, 1 black and 4 red
+        let build = ||{
+            let mut tree = RedBlackTree::<i32>::new();
+            for x in [2, 1, 3, 4]{
+                tree.insert(x);
+            }
+            assert_eq!(tree.validate(), Ok(()));
+            tree
+        };
+        let node = |tree: &RedBlackTree<i32>, value: i32| search_node(tree.root.clone(), &value, &NaturalOrder).flatten().unwrap();
+
+        let tree = build();
+        tree.root.as_ref().unwrap().borrow_mut().color = NodeColor::Red;
+        assert_eq!(tree.validate(), Err(InvariantViolation::RedRoot(2)));
+
+        let tree = build();
+        node(&tree, 3).borrow_mut().color = NodeColor::Red;
+        assert_eq!(tree.validate(), Err(InvariantViolation::RedRed{ parent: 3, child: 4 }));
+
+        let tree = build();
+        node(&tree, 4).borrow_mut().color = NodeColor::Black;
+        let violation = tree.validate().unwrap_err();
+        assert_eq!(violation, InvariantViolation::BlackHeight{ node: 3, left: 0, right: 1 });
+        assert_eq!(violation.to_string(), "Black nodes in the paths don't agree under 3: 0 on the left, 1 on the right");
+
+        let tree = build();
+        node(&tree, 1).borrow_mut().value = 5;
+        assert_eq!(tree.validate(), Err(InvariantViolation::Order{ prev: 5, next: 2 }));
+
+        let tree = build();
+        node(&tree, 3).borrow_mut().size = 3;
+        assert_eq!(tree.validate(), Err(InvariantViolation::Size{ node: 3, stored: 3, actual: 2 }));
+
+        let tree = build();
+        node(&tree, 4).borrow_mut().parent = None;
+        assert_eq!(tree.validate(), Err(InvariantViolation::ParentLink{ node: 4, parent: Some(3) }));
+        let stranger = node(&tree, 1);
+        node(&tree, 4).borrow_mut().set_parent(Some(stranger));
+        assert_eq!(tree.validate(), Err(InvariantViolation::ParentLink{ node: 4, parent: Some(3) }));
+        let tree = build();
+        let stranger = node(&tree, 1);
+        tree.root.as_ref().unwrap().borrow_mut().set_parent(Some(stranger));
+        assert_eq!(tree.validate(), Err(InvariantViolation::ParentLink{ node: 2, parent: None }));
+        assert!(!tree.check_valid());
+    }
+
+    #[test]
+    fn test_try_insert_delete(){
+        let mut tree = RedBlackTree::<i32>::new();
+        for x in [2, 1, 3, 4]{
+            assert_eq!(tree.try_insert(x), Ok(true));
+        }
+        assert_eq!(tree.try_insert(4), Ok(false));
+        assert_eq!(tree.try_delete(&5), Ok(None));
+        assert_eq!(tree.try_delete(&4), Ok(Some(4)));
+        assert_eq!(tree.len(), 3);
+        assert_eq!(tree.validate(), Ok(()));
+
+        // 2 and 3 black, 1 black and 4 red
+        let build = ||{
+            let mut tree = RedBlackTree::<i32>::new();
+            for x in [2, 1, 3, 4]{
+                tree.insert(x);
+            }
+            tree
+        };
+        let node = |tree: &RedBlackTree<i32>, value: i32| search_node(tree.root.clone(), &value, &NaturalOrder).flatten().unwrap();
+
+        let mut tree = build();
+        node(&tree, 4).borrow_mut().color = NodeColor::Black;
+        let error = tree.try_delete(&3).unwrap_err();
+        assert_eq!(error, Error::BlackChildOfBlackNode);
+        assert_eq!(error.to_string(), "If current node is black, its unique child cannot be black");
+
+        // the black leaf 1 has lost its sibling
+        let mut tree = build();
+        tree.root.as_ref().unwrap().borrow_mut().right = None;
+        assert_eq!(tree.try_delete(&1), Err(Error::MissingChild));
+
+        let mut tree = build();
+        let held = node(&tree, 4);
+        assert_eq!(tree.try_delete(&4), Err(Error::StillReferenced));
+        assert_eq!(held.borrow().value, 4);
+        // found before anything changed
+        assert_eq!(tree.len(), 4);
+        assert_eq!(tree.validate(), Ok(()));
+        drop(held);
+        assert_eq!(tree.try_delete(&4), Ok(Some(4)));
+
+        let tree = build();
+        let root = tree.root.clone().unwrap();
+        assert_eq!(root.borrow().get_child_delete_helper().unwrap_err(), Error::TwoChildren);
+        assert_eq!(root.borrow_mut().try_delete_node().unwrap_err(), Error::TwoChildren);
+        drop(root);
+        assert_eq!(tree.validate(), Ok(()));
+
+        // the red parent of the new leaf has a red parent, found before the leaf is linked
+        let mut tree = build();
+        node(&tree, 3).borrow_mut().color = NodeColor::Red;
+        assert_eq!(tree.try_insert(5), Err(Error::RedGrandParent));
+        assert_eq!(tree.len(), 4);
+        assert!(!tree.search(&5));
+
+        // the black leaf 1 has a parent link to a dropped node
+        let mut tree = build();
+        let stranger = Shared::new(NodeCell::new(TreeNode::<i32>::new(0, ())));
+        node(&tree, 1).borrow_mut().parent = Some(Shared::downgrade(&stranger));
+        drop(stranger);
+        assert_eq!(tree.try_delete(&1), Err(Error::MissingParent));
+        assert_eq!(tree.len(), 4);
+
+        // 4 red with the red child 3 next to the black leaf 1; the tree is poisoned
+        // once the rebalancing has rotated 4 up and found 3 as the new sibling
+        let mut tree = RedBlackTree::<i32>::new();
+        for x in [2, 1, 4, 3, 5]{
+            tree.insert(x);
+        }
+        node(&tree, 4).borrow_mut().color = NodeColor::Red;
+        node(&tree, 3).borrow_mut().color = NodeColor::Red;
+        assert_eq!(tree.try_delete(&1), Err(Error::RedSibling));
+        assert!(tree.validate().is_err());
+
+        let mut dynamic: Box<dyn SimpleTreeTrait<i32>> = Box::new(build());
+        assert_eq!(dynamic.try_insert(5), Ok(true));
+        assert_eq!(dynamic.try_delete(&2), Ok(Some(2)));
+    }
+
+    #[test]
+    #[should_panic(expected = "Error! If current node is black, its unique child cannot be black!")]
+    fn test_delete_corrupted(){
+        let mut tree: RedBlackTree<i32> = [2, 1, 3, 4].into_iter().collect();
+        search_node(tree.root.clone(), &4, &NaturalOrder).flatten().unwrap().borrow_mut().color = NodeColor::Black;
+        tree.delete(&3);
+    }
+
+    #[test]
+    fn test_render(){
+        let mut tree = RedBlackTree::<i32>::new();
+        assert_eq!(tree.to_string(), "Empty tree!\n");
+        assert_eq!(format!("{:?}", tree), "Empty tree!\n");
+        for x in [2, 1, 3]{
+            tree.insert(x);
+        }
+        assert_eq!(tree.to_string(), "   2b  \n  / \\  \n 1   3 \n");
+        assert_eq!(format!("{:?}", tree), concat!("(Color: Black, Value: 2, Is Leaf: false)\n",
+            "  left: (Color: Red, Value: 1, Is Leaf: true)\n",
+            "  right: (Color: Red, Value: 3, Is Leaf: true)\n"));
+
+        let mut verbose = String::new();
+        tree.render(&mut verbose, true).unwrap();
+        assert_eq!(verbose, format!("{:?}{}", tree, tree));
+        #[cfg(feature = "std")]
+        {
+            let mut bytes = Vec::new();
+            tree.render_io(&mut bytes, true).unwrap();
+            assert_eq!(bytes, verbose.as_bytes());
+        }
+
+        // check_valid_into() tells why the tree is broken instead of printing it
+        tree.root.as_ref().unwrap().borrow_mut().color = NodeColor::Red;
+        let mut why = String::new();
+        assert!(!tree.check_valid_into(&mut why));
+        assert_eq!(why, "Root node 2 should be black\n");
+    }
+
+    #[test]
+    fn test_small_stack(){
+        // every walk down or up the tree is a loop or an explicit stack, so a 64 KiB stack is plenty
+        let worker = std::thread::Builder::new().stack_size(64 * 1024).spawn(||{
+            let mut tree = RedBlackTree::new();
+            for v in 0..100_000{
+                tree.insert(v);
+            }
+            for v in (0..100_000).step_by(2){
+                tree.delete(&v);
+            }
+            assert!(tree.check_valid());
+            assert_eq!(tree.len(), 50_000);
+            assert!(tree.search(&99_999) && !tree.search(&0));
+            assert_eq!(tree.in_order_traverse(), (1..100_000).step_by(2).collect::<Vec<_>>());
+
+            // building, splitting, joining and the set operations keep their pending work on the heap
+            let sorted = |values: &dyn Fn(&i32)->bool| (0..100_000).filter(values).collect::<Vec<i32>>();
+            let built = |values: &dyn Fn(&i32)->bool| RedBlackTree::from_sorted_iter(sorted(values));
+            let mut upper = tree.split_off(&50_001);
+            assert!(tree.check_valid() && upper.check_valid());
+            assert_eq!((tree.len(), upper.len()), (25_000, 25_000));
+            tree.append(&mut upper);
+            assert!(tree.check_valid() && upper.is_empty());
+            assert_eq!(tree.in_order_traverse(), sorted(&|v| v % 2 == 1));
+            let evens = built(&|v| v % 2 == 0);
+            assert!(evens.check_valid());
+            assert_eq!(evens.len(), 50_000);
+            let union = tree.into_union(evens);
+            assert!(union.check_valid());
+            assert_eq!(union.in_order_traverse(), sorted(&|_| true));
+            let intersection = union.into_intersection(built(&|v| v % 3 == 0));
+            assert!(intersection.check_valid());
+            assert_eq!(intersection.in_order_traverse(), sorted(&|v| v % 3 == 0));
+            let difference = built(&|v| v % 2 == 0).into_difference(intersection);
+            assert!(difference.check_valid());
+            assert_eq!(difference.in_order_traverse(), sorted(&|v| v % 2 == 0 && v % 3 != 0));
+            let symmetric = difference.into_symmetric_difference(built(&|v| v % 5 == 0));
+            assert!(symmetric.check_valid());
+            assert_eq!(symmetric.in_order_traverse(), sorted(&|v| (v % 2 == 0 && v % 3 != 0) != (v % 5 == 0)));
+
+            // a chain breaks the colors, yet it is checked, walked and dropped without recursing
+            let nodes: Vec<_> = (0..100_000).map(|v|{
+                let mut nd: TreeNode<i32> = TreeNode::new(v, ());
+                nd.color = NodeColor::Black;
+                Shared::new(NodeCell::new(nd))
+            }).collect();
+            for pair in nodes.windows(2).rev(){
+                link_children(&pair[0], None, Some(pair[1].clone()));
+            }
+            let chain = RedBlackTree{root: Some(nodes[0].clone()), len: nodes.len(), comparator: NaturalOrder};
+            drop(nodes);
+            assert!(!chain.check_valid());
+            assert_eq!(chain.height(), 100_000);
+            assert_eq!(chain.count_leaves(), 1);
+            assert!(chain.search(&99_999));
+            assert_eq!(chain.in_order_traverse(), (0..100_000).collect::<Vec<_>>());
+        }).unwrap();
+        worker.join().unwrap();
+    }
+
+    /// Value holding a token, so the live copies can be counted
+    #[derive(Debug, Clone)]
+    struct Tracked(i32, #[allow(dead_code)] std::rc::Rc<()>);
+
+    impl PartialEq for Tracked{
+        fn eq(&self, other: &Self)->bool{
+            self.0 == other.0
+        }
+    }
+
+    impl Eq for Tracked{}
+
+    impl PartialOrd for Tracked{
+        fn partial_cmp(&self, other: &Self)->Option<Ordering>{
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Tracked{
+        fn cmp(&self, other: &Self)->Ordering{
+            self.0.cmp(&other.0)
+        }
+    }
+
+    #[test]
+    fn test_no_leaks(){
+        let token = std::rc::Rc::new(());
+        let live = |token: &std::rc::Rc<()>| std::rc::Rc::strong_count(token) - 1;
+        let mut rng = StdRng::seed_from_u64(17);
+
+        // every node holds one token, a leaked node would keep it alive
+        let mut tree = RedBlackTree::new();
+        for _ in 0..5000{
+            let v = rng.gen_range(0, 500);
+            if rng.gen_bool(0.6){
+                tree.insert(Tracked(v, token.clone()));
+            }
+            else{
+                tree.delete(&Tracked(v, token.clone()));
+            }
+            assert_eq!(live(&token), tree.len());
+        }
+        assert!(tree.check_valid());
+        let mut upper = tree.split_off(&Tracked(250, token.clone()));
+        assert_eq!(live(&token), tree.len() + upper.len());
+        tree.append(&mut upper);
+        assert_eq!(live(&token), tree.len());
+        drop(tree);
+        drop(upper);
+        assert_eq!(live(&token), 0);
+
+        let mut map = RedBlackMap::new();
+        for _ in 0..5000{
+            let k: i32 = rng.gen_range(0, 500);
+            if rng.gen_bool(0.6){
+                map.insert(k, token.clone());
+            }
+            else{
+                map.remove(&k);
+            }
+            assert_eq!(live(&token), map.len());
+        }
+        drop(map);
+        assert_eq!(live(&token), 0);
+
+        // a large tree is freed without recursing down to the leaves
+        let tree: RedBlackTree<Tracked> = (0..200_000).map(|v| Tracked(v, token.clone())).collect();
+        assert_eq!(live(&token), 200_000);
+        drop(tree);
+        assert_eq!(live(&token), 0);
+    }
+
+    #[test]
+    #[cfg(feature = "sync")]
+    fn test_threads(){
+        use std::sync::{Arc, RwLock};
+        use std::thread;
+
+        fn assert_send_sync<X: Send+Sync>(){}
+        assert_send_sync::<RedBlackTree<i32>>();
+        assert_send_sync::<RedBlackMap<i32, String>>();
+        assert_send_sync::<RedBlackMultiSet<i32>>();
+
+        // a tree built on one thread can be read from several at once
+        let tree: RedBlackTree<i32> = thread::spawn(|| (0..1000).collect()).join().unwrap();
+        let tree = Arc::new(tree);
+        let readers: Vec<_> = (0..4).map(|i|{
+            let tree = Arc::clone(&tree);
+            thread::spawn(move ||{
+                for v in (i..1000).step_by(4){
+                    assert!(tree.search(&v));
+                    assert_eq!(tree.rank(&v), v as usize);
+                }
+                assert_eq!(tree.iter().count(), 1000);
+            })
+        }).collect();
+        for reader in readers{
+            reader.join().unwrap();
+        }
+
+        // one writer and several readers share the tree behind a lock
+        let tree = Arc::new(RwLock::new(RedBlackTree::new()));
+        let writer = {
+            let tree = Arc::clone(&tree);
+            thread::spawn(move ||{
+                for v in 0..2000{
+                    let mut tree = tree.write().unwrap();
+                    tree.insert(v);
+                    if v % 3 == 0{
+                        tree.delete(&(v / 2));
+                    }
+                }
+            })
+        };
+        let readers: Vec<_> = (0..4).map(|_|{
+            let tree = Arc::clone(&tree);
+            thread::spawn(move ||{
+                for _ in 0..200{
+                    let tree = tree.read().unwrap();
+                    let values: Vec<i32> = tree.iter().map(|v| *v).collect();
+                    assert_eq!(values.len(), tree.len());
+                    assert!(values.windows(2).all(|w| w[0] < w[1]));
+                    if let Some(first) = tree.first(){
+                        assert!(tree.search(&first));
+                    };
+                }
+            })
+        }).collect();
+        writer.join().unwrap();
+        for reader in readers{
+            reader.join().unwrap();
+        }
+
+        let mut expected = BTreeSet::new();
+        for v in 0..2000{
+            expected.insert(v);
+            if v % 3 == 0{
+                expected.remove(&(v / 2));
+            }
+        }
+        let tree = tree.read().unwrap();
+        assert!(tree.check_valid());
+        assert_eq!(tree.in_order_traverse(), expected.into_iter().collect::<Vec<_>>());
+    }
+
+    #[test]
+    #[cfg(feature = "sync")]
+    fn test_threads_hold_guards(){
+        use std::sync::{Arc, Barrier, RwLock};
+        use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+        use std::thread;
+        use std::time::Duration;
+
+        let tree: Arc<RwLock<RedBlackTree<i32>>> = Arc::new(RwLock::new((0..1000).collect()));
+        let holding = Arc::new(Barrier::new(5));
+        let released = Arc::new(AtomicUsize::new(0));
+        // the readers borrow the same nodes at once and keep the guards
+        let readers: Vec<_> = (0..4).map(|_|{
+            let (tree, holding, released) = (Arc::clone(&tree), Arc::clone(&holding), Arc::clone(&released));
+            thread::spawn(move ||{
+                let tree = tree.read().unwrap();
+                let first = tree.first().unwrap();
+                let middle = tree.select(500).unwrap();
+                let values: Vec<_> = tree.iter().take(10).collect();
+                holding.wait();
+                thread::sleep(Duration::from_millis(50));
+                assert_eq!((*first, *middle, *values[9]), (0, 500, 9));
+                assert!(tree.search(&middle));
+                released.fetch_add(1, SeqCst);
+            })
+        }).collect();
+        holding.wait();
+        // the writer waits on the outer lock until every reader dropped its guards
+        let mut writer = tree.write().unwrap();
+        assert_eq!(released.load(SeqCst), 4);
+        writer.delete(&0);
+        writer.insert(1000);
+        drop(writer);
+        for reader in readers{
+            reader.join().unwrap();
+        }
+        let tree = tree.read().unwrap();
+        assert_eq!(*tree.first().unwrap(), 1);
+        assert!(tree.check_valid());
+    }
+
+    #[test]
+    #[should_panic(expected = "already borrowed")]
+    fn test_borrow_conflict(){
+        // the same thread borrowing a node it holds a guard on panics instead of deadlocking
+        let tree: RedBlackTree<i32> = (0..10).collect();
+        let _first = tree.first().unwrap();
+        get_min_node(tree.root.clone().unwrap()).borrow_mut().value = 5;
+    }
 }
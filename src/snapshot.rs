@@ -0,0 +1,502 @@
+//! Binary Snapshots
+//!
+//! A compact on-disk format for RedBlackTree and AVLTree, written by `write_snapshot`
+//! and read back by `read_snapshot` without going through serde.
+//!
+//! A snapshot is laid out as
+//!
+//! | bytes    | content                                        |
+//! |----------|------------------------------------------------|
+//! | 4        | magic `BTSN`                                   |
+//! | 1        | format version, currently 1                    |
+//! | 1        | tree kind, see [`TreeKind`]                    |
+//! | varint   | number of values                               |
+//! | varint   | length of the payload in bytes                 |
+//! | payload  | the values in order, encoded by SnapshotValue  |
+//! | 4        | CRC-32 of everything before, little endian     |
+//!
+//! Values are written in order, so integers are stored as the varint of the gap to the
+//! previous value and strings as the length of the prefix shared with the previous one
+//! plus the rest. Reading checks the checksum and that the values are strictly increasing
+//! before building the tree in linear time.
+
+use core::cmp::Ordering;
+use core::fmt;
+use core::ops::Deref;
+use std::io::{Read, Write};
+
+use crate::tree::Comparator;
+
+const MAGIC: [u8; 4] = *b"BTSN";
+const VERSION: u8 = 1;
+/// Magic, version and kind
+const FIXED_HEADER_LEN: usize = 6;
+const CRC_LEN: usize = 4;
+
+/// Kind of tree stored in a snapshot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TreeKind{
+    RedBlack = 1,
+    AVL = 2
+}
+
+/// Why a snapshot couldn't be written or read
+#[derive(Debug)]
+pub enum SnapshotError{
+    /// The underlying reader or writer failed
+    Io(std::io::Error),
+    /// The data doesn't start with the snapshot magic
+    BadMagic,
+    /// The snapshot was written by an unknown version of the format
+    UnsupportedVersion(u8),
+    /// The snapshot holds another kind of tree
+    WrongKind{ expected: TreeKind, found: u8 },
+    /// The data ends before the snapshot does
+    Truncated,
+    /// The checksum doesn't match the content
+    ChecksumMismatch{ expected: u32, found: u32 },
+    /// The content is malformed although the checksum matches
+    Corrupt(&'static str),
+    /// The value at this index isn't greater than the one before
+    Unsorted(usize)
+}
+
+impl fmt::Display for SnapshotError{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        match self{
+            SnapshotError::Io(err) => write!(f, "snapshot i/o failed: {}", err),
+            SnapshotError::BadMagic => write!(f, "not a tree snapshot"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {}", version),
+            SnapshotError::WrongKind{ expected, found } => write!(f, "expected a {:?} snapshot, found kind {}", expected, found),
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::ChecksumMismatch{ expected, found } =>
+                write!(f, "snapshot checksum mismatch: expected {:#010x}, found {:#010x}", expected, found),
+            SnapshotError::Corrupt(reason) => write!(f, "snapshot is corrupt: {}", reason),
+            SnapshotError::Unsorted(index) => write!(f, "snapshot value at index {} is not greater than the one before", index)
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError{
+    fn source(&self)->Option<&(dyn std::error::Error+'static)>{
+        match self{
+            SnapshotError::Io(err) => Some(err),
+            _ => None
+        }
+    }
+}
+
+impl From<std::io::Error> for SnapshotError{
+    fn from(err: std::io::Error)->Self{
+        SnapshotError::Io(err)
+    }
+}
+
+/// Values that can be stored in a snapshot
+///
+/// Values are encoded in order and get the previous value, so they can store
+/// only what changed since then.
+pub trait SnapshotValue: Sized{
+    /// Append the value to `out`, `prev` being the value written just before it
+    fn encode(&self, prev: Option<&Self>, out: &mut Vec<u8>);
+
+    /// Read a value written by encode from the front of `input` and advance past it
+    fn decode(prev: Option<&Self>, input: &mut &[u8])->Result<Self, SnapshotError>;
+}
+
+/// Append `value` as a LEB128 varint
+pub fn write_varint(mut value: u64, out: &mut Vec<u8>){
+    while value >= 0x80{
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Read a LEB128 varint from the front of `input`
+pub fn read_varint(input: &mut &[u8])->Result<u64, SnapshotError>{
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop{
+        let (&byte, rest) = input.split_first().ok_or(SnapshotError::Truncated)?;
+        *input = rest;
+        if shift == 63 && byte > 1{
+            return Err(SnapshotError::Corrupt("varint overflows 64 bits"));
+        }
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0{
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+/// Read a varint length and check `input` holds that many bytes
+fn read_len(input: &mut &[u8])->Result<usize, SnapshotError>{
+    let len = read_varint(input)?;
+    if len > input.len() as u64{
+        return Err(SnapshotError::Truncated);
+    }
+    Ok(len as usize)
+}
+
+macro_rules! impl_snapshot_unsigned{
+    ($($ty:ty),*)=>{$(
+        impl SnapshotValue for $ty{
+            fn encode(&self, prev: Option<&Self>, out: &mut Vec<u8>){
+                let prev = prev.copied().unwrap_or(0) as u64;
+                write_varint((*self as u64).wrapping_sub(prev), out);
+            }
+
+            fn decode(prev: Option<&Self>, input: &mut &[u8])->Result<Self, SnapshotError>{
+                let prev = prev.copied().unwrap_or(0) as u64;
+                let value = prev.wrapping_add(read_varint(input)?);
+                <$ty>::try_from(value).map_err(|_| SnapshotError::Corrupt("value out of range"))
+            }
+        }
+    )*};
+}
+
+macro_rules! impl_snapshot_signed{
+    ($($ty:ty),*)=>{$(
+        impl SnapshotValue for $ty{
+            fn encode(&self, prev: Option<&Self>, out: &mut Vec<u8>){
+                let prev = prev.copied().unwrap_or(0) as i64;
+                let delta = (*self as i64).wrapping_sub(prev);
+                // zigzag, so a custom order going down still gets small gaps
+                write_varint(((delta << 1) ^ (delta >> 63)) as u64, out);
+            }
+
+            fn decode(prev: Option<&Self>, input: &mut &[u8])->Result<Self, SnapshotError>{
+                let prev = prev.copied().unwrap_or(0) as i64;
+                let zigzag = read_varint(input)?;
+                let delta = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+                <$ty>::try_from(prev.wrapping_add(delta)).map_err(|_| SnapshotError::Corrupt("value out of range"))
+            }
+        }
+    )*};
+}
+
+impl_snapshot_unsigned!(u8, u16, u32, u64, usize);
+impl_snapshot_signed!(i8, i16, i32, i64, isize);
+
+impl SnapshotValue for String{
+    fn encode(&self, prev: Option<&Self>, out: &mut Vec<u8>){
+        let prev = prev.map_or("", |prev| prev.as_str());
+        let mut shared = self.bytes().zip(prev.bytes()).take_while(|(a, b)| a == b).count();
+        while !self.is_char_boundary(shared){
+            shared -= 1;
+        }
+        write_varint(shared as u64, out);
+        write_varint((self.len() - shared) as u64, out);
+        out.extend_from_slice(&self.as_bytes()[shared..]);
+    }
+
+    fn decode(prev: Option<&Self>, input: &mut &[u8])->Result<Self, SnapshotError>{
+        let prev = prev.map_or("", |prev| prev.as_str());
+        let shared = read_varint(input)?;
+        if shared > prev.len() as u64 || !prev.is_char_boundary(shared as usize){
+            return Err(SnapshotError::Corrupt("shared prefix doesn't fit the previous string"));
+        }
+        let len = read_len(input)?;
+        let (suffix, rest) = input.split_at(len);
+        *input = rest;
+        let suffix = core::str::from_utf8(suffix).map_err(|_| SnapshotError::Corrupt("string is not valid UTF-8"))?;
+        let mut value = String::with_capacity(shared as usize + len);
+        value.push_str(&prev[..shared as usize]);
+        value.push_str(suffix);
+        Ok(value)
+    }
+}
+
+/// CRC-32 lookup table for the reflected IEEE polynomial
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256{
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8{
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// CRC-32 (IEEE) of `data`, the one of zip and PNG
+pub fn crc32(data: &[u8])->u32{
+    let mut crc = !0u32;
+    for &byte in data{
+        crc = CRC_TABLE[((crc ^ u32::from(byte)) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+/// Write the in-order `values` of a tree as a snapshot
+pub(crate) fn write_values<T, I, W>(kind: TreeKind, values: I, mut out: W)->Result<(), SnapshotError>
+    where T: SnapshotValue, I: ExactSizeIterator, I::Item: Deref<Target = T>, W: Write{
+    let len = values.len();
+    let mut payload = Vec::new();
+    let mut prev: Option<I::Item> = None;
+    for value in values{
+        value.encode(prev.as_deref(), &mut payload);
+        prev = Some(value);
+    }
+    let mut data = Vec::with_capacity(FIXED_HEADER_LEN + 20 + payload.len() + CRC_LEN);
+    data.extend_from_slice(&MAGIC);
+    data.push(VERSION);
+    data.push(kind as u8);
+    write_varint(len as u64, &mut data);
+    write_varint(payload.len() as u64, &mut data);
+    data.extend_from_slice(&payload);
+    let crc = crc32(&data);
+    data.extend_from_slice(&crc.to_le_bytes());
+    out.write_all(&data)?;
+    out.flush()?;
+    Ok(())
+}
+
+/// Read a snapshot of the given kind, checking the values are strictly increasing under `cmp`
+pub(crate) fn read_values<T, C, R>(kind: TreeKind, mut input: R, cmp: &C)->Result<Vec<T>, SnapshotError>
+    where T: SnapshotValue, C: Comparator<T>, R: Read{
+    let mut data = Vec::new();
+    input.read_to_end(&mut data)?;
+    if data.len() < FIXED_HEADER_LEN{
+        return Err(if MAGIC.starts_with(&data[..data.len().min(MAGIC.len())]) {
+            SnapshotError::Truncated
+        } else {
+            SnapshotError::BadMagic
+        });
+    }
+    if data[..MAGIC.len()] != MAGIC{
+        return Err(SnapshotError::BadMagic);
+    }
+    if data[4] != VERSION{
+        return Err(SnapshotError::UnsupportedVersion(data[4]));
+    }
+    if data[5] != kind as u8{
+        return Err(SnapshotError::WrongKind{ expected: kind, found: data[5] });
+    }
+
+    let mut header = &data[FIXED_HEADER_LEN..];
+    let len = read_varint(&mut header)?;
+    let payload_len = read_varint(&mut header)?;
+    let body_len = data.len() - header.len();
+    if payload_len.saturating_add(CRC_LEN as u64) > header.len() as u64{
+        return Err(SnapshotError::Truncated);
+    }
+    let end = body_len + payload_len as usize;
+    if data.len() > end + CRC_LEN{
+        return Err(SnapshotError::Corrupt("trailing data after the checksum"));
+    }
+    let expected = u32::from_le_bytes([data[end], data[end + 1], data[end + 2], data[end + 3]]);
+    let found = crc32(&data[..end]);
+    if expected != found{
+        return Err(SnapshotError::ChecksumMismatch{ expected, found });
+    }
+
+    let mut payload = &data[body_len..end];
+    // don't trust the count with a large allocation
+    let mut values: Vec<T> = Vec::with_capacity(len.min(payload.len() as u64) as usize);
+    for index in 0..len{
+        let value = T::decode(values.last(), &mut payload)?;
+        if let Some(last) = values.last(){
+            if cmp.compare(last, &value) != Ordering::Less{
+                return Err(SnapshotError::Unsorted(index as usize));
+            }
+        }
+        values.push(value);
+    }
+    if !payload.is_empty(){
+        return Err(SnapshotError::Corrupt("payload is longer than its values"));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod test{
+    use super::*;
+    use crate::avltree::AVLTree;
+    use crate::rbtree::RedBlackTree;
+
+    #[derive(Debug, Default, Clone)]
+    struct Descending;
+
+    impl Comparator<i64> for Descending{
+        fn compare(&self, a: &i64, b: &i64)->Ordering{
+            b.cmp(a)
+        }
+    }
+
+    fn snapshot_of<T: core::fmt::Debug+SnapshotValue+Ord+Clone>(values: &[T])->Vec<u8>{
+        let tree: RedBlackTree<T> = values.iter().cloned().collect();
+        let mut bytes = Vec::new();
+        tree.write_snapshot(&mut bytes).unwrap();
+        bytes
+    }
+
+    /// Snapshot with the given payload and a valid checksum
+    fn forge(kind: TreeKind, len: u64, payload: &[u8])->Vec<u8>{
+        let mut data = MAGIC.to_vec();
+        data.push(VERSION);
+        data.push(kind as u8);
+        write_varint(len, &mut data);
+        write_varint(payload.len() as u64, &mut data);
+        data.extend_from_slice(payload);
+        let crc = crc32(&data);
+        data.extend_from_slice(&crc.to_le_bytes());
+        data
+    }
+
+    #[test]
+    fn test_varint(){
+        for value in [0, 1, 127, 128, 300, u32::MAX as u64, u64::MAX - 1, u64::MAX]{
+            let mut bytes = Vec::new();
+            write_varint(value, &mut bytes);
+            let mut input = bytes.as_slice();
+            assert_eq!(read_varint(&mut input).unwrap(), value);
+            assert!(input.is_empty());
+        }
+        assert!(matches!(read_varint(&mut &[0x80, 0x80][..]), Err(SnapshotError::Truncated)));
+        let too_long = [0xff; 10];
+        assert!(matches!(read_varint(&mut &too_long[..]), Err(SnapshotError::Corrupt(_))));
+        // check value from the zlib docs
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn test_round_trip(){
+        let rbtree: RedBlackTree<u32> = (0..10000).map(|x| x * 3).collect();
+        let mut bytes = Vec::new();
+        rbtree.write_snapshot(&mut bytes).unwrap();
+        // small gaps take a byte each
+        assert!(bytes.len() < 10000 + 20);
+        let copy: RedBlackTree<u32> = RedBlackTree::read_snapshot(bytes.as_slice()).unwrap();
+        assert!(copy.check_valid());
+        assert_eq!(copy.in_order_traverse(), rbtree.in_order_traverse());
+
+        let avltree: AVLTree<i64> = [i64::MIN, -5, 0, 7, i64::MAX].into_iter().collect();
+        let mut bytes = Vec::new();
+        avltree.write_snapshot(&mut bytes).unwrap();
+        let copy: AVLTree<i64> = AVLTree::read_snapshot(bytes.as_slice()).unwrap();
+        assert!(copy.check_valid());
+        assert_eq!(copy.in_order_traverse(), avltree.in_order_traverse());
+
+        let bytes = snapshot_of(&[0u8, 1, 254, 255]);
+        let copy: RedBlackTree<u8> = RedBlackTree::read_snapshot(bytes.as_slice()).unwrap();
+        assert_eq!(copy.in_order_traverse(), vec![0, 1, 254, 255]);
+
+        let words: Vec<String> = ["", "apple", "applesauce", "apricot", "bänana", "bär", "zebra"].iter().map(|s| s.to_string()).collect();
+        let bytes = snapshot_of(&words);
+        let copy: RedBlackTree<String> = RedBlackTree::read_snapshot(bytes.as_slice()).unwrap();
+        assert_eq!(copy.in_order_traverse(), words);
+
+        let empty: AVLTree<u64> = AVLTree::new();
+        let mut bytes = Vec::new();
+        empty.write_snapshot(&mut bytes).unwrap();
+        let copy: AVLTree<u64> = AVLTree::read_snapshot(bytes.as_slice()).unwrap();
+        assert!(copy.is_empty() && copy.check_valid());
+
+        // the order of the comparator is kept
+        let descending: AVLTree<i64, Descending> = (-50..50).collect();
+        let mut bytes = Vec::new();
+        descending.write_snapshot(&mut bytes).unwrap();
+        let copy: AVLTree<i64, Descending> = AVLTree::read_snapshot(bytes.as_slice()).unwrap();
+        assert!(copy.check_valid());
+        assert_eq!(copy.in_order_traverse(), descending.in_order_traverse());
+        assert!(matches!(AVLTree::<i64>::read_snapshot(bytes.as_slice()), Err(SnapshotError::Unsorted(1))));
+    }
+
+    #[test]
+    fn test_bad_header(){
+        let bytes = snapshot_of(&[1u32, 2, 3]);
+        assert!(matches!(AVLTree::<u32>::read_snapshot(bytes.as_slice()),
+            Err(SnapshotError::WrongKind{ expected: TreeKind::AVL, found: 1 })));
+
+        let mut wrong = bytes.clone();
+        wrong[0] = b'X';
+        assert!(matches!(RedBlackTree::<u32>::read_snapshot(wrong.as_slice()), Err(SnapshotError::BadMagic)));
+        assert!(matches!(RedBlackTree::<u32>::read_snapshot(&b"{}"[..]), Err(SnapshotError::BadMagic)));
+
+        let mut wrong = bytes.clone();
+        wrong[4] = 2;
+        assert!(matches!(RedBlackTree::<u32>::read_snapshot(wrong.as_slice()), Err(SnapshotError::UnsupportedVersion(2))));
+    }
+
+    #[test]
+    fn test_truncated(){
+        let bytes = snapshot_of(&(0..300u32).collect::<Vec<_>>());
+        for len in 0..bytes.len(){
+            let result = RedBlackTree::<u32>::read_snapshot(&bytes[..len]);
+            assert!(matches!(result, Err(SnapshotError::Truncated)), "{} bytes: {:?}", len, result.err());
+        }
+    }
+
+    #[test]
+    fn test_corrupt(){
+        let bytes = snapshot_of(&(0..300u32).collect::<Vec<_>>());
+        // any flipped bit after the header is caught by the checksum
+        for index in 8..bytes.len(){
+            let mut corrupt = bytes.clone();
+            corrupt[index] ^= 0x10;
+            let result = RedBlackTree::<u32>::read_snapshot(corrupt.as_slice());
+            assert!(matches!(result, Err(SnapshotError::ChecksumMismatch{ .. }) | Err(SnapshotError::Truncated)),
+                "byte {}: {:?}", index, result.err());
+        }
+
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(matches!(RedBlackTree::<u32>::read_snapshot(trailing.as_slice()), Err(SnapshotError::Corrupt(_))));
+
+        // malformed content behind a valid checksum
+        let forged = forge(TreeKind::RedBlack, 2, &[1]);
+        assert!(matches!(RedBlackTree::<u32>::read_snapshot(forged.as_slice()), Err(SnapshotError::Truncated)));
+        let forged = forge(TreeKind::RedBlack, 1, &[1, 1]);
+        assert!(matches!(RedBlackTree::<u32>::read_snapshot(forged.as_slice()), Err(SnapshotError::Corrupt(_))));
+        let forged = forge(TreeKind::RedBlack, 1, &[0x80, 0x02]);
+        assert!(matches!(RedBlackTree::<u8>::read_snapshot(forged.as_slice()), Err(SnapshotError::Corrupt(_))));
+        let forged = forge(TreeKind::AVL, 1, &[0, 2, 0xff, 0xfe]);
+        assert!(matches!(AVLTree::<String>::read_snapshot(forged.as_slice()), Err(SnapshotError::Corrupt(_))));
+        let forged = forge(TreeKind::AVL, u64::MAX, &[]);
+        assert!(matches!(AVLTree::<u32>::read_snapshot(forged.as_slice()), Err(SnapshotError::Truncated)));
+    }
+
+    #[test]
+    fn test_unsorted(){
+        // 5, then a gap wrapping around to 3
+        let mut payload = Vec::new();
+        5u32.encode(None, &mut payload);
+        3u32.encode(Some(&5), &mut payload);
+        let forged = forge(TreeKind::RedBlack, 2, &payload);
+        let result = RedBlackTree::<u32>::read_snapshot(forged.as_slice());
+        assert!(matches!(result, Err(SnapshotError::Unsorted(1))));
+        assert_eq!(result.err().unwrap().to_string(), "snapshot value at index 1 is not greater than the one before");
+
+        // duplicates
+        let forged = forge(TreeKind::AVL, 3, &[1, 1, 0]);
+        assert!(matches!(AVLTree::<u32>::read_snapshot(forged.as_slice()), Err(SnapshotError::Unsorted(2))));
+    }
+
+    #[test]
+    fn test_io_error(){
+        struct Broken;
+
+        impl Write for Broken{
+            fn write(&mut self, _: &[u8])->std::io::Result<usize>{
+                Err(std::io::Error::other("disk full"))
+            }
+
+            fn flush(&mut self)->std::io::Result<()>{
+                Ok(())
+            }
+        }
+
+        let tree: AVLTree<u32> = (0..10).collect();
+        let err = tree.write_snapshot(Broken).err().unwrap();
+        assert!(matches!(err, SnapshotError::Io(_)));
+        assert!(std::error::Error::source(&err).is_some());
+    }
+}
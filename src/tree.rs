@@ -2,11 +2,37 @@
 //!
 //! Define traits for tree structs and tree node structs
 
-use std::cell::RefCell;
-use std::rc::Rc;
-use std::cmp::max;
+#[cfg(not(feature = "sync"))]
+use core::cell::{Ref, RefCell, RefMut};
+use core::cmp::{max, Ordering};
+use core::ops::{Bound, Deref, DerefMut, RangeBounds};
+use core::marker::PhantomData;
+use core::iter::Peekable;
 
-use std::fmt::{Debug, Display};
+use core::fmt;
+use core::fmt::Debug;
+use alloc::vec;
+use alloc::vec::Vec;
+use alloc::format;
+use alloc::string::String;
+
+use crate::error::Error;
+
+/// Shared pointer to the tree nodes
+///
+/// `Rc` by default and `Arc` with the `sync` feature
+#[cfg(not(feature = "sync"))]
+pub use alloc::rc::Rc as Shared;
+#[cfg(feature = "sync")]
+pub use alloc::sync::Arc as Shared;
+
+/// Non-owning pointer to the tree nodes, used for the parent links
+///
+/// Children don't keep their parents alive, so the nodes form no reference cycles
+#[cfg(not(feature = "sync"))]
+pub use alloc::rc::Weak;
+#[cfg(feature = "sync")]
+pub use alloc::sync::Weak;
 
 /// Enum of direction for binary trees
 #[derive(Clone, Debug, PartialEq)]
@@ -38,65 +64,275 @@ impl Direction{
     /// if l.is_left(){
     ///     println!("Left");
     /// }
-    fn is_left(&self)->bool{
+    pub fn is_left(&self)->bool{
         match self{
             Direction::Left=>true,
             Direction::Right=>false
         }
     }
+
+    /// Check if current node is Direction::Right
+    pub fn is_right(&self)->bool{
+        !self.is_left()
+    }
+}
+
+
+
+/// Summary of a sub-tree kept in every node, as a monoid over the values
+///
+/// `combine` must be associative with `identity` as its neutral element;
+/// summaries are combined in the order of the values. They are refreshed
+/// from the children whenever the shape below a node changes, including
+/// rotations, so range aggregates take O(log n).
+///
+/// # Example
+///
+/// ```
+/// use BinaryTrees::tree::Augment;
+/// struct Sum(u64);
+/// impl Augment<u64> for Sum{
+///     fn identity()->Sum{
+///         Sum(0)
+///     }
+///     fn summarize(value: &u64)->Sum{
+///         Sum(*value)
+///     }
+///     fn combine(&self, other: &Sum)->Sum{
+///         Sum(self.0 + other.0)
+///     }
+/// }
+/// assert_eq!(Sum::refresh(&1, Some(&Sum(2)), None).0, 3);
+/// ```
+pub trait Augment<T: ?Sized>: Sized{
+    /// Summary of no values
+    fn identity()->Self;
+    /// Summary of a single value
+    fn summarize(value: &T)->Self;
+    /// Summary of the values of `self` followed by those of `other`
+    fn combine(&self, other: &Self)->Self;
+
+    /// Summarize the sub-tree of a node holding `value` with sub-trees summarized by `left` and `right`
+    fn refresh(value: &T, left: Option<&Self>, right: Option<&Self>)->Self{
+        let mut summary = Self::summarize(value);
+        if let Some(left) = left{
+            summary = left.combine(&summary);
+        }
+        if let Some(right) = right{
+            summary = summary.combine(right);
+        }
+        summary
+    }
+}
+
+impl<T: ?Sized> Augment<T> for (){
+    fn identity(){}
+    fn summarize(_value: &T){}
+    fn combine(&self, _other: &()){}
+    fn refresh(_value: &T, _left: Option<&()>, _right: Option<&()>){}
+}
+
+/// Ordering of the values in a tree
+///
+/// Implemented by NaturalOrder for `Ord` values and by closures
+/// `Fn(&T, &T)->Ordering`, so case-insensitive strings, reverse order or
+/// ordering by a field need no wrapper types.
+///
+/// # Example
+///
+/// ```
+/// use std::cmp::Ordering;
+/// use BinaryTrees::tree::{Comparator, NaturalOrder};
+/// assert_eq!(NaturalOrder.compare(&1, &2), Ordering::Less);
+/// let reverse = |a: &i32, b: &i32| b.cmp(a);
+/// assert_eq!(reverse.compare(&1, &2), Ordering::Greater);
+/// ```
+pub trait Comparator<T: ?Sized>{
+    /// Compare two values
+    fn compare(&self, a: &T, b: &T)->Ordering;
 }
 
+/// Comparator using the `Ord` implementation of the values
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct NaturalOrder;
+
+impl<T: Ord+?Sized> Comparator<T> for NaturalOrder{
+    fn compare(&self, a: &T, b: &T)->Ordering{
+        a.cmp(b)
+    }
+}
 
+impl<T: ?Sized, F: Fn(&T, &T)->Ordering> Comparator<T> for F{
+    fn compare(&self, a: &T, b: &T)->Ordering{
+        self(a, b)
+    }
+}
 
 /// A simple and general trait for trees
 ///
 /// All the trees in the lib implements it. So can be used for dynamic dispatch.
-pub trait SimpleTreeTrait<T: Ord+Copy+Debug+Display>{
+pub trait SimpleTreeTrait<T: Debug>{
     
     fn insert(&mut self, value: T)->bool;
-    fn delete(&mut self, value: T)->Option<T>;
+    fn delete(&mut self, value: &T)->Option<T>;
+
+    /// Insert, reporting a corrupted tree as an error instead of panicking
+    fn try_insert(&mut self, value: T)->Result<bool, Error>{
+        Ok(self.insert(value))
+    }
+
+    /// Delete, reporting a corrupted tree as an error instead of panicking
+    fn try_delete(&mut self, value: &T)->Result<Option<T>, Error>{
+        Ok(self.delete(value))
+    }
     fn count_leaves(&self)->u32;
     fn is_empty(&self)->bool;
+    #[cfg(feature = "std")]
     fn print(&self, verbose: bool);
     fn height(&self)->u32;
-    fn in_order_traverse(&self)->Vec<T>;
+    fn in_order_traverse(&self)->Vec<T> where T: Clone;
 
 }
 
 /// Trait for the binary trees
 ///
 /// Should implement SimpleTreeTrait
-pub trait TreeTrait<T: Ord+Copy+Debug+Display, TreeNode: TreeNodeTrait<T>>: SimpleTreeTrait<T>{
+pub trait TreeTrait<T: Debug, TreeNode: TreeNodeTrait<T>>: SimpleTreeTrait<T>{
     
+    /// Comparator ordering the values of the tree
+    type Order: Comparator<T>;
+
     /// Get the root of the tree
-    fn root(&self)->Option<Rc<RefCell<TreeNode>>>;
+    fn root(&self)->Option<Shared<NodeCell<TreeNode>>>;
+
+    /// Get the comparator of the tree
+    fn comparator(&self)->&Self::Order;
+
+    /// Number of values in the tree
+    fn len(&self)->usize;
+
+    /// Lazy in-order iterator over the values of the tree
+    fn iter<'a>(&'a self)->Iter<'a, T, TreeNode> where T: 'a, TreeNode: 'a{
+        Iter::new(self.root(), self.len())
+    }
+
+    /// Lazy in-order iterator over the values of the tree within `range`
+    ///
+    /// Finds both ends in O(log n), then yields each value in O(1) amortized.
+    fn range<'a, R: RangeBounds<T>>(&'a self, range: R)->Range<'a, T, TreeNode> where T: 'a, TreeNode: 'a{
+        Range::new(self.root(), range, self.comparator())
+    }
+
+    /// Lazy in-order iterator over the values in either tree
+    fn union<'a>(&'a self, other: &'a Self)->SetIter<'a, T, TreeNode, Self::Order> where Self: Sized, T: 'a, TreeNode: 'a{
+        SetIter::new(self.iter(), other.iter(), self.comparator(), SetOperation::Union)
+    }
+
+    /// Lazy in-order iterator over the values in both trees
+    fn intersection<'a>(&'a self, other: &'a Self)->SetIter<'a, T, TreeNode, Self::Order>
+        where Self: Sized, T: 'a, TreeNode: 'a{
+        SetIter::new(self.iter(), other.iter(), self.comparator(), SetOperation::Intersection)
+    }
+
+    /// Lazy in-order iterator over the values not in `other`
+    fn difference<'a>(&'a self, other: &'a Self)->SetIter<'a, T, TreeNode, Self::Order>
+        where Self: Sized, T: 'a, TreeNode: 'a{
+        SetIter::new(self.iter(), other.iter(), self.comparator(), SetOperation::Difference)
+    }
+
+    /// Lazy in-order iterator over the values in exactly one of the trees
+    fn symmetric_difference<'a>(&'a self, other: &'a Self)->SetIter<'a, T, TreeNode, Self::Order>
+        where Self: Sized, T: 'a, TreeNode: 'a{
+        SetIter::new(self.iter(), other.iter(), self.comparator(), SetOperation::SymmetricDifference)
+    }
 
     /// Count number of leaves in the tree
     fn count_leaves(&self)->u32{
         if self.root().is_some(){
             return self.root().unwrap().borrow().count_leaves();
         }
-        return self.DEFAULT_LEAF_NUM();
+        self.DEFAULT_LEAF_NUM()
     }
     /// Check whether the tree is empty
     fn is_empty(&self)->bool{
         self.root().is_none()
     }
+    /// Render the information of the tree into `out`
+    ///
+    /// Render the tree structure;
+    ///
+    /// Additional verbose information of the tree if verbose is true.
+    fn render<W: fmt::Write+?Sized>(&self, out: &mut W, verbose: bool)->fmt::Result{
+        let Some(root) = self.root() else{
+            return writeln!(out, "Empty tree!");
+        };
+        let root = root.borrow();
+        if verbose{
+            root.write_tree_as_fmt(out, "  ")?;
+        }
+        root.write_structure(out)
+    }
+
+    /// Render the information of the tree into `out`, see render()
+    #[cfg(feature = "std")]
+    fn render_io<W: std::io::Write+?Sized>(&self, out: &mut W, verbose: bool)->std::io::Result<()>{
+        let mut text = String::new();
+        self.render(&mut text, verbose).expect("Error! Writing to a String failed!");
+        out.write_all(text.as_bytes())
+    }
+
     /// Print the information of the tree
     ///
     /// Print the tree structure;
     ///
     /// Additional verbose information of the tree if verbose is true. 
+    #[cfg(feature = "std")]
     fn print(&self, verbose: bool){
-        let root = self.root();
-        if root.is_none() {
-            println!("Empty tree!");
-            return;
-        }
-        if verbose{
-            root.clone().unwrap().borrow().print_tree_as_fmt("  ".to_string());
+        let _ = self.render(&mut Stdout, verbose);
+    }
+
+    /// Graphviz DOT digraph of the tree structure, without NIL leaves
+    ///
+    /// Render it with e.g. `dot -Tsvg`.
+    fn to_dot(&self)->String{
+        self.to_dot_with(false)
+    }
+
+    /// Graphviz DOT digraph of the tree structure
+    ///
+    /// Nodes are labelled as the node type sees fit, see `TreeNodeTrait::dot_info`.
+    /// With `nil_leaves` every missing child is drawn as a NIL box; otherwise an invisible
+    /// placeholder keeps a lone child on its side.
+    fn to_dot_with(&self, nil_leaves: bool)->String{
+        let mut dot = String::from("digraph {\n    graph [ordering=out];\n    node [fontname=\"monospace\"];\n");
+        let mut next_id = 1;
+        // nodes to draw with their ids, the edges to them are already drawn
+        let mut stack: Vec<_> = self.root().into_iter().map(|root| (root, 0)).collect();
+        while let Some((node, id)) = stack.pop(){
+            let nd = node.borrow();
+            dot += &format!("    n{} [{}];\n", id, nd.dot_info());
+            let (left, right) = (nd.left(), nd.right());
+            if left.is_none() && right.is_none() && !nil_leaves{
+                continue;
+            }
+            // edges in left to right order, which ordering=out keeps
+            for child in [left, right]{
+                let child_id = next_id;
+                next_id += 1;
+                match child{
+                    Some(child) => {
+                        dot += &format!("    n{} -> n{};\n", id, child_id);
+                        stack.push((child, child_id));
+                    },
+                    None if nil_leaves => dot += &format!(
+                        "    n{} [label=\"NIL\", shape=box, width=0.3, height=0.2, fontsize=8];\n    n{} -> n{};\n",
+                        child_id, id, child_id),
+                    None => dot += &format!("    n{} [style=invis];\n    n{} -> n{} [style=invis];\n", child_id, id, child_id)
+                }
+            }
         }
-        root.unwrap().borrow().print_structure();
+        dot += "}\n";
+        dot
     }
 
     /// Get height of the AVLTree
@@ -110,84 +346,174 @@ pub trait TreeTrait<T: Ord+Copy+Debug+Display, TreeNode: TreeNodeTrait<T>>: Simp
     /// In-order traverse of the tree
     ///
     /// The output will be a sorted vector
-    fn in_order_traverse(&self)->Vec<T>{
+    fn in_order_traverse(&self)->Vec<T> where T: Clone{
         let mut result = Vec::<T>::new();
-        let root = self.root();
-        if root.is_some(){
-            root.unwrap().borrow().inorder(&mut result);
+        if let Some(root) = self.root(){
+            root.borrow().inorder(&mut result);
         }
-        return result;
+        result
+    }
+
+    /// Get the `k`-th smallest value of the tree, counting from 0
+    ///
+    /// Descends by the subtree sizes in O(log n)
+    fn select<'a>(&'a self, k: usize)->Option<ValueRef<'a, T>> where T: 'a, TreeNode: 'a{
+        let node = select_node(self.root(), k)?;
+        Some(ValueRef::new(node, |nd: &TreeNode| nd.value()))
+    }
+
+    /// Count the values of the tree that are smaller than `value`
+    ///
+    /// Descends by the subtree sizes in O(log n)
+    fn rank(&self, value: &T)->usize{
+        rank_of(self.root(), value, self.comparator())
+    }
+
+    /// Get the value closest to `bound` on the side given by `direction`
+    ///
+    /// Shared by the neighbor queries below
+    fn neighbor<'a>(&'a self, bound: Bound<&T>, direction: &Direction)->Option<ValueRef<'a, T>>
+        where T: 'a, TreeNode: 'a{
+        let node = search_bound(self.root(), bound, direction, self.comparator())?;
+        Some(ValueRef::new(node, |nd: &TreeNode| nd.value()))
+    }
+
+    /// Get the greatest value not greater than `value`
+    fn floor<'a>(&'a self, value: &T)->Option<ValueRef<'a, T>> where T: 'a, TreeNode: 'a{
+        self.neighbor(Bound::Included(value), &Direction::Left)
+    }
+
+    /// Get the smallest value not smaller than `value`
+    fn ceiling<'a>(&'a self, value: &T)->Option<ValueRef<'a, T>> where T: 'a, TreeNode: 'a{
+        self.neighbor(Bound::Included(value), &Direction::Right)
+    }
+
+    /// Get the greatest value smaller than `value`
+    fn predecessor<'a>(&'a self, value: &T)->Option<ValueRef<'a, T>> where T: 'a, TreeNode: 'a{
+        self.neighbor(Bound::Excluded(value), &Direction::Left)
+    }
+
+    /// Get the smallest value greater than `value`
+    fn successor<'a>(&'a self, value: &T)->Option<ValueRef<'a, T>> where T: 'a, TreeNode: 'a{
+        self.neighbor(Bound::Excluded(value), &Direction::Right)
+    }
+
+    /// Get the minimum of the tree
+    fn first<'a>(&'a self)->Option<ValueRef<'a, T>> where T: 'a, TreeNode: 'a{
+        self.neighbor(Bound::Unbounded, &Direction::Right)
+    }
+
+    /// Get the maximum of the tree
+    fn last<'a>(&'a self)->Option<ValueRef<'a, T>> where T: 'a, TreeNode: 'a{
+        self.neighbor(Bound::Unbounded, &Direction::Left)
     }
 
     /// Search a node in the Tree
     ///
     /// ```
-    /// use BinaryTress::avltree::AVLTree;
+    /// use BinaryTrees::avltree::AVLTree;
     /// let mut avltree: AVLTree<u32> = AVLTree::new();
-    /// let is_contain = avltree.search(8);
+    /// let is_contain = avltree.search(&8);
     /// ```
-    fn search(&self, value: T)->bool{
-        search_node(self.root(), value).is_some()
+    fn search(&self, value: &T)->bool{
+        search_node(self.root(), value, self.comparator()).is_some()
     }
 
+    /// Check whether the tree is valid, writing why it isn't into `out`
+    fn check_valid_into<W: fmt::Write+?Sized>(&self, out: &mut W)->bool;
+
     /// Check whether the tree is valid
-    fn check_valid(&self)->bool;
+    ///
+    /// With the `std` feature why it isn't is printed, see check_valid_into()
+    fn check_valid(&self)->bool{
+        self.check_valid_into(&mut Stdout)
+    }
 
     // assocated constants
     /// An associated value for count_leaves
     ///
     /// 0 for default and 2 for NIL nodes
     fn DEFAULT_LEAF_NUM(&self)->u32{
-        0 as u32
+        0
     }
 
     /// An associated value for height
     ///
     /// 0 for default and 1 for NIL nodes
     fn DEFAULT_HEIGHT_NUM(&self)->u32{
-        0 as u32
+        0
     }
 }
 
+/// The only child of a node being unlinked and the side it hangs on
+type UnlinkedChild<N> = (Option<Shared<NodeCell<N>>>, Direction);
+
 /// Trait for the tree nodes
-pub trait TreeNodeTrait<T: Ord+Copy+Debug+Display>{
+pub trait TreeNodeTrait<T: Debug>{
     /// Get reference to left child
-    fn left(&self)->Option<Rc<RefCell<Self>>>;
+    fn left(&self)->Option<Shared<NodeCell<Self>>>;
     /// Get reference to right child
-    fn right(&self)->Option<Rc<RefCell<Self>>>;
+    fn right(&self)->Option<Shared<NodeCell<Self>>>;
     /// Get reference to parent
-    fn parent(&self)->Option<Rc<RefCell<Self>>>;
-    /// Get the nodes' value
-    fn value(&self)->T;
+    fn parent(&self)->Option<Shared<NodeCell<Self>>>;
+    /// Get reference to the nodes' value
+    fn value(&self)->&T;
 
     /// Set left child
-    fn set_left(&mut self, v: Option<Rc<RefCell<Self>>>);
+    fn set_left(&mut self, v: Option<Shared<NodeCell<Self>>>);
     /// Set right child
-    fn set_right(&mut self, v: Option<Rc<RefCell<Self>>>);
+    fn set_right(&mut self, v: Option<Shared<NodeCell<Self>>>);
     /// Set parent
-    fn set_parent(&mut self, v: Option<Rc<RefCell<Self>>>);
+    fn set_parent(&mut self, v: Option<Shared<NodeCell<Self>>>);
     /// Set value
     fn set_value(&mut self, v: T);
+    /// Get number of nodes in the sub-tree rooted at the node
+    fn size(&self)->usize;
+    /// Set number of nodes in the sub-tree rooted at the node
+    fn set_size(&mut self, v: usize);
 
-    /// Get the minimum of its right sub-tree
-    ///
-    /// Used for deletion
-    fn get_min(&self)->T{
-        match &self.left(){
-            None=>self.value(),
-            Some(nd)=>{
-                nd.borrow().get_min()
-            }
-        }
+    /// Get the sizes of the left and right sub-trees
+    fn get_children_size(&self)->(usize, usize){
+        let left_size = match self.left(){
+            Some(ln)=>ln.borrow().size(),
+            None=>0
+        };
+        let right_size = match self.right(){
+            Some(rn)=>rn.borrow().size(),
+            None=>0
+        };
+        (left_size, right_size)
+    }
+
+    /// Recompute the size and the augmentation from the children
+    fn update_size(&mut self){
+        let (left_size, right_size) = self.get_children_size();
+        self.set_size(left_size + right_size + 1);
+        self.update_augmentation();
     }
 
+    /// Recompute the augmentation of the node from its children, if the tree keeps one
+    fn update_augmentation(&mut self){}
+
     /// Delete a node
     ///
     /// Link its parent and children;
     /// Clear its reference
-    fn delete_node(&mut self)->Option<Option<Rc<RefCell<Self>>>>{
+    ///
+    /// # Panic
+    /// if current node has two children
+    fn delete_node(&mut self)->Option<Option<Shared<NodeCell<Self>>>>{
+        self.try_delete_node().unwrap_or_else(Error::raise)
+    }
+
+    /// Delete a node, see delete_node()
+    ///
+    /// Fails on a node with two children
+    fn try_delete_node(&mut self)->Result<Option<Option<Shared<NodeCell<Self>>>>, Error>{
         // deal nodes with 1 or 0 child
-        assert!(!(self.left().is_some()&&self.right().is_some()));
+        if self.left().is_some() && self.right().is_some(){
+            return Err(Error::TwoChildren);
+        }
 
         let child = match self.right(){
             None=>self.left(),
@@ -199,10 +525,10 @@ pub trait TreeNodeTrait<T: Ord+Copy+Debug+Display>{
         }
         let ret = match self.parent(){
             Some(parent)=>{
-                let direction = self.get_direction_to_parent();
+                let direction = self.try_get_direction_to_parent()?;
                 match direction{
-                    Direction::Left=>parent.borrow_mut().set_left(self.left()),
-                    Direction::Right=>parent.borrow_mut().set_right(self.right())
+                    Direction::Left=>parent.borrow_mut().set_left(child),
+                    Direction::Right=>parent.borrow_mut().set_right(child)
                 };
                 None
             },
@@ -211,52 +537,55 @@ pub trait TreeNodeTrait<T: Ord+Copy+Debug+Display>{
         self.set_parent(None);
         self.set_left(None);
         self.set_right(None);
-        return ret;
+        Ok(ret)
     }
 
     /// A helper function for deletion
     ///
     /// Get current node's child
     ///
-    /// Assumption: current node has only one or no child
-    ///
-    /// # Panic
-    /// if current node has two children
-    fn get_child_delete_helper(&self)->(Option<Rc<RefCell<Self>>>, Direction){
-        assert!(!(self.left().is_some()&&self.right().is_some()));
-        // one child or no child
-        if self.left().is_some(){
-            return (self.left(), Direction::Left);
-        }
-        if self.right().is_some(){
-            return (self.right(), Direction::Right);
+    /// Fails if current node has two children
+    fn get_child_delete_helper(&self)->Result<UnlinkedChild<Self>, Error>{
+        match (self.left(), self.right()){
+            (Some(_), Some(_))=>Err(Error::TwoChildren),
+            (Some(left), None)=>Ok((Some(left), Direction::Left)),
+            (None, Some(right))=>Ok((Some(right), Direction::Right)),
+            (None, None)=>Ok((None, Direction::Left))
         }
-        return (None, Direction::Left);
     }
     
     /// Get whether current node is the left child of its parent or right
     ///
+    /// Decided by node identity rather than by value, so it stays correct
+    /// while values are being swapped during deletion.
+    ///
     /// # Panic
     /// parent is None
     fn get_direction_to_parent(&self)->Direction{
-        assert!(self.parent().is_some());
-        let p = self.parent().unwrap();
+        self.try_get_direction_to_parent().unwrap_or_else(Error::raise)
+    }
+
+    /// Get whether current node is the left child of its parent or right, see get_direction_to_parent()
+    ///
+    /// Fails when there is no parent
+    fn try_get_direction_to_parent(&self)->Result<Direction, Error>{
+        let p = self.parent().ok_or(Error::MissingParent)?;
         let parent = p.borrow();
-        assert!(parent.value() != self.value());
-        if parent.value() > self.value(){
-            Direction::Left
+        let is_left = match parent.left(){
+            Some(left)=>core::ptr::eq(left.as_ptr(), self),
+            None=>false
+        };
+        if is_left{
+            Ok(Direction::Left)
         }
         else{
-            Direction::Right
+            Ok(Direction::Right)
         }
     }
 
     /// Get the sibling of current node
-    fn get_sibling(&self)->Option<Rc<RefCell<Self>>>{
-        if self.parent().is_none(){
-            return None;
-        }
-        let p = self.parent().unwrap();
+    fn get_sibling(&self)->Option<Shared<NodeCell<Self>>>{
+        let p = self.parent()?;
         let direc = self.get_direction_to_parent();
         match direc{
             Direction::Left=>p.borrow().right().clone(),
@@ -269,115 +598,172 @@ pub trait TreeNodeTrait<T: Ord+Copy+Debug+Display>{
         self.left().is_none() && self.right().is_none()
     }
     
+    /// Write the verbose information of the node and its sub-trees into `out`
+    ///
+    /// Helper of render()
+    fn write_tree_as_fmt<W: fmt::Write+?Sized>(&self, out: &mut W, ident: &str)->fmt::Result{
+        writeln!(out, "{}", self.fmt_info())?;
+
+        // sub-trees still to write with their label and indentation, the left one on top
+        let mut stack = vec![(self.right(), "right", String::from(ident)), (self.left(), "left", String::from(ident))];
+        while let Some((node, label, ident)) = stack.pop(){
+            let Some(node) = node else{
+                continue;
+            };
+            let nd = node.borrow();
+            writeln!(out, "{}{}: {}", ident, label, nd.fmt_info())?;
+            let ident = ident + "  ";
+            stack.push((nd.right(), "right", ident.clone()));
+            stack.push((nd.left(), "left", ident));
+        }
+        Ok(())
+    }
+
     /// Print the verbose information of the node
     ///
     /// Helper of print()
+    #[cfg(feature = "std")]
     fn print_tree_as_fmt(&self, ident: String){
-        println!("{}",self.fmt_info());
+        let _ = self.write_tree_as_fmt(&mut Stdout, &ident);
+    }
 
-        if self.left().is_some() {
-            print!("{}left: ", ident);
-            self.left().unwrap().borrow().print_tree_as_fmt(ident.clone()+"  ");
-        }
+    /// Helper of in_order_traverse()
+    fn inorder(&self, result: &mut Vec<T>) where T: Clone{
+        walk_in_order::<T, Self>(self.left(), &mut |nd|{
+            result.push(nd.borrow().value().clone());
+            true
+        });
+        result.push(self.value().clone());
+        walk_in_order::<T, Self>(self.right(), &mut |nd|{
+            result.push(nd.borrow().value().clone());
+            true
+        });
+    }
 
-        if self.right().is_some() {
-            print!("{}right: ", ident);
-            self.right().unwrap().borrow().print_tree_as_fmt(ident.clone()+"  ");
+    /// Helper of check_valid()
+    ///
+    /// Check the values of the sub-tree are strictly increasing
+    /// and lie between `lower` and `upper`
+    fn check_order<C: Comparator<T>>(&self, cmp: &C, lower: Option<&T>, upper: Option<&T>)->bool{
+        let value = self.value();
+        if lower.is_some_and(|l| cmp.compare(l, value) != Ordering::Less)
+            || upper.is_some_and(|u| cmp.compare(u, value) != Ordering::Greater){
+            return false;
         }
+        check_order_between::<T, Self, C>(self.left(), cmp, lower, Some(value))
+            && check_order_between::<T, Self, C>(self.right(), cmp, Some(value), upper)
     }
 
-    /// Helper of in_order_traverse()
-    fn inorder(&self, result: &mut Vec<T>) {
-        if self.left().is_some() {
-            self.left().unwrap().borrow().inorder(result);
+    /// Helper of check_valid()
+    ///
+    /// Return the size of the sub-tree if every stored size is correct
+    fn check_size(&self)->Option<usize>{
+        // every size adding up from the sizes of the children makes all of them correct, from the leaves up
+        let size_of = |nd: Option<Shared<NodeCell<Self>>>| nd.map_or(0, |nd| nd.borrow().size());
+        let adds_up = |nd: &Self| nd.size() == size_of(nd.left()) + size_of(nd.right()) + 1;
+        if !adds_up(self){
+            return None;
         }
-        result.push(self.value());
-        if self.right().is_some() {
-            self.right().unwrap().borrow().inorder(result);
+        let mut stack: Vec<_> = self.left().into_iter().chain(self.right()).collect();
+        while let Some(node) = stack.pop(){
+            let nd = node.borrow();
+            if !adds_up(&nd){
+                return None;
+            }
+            stack.extend(nd.left().into_iter().chain(nd.right()));
         }
+        Some(self.size())
     }
 
     /// Helper of height()
     fn get_height(&self)->u32{
-        let left_height = if let Some(ln)=self.left().clone(){
-            ln.borrow().get_height()
-        }
-        else{
-            0
-        };
-        let right_height = if let Some(rn)=self.right().clone(){
-            rn.borrow().get_height()
+        let mut height = 1;
+        // nodes still to visit with their depth
+        let mut stack: Vec<_> = self.left().into_iter().chain(self.right()).map(|nd| (nd, 2)).collect();
+        while let Some((node, depth)) = stack.pop(){
+            height = max(height, depth);
+            let nd = node.borrow();
+            stack.extend(nd.left().into_iter().chain(nd.right()).map(|nd| (nd, depth + 1)));
         }
-        else{
-            0
-        };
-
-        return max(left_height, right_height)+1;
+        height
     }
 
-    /// Helper to print the stucture of the tree as a tree
-    fn print_structure(&self){
+    /// Write the stucture of the tree as a tree into `out`
+    fn write_structure<W: fmt::Write+?Sized>(&self, out: &mut W)->fmt::Result{
         let height = self.get_height() as usize;
         if height < 2{
             let info = self.structure_info();
-            println!("{}", info);
-            return
+            return writeln!(out, "{}", info);
         }
 
         let array_height = height*2-1;
-        let array_width = (2 << (height-2))*3+1 as usize;
+        let array_width = (2 << (height-2))*3+1;
         let mut container_raw = vec![String::from(" "); array_width*array_height];
         let mut container_base: Vec<_> = container_raw.as_mut_slice().chunks_mut(array_width).collect();
         let container: &mut [&mut [String]] = container_base.as_mut_slice();
 
         self.print_structure_helper(0, array_width/2, container, height);
 
-        for i in 0..container.len() {
+        for row in container.iter() {
             let mut line = String::new();
             let mut j = 0;
-            let len = container[i].len();
+            let len = row.len();
             loop{
                 if j >= len{
                     break;
                 }
-                line += &container[i][j];
-                if container[i][j].chars().next().unwrap() != ' '{
-                    if container[i][j].len() > 4 {
+                line += &row[j];
+                if !row[j].starts_with(' '){
+                    if row[j].len() > 4 {
                         j += 3;
                     } 
                     else{
-                        j += container[i][j].len();
+                        j += row[j].len();
                     }
                 }
                 else{
                     j += 1;
                 }
             }
-            println!("{}",line);
+            writeln!(out, "{}", line)?;
         }
+        Ok(())
+    }
+
+    /// Helper to print the stucture of the tree as a tree
+    #[cfg(feature = "std")]
+    fn print_structure(&self){
+        let _ = self.write_structure(&mut Stdout);
     }
 
-    /// Helper to print_structure
+    /// Helper to write_structure
     fn print_structure_helper(&self, row_index: usize, column_index: usize,
         container: &mut [&mut [String]], height: usize){
-        container[row_index][column_index] = self.structure_info();
-        let curr_height = (row_index+1)/2;
+        // put a node in its cell and queue its children with their cells
+        let place = |nd: &Self, row_index: usize, column_index: usize, container: &mut [&mut [String]],
+            stack: &mut Vec<(Shared<NodeCell<Self>>, usize, usize)>|{
+            container[row_index][column_index] = nd.structure_info();
+            let curr_height = row_index.div_ceil(2);
 
-        if curr_height == height {
-            return;
-        }
-        let gap = height-curr_height-1;
+            if curr_height == height {
+                return;
+            }
+            let gap = height-curr_height-1;
 
-        if self.left().is_some(){
-            container[row_index+1][column_index-gap] = String::from("/");
-            let left_child = self.left().unwrap();
-            left_child.borrow().print_structure_helper(row_index+2, column_index-gap*2, container, height);
-        }
+            if let Some(left_child) = nd.left(){
+                container[row_index+1][column_index-gap] = String::from("/");
+                stack.push((left_child, row_index+2, column_index-gap*2));
+            }
 
-        if self.right().is_some(){
-            container[row_index+1][column_index+gap] = String::from("\\");
-            let right_child = self.right().unwrap();
-            right_child.borrow().print_structure_helper(row_index+2, column_index+gap*2, container, height);
+            if let Some(right_child) = nd.right(){
+                container[row_index+1][column_index+gap] = String::from("\\");
+                stack.push((right_child, row_index+2, column_index+gap*2));
+            }
+        };
+        let mut stack = Vec::new();
+        place(self, row_index, column_index, container, &mut stack);
+        while let Some((node, row_index, column_index)) = stack.pop(){
+            place(&node.borrow(), row_index, column_index, container, &mut stack);
         }
     }
 
@@ -387,62 +773,87 @@ pub trait TreeNodeTrait<T: Ord+Copy+Debug+Display>{
     /// Information in print_tree_as_fmt
     fn fmt_info(&self)->String;
 
+    /// Attributes of the node in to_dot, e.g. `label="5"`
+    fn dot_info(&self)->String;
+
     /// Helper of count_leaves
     fn count_leaves(&self)->u32{
         if self.is_leaf(){
             return 1;
         }
-        let left_leaves = if let Some(l)=self.left(){
-            l.borrow().count_leaves()
-        }else{ 0 };
-        let right_leaves = if let Some(r)=self.right(){
-            r.borrow().count_leaves()
-        }else{ 0 };
-        left_leaves + right_leaves
+        let mut leaves = 0;
+        let mut stack: Vec<_> = self.left().into_iter().chain(self.right()).collect();
+        while let Some(node) = stack.pop(){
+            let nd = node.borrow();
+            if nd.is_leaf(){
+                leaves += 1;
+            }
+            stack.extend(nd.left().into_iter().chain(nd.right()));
+        }
+        leaves
+    }
+}
+
+/// `fmt::Write` to stdout, or nowhere without the `std` feature
+pub(crate) struct Stdout;
+
+impl fmt::Write for Stdout{
+    fn write_str(&mut self, text: &str)->fmt::Result{
+        #[cfg(feature = "std")]
+        print!("{}", text);
+        #[cfg(not(feature = "std"))]
+        let _ = text;
+        Ok(())
     }
 }
 
+/// Escape `text` for a quoted DOT string
+pub(crate) fn dot_escape(text: &str)->String{
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
 /// Rotation between the parent and the child
 ///
-/// # Example
-/// ```
+/// Return `child`, which took the place of `parent`. Parent links are weak,
+/// so when `parent` was the root the caller has to hold on to the new root.
 ///
-/// fn left_rotate<T: Ord+Copy+Debug+Display>(root: &TreeRoot<T>){
+/// # Example
+/// ```ignore
+/// fn left_rotate<T: Debug>(root: &TreeRoot<T>)->Shared<NodeCell<TreeNode<T>>>{
 ///    let right = root.clone().unwrap().borrow().right.clone();
-///   rotate(&root, &right);
+///   return rotate(&root, &right);
 ///}
-
-///fn right_rotate<T: Ord+Copy+Debug+Display>(root: &TreeRoot<T>){
+///
+///fn right_rotate<T: Debug>(root: &TreeRoot<T>)->Shared<NodeCell<TreeNode<T>>>{
 ///   let left = root.clone().unwrap().borrow().left.clone();
-///   rotate(&root, &left);
+///   return rotate(&root, &left);
 ///}
-///fn left_left_rotate<T: Ord+Copy+Debug+Display>(root: &TreeRoot<T>){
-///   left_rotate(&root);
+///fn left_left_rotate<T: Debug>(root: &TreeRoot<T>){
+///   let top = left_rotate(&root);
 ///   root.clone().unwrap().borrow_mut().update_height();
-///   root.clone().unwrap().borrow().parent.clone().unwrap().borrow_mut().update_height();
+///   top.borrow_mut().update_height();
 ///}
-
-///fn right_right_rotate<T: Ord+Copy+Debug+Display>(root: &TreeRoot<T>){
-///   right_rotate(&root);
+///
+///fn right_right_rotate<T: Debug>(root: &TreeRoot<T>){
+///   let top = right_rotate(&root);
 ///   root.clone().unwrap().borrow_mut().update_height();
-///   root.clone().unwrap().borrow().parent.clone().unwrap().borrow_mut().update_height();
+///   top.borrow_mut().update_height();
 ///}
-
-///fn left_right_rotate<T: Ord+Copy+Debug+Display>(root: &TreeRoot<T>){
+///
+///fn left_right_rotate<T: Debug>(root: &TreeRoot<T>){
 ///   let right = root.clone().unwrap().borrow().right();
 ///   right_right_rotate(&right);
 ///   left_left_rotate(&root);
 ///}
-
-///fn right_left_rotate<T: Ord+Copy+Debug+Display>(root: &TreeRoot<T>){
+///
+///fn right_left_rotate<T: Debug>(root: &TreeRoot<T>){
 ///   let left = root.clone().unwrap().borrow().left.clone();
 ///   left_left_rotate(&left);
 ///   right_right_rotate(&root);
 ///}
 /// ```
-
-pub fn rotate<T: Ord+Copy+Debug+Display, N: TreeNodeTrait<T>>(parent: &Option<Rc<RefCell<N>>>,
-    child: &Option<Rc<RefCell<N>>>){
+pub fn rotate<T: Debug, N: TreeNodeTrait<T>>(parent: &Option<Shared<NodeCell<N>>>,
+    child: &Option<Shared<NodeCell<N>>>)->Shared<NodeCell<N>>{
     let p = parent.clone().unwrap();
     let c = child.clone().unwrap();
 
@@ -466,8 +877,8 @@ pub fn rotate<T: Ord+Copy+Debug+Display, N: TreeNodeTrait<T>>(parent: &Option<Rc
             let gc = c.borrow().right();
             p.borrow_mut().set_left(gc.clone());
             c.borrow_mut().set_right(parent.clone());
-            if gc.is_some(){
-                gc.unwrap().borrow_mut().set_parent(parent.clone());
+            if let Some(gc) = gc{
+                gc.borrow_mut().set_parent(parent.clone());
             }
         },
         Direction::Right=>{
@@ -475,84 +886,1111 @@ pub fn rotate<T: Ord+Copy+Debug+Display, N: TreeNodeTrait<T>>(parent: &Option<Rc
             let gc = c.borrow().left();
             p.borrow_mut().set_right(gc.clone());
             c.borrow_mut().set_left(parent.clone());
-            if gc.is_some(){
-                gc.unwrap().borrow_mut().set_parent(parent.clone());
+            if let Some(gc) = gc{
+                gc.borrow_mut().set_parent(parent.clone());
             }
 
         },
     }
     p.borrow_mut().set_parent(child.clone());
     c.borrow_mut().set_parent(grad.clone());
+    // parent is now below child
+    p.borrow_mut().update_size();
+    c.borrow_mut().update_size();
+    c
 }
 
-/// Helper for Tree.search()
-pub fn search_node<T: Ord+Copy+Debug+Display, N: TreeNodeTrait<T>>(root: Option<Rc<RefCell<N>>>, value: T)->
-    Option<Option<Rc<RefCell<N>>>>{
-    if root.is_none(){
-        return None;
-    }
-
-    let node = root.clone().unwrap();
-
-    // return None, None if value is not in the tree
-    let nd_val = node.borrow().value();
-    match value{
-        v if v < nd_val=>{
-            let left = node.borrow().left();
-            match left{
-                None=>{return None;}
-                Some(_)=>{
-                    let left = node.borrow().left();
-                    return search_node(left, value);
-                }
+/// Recompute the sizes from `node` up to the root of the tree
+///
+/// Used after linking or unlinking a node below `node`
+pub fn update_size_to_root<T: Debug, N: TreeNodeTrait<T>>(node: Option<Shared<NodeCell<N>>>){
+    let mut current = node;
+    while let Some(nd) = current{
+        nd.borrow_mut().update_size();
+        current = nd.borrow().parent();
+    }
+}
+
+/// Optional sub-tree of nodes `N`
+type TreeRoot<N> = Option<Shared<NodeCell<N>>>;
+
+/// Cut `node` loose from its parent and children
+///
+/// Return the detached left and right sub-trees; used by split
+pub fn detach_node<T: Debug, N: TreeNodeTrait<T>>(node: &Shared<NodeCell<N>>)->(TreeRoot<N>, TreeRoot<N>){
+    let left = node.borrow().left();
+    let right = node.borrow().right();
+    for child in [&left, &right].into_iter().flatten(){
+        child.borrow_mut().set_parent(None);
+    }
+    let mut nd = node.borrow_mut();
+    nd.set_parent(None);
+    nd.set_left(None);
+    nd.set_right(None);
+    nd.update_size();
+    (left, right)
+}
+
+/// Make `left` and `right` the children of `node` and refresh its size
+///
+/// Used by join
+pub fn link_children<T: Debug, N: TreeNodeTrait<T>>(node: &Shared<NodeCell<N>>,
+    left: Option<Shared<NodeCell<N>>>, right: Option<Shared<NodeCell<N>>>){
+    for child in [&left, &right].into_iter().flatten(){
+        child.borrow_mut().set_parent(Some(node.clone()));
+    }
+    let mut nd = node.borrow_mut();
+    nd.set_left(left);
+    nd.set_right(right);
+    nd.update_size();
+}
+
+/// Link the sorted `nodes` into a balanced tree without recursing
+///
+/// The middle node of every range becomes the root of its sub-tree, so the sizes of
+/// two siblings differ by at most one; `finish` is called on each node with its depth
+/// once its children are linked. Used by from_sorted_iter
+pub fn link_sorted<T: Debug, N: TreeNodeTrait<T>>(nodes: Vec<Shared<NodeCell<N>>>,
+    mut finish: impl FnMut(&Shared<NodeCell<N>>, usize))->Option<Shared<NodeCell<N>>>{
+    // root of the range start..end; the left part gets the smaller half
+    let middle = |start: usize, end: usize| (start < end).then(|| start + (end - start - 1) / 2);
+    // ranges with their depth and whether both halves are linked already
+    let mut stack = vec![(0, nodes.len(), 0, false)];
+    while let Some((start, end, depth, halves_linked)) = stack.pop(){
+        let Some(mid) = middle(start, end) else{
+            continue;
+        };
+        if halves_linked{
+            let root_of = |start, end| middle(start, end).map(|root| nodes[root].clone());
+            link_children(&nodes[mid], root_of(start, mid), root_of(mid + 1, end));
+            finish(&nodes[mid], depth);
+        }else{
+            stack.push((start, end, depth, true));
+            stack.push((start, mid, depth + 1, false));
+            stack.push((mid + 1, end, depth + 1, false));
+        }
+    }
+    middle(0, nodes.len()).map(|root| nodes[root].clone())
+}
+
+/// Free the nodes of a sub-tree one at a time
+///
+/// Used when dropping a tree, so a deep tree can't overflow the stack;
+/// `take_children` unlinks both children of a node. Nodes still held
+/// elsewhere, e.g. by a clone of the tree, are left as they are.
+pub fn drop_tree<N>(root: Option<Shared<NodeCell<N>>>, take_children: impl Fn(&mut N)->[Option<Shared<NodeCell<N>>>; 2]){
+    let mut stack: Vec<_> = root.into_iter().collect();
+    while let Some(node) = stack.pop(){
+        if Shared::strong_count(&node) > 1{
+            continue;
+        }
+        stack.extend(take_children(&mut node.borrow_mut()).into_iter().flatten());
+    }
+}
+
+/// Helper for Tree.select()
+///
+/// Get the node holding the `k`-th smallest value, counting from 0
+pub fn select_node<T: Debug, N: TreeNodeTrait<T>>(root: Option<Shared<NodeCell<N>>>, k: usize)->
+    Option<Shared<NodeCell<N>>>{
+    let mut k = k;
+    let mut current = root;
+    while let Some(node) = current{
+        let (left_size, _) = node.borrow().get_children_size();
+        current = match k.cmp(&left_size){
+            Ordering::Less=>node.borrow().left(),
+            Ordering::Equal=>return Some(node),
+            Ordering::Greater=>{
+                k -= left_size + 1;
+                node.borrow().right()
             }
-        },
-        v if v > nd_val =>{
-            let right = node.borrow().right();
-            match right{
-                None=>{return None;}
-                Some(_)=>{
-                    let right = node.borrow().right();
-                    return search_node(right, value);
-                }
+        };
+    }
+    None
+}
+
+/// Helper for Tree.rank()
+///
+/// Count the values smaller than `value` in the tree rooted at `root`
+pub fn rank_of<T: Debug, N: TreeNodeTrait<T>, C: Comparator<T>>(root: Option<Shared<NodeCell<N>>>, value: &T, cmp: &C)->usize{
+    let mut rank = 0;
+    let mut current = root;
+    while let Some(node) = current{
+        let (left_size, _) = node.borrow().get_children_size();
+        current = match cmp.compare(value, node.borrow().value()){
+            Ordering::Less=>node.borrow().left(),
+            Ordering::Equal=>return rank + left_size,
+            Ordering::Greater=>{
+                rank += left_size + 1;
+                node.borrow().right()
             }
-        },
-        _=>{return Some(root);}
-    };
+        };
+    }
+    rank
 }
 
-/// Helper for Tree.insert()
-pub fn search_insert_point<T: Ord+Copy+Debug+Display, N: TreeNodeTrait<T>>(root: Option<Rc<RefCell<N>>>, value: T)->
-    Option<Rc<RefCell<N>>>{
-    if root.is_none(){
-        return None;
-    }
-
-    let node = root.clone().unwrap();
-
-    // return None, None if value is in the tree
-    let nd_val = node.borrow().value();
-    match value{
-        v if v < nd_val=>{
-            let left = node.borrow().left();
-            match left{
-                None=>{return root;}
-                Some(_)=>{
-                    let left = node.borrow().left();
-                    return search_insert_point(left, value);
-                }
+/// Helper for range aggregates
+///
+/// Combine the summaries of the values within `range` in the tree rooted at `root`;
+/// `summary` gets the summary kept in a node. Descends to the node where the
+/// paths to both ends of the range split, then along each path, O(log n)
+pub fn aggregate_range<T: Debug, N: TreeNodeTrait<T>, A: Augment<T>, C: Comparator<T>, R: RangeBounds<T>>(
+    root: Option<Shared<NodeCell<N>>>, range: R, cmp: &C, summary: fn(&N)->&A)->A{
+    let (start, end) = (range.start_bound(), range.end_bound());
+    let mut current = root;
+    while let Some(node) = current{
+        let nd = node.borrow();
+        if !within_bound(nd.value(), start, &Direction::Right, cmp){
+            current = nd.right();
+        }
+        else if !within_bound(nd.value(), end, &Direction::Left, cmp){
+            current = nd.left();
+        }
+        else{
+            let lower = aggregate_from(nd.left(), start, cmp, summary);
+            let upper = aggregate_to(nd.right(), end, cmp, summary);
+            return lower.combine(&A::summarize(nd.value())).combine(&upper);
+        }
+    }
+    A::identity()
+}
+
+/// Helper for aggregate_range()
+///
+/// Combine the summaries of the values after `start` in the tree rooted at `root`
+fn aggregate_from<T: Debug, N: TreeNodeTrait<T>, A: Augment<T>, C: Comparator<T>>(root: Option<Shared<NodeCell<N>>>,
+    start: Bound<&T>, cmp: &C, summary: fn(&N)->&A)->A{
+    let mut total = A::identity();
+    let mut current = root;
+    while let Some(node) = current{
+        let nd = node.borrow();
+        if within_bound(nd.value(), start, &Direction::Right, cmp){
+            // the node and its right sub-tree come before the values found so far
+            let mut part = A::summarize(nd.value());
+            if let Some(right) = nd.right(){
+                part = part.combine(summary(&right.borrow()));
             }
-        },
-        v if v > nd_val =>{
-            let right = node.borrow().right();
-            match right{
-                None=>{return root;}
-                Some(_)=>{
-                    let right = node.borrow().right();
-                    return search_insert_point(right, value);
-                }
+            total = part.combine(&total);
+            current = nd.left();
+        }
+        else{
+            current = nd.right();
+        }
+    }
+    total
+}
+
+/// Helper for aggregate_range()
+///
+/// Combine the summaries of the values before `end` in the tree rooted at `root`
+fn aggregate_to<T: Debug, N: TreeNodeTrait<T>, A: Augment<T>, C: Comparator<T>>(root: Option<Shared<NodeCell<N>>>,
+    end: Bound<&T>, cmp: &C, summary: fn(&N)->&A)->A{
+    let mut total = A::identity();
+    let mut current = root;
+    while let Some(node) = current{
+        let nd = node.borrow();
+        if within_bound(nd.value(), end, &Direction::Left, cmp){
+            // the left sub-tree and the node come after the values found so far
+            let mut part = A::summarize(nd.value());
+            if let Some(left) = nd.left(){
+                part = summary(&left.borrow()).combine(&part);
             }
-        },
-        _=>{return None;}
-    };
+            total = total.combine(&part);
+            current = nd.right();
+        }
+        else{
+            current = nd.left();
+        }
+    }
+    total
+}
+
+/// Get the node holding the minimum of the sub-tree rooted at `root`
+///
+/// Used for deletion
+pub fn get_min_node<T: Debug, N: TreeNodeTrait<T>>(root: Shared<NodeCell<N>>)->Shared<NodeCell<N>>{
+    let mut node = root;
+    loop{
+        let left = node.borrow().left();
+        match left{
+            None=>return node,
+            Some(left)=>node = left
+        }
+    }
+}
+
+/// Get the node holding the maximum of the sub-tree rooted at `root`
+pub fn get_max_node<T: Debug, N: TreeNodeTrait<T>>(root: Shared<NodeCell<N>>)->Shared<NodeCell<N>>{
+    let mut node = root;
+    loop{
+        let right = node.borrow().right();
+        match right{
+            None=>return node,
+            Some(right)=>node = right
+        }
+    }
+}
+
+/// Get the in-order successor of a node
+///
+/// Follows the parent pointers when the node has no right child
+pub fn get_next_node<T: Debug, N: TreeNodeTrait<T>>(node: &Shared<NodeCell<N>>)->Option<Shared<NodeCell<N>>>{
+    let right = node.borrow().right();
+    if let Some(right) = right{
+        return Some(get_min_node(right));
+    }
+    let mut current = node.clone();
+    loop{
+        let parent = current.borrow().parent()?;
+        if current.borrow().get_direction_to_parent() == Direction::Left{
+            return Some(parent);
+        }
+        current = parent;
+    }
+}
+
+/// Get the in-order predecessor of a node
+///
+/// Follows the parent pointers when the node has no left child
+pub fn get_prev_node<T: Debug, N: TreeNodeTrait<T>>(node: &Shared<NodeCell<N>>)->Option<Shared<NodeCell<N>>>{
+    let left = node.borrow().left();
+    if let Some(left) = left{
+        return Some(get_max_node(left));
+    }
+    let mut current = node.clone();
+    loop{
+        let parent = current.borrow().parent()?;
+        if current.borrow().get_direction_to_parent() == Direction::Right{
+            return Some(parent);
+        }
+        current = parent;
+    }
+}
+
+/// Check whether `value` lies on the side of `bound` given by `direction`
+///
+/// An included bound also accepts a value equal to it
+fn within_bound<T, C: Comparator<T>>(value: &T, bound: Bound<&T>, direction: &Direction, cmp: &C)->bool{
+    let ordering = match bound{
+        Bound::Unbounded=>return true,
+        Bound::Included(b) | Bound::Excluded(b)=>cmp.compare(value, b)
+    };
+    match (ordering, bound, direction){
+        (Ordering::Equal, Bound::Included(_), _)=>true,
+        (Ordering::Equal, _, _)=>false,
+        (ord, _, Direction::Right)=>ord == Ordering::Greater,
+        (ord, _, Direction::Left)=>ord == Ordering::Less,
+    }
+}
+
+/// Find the node closest to `bound` on the side given by `direction`
+///
+/// Direction::Right gives the first node after the bound and
+/// Direction::Left the last node before it; an included bound also
+/// accepts a node equal to it. Descends from the root like search_node.
+pub fn search_bound<T: Debug, N: TreeNodeTrait<T>, C: Comparator<T>>(root: Option<Shared<NodeCell<N>>>, bound: Bound<&T>,
+    direction: &Direction, cmp: &C)->Option<Shared<NodeCell<N>>>{
+    let mut candidate = None;
+    let mut current = root;
+    while let Some(node) = current{
+        let on_side = within_bound(node.borrow().value(), bound, direction, cmp);
+        // a node on the requested side is a candidate, look for a closer one towards the bound
+        let go_left = on_side == direction.is_right();
+        if on_side{
+            candidate = Some(node.clone());
+        }
+        current = if go_left { node.borrow().left() } else { node.borrow().right() };
+    }
+    candidate
+}
+
+/// Check that all values of `left` are smaller than all values of `right`
+///
+/// Used to join trees directly when their values do not overlap
+pub fn precedes<T: Debug, N: TreeNodeTrait<T>, C: Comparator<T>>(left: &Option<Shared<NodeCell<N>>>,
+    right: &Option<Shared<NodeCell<N>>>, cmp: &C)->bool{
+    let last = search_bound(left.clone(), Bound::Unbounded, &Direction::Left, cmp);
+    let first = search_bound(right.clone(), Bound::Unbounded, &Direction::Right, cmp);
+    match (last, first){
+        (Some(last), Some(first))=>cmp.compare(last.borrow().value(), first.borrow().value()) == Ordering::Less,
+        _=>true
+    }
+}
+
+/// Helper for Tree.search()
+pub fn search_node<T: Debug, N: TreeNodeTrait<T>, C: Comparator<T>>(root: Option<Shared<NodeCell<N>>>, value: &T, cmp: &C)->
+    Option<Option<Shared<NodeCell<N>>>>{
+    let mut current = root;
+    // return None if value is not in the tree
+    while let Some(node) = current{
+        let ordering = cmp.compare(value, node.borrow().value());
+        current = match ordering{
+            Ordering::Less=>node.borrow().left(),
+            Ordering::Greater=>node.borrow().right(),
+            Ordering::Equal=>return Some(Some(node))
+        };
+    }
+    None
+}
+
+/// Helper for Tree.insert()
+pub fn search_insert_point<T: Debug, N: TreeNodeTrait<T>, C: Comparator<T>>(root: Option<Shared<NodeCell<N>>>, value: &T,
+    cmp: &C)->
+    Option<Shared<NodeCell<N>>>{
+    let mut current = root?;
+    // return None if value is in the tree
+    loop{
+        let ordering = cmp.compare(value, current.borrow().value());
+        let next = match ordering{
+            Ordering::Less=>current.borrow().left(),
+            Ordering::Greater=>current.borrow().right(),
+            Ordering::Equal=>return None
+        };
+        match next{
+            None=>return Some(current),
+            Some(next)=>current = next
+        }
+    }
+}
+
+/// Visit the nodes of the sub-tree rooted at `root` in order, without recursing
+///
+/// Stops as soon as `visit` returns false; return whether every node was visited
+pub fn walk_in_order<T: Debug, N: TreeNodeTrait<T>+?Sized>(root: Option<Shared<NodeCell<N>>>,
+    visit: &mut impl FnMut(&Shared<NodeCell<N>>)->bool)->bool{
+    // the nodes whose left sub-tree is being visited, innermost on top
+    let mut stack = Vec::new();
+    let mut current = root;
+    loop{
+        while let Some(node) = current{
+            current = node.borrow().left();
+            stack.push(node);
+        }
+        let Some(node) = stack.pop() else{
+            return true;
+        };
+        if !visit(&node){
+            return false;
+        }
+        current = node.borrow().right();
+    }
+}
+
+/// Fold the sub-tree rooted at `root` from the leaves up
+///
+/// `visit` gets each node with what it returned for the left and right children,
+/// None for missing ones. Stops at the first error.
+pub(crate) fn fold_post_order<T: Debug, N: TreeNodeTrait<T>, R, E>(root: Option<Shared<NodeCell<N>>>,
+    visit: &mut impl FnMut(&N, Option<R>, Option<R>)->Result<R, E>)->Result<Option<R>, E>{
+    // nodes to expand, or to visit once both children are folded
+    let mut stack = vec![(root, false)];
+    let mut results = Vec::new();
+    while let Some((node, expanded)) = stack.pop(){
+        let Some(node) = node else{
+            results.push(None);
+            continue;
+        };
+        if expanded{
+            let right = results.pop().expect("Error! Right child wasn't folded!");
+            let left = results.pop().expect("Error! Left child wasn't folded!");
+            results.push(Some(visit(&node.borrow(), left, right)?));
+        }
+        else{
+            let (left, right) = (node.borrow().left(), node.borrow().right());
+            stack.push((Some(node), true));
+            stack.push((right, false));
+            stack.push((left, false));
+        }
+    }
+    Ok(results.pop().flatten())
+}
+
+/// Check the parent links and the order of the tree rooted at `root`
+///
+/// The part of validate() shared by the trees; `capture` turns the values involved into the report
+pub(crate) fn validate_links_and_order<T: Debug, N: TreeNodeTrait<T>, C: Comparator<T>, U>(root: Option<Shared<NodeCell<N>>>,
+    cmp: &C, capture: &impl Fn(&T)->U)->Result<(), InvariantViolation<U>>{
+    let Some(root) = root else{
+        return Ok(());
+    };
+    if root.borrow().parent().is_some(){
+        return Err(InvariantViolation::ParentLink{ node: capture(root.borrow().value()), parent: None });
+    }
+    let mut stack = vec![root.clone()];
+    while let Some(node) = stack.pop(){
+        let nd = node.borrow();
+        for child in nd.left().into_iter().chain(nd.right()){
+            if !child.borrow().parent().is_some_and(|parent| Shared::ptr_eq(&parent, &node)){
+                return Err(InvariantViolation::ParentLink{ node: capture(child.borrow().value()), parent: Some(capture(nd.value())) });
+            }
+            stack.push(child);
+        }
+    }
+
+    let mut prev: Option<Shared<NodeCell<N>>> = None;
+    let mut violation = None;
+    walk_in_order::<T, N>(Some(root), &mut |node|{
+        if let Some(p) = &prev{
+            if cmp.compare(p.borrow().value(), node.borrow().value()) != Ordering::Less{
+                violation = Some(InvariantViolation::Order{ prev: capture(p.borrow().value()), next: capture(node.borrow().value()) });
+                return false;
+            }
+        }
+        prev = Some(node.clone());
+        true
+    });
+    violation.map_or(Ok(()), Err)
+}
+
+/// Debug text of a value, standing in for it in the reports of check_valid()
+pub(crate) struct DebugText(String);
+
+impl Debug for DebugText{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        f.write_str(&self.0)
+    }
+}
+
+/// Capture a value by its Debug text, see DebugText
+pub(crate) fn debug_text<T: Debug>(value: &T)->DebugText{
+    DebugText(format!("{:?}", value))
+}
+
+/// Helper of TreeNodeTrait::check_order()
+///
+/// Check the values of the sub-tree rooted at `root` are strictly increasing
+/// and lie between `lower` and `upper`
+fn check_order_between<T: Debug, N: TreeNodeTrait<T>+?Sized, C: Comparator<T>>(root: Option<Shared<NodeCell<N>>>, cmp: &C,
+    lower: Option<&T>, upper: Option<&T>)->bool{
+    let mut prev: Option<Shared<NodeCell<N>>> = None;
+    let increasing = walk_in_order::<T, N>(root, &mut |node|{
+        let above = match &prev{
+            Some(p)=>cmp.compare(p.borrow().value(), node.borrow().value()) == Ordering::Less,
+            None=>lower.is_none_or(|l| cmp.compare(l, node.borrow().value()) == Ordering::Less)
+        };
+        prev = Some(node.clone());
+        above
+    });
+    increasing && match prev{
+        Some(last)=>upper.is_none_or(|u| cmp.compare(u, last.borrow().value()) == Ordering::Greater),
+        None=>true
+    }
+}
+
+
+/// Serialize the values of a tree in order as a sequence
+#[cfg(feature = "serde")]
+pub(crate) fn serialize_sorted<'a, T, N, S>(iter: Iter<'a, T, N>, serializer: S)->Result<S::Ok, S::Error>
+    where T: Debug+serde::Serialize+'a, N: TreeNodeTrait<T>+'a, S: serde::Serializer{
+    use serde::ser::SerializeSeq;
+    let mut seq = serializer.serialize_seq(Some(iter.len()))?;
+    for value in iter{
+        seq.serialize_element(&*value)?;
+    }
+    seq.end()
+}
+
+/// Deserialize a sequence of values, which must be strictly increasing under `cmp`
+///
+/// The values can then be built into a tree in linear time
+#[cfg(feature = "serde")]
+pub(crate) fn deserialize_sorted<'de, T, C, D>(deserializer: D, cmp: &C)->Result<Vec<T>, D::Error>
+    where T: serde::Deserialize<'de>, C: Comparator<T>, D: serde::Deserializer<'de>{
+    struct SortedVisitor<'c, T, C>{
+        cmp: &'c C,
+        _marker: PhantomData<T>
+    }
+
+    impl<'de, T: serde::Deserialize<'de>, C: Comparator<T>> serde::de::Visitor<'de> for SortedVisitor<'_, T, C>{
+        type Value = Vec<T>;
+
+        fn expecting(&self, f: &mut core::fmt::Formatter<'_>)->core::fmt::Result{
+            f.write_str("a strictly increasing sequence of values")
+        }
+
+        fn visit_seq<S: serde::de::SeqAccess<'de>>(self, mut seq: S)->Result<Vec<T>, S::Error>{
+            // don't trust the hint with a large allocation
+            let mut values = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+            while let Some(value) = seq.next_element::<T>()?{
+                if let Some(last) = values.last(){
+                    if self.cmp.compare(last, &value) != Ordering::Less{
+                        return Err(serde::de::Error::custom(format_args!(
+                            "value at index {} is not greater than the one before", values.len())));
+                    }
+                }
+                values.push(value);
+            }
+            Ok(values)
+        }
+    }
+
+    deserializer.deserialize_seq(SortedVisitor{cmp, _marker: PhantomData})
+}
+
+/// Broken invariant of a tree, found by validate()
+///
+/// Nodes are named by their values.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InvariantViolation<T>{
+    /// The parent link of `node` doesn't lead to `parent`, or the root has a parent when `parent` is None
+    ParentLink{ node: T, parent: Option<T> },
+    /// `next` comes right after `prev` in order but isn't greater
+    Order{ prev: T, next: T },
+    /// The stored sub-tree size of `node` is out of date
+    Size{ node: T, stored: usize, actual: usize },
+    /// The root of a red black tree is red
+    RedRoot(T),
+    /// A red node has a red child
+    RedRed{ parent: T, child: T },
+    /// The paths down the left and right of `node` pass different numbers of black nodes
+    BlackHeight{ node: T, left: usize, right: usize },
+    /// The stored height of an AVL node is out of date
+    StaleHeight{ node: T, stored: u32, actual: u32 },
+    /// The sub-trees of an AVL node differ in height by more than one
+    Unbalanced{ node: T, left: u32, right: u32 }
+}
+
+impl<T: Debug> fmt::Display for InvariantViolation<T>{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>)->fmt::Result{
+        match self{
+            InvariantViolation::ParentLink{ node, parent: None } => write!(f, "Root {:?} has a parent", node),
+            InvariantViolation::ParentLink{ node, parent: Some(parent) } =>
+                write!(f, "Parent link of {:?} doesn't lead to its parent {:?}", node, parent),
+            InvariantViolation::Order{ prev, next } => write!(f, "Order error: {:?} comes after {:?}", next, prev),
+            InvariantViolation::Size{ node, stored, actual } =>
+                write!(f, "Sub-tree size of {:?} is {} instead of {}", node, stored, actual),
+            InvariantViolation::RedRoot(root) => write!(f, "Root node {:?} should be black", root),
+            InvariantViolation::RedRed{ parent, child } => write!(f, "Red node {:?} has a red child {:?}", parent, child),
+            InvariantViolation::BlackHeight{ node, left, right } =>
+                write!(f, "Black nodes in the paths don't agree under {:?}: {} on the left, {} on the right", node, left, right),
+            InvariantViolation::StaleHeight{ node, stored, actual } =>
+                write!(f, "Height of {:?} is {} instead of {}", node, stored, actual),
+            InvariantViolation::Unbalanced{ node, left, right } =>
+                write!(f, "{:?} is unbalanced: height {} on the left, {} on the right", node, left, right)
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Debug> std::error::Error for InvariantViolation<T>{}
+
+/// Interior mutability of the tree nodes
+///
+/// A `RefCell` by default. With the `sync` feature it is a lock instead, see `sync_cell`
+#[cfg(not(feature = "sync"))]
+pub type NodeCell<N> = RefCell<N>;
+
+/// Shared borrow of a node, or of a part of it
+#[cfg(not(feature = "sync"))]
+pub type NodeRef<'a, N> = Ref<'a, N>;
+
+/// Exclusive borrow of a node, or of a part of it
+#[cfg(not(feature = "sync"))]
+pub type NodeRefMut<'a, N> = RefMut<'a, N>;
+
+#[cfg(feature = "sync")]
+pub use self::sync_cell::{NodeCell, NodeRef, NodeRefMut};
+
+/// Node cells that can be shared between threads
+#[cfg(feature = "sync")]
+mod sync_cell{
+    use core::cell::UnsafeCell;
+    use core::fmt::Debug;
+    use core::ops::{Deref, DerefMut};
+    use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+    /// Lock around a tree node
+    ///
+    /// A `RefCell` that can be shared between threads: readers share the node and a writer
+    /// borrows it exclusively. Trees are therefore `Send` and `Sync` whenever their values are.
+    ///
+    /// The lock never waits. Every tree only mutates its nodes through `&mut self`, so the
+    /// borrow checker already keeps writers apart from each other and from readers on other
+    /// threads; many readers of a `&Tree` share the read locks. A borrow that conflicts can
+    /// therefore only come from the same thread and panics, as `RefCell` does, instead of
+    /// deadlocking. For a single writer alongside readers, put the whole tree behind an
+    /// `Arc<RwLock<_>>`: the writer then waits for the readers on that outer lock.
+    pub struct NodeCell<N: ?Sized>{
+        lock: RwLock<()>,
+        node: UnsafeCell<N>
+    }
+
+    // SAFETY: the cell owns its node, so sending the cell sends the node, which needs
+    // `N: Send`. Guards borrow the cell, so none of them exists while it is moved.
+    unsafe impl<N: ?Sized+Send> Send for NodeCell<N>{}
+    // SAFETY: a `&NodeCell` gives `&N` to several threads at once through read guards,
+    // which needs `N: Sync`, and `&mut N` to one thread through the write guard, which
+    // moves the use of the node to that thread and needs `N: Send`; the same bounds as
+    // `RwLock<N>`. `lock` makes the read and write guards mutually exclusive.
+    unsafe impl<N: ?Sized+Send+Sync> Sync for NodeCell<N>{}
+
+    /// Shared borrow of a node, or of a part of it
+    pub struct NodeRef<'a, N: ?Sized>{
+        node: &'a N,
+        lock: RwLockReadGuard<'a, ()>
+    }
+
+    /// Exclusive borrow of a node, or of a part of it
+    pub struct NodeRefMut<'a, N: ?Sized>{
+        node: &'a mut N,
+        lock: RwLockWriteGuard<'a, ()>
+    }
+
+    impl<N> NodeCell<N>{
+        /// Wrap a node
+        pub fn new(node: N)->Self{
+            NodeCell{lock: RwLock::new(()), node: UnsafeCell::new(node)}
+        }
+
+        /// Unwrap the node
+        pub fn into_inner(self)->N{
+            self.node.into_inner()
+        }
+    }
+
+    impl<N: ?Sized> NodeCell<N>{
+        /// Borrow the node
+        ///
+        /// # Panic
+        /// The node is mutably borrowed
+        pub fn borrow(&self)->NodeRef<'_, N>{
+            let lock = match self.lock.try_read(){
+                Ok(lock)=>lock,
+                // a panic while the node was borrowed leaves no broken lock state behind
+                Err(TryLockError::Poisoned(poisoned))=>poisoned.into_inner(),
+                Err(TryLockError::WouldBlock)=>panic!("Error! Node already mutably borrowed!")
+            };
+            // SAFETY: `lock` holds the read lock for as long as the returned guard lives, so
+            // no write guard, the only source of `&mut N`, exists meanwhile. The reference
+            // cannot outlive `self`, which owns the node.
+            NodeRef{node: unsafe{ &*self.node.get() }, lock}
+        }
+
+        /// Mutably borrow the node
+        ///
+        /// # Panic
+        /// The node is borrowed
+        pub fn borrow_mut(&self)->NodeRefMut<'_, N>{
+            let lock = match self.lock.try_write(){
+                Ok(lock)=>lock,
+                Err(TryLockError::Poisoned(poisoned))=>poisoned.into_inner(),
+                Err(TryLockError::WouldBlock)=>panic!("Error! Node already borrowed!")
+            };
+            // SAFETY: `lock` holds the write lock for as long as the returned guard lives, so
+            // no other guard, and no other reference to the node, exists meanwhile. The
+            // reference cannot outlive `self`, which owns the node.
+            NodeRefMut{node: unsafe{ &mut *self.node.get() }, lock}
+        }
+
+        /// Raw pointer to the node, without borrowing it
+        pub fn as_ptr(&self)->*mut N{
+            self.node.get()
+        }
+    }
+
+    impl<N: ?Sized+PartialEq> PartialEq for NodeCell<N>{
+        fn eq(&self, other: &Self)->bool{
+            *self.borrow() == *other.borrow()
+        }
+    }
+
+    impl<N: ?Sized+Debug> Debug for NodeCell<N>{
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>)->core::fmt::Result{
+            match self.lock.try_read(){
+                // SAFETY: `_lock` holds the read lock while the node is formatted, so no
+                // `&mut N` exists meanwhile
+                Ok(_lock)=>f.debug_struct("NodeCell").field("node", &unsafe{ &*self.node.get() }).finish(),
+                Err(_)=>f.write_str("NodeCell { <borrowed> }")
+            }
+        }
+    }
+
+    impl<'a, N: ?Sized> NodeRef<'a, N>{
+        /// Narrow the borrow to the part of the node selected by `f`
+        pub fn map<U: ?Sized>(orig: Self, f: impl FnOnce(&N)->&U)->NodeRef<'a, U>{
+            NodeRef{node: f(orig.node), lock: orig.lock}
+        }
+    }
+
+    impl<'a, N: ?Sized> NodeRefMut<'a, N>{
+        /// Narrow the borrow to the part of the node selected by `f`
+        pub fn map<U: ?Sized>(orig: Self, f: impl FnOnce(&mut N)->&mut U)->NodeRefMut<'a, U>{
+            NodeRefMut{node: f(orig.node), lock: orig.lock}
+        }
+    }
+
+    impl<N: ?Sized> Deref for NodeRef<'_, N>{
+        type Target = N;
+        fn deref(&self)->&N{
+            self.node
+        }
+    }
+
+    impl<N: ?Sized> Deref for NodeRefMut<'_, N>{
+        type Target = N;
+        fn deref(&self)->&N{
+            self.node
+        }
+    }
+
+    impl<N: ?Sized> DerefMut for NodeRefMut<'_, N>{
+        fn deref_mut(&mut self)->&mut N{
+            self.node
+        }
+    }
+}
+
+/// Keeps a node alive while one of its fields is borrowed
+trait KeepAlive{}
+impl<X: ?Sized> KeepAlive for X{}
+
+/// Shared borrow of a value stored in a tree node
+///
+/// Returned by lookups. The node stays borrowed until the guard is dropped.
+pub struct ValueRef<'a, U: ?Sized>{
+    // declared before `_node` so the borrow is released before the node
+    guard: NodeRef<'a, U>,
+    _node: Shared<dyn KeepAlive+'a>,
+}
+
+impl<'a, U: ?Sized> ValueRef<'a, U>{
+    /// Borrow the part of `node` selected by `f`
+    pub(crate) fn new<N: 'a>(node: Shared<NodeCell<N>>, f: impl FnOnce(&N)->&U)->Self{
+        // SAFETY: the cell lives in the heap allocation of `node`, which does not move when
+        // `node` or the guard is moved. The guard keeps a strong reference to it in `_node`,
+        // which is dropped after `guard` as fields drop in declaration order, so the cell
+        // outlives every use of this reference. Only `guard` uses the reference, and the
+        // borrow it holds keeps the aliasing rules of the cell.
+        let cell: &'a NodeCell<N> = unsafe{ &*Shared::as_ptr(&node) };
+        ValueRef{guard: NodeRef::map(cell.borrow(), f), _node: node}
+    }
+}
+
+impl<U: ?Sized> Deref for ValueRef<'_, U>{
+    type Target = U;
+    fn deref(&self)->&U{
+        &self.guard
+    }
+}
+
+impl<U: ?Sized+Debug> Debug for ValueRef<'_, U>{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>)->core::fmt::Result{
+        (*self.guard).fmt(f)
+    }
+}
+
+/// Mutable borrow of a value stored in a tree node
+///
+/// Returned by `get_mut` on the maps. The node stays borrowed until the guard is dropped.
+pub struct ValueMut<'a, U: ?Sized>{
+    // declared before `_node` so the borrow is released before the node
+    guard: NodeRefMut<'a, U>,
+    _node: Shared<dyn KeepAlive+'a>,
+}
+
+impl<'a, U: ?Sized> ValueMut<'a, U>{
+    /// Mutably borrow the part of `node` selected by `f`
+    pub(crate) fn new<N: 'a>(node: Shared<NodeCell<N>>, f: impl FnOnce(&mut N)->&mut U)->Self{
+        // SAFETY: the reference only extends the lifetime of the shared `&NodeCell`, as in
+        // ValueRef::new: `_node` keeps the heap allocation of the cell alive until after
+        // `guard` is dropped. Exclusive access comes from the write borrow `guard` holds,
+        // not from this reference.
+        let cell: &'a NodeCell<N> = unsafe{ &*Shared::as_ptr(&node) };
+        ValueMut{guard: NodeRefMut::map(cell.borrow_mut(), f), _node: node}
+    }
+}
+
+impl<U: ?Sized> Deref for ValueMut<'_, U>{
+    type Target = U;
+    fn deref(&self)->&U{
+        &self.guard
+    }
+}
+
+impl<U: ?Sized> DerefMut for ValueMut<'_, U>{
+    fn deref_mut(&mut self)->&mut U{
+        &mut self.guard
+    }
+}
+
+impl<U: ?Sized+Debug> Debug for ValueMut<'_, U>{
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>)->core::fmt::Result{
+        (*self.guard).fmt(f)
+    }
+}
+
+/// Lazy in-order iterator over the values of a tree
+///
+/// Holds only the next node from each end and walks the parent pointers,
+/// so creating it costs O(log n) and each step O(1) amortized.
+pub struct Iter<'a, T, N>{
+    front: Option<Shared<NodeCell<N>>>,
+    back: Option<Shared<NodeCell<N>>>,
+    len: usize,
+    _marker: PhantomData<&'a T>,
+}
+
+impl<'a, T: Debug+'a, N: TreeNodeTrait<T>+'a> Iter<'a, T, N>{
+    /// Iterate over the `len` values of the tree rooted at `root`
+    pub(crate) fn new(root: Option<Shared<NodeCell<N>>>, len: usize)->Self{
+        Iter{
+            front: root.clone().map(get_min_node),
+            back: root.map(get_max_node),
+            len,
+            _marker: PhantomData
+        }
+    }
+}
+
+impl<T, N> Clone for Iter<'_, T, N>{
+    fn clone(&self)->Self{
+        Iter{front: self.front.clone(), back: self.back.clone(), len: self.len, _marker: PhantomData}
+    }
+}
+
+impl<'a, T: Debug+'a, N: TreeNodeTrait<T>+'a> Iterator for Iter<'a, T, N>{
+    type Item = ValueRef<'a, T>;
+
+    fn next(&mut self)->Option<ValueRef<'a, T>>{
+        if self.len == 0{
+            return None;
+        }
+        let node = self.front.take()?;
+        self.len -= 1;
+        self.front = get_next_node(&node);
+        Some(ValueRef::new(node, |nd: &N| nd.value()))
+    }
+
+    fn size_hint(&self)->(usize, Option<usize>){
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a, T: Debug+'a, N: TreeNodeTrait<T>+'a> DoubleEndedIterator for Iter<'a, T, N>{
+    fn next_back(&mut self)->Option<ValueRef<'a, T>>{
+        if self.len == 0{
+            return None;
+        }
+        let node = self.back.take()?;
+        self.len -= 1;
+        self.back = get_prev_node(&node);
+        Some(ValueRef::new(node, |nd: &N| nd.value()))
+    }
+}
+
+impl<'a, T: Debug+'a, N: TreeNodeTrait<T>+'a> ExactSizeIterator for Iter<'a, T, N>{}
+
+/// Lazy in-order iterator over a tree that stores further copies of its value in each node
+///
+/// Yields the value of every node followed by the copies `copies` returns for it,
+/// walking the parent pointers like Iter.
+pub struct MultiIter<'a, T, N>{
+    front: Option<Shared<NodeCell<N>>>,
+    back: Option<Shared<NodeCell<N>>>,
+    /// Copies of the front and back values not yielded yet
+    front_left: usize,
+    back_left: usize,
+    len: usize,
+    copies: fn(&N)->&[T],
+    _marker: PhantomData<&'a T>,
+}
+
+impl<'a, T: Debug+'a, N: TreeNodeTrait<T>+'a> MultiIter<'a, T, N>{
+    /// Iterate over the `len` values of the tree rooted at `root` and their copies
+    pub(crate) fn new(root: Option<Shared<NodeCell<N>>>, len: usize, copies: fn(&N)->&[T])->Self{
+        let front = root.clone().map(get_min_node);
+        let back = root.map(get_max_node);
+        let mut iter = MultiIter{front, back, front_left: 0, back_left: 0, len, copies, _marker: PhantomData};
+        iter.front_left = iter.held(&iter.front);
+        iter.back_left = iter.held(&iter.back);
+        iter
+    }
+
+    /// Borrow the `index`-th copy of the value of `node`, the value itself first
+    fn copy(&self, node: Shared<NodeCell<N>>, index: usize)->ValueRef<'a, T>{
+        let copies = self.copies;
+        ValueRef::new(node, move |nd: &N| match index{
+            0=>nd.value(),
+            _=>&copies(nd)[index - 1]
+        })
+    }
+
+    /// Number of values held by `node`, counting its copies
+    fn held(&self, node: &Option<Shared<NodeCell<N>>>)->usize{
+        node.as_ref().map_or(0, |nd| 1 + (self.copies)(&nd.borrow()).len())
+    }
+}
+
+impl<T, N> Clone for MultiIter<'_, T, N>{
+    fn clone(&self)->Self{
+        MultiIter{front: self.front.clone(), back: self.back.clone(), front_left: self.front_left,
+            back_left: self.back_left, len: self.len, copies: self.copies, _marker: PhantomData}
+    }
+}
+
+impl<'a, T: Debug+'a, N: TreeNodeTrait<T>+'a> Iterator for MultiIter<'a, T, N>{
+    type Item = ValueRef<'a, T>;
+
+    fn next(&mut self)->Option<ValueRef<'a, T>>{
+        if self.len == 0{
+            return None;
+        }
+        let node = self.front.clone()?;
+        self.len -= 1;
+        let index = self.held(&self.front) - self.front_left;
+        self.front_left -= 1;
+        if self.front_left == 0{
+            self.front = get_next_node(&node);
+            self.front_left = self.held(&self.front);
+        }
+        Some(self.copy(node, index))
+    }
+
+    fn size_hint(&self)->(usize, Option<usize>){
+        (self.len, Some(self.len))
+    }
+}
+
+impl<'a, T: Debug+'a, N: TreeNodeTrait<T>+'a> DoubleEndedIterator for MultiIter<'a, T, N>{
+    fn next_back(&mut self)->Option<ValueRef<'a, T>>{
+        if self.len == 0{
+            return None;
+        }
+        let node = self.back.clone()?;
+        self.len -= 1;
+        self.back_left -= 1;
+        let index = self.back_left;
+        if self.back_left == 0{
+            self.back = get_prev_node(&node);
+            self.back_left = self.held(&self.back);
+        }
+        Some(self.copy(node, index))
+    }
+}
+
+impl<'a, T: Debug+'a, N: TreeNodeTrait<T>+'a> ExactSizeIterator for MultiIter<'a, T, N>{}
+
+/// Lazy in-order iterator over the values of a tree within a range
+///
+/// Both ends are found by search_bound, then the iterator walks the
+/// parent pointers like Iter until the two ends meet.
+pub struct Range<'a, T, N>{
+    front: Option<Shared<NodeCell<N>>>,
+    back: Option<Shared<NodeCell<N>>>,
+    _marker: PhantomData<&'a T>,
+}
+
+impl<'a, T: Debug+'a, N: TreeNodeTrait<T>+'a> Range<'a, T, N>{
+    /// Iterate over the values of the tree rooted at `root` within `range`
+    pub(crate) fn new<R: RangeBounds<T>, C: Comparator<T>>(root: Option<Shared<NodeCell<N>>>, range: R, cmp: &C)->Self{
+        let front = search_bound(root.clone(), range.start_bound(), &Direction::Right, cmp);
+        let back = search_bound(root, range.end_bound(), &Direction::Left, cmp);
+        let (front, back) = match (front, back){
+            (Some(f), Some(b)) if cmp.compare(f.borrow().value(), b.borrow().value()) != Ordering::Greater=>
+                (Some(f), Some(b)),
+            _=>(None, None)
+        };
+        Range{front, back, _marker: PhantomData}
+    }
+}
+
+impl<T, N> Clone for Range<'_, T, N>{
+    fn clone(&self)->Self{
+        Range{front: self.front.clone(), back: self.back.clone(), _marker: PhantomData}
+    }
+}
+
+impl<'a, T: Debug+'a, N: TreeNodeTrait<T>+'a> Iterator for Range<'a, T, N>{
+    type Item = ValueRef<'a, T>;
+
+    fn next(&mut self)->Option<ValueRef<'a, T>>{
+        let node = self.front.take()?;
+        if Shared::ptr_eq(&node, self.back.as_ref().unwrap()){
+            self.back = None;
+        }
+        else{
+            self.front = get_next_node(&node);
+        }
+        Some(ValueRef::new(node, |nd: &N| nd.value()))
+    }
+}
+
+impl<'a, T: Debug+'a, N: TreeNodeTrait<T>+'a> DoubleEndedIterator for Range<'a, T, N>{
+    fn next_back(&mut self)->Option<ValueRef<'a, T>>{
+        let node = self.back.take()?;
+        if Shared::ptr_eq(&node, self.front.as_ref().unwrap()){
+            self.front = None;
+        }
+        else{
+            self.back = get_prev_node(&node);
+        }
+        Some(ValueRef::new(node, |nd: &N| nd.value()))
+    }
+}
+
+/// Set operation computed by SetIter
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SetOperation{
+    Union,
+    Intersection,
+    Difference,
+    SymmetricDifference
+}
+
+/// Lazy in-order merge of two trees for a set operation
+///
+/// Walks both trees side by side, O(n + m)
+pub struct SetIter<'a, T: Debug+'a, N: TreeNodeTrait<T>+'a, C>{
+    left: Peekable<Iter<'a, T, N>>,
+    right: Peekable<Iter<'a, T, N>>,
+    cmp: &'a C,
+    operation: SetOperation,
+}
+
+impl<'a, T: Debug+'a, N: TreeNodeTrait<T>+'a, C: Comparator<T>> SetIter<'a, T, N, C>{
+    fn new(left: Iter<'a, T, N>, right: Iter<'a, T, N>, cmp: &'a C, operation: SetOperation)->Self{
+        SetIter{left: left.peekable(), right: right.peekable(), cmp, operation}
+    }
+}
+
+impl<'a, T: Debug+'a, N: TreeNodeTrait<T>+'a, C: Comparator<T>> Iterator for SetIter<'a, T, N, C>{
+    type Item = ValueRef<'a, T>;
+
+    fn next(&mut self)->Option<ValueRef<'a, T>>{
+        loop{
+            let ordering = match (self.left.peek(), self.right.peek()){
+                (None, None)=>return None,
+                (None, Some(_))=>match self.operation{
+                    SetOperation::Intersection | SetOperation::Difference=>return None,
+                    _=>Ordering::Greater
+                },
+                (Some(_), None)=>match self.operation{
+                    SetOperation::Intersection=>return None,
+                    _=>Ordering::Less
+                },
+                (Some(left), Some(right))=>self.cmp.compare(left, right)
+            };
+            match ordering{
+                Ordering::Less=>{
+                    let value = self.left.next();
+                    if self.operation != SetOperation::Intersection{
+                        return value;
+                    }
+                },
+                Ordering::Greater=>{
+                    let value = self.right.next();
+                    if self.operation == SetOperation::Union || self.operation == SetOperation::SymmetricDifference{
+                        return value;
+                    }
+                },
+                Ordering::Equal=>{
+                    let value = self.left.next();
+                    self.right.next();
+                    if self.operation == SetOperation::Union || self.operation == SetOperation::Intersection{
+                        return value;
+                    }
+                }
+            }
+        }
+    }
 }